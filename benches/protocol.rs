@@ -0,0 +1,355 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+use async_trait::async_trait;
+use criterion::{
+    criterion_group, criterion_main, BenchmarkId, Criterion, Throughput,
+};
+use dbus::channel::Sender;
+use dbus::Message;
+use dbus_crossroads::Crossroads;
+use identity_dbus_broker::fuzzing::{
+    decode_chunks, decode_chunks_in, decode_response_in, encode_request_in,
+    encode_response, encode_response_in, ClientRequest, ClientResponse,
+};
+use identity_dbus_broker::{
+    himmelblau_broker_serve, register_device_broker, BrokerError, DeviceBroker,
+    HimmelblauBroker, WireFormatKind,
+};
+use libc::uid_t;
+use std::cell::RefCell;
+use std::path::PathBuf;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+use tokio::runtime::Runtime;
+use tokio::sync::broadcast;
+
+const TOKEN_RESPONSE: &str = r#"{"brokerTokenResponse":{"accessToken":"eyJ0eXAiOiJKV1QiLCJhbGciOiJSUzI1NiJ9.e30.c2lnbmF0dXJl","tokenType":"Bearer","expiresOn":1700000000,"grantedScopes":"https://graph.microsoft.com/.default"}}"#;
+
+fn request(request_json: &str) -> ClientRequest {
+    ClientRequest::acquireTokenSilently(
+        "1.0".to_string(),
+        "c0ffee00-0000-0000-0000-000000000000".to_string(),
+        request_json.to_string(),
+    )
+}
+
+fn request_json(size: usize) -> String {
+    format!(
+        r#"{{"authParameters":{{"clientId":"d7b530a4-7680-4c23-a8bf-c52c121d2e87","authority":"https://login.microsoftonline.com/common","requestedScopes":["{}"]}}}}"#,
+        "x".repeat(size)
+    )
+}
+
+fn codec(c: &mut Criterion) {
+    let mut group = c.benchmark_group("codec");
+    for size in [256, 4096, 65536] {
+        let data = serde_json::to_vec(&request(&request_json(size))).unwrap();
+        group.throughput(Throughput::Bytes(data.len() as u64));
+        group.bench_with_input(
+            BenchmarkId::new("decode", size),
+            &data,
+            |b, data| b.iter(|| decode_chunks(usize::MAX, &[data])),
+        );
+        let chunks: Vec<&[u8]> = data.chunks(1024).collect();
+        group.bench_with_input(
+            BenchmarkId::new("decode_split", size),
+            &chunks,
+            |b, chunks| b.iter(|| decode_chunks(usize::MAX, chunks)),
+        );
+    }
+    let pipelined: Vec<u8> = (0..32)
+        .flat_map(|_| serde_json::to_vec(&request(&request_json(256))).unwrap())
+        .collect();
+    group.throughput(Throughput::Bytes(pipelined.len() as u64));
+    group.bench_function("decode_pipelined", |b| {
+        b.iter(|| decode_chunks(usize::MAX, &[&pipelined]))
+    });
+    group.throughput(Throughput::Bytes(TOKEN_RESPONSE.len() as u64));
+    group.bench_function("encode", |b| {
+        b.iter(|| {
+            encode_response(ClientResponse::result(TOKEN_RESPONSE.to_string()))
+                .unwrap()
+        })
+    });
+    group.finish();
+}
+
+/* Compare the wire formats compiled in (build with --features cbor,bincode
+ * to include them all) on the frames which dominate traffic: a large
+ * request, and a token response.
+ */
+fn wire_formats(c: &mut Criterion) {
+    let mut group = c.benchmark_group("wire_format");
+    let req = request(&request_json(4096));
+    let resp = ClientResponse::result(TOKEN_RESPONSE.to_string());
+    for format in WireFormatKind::available() {
+        let name = format!("{:?}", format);
+        let data = encode_request_in(format, &req).unwrap();
+        group.throughput(Throughput::Bytes(data.len() as u64));
+        group.bench_with_input(
+            BenchmarkId::new("decode_request", &name),
+            &data,
+            |b, data| b.iter(|| decode_chunks_in(format, usize::MAX, &[data])),
+        );
+        let encoded = encode_response_in(format, resp.clone()).unwrap();
+        group.throughput(Throughput::Bytes(encoded.len() as u64));
+        group.bench_function(BenchmarkId::new("encode_response", &name), |b| {
+            b.iter(|| encode_response_in(format, resp.clone()).unwrap())
+        });
+        group.bench_with_input(
+            BenchmarkId::new("decode_response", &name),
+            &encoded,
+            |b, encoded| {
+                b.iter(|| decode_response_in(format, encoded).unwrap())
+            },
+        );
+    }
+    group.finish();
+}
+
+#[derive(Clone)]
+struct StaticBroker;
+
+#[async_trait]
+impl HimmelblauBroker for StaticBroker {
+    async fn acquire_token_interactively(
+        &mut self,
+        _protocol_version: String,
+        _correlation_id: String,
+        _request_json: String,
+        _uid: uid_t,
+    ) -> Result<String, BrokerError> {
+        Ok(TOKEN_RESPONSE.to_string())
+    }
+    async fn acquire_token_silently(
+        &mut self,
+        _protocol_version: String,
+        _correlation_id: String,
+        _request_json: String,
+        _uid: uid_t,
+    ) -> Result<String, BrokerError> {
+        Ok(TOKEN_RESPONSE.to_string())
+    }
+    async fn get_accounts(
+        &mut self,
+        _protocol_version: String,
+        _correlation_id: String,
+        _request_json: String,
+        _uid: uid_t,
+    ) -> Result<String, BrokerError> {
+        Ok(r#"{"accounts":[]}"#.to_string())
+    }
+    async fn remove_account(
+        &mut self,
+        _protocol_version: String,
+        _correlation_id: String,
+        _request_json: String,
+        _uid: uid_t,
+    ) -> Result<String, BrokerError> {
+        Ok("{}".to_string())
+    }
+    async fn acquire_prt_sso_cookie(
+        &mut self,
+        _protocol_version: String,
+        _correlation_id: String,
+        _request_json: String,
+        _uid: uid_t,
+    ) -> Result<String, BrokerError> {
+        Ok("{}".to_string())
+    }
+    async fn generate_signed_http_request(
+        &mut self,
+        _protocol_version: String,
+        _correlation_id: String,
+        _request_json: String,
+        _uid: uid_t,
+    ) -> Result<String, BrokerError> {
+        Ok("{}".to_string())
+    }
+    async fn cancel_interactive_flow(
+        &mut self,
+        _protocol_version: String,
+        _correlation_id: String,
+        _request_json: String,
+        _uid: uid_t,
+    ) -> Result<String, BrokerError> {
+        Ok("{}".to_string())
+    }
+    async fn get_linux_broker_version(
+        &mut self,
+        _protocol_version: String,
+        _correlation_id: String,
+        _request_json: String,
+        _uid: uid_t,
+    ) -> Result<String, BrokerError> {
+        Ok(r#"{"linuxBrokerVersion":"0.1.3"}"#.to_string())
+    }
+}
+
+async fn round_trip(sock_path: &PathBuf, data: &[u8]) -> ClientResponse {
+    let mut stream = UnixStream::connect(sock_path).await.unwrap();
+    stream.write_all(data).await.unwrap();
+    let mut buf = vec![];
+    loop {
+        let mut chunk = [0; 4096];
+        let count = stream.read(&mut chunk).await.unwrap();
+        assert!(count > 0, "broker closed the connection");
+        buf.extend_from_slice(&chunk[..count]);
+        if let Ok(resp) = serde_json::from_slice(&buf) {
+            return resp;
+        }
+    }
+}
+
+fn socket(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let sock_path = std::env::temp_dir().join(format!(
+        "identity_dbus_broker-bench-{}.sock",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&sock_path);
+    let (tx, rx) = broadcast::channel(1);
+    let server = rt
+        .block_on(himmelblau_broker_serve(
+            StaticBroker,
+            sock_path.to_str().unwrap(),
+            rx,
+        ))
+        .unwrap();
+    let data = serde_json::to_vec(&request(&request_json(256))).unwrap();
+
+    let mut group = c.benchmark_group("socket_round_trip");
+    for clients in [1, 8, 32] {
+        group.throughput(Throughput::Elements(clients as u64));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(clients),
+            &clients,
+            |b, &clients| {
+                b.to_async(&rt).iter(|| async {
+                    let tasks: Vec<_> = (0..clients)
+                        .map(|_| {
+                            let sock_path = sock_path.clone();
+                            let data = data.clone();
+                            tokio::spawn(async move {
+                                round_trip(&sock_path, &data).await
+                            })
+                        })
+                        .collect();
+                    for task in tasks {
+                        task.await.unwrap();
+                    }
+                })
+            },
+        );
+    }
+    group.finish();
+
+    let _ = tx.send(true);
+    let _ = rt.block_on(server);
+    let _ = std::fs::remove_file(&sock_path);
+}
+
+struct StaticDeviceBroker;
+
+macro_rules! static_device_methods {
+    ($($name:ident),*) => {
+        $(
+            fn $name(
+                &mut self,
+                _session_id: String,
+                _request_json: String,
+            ) -> Result<String, dbus::MethodErr> {
+                Ok("{}".to_string())
+            }
+        )*
+    };
+}
+
+impl DeviceBroker for StaticDeviceBroker {
+    static_device_methods!(
+        sign,
+        generate_key_pair,
+        load_key_pair,
+        persist_key,
+        generate_derived_key,
+        delete_key,
+        decrypt,
+        generate_pkcs10_cert_signing_request,
+        asymmetric_key_exists,
+        asymmetric_key_with_thumbprint_exists,
+        get_asymmetric_key_thumbprint,
+        generate_asymmetric_key,
+        get_asymmetric_key_creation_date,
+        clear_asymmetric_key,
+        get_request_confirmation,
+        mint_signed_access_token,
+        mint_signed_http_request,
+        make_http_request_with_client_tls
+    );
+}
+
+/* Keeps the last reply in place of a bus connection. */
+#[derive(Default)]
+struct LastReply(RefCell<Option<Message>>);
+
+impl Sender for LastReply {
+    fn send(&self, msg: Message) -> Result<u32, ()> {
+        *self.0.borrow_mut() = Some(msg);
+        Ok(0)
+    }
+}
+
+fn device_call(method: &str) -> Message {
+    let mut msg = Message::new_method_call(
+        "com.microsoft.identity.DeviceBroker1",
+        "/com/microsoft/identity/devicebroker1",
+        "com.microsoft.identity.DeviceBroker1",
+        method,
+    )
+    .unwrap();
+    msg.set_sender(Some(":1.1".into()));
+    msg.set_serial(1);
+    msg
+}
+
+fn dispatch(c: &mut Criterion) {
+    let mut cr = Crossroads::new();
+    let token = register_device_broker(&mut cr);
+    cr.insert(
+        "/com/microsoft/identity/devicebroker1",
+        &[token],
+        StaticDeviceBroker,
+    );
+    let reply = LastReply::default();
+    cr.handle_message(device_call("openSession"), &reply)
+        .unwrap();
+    let session_id: String =
+        reply.0.borrow_mut().take().unwrap().read1().unwrap();
+    let msg = device_call("sign").append2(session_id, request_json(256));
+
+    c.bench_function("dbus_dispatch", |b| {
+        b.iter(|| {
+            let mut msg = msg.duplicate().unwrap();
+            msg.set_serial(1);
+            cr.handle_message(msg, &reply).unwrap()
+        })
+    });
+}
+
+criterion_group!(benches, codec, wire_formats, socket, dispatch);
+criterion_main!(benches);
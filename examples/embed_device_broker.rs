@@ -0,0 +1,96 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Mount a device broker on a system daemon's existing Crossroads instance
+//! and connection. This broker supports no operations; see `DeviceKeyStore`
+//! for the key storage a real one builds on.
+
+use dbus::blocking::Connection;
+use dbus_crossroads::Crossroads;
+use identity_dbus_broker::{
+    device_broker_add_to_crossroads, not_supported, Capabilities, DeviceBroker,
+    ServeOptions,
+};
+use std::error::Error;
+
+struct Broker;
+
+macro_rules! unsupported {
+    ($($method:ident => $name:literal),* $(,)?) => {
+        $(
+            fn $method(
+                &mut self,
+                _session_id: String,
+                _request_json: String,
+            ) -> Result<String, dbus::MethodErr> {
+                Err(not_supported($name))
+            }
+        )*
+    };
+}
+
+impl DeviceBroker for Broker {
+    unsupported! {
+        sign => "sign",
+        generate_key_pair => "generateKeyPair",
+        load_key_pair => "loadKeyPair",
+        persist_key => "persistKey",
+        generate_derived_key => "generateDerivedKey",
+        delete_key => "deleteKey",
+        decrypt => "decrypt",
+        generate_pkcs10_cert_signing_request =>
+            "generatePKCS10CertSigningRequest",
+        asymmetric_key_exists => "asymmetricKeyExists",
+        asymmetric_key_with_thumbprint_exists =>
+            "asymmetricKeyWithThumbprintExists",
+        get_asymmetric_key_thumbprint => "getAsymmetricKeyThumbprint",
+        generate_asymmetric_key => "generateAsymmetricKey",
+        get_asymmetric_key_creation_date => "getAsymmetricKeyCreationDate",
+        clear_asymmetric_key => "clearAsymmetricKey",
+        get_request_confirmation => "getRequestConfirmation",
+        mint_signed_access_token => "mintSignedAccessToken",
+        mint_signed_http_request => "mintSignedHttpRequest",
+        make_http_request_with_client_tls => "makeHttpRequestWithClientTls",
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::new([])
+    }
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let c = Connection::new_system()?;
+    let mut cr = Crossroads::new();
+
+    // The daemon's own object.
+    let status = cr.register("org.example.DeviceDaemon1", |b| {
+        b.method("Status", (), ("status",), |_, _, ()| {
+            Ok(("running".to_string(),))
+        });
+    });
+    cr.insert("/org/example/DeviceDaemon1", &[status], ());
+
+    // The broker, at /com/microsoft/identity/devicebroker1. Sessions are
+    // released as their callers leave the bus, which is watched on `c`.
+    device_broker_add_to_crossroads(&c, &mut cr, Broker, &ServeOptions::new())?;
+    c.request_name("org.example.DeviceDaemon1", false, true, false)?;
+    c.request_name("com.microsoft.identity.DeviceBroker1", false, true, false)?;
+
+    cr.serve(&c)?;
+    Ok(())
+}
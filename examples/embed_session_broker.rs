@@ -0,0 +1,131 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Mount a session broker alongside a daemon's own D-Bus object, on a
+//! Crossroads instance and connection the daemon already serves.
+
+use dbus::blocking::Connection;
+use dbus_crossroads::Crossroads;
+use identity_dbus_broker::{
+    session_broker_add_to_crossroads, ServeOptions, SessionBroker,
+};
+use std::error::Error;
+
+struct Broker;
+
+impl Broker {
+    fn interaction_required() -> Result<String, dbus::MethodErr> {
+        Ok(r#"{"error":{"status":"InteractionRequired"}}"#.to_string())
+    }
+}
+
+impl SessionBroker for Broker {
+    fn acquire_token_interactively(
+        &mut self,
+        _: String,
+        _: String,
+        _: String,
+    ) -> Result<String, dbus::MethodErr> {
+        Broker::interaction_required()
+    }
+
+    fn acquire_token_silently(
+        &mut self,
+        _: String,
+        _: String,
+        _: String,
+    ) -> Result<String, dbus::MethodErr> {
+        Broker::interaction_required()
+    }
+
+    fn get_accounts(
+        &mut self,
+        _: String,
+        _: String,
+        _: String,
+    ) -> Result<String, dbus::MethodErr> {
+        Ok(r#"{"accounts":[]}"#.to_string())
+    }
+
+    fn remove_account(
+        &mut self,
+        _: String,
+        _: String,
+        _: String,
+    ) -> Result<String, dbus::MethodErr> {
+        Ok("{}".to_string())
+    }
+
+    fn acquire_prt_sso_cookie(
+        &mut self,
+        _: String,
+        _: String,
+        _: String,
+    ) -> Result<String, dbus::MethodErr> {
+        Broker::interaction_required()
+    }
+
+    fn generate_signed_http_request(
+        &mut self,
+        _: String,
+        _: String,
+        _: String,
+    ) -> Result<String, dbus::MethodErr> {
+        Broker::interaction_required()
+    }
+
+    fn cancel_interactive_flow(
+        &mut self,
+        _: String,
+        _: String,
+        _: String,
+    ) -> Result<String, dbus::MethodErr> {
+        Ok("{}".to_string())
+    }
+
+    fn get_linux_broker_version(
+        &mut self,
+        _: String,
+        _: String,
+        _: String,
+    ) -> Result<String, dbus::MethodErr> {
+        Ok(r#"{"linuxBrokerVersion":"0.0.0"}"#.to_string())
+    }
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let c = Connection::new_session()?;
+    let mut cr = Crossroads::new();
+
+    // The daemon's own object.
+    let status = cr.register("org.example.Daemon1", |b| {
+        b.method("Status", (), ("status",), |_, _, ()| {
+            Ok(("running".to_string(),))
+        });
+    });
+    cr.insert("/org/example/Daemon1", &[status], ());
+
+    // The broker, at /com/microsoft/identity/broker1. Its name has to be
+    // requested by the daemon.
+    session_broker_add_to_crossroads(&mut cr, Broker, &ServeOptions::new());
+    c.request_name("org.example.Daemon1", false, true, false)?;
+    c.request_name("com.microsoft.identity.broker1", false, true, false)?;
+
+    cr.serve(&c)?;
+    Ok(())
+}
@@ -0,0 +1,16 @@
+#![no_main]
+
+use identity_dbus_broker::fuzzing::decode_chunks;
+use libfuzzer_sys::fuzz_target;
+
+/* Raw bytes from an untrusted local client. The first byte picks the read
+ * size, so the same input is also decoded as a run of split reads.
+ */
+fuzz_target!(|data: &[u8]| {
+    if let Some((size, data)) = data.split_first() {
+        decode_chunks(4096, &[data]);
+        let chunks: Vec<&[u8]> =
+            data.chunks(usize::from(*size).max(1)).collect();
+        decode_chunks(4096, &chunks);
+    }
+});
@@ -0,0 +1,24 @@
+#![no_main]
+
+use identity_dbus_broker::fuzzing::{
+    decode_chunks, encode_response, ClientRequest, ClientResponse, Decoded,
+};
+use libfuzzer_sys::fuzz_target;
+
+/* Every well formed request must survive serialization and decode in order
+ * when pipelined, and every response must survive the server codec.
+ */
+fuzz_target!(|input: (Vec<ClientRequest>, ClientResponse)| {
+    let (reqs, resp) = input;
+    let data: Vec<u8> = reqs
+        .iter()
+        .flat_map(|req| serde_json::to_vec(req).unwrap())
+        .collect();
+    let expected: Vec<Decoded> =
+        reqs.into_iter().map(Decoded::Request).collect();
+    assert_eq!(decode_chunks(usize::MAX, &[&data]), expected);
+
+    let encoded = encode_response(resp.clone()).unwrap();
+    let decoded: ClientResponse = serde_json::from_slice(&encoded).unwrap();
+    assert_eq!(decoded, resp);
+});
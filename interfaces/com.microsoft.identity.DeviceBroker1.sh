@@ -0,0 +1,129 @@
+#!/bin/sh
+# Generated by himmelblau-broker-introspect. Do not edit.
+# Session ids are only accepted from the connection which opened them, and
+# each gdbus call makes a connection of its own, so the calls taking a
+# session_id show their signatures rather than a working sequence.
+
+# com.microsoft.identity.DeviceBroker1.asymmetricKeyExists
+gdbus call --system --dest com.microsoft.identity.DeviceBroker1 \
+    --object-path /com/microsoft/identity/devicebroker1 \
+    --method com.microsoft.identity.DeviceBroker1.asymmetricKeyExists \
+    "$SESSION_ID" '{}'
+
+# com.microsoft.identity.DeviceBroker1.asymmetricKeyWithThumbprintExists
+gdbus call --system --dest com.microsoft.identity.DeviceBroker1 \
+    --object-path /com/microsoft/identity/devicebroker1 \
+    --method com.microsoft.identity.DeviceBroker1.asymmetricKeyWithThumbprintExists \
+    "$SESSION_ID" '{}'
+
+# com.microsoft.identity.DeviceBroker1.clearAsymmetricKey
+gdbus call --system --dest com.microsoft.identity.DeviceBroker1 \
+    --object-path /com/microsoft/identity/devicebroker1 \
+    --method com.microsoft.identity.DeviceBroker1.clearAsymmetricKey \
+    "$SESSION_ID" '{}'
+
+# com.microsoft.identity.DeviceBroker1.closeSession
+gdbus call --system --dest com.microsoft.identity.DeviceBroker1 \
+    --object-path /com/microsoft/identity/devicebroker1 \
+    --method com.microsoft.identity.DeviceBroker1.closeSession \
+    "$SESSION_ID"
+
+# com.microsoft.identity.DeviceBroker1.decrypt
+gdbus call --system --dest com.microsoft.identity.DeviceBroker1 \
+    --object-path /com/microsoft/identity/devicebroker1 \
+    --method com.microsoft.identity.DeviceBroker1.decrypt \
+    "$SESSION_ID" '{}'
+
+# com.microsoft.identity.DeviceBroker1.deleteKey
+gdbus call --system --dest com.microsoft.identity.DeviceBroker1 \
+    --object-path /com/microsoft/identity/devicebroker1 \
+    --method com.microsoft.identity.DeviceBroker1.deleteKey \
+    "$SESSION_ID" '{}'
+
+# com.microsoft.identity.DeviceBroker1.generateAsymmetricKey
+gdbus call --system --dest com.microsoft.identity.DeviceBroker1 \
+    --object-path /com/microsoft/identity/devicebroker1 \
+    --method com.microsoft.identity.DeviceBroker1.generateAsymmetricKey \
+    "$SESSION_ID" '{}'
+
+# com.microsoft.identity.DeviceBroker1.generateDerivedKey
+gdbus call --system --dest com.microsoft.identity.DeviceBroker1 \
+    --object-path /com/microsoft/identity/devicebroker1 \
+    --method com.microsoft.identity.DeviceBroker1.generateDerivedKey \
+    "$SESSION_ID" '{}'
+
+# com.microsoft.identity.DeviceBroker1.generateKeyPair
+gdbus call --system --dest com.microsoft.identity.DeviceBroker1 \
+    --object-path /com/microsoft/identity/devicebroker1 \
+    --method com.microsoft.identity.DeviceBroker1.generateKeyPair \
+    "$SESSION_ID" '{}'
+
+# com.microsoft.identity.DeviceBroker1.generatePKCS10CertSigningRequest
+gdbus call --system --dest com.microsoft.identity.DeviceBroker1 \
+    --object-path /com/microsoft/identity/devicebroker1 \
+    --method com.microsoft.identity.DeviceBroker1.generatePKCS10CertSigningRequest \
+    "$SESSION_ID" '{}'
+
+# com.microsoft.identity.DeviceBroker1.getAsymmetricKeyCreationDate
+gdbus call --system --dest com.microsoft.identity.DeviceBroker1 \
+    --object-path /com/microsoft/identity/devicebroker1 \
+    --method com.microsoft.identity.DeviceBroker1.getAsymmetricKeyCreationDate \
+    "$SESSION_ID" '{}'
+
+# com.microsoft.identity.DeviceBroker1.getAsymmetricKeyThumbprint
+gdbus call --system --dest com.microsoft.identity.DeviceBroker1 \
+    --object-path /com/microsoft/identity/devicebroker1 \
+    --method com.microsoft.identity.DeviceBroker1.getAsymmetricKeyThumbprint \
+    "$SESSION_ID" '{}'
+
+# com.microsoft.identity.DeviceBroker1.getRequestConfirmation
+gdbus call --system --dest com.microsoft.identity.DeviceBroker1 \
+    --object-path /com/microsoft/identity/devicebroker1 \
+    --method com.microsoft.identity.DeviceBroker1.getRequestConfirmation \
+    "$SESSION_ID" '{}'
+
+# com.microsoft.identity.DeviceBroker1.loadKeyPair
+gdbus call --system --dest com.microsoft.identity.DeviceBroker1 \
+    --object-path /com/microsoft/identity/devicebroker1 \
+    --method com.microsoft.identity.DeviceBroker1.loadKeyPair \
+    "$SESSION_ID" '{}'
+
+# com.microsoft.identity.DeviceBroker1.makeHttpRequestWithClientTls
+gdbus call --system --dest com.microsoft.identity.DeviceBroker1 \
+    --object-path /com/microsoft/identity/devicebroker1 \
+    --method com.microsoft.identity.DeviceBroker1.makeHttpRequestWithClientTls \
+    "$SESSION_ID" '{}'
+
+# com.microsoft.identity.DeviceBroker1.mintSignedAccessToken
+gdbus call --system --dest com.microsoft.identity.DeviceBroker1 \
+    --object-path /com/microsoft/identity/devicebroker1 \
+    --method com.microsoft.identity.DeviceBroker1.mintSignedAccessToken \
+    "$SESSION_ID" '{}'
+
+# com.microsoft.identity.DeviceBroker1.mintSignedHttpRequest
+gdbus call --system --dest com.microsoft.identity.DeviceBroker1 \
+    --object-path /com/microsoft/identity/devicebroker1 \
+    --method com.microsoft.identity.DeviceBroker1.mintSignedHttpRequest \
+    "$SESSION_ID" '{}'
+
+# com.microsoft.identity.DeviceBroker1.openSession
+gdbus call --system --dest com.microsoft.identity.DeviceBroker1 \
+    --object-path /com/microsoft/identity/devicebroker1 \
+    --method com.microsoft.identity.DeviceBroker1.openSession
+
+# com.microsoft.identity.DeviceBroker1.persistKey
+gdbus call --system --dest com.microsoft.identity.DeviceBroker1 \
+    --object-path /com/microsoft/identity/devicebroker1 \
+    --method com.microsoft.identity.DeviceBroker1.persistKey \
+    "$SESSION_ID" '{}'
+
+# com.microsoft.identity.DeviceBroker1.sign
+gdbus call --system --dest com.microsoft.identity.DeviceBroker1 \
+    --object-path /com/microsoft/identity/devicebroker1 \
+    --method com.microsoft.identity.DeviceBroker1.sign \
+    "$SESSION_ID" '{}'
+
+# org.himmelblau.BrokerExt1.getCapabilities
+gdbus call --system --dest com.microsoft.identity.DeviceBroker1 \
+    --object-path /com/microsoft/identity/devicebroker1 \
+    --method org.himmelblau.BrokerExt1.getCapabilities
@@ -0,0 +1,215 @@
+#!/bin/sh
+# Generated by himmelblau-broker-introspect. Do not edit.
+
+# com.microsoft.identity.Broker1.acquirePrtSsoCookie
+gdbus call --session --dest com.microsoft.identity.broker1 \
+    --object-path /com/microsoft/identity/broker1 \
+    --method com.microsoft.identity.Broker1.acquirePrtSsoCookie \
+    '0.0' "$(cat /proc/sys/kernel/random/uuid)" '{}'
+
+# com.microsoft.identity.Broker1.acquireTokenInteractively
+gdbus call --session --dest com.microsoft.identity.broker1 \
+    --object-path /com/microsoft/identity/broker1 \
+    --method com.microsoft.identity.Broker1.acquireTokenInteractively \
+    '0.0' "$(cat /proc/sys/kernel/random/uuid)" '{}'
+
+# com.microsoft.identity.Broker1.acquireTokenSilently
+gdbus call --session --dest com.microsoft.identity.broker1 \
+    --object-path /com/microsoft/identity/broker1 \
+    --method com.microsoft.identity.Broker1.acquireTokenSilently \
+    '0.0' "$(cat /proc/sys/kernel/random/uuid)" '{}'
+
+# com.microsoft.identity.Broker1.cancelInteractiveFlow
+gdbus call --session --dest com.microsoft.identity.broker1 \
+    --object-path /com/microsoft/identity/broker1 \
+    --method com.microsoft.identity.Broker1.cancelInteractiveFlow \
+    '0.0' "$(cat /proc/sys/kernel/random/uuid)" '{}'
+
+# com.microsoft.identity.Broker1.generateSignedHttpRequest
+gdbus call --session --dest com.microsoft.identity.broker1 \
+    --object-path /com/microsoft/identity/broker1 \
+    --method com.microsoft.identity.Broker1.generateSignedHttpRequest \
+    '0.0' "$(cat /proc/sys/kernel/random/uuid)" '{}'
+
+# com.microsoft.identity.Broker1.getAccounts
+gdbus call --session --dest com.microsoft.identity.broker1 \
+    --object-path /com/microsoft/identity/broker1 \
+    --method com.microsoft.identity.Broker1.getAccounts \
+    '0.0' "$(cat /proc/sys/kernel/random/uuid)" '{}'
+
+# com.microsoft.identity.Broker1.getLinuxBrokerVersion
+gdbus call --session --dest com.microsoft.identity.broker1 \
+    --object-path /com/microsoft/identity/broker1 \
+    --method com.microsoft.identity.Broker1.getLinuxBrokerVersion \
+    '0.0' "$(cat /proc/sys/kernel/random/uuid)" '{}'
+
+# com.microsoft.identity.Broker1.removeAccount
+gdbus call --session --dest com.microsoft.identity.broker1 \
+    --object-path /com/microsoft/identity/broker1 \
+    --method com.microsoft.identity.Broker1.removeAccount \
+    '0.0' "$(cat /proc/sys/kernel/random/uuid)" '{}'
+
+# org.himmelblau.Broker1.AccountManager.GetDefaultAccount
+gdbus call --session --dest com.microsoft.identity.broker1 \
+    --object-path /com/microsoft/identity/broker1 \
+    --method org.himmelblau.Broker1.AccountManager.GetDefaultAccount
+
+# org.himmelblau.Broker1.AccountManager.ListAccounts
+gdbus call --session --dest com.microsoft.identity.broker1 \
+    --object-path /com/microsoft/identity/broker1 \
+    --method org.himmelblau.Broker1.AccountManager.ListAccounts
+
+# org.himmelblau.Broker1.AccountManager.RemoveAccount
+gdbus call --session --dest com.microsoft.identity.broker1 \
+    --object-path /com/microsoft/identity/broker1 \
+    --method org.himmelblau.Broker1.AccountManager.RemoveAccount \
+    ''
+
+# org.himmelblau.Broker1.AccountManager.SetDefaultAccount
+gdbus call --session --dest com.microsoft.identity.broker1 \
+    --object-path /com/microsoft/identity/broker1 \
+    --method org.himmelblau.Broker1.AccountManager.SetDefaultAccount \
+    ''
+
+# org.himmelblau.Broker1.Accounts.getAccountsPage
+gdbus call --session --dest com.microsoft.identity.broker1 \
+    --object-path /com/microsoft/identity/broker1 \
+    --method org.himmelblau.Broker1.Accounts.getAccountsPage \
+    '0.0' "$(cat /proc/sys/kernel/random/uuid)" '{}'
+
+# org.himmelblau.Broker1.Accounts.getDefaultAccount
+gdbus call --session --dest com.microsoft.identity.broker1 \
+    --object-path /com/microsoft/identity/broker1 \
+    --method org.himmelblau.Broker1.Accounts.getDefaultAccount \
+    '0.0' "$(cat /proc/sys/kernel/random/uuid)" '{}'
+
+# org.himmelblau.Broker1.Accounts.removeAccounts
+gdbus call --session --dest com.microsoft.identity.broker1 \
+    --object-path /com/microsoft/identity/broker1 \
+    --method org.himmelblau.Broker1.Accounts.removeAccounts \
+    '0.0' "$(cat /proc/sys/kernel/random/uuid)" '{}'
+
+# org.himmelblau.Broker1.Accounts.setDefaultAccount
+gdbus call --session --dest com.microsoft.identity.broker1 \
+    --object-path /com/microsoft/identity/broker1 \
+    --method org.himmelblau.Broker1.Accounts.setDefaultAccount \
+    '0.0' "$(cat /proc/sys/kernel/random/uuid)" '{}'
+
+# org.himmelblau.Broker1.Compliance.getDeviceComplianceStatus
+gdbus call --session --dest com.microsoft.identity.broker1 \
+    --object-path /com/microsoft/identity/broker1 \
+    --method org.himmelblau.Broker1.Compliance.getDeviceComplianceStatus \
+    '0.0' "$(cat /proc/sys/kernel/random/uuid)" '{}'
+
+# org.himmelblau.Broker1.DeviceCode.completeDeviceCodeFlow
+gdbus call --session --dest com.microsoft.identity.broker1 \
+    --object-path /com/microsoft/identity/broker1 \
+    --method org.himmelblau.Broker1.DeviceCode.completeDeviceCodeFlow \
+    '0.0' "$(cat /proc/sys/kernel/random/uuid)" '{}'
+
+# org.himmelblau.Broker1.DeviceCode.pollDeviceCodeFlow
+gdbus call --session --dest com.microsoft.identity.broker1 \
+    --object-path /com/microsoft/identity/broker1 \
+    --method org.himmelblau.Broker1.DeviceCode.pollDeviceCodeFlow \
+    '0.0' "$(cat /proc/sys/kernel/random/uuid)" '{}'
+
+# org.himmelblau.Broker1.DeviceCode.startDeviceCodeFlow
+gdbus call --session --dest com.microsoft.identity.broker1 \
+    --object-path /com/microsoft/identity/broker1 \
+    --method org.himmelblau.Broker1.DeviceCode.startDeviceCodeFlow \
+    '0.0' "$(cat /proc/sys/kernel/random/uuid)" '{}'
+
+# org.himmelblau.Broker1.Diagnostics.SetLogLevel
+gdbus call --session --dest com.microsoft.identity.broker1 \
+    --object-path /com/microsoft/identity/broker1 \
+    --method org.himmelblau.Broker1.Diagnostics.SetLogLevel \
+    '' ''
+
+# org.himmelblau.Broker1.Interactive.completeInteractiveFlow
+gdbus call --session --dest com.microsoft.identity.broker1 \
+    --object-path /com/microsoft/identity/broker1 \
+    --method org.himmelblau.Broker1.Interactive.completeInteractiveFlow \
+    '0.0' "$(cat /proc/sys/kernel/random/uuid)" '{}'
+
+# org.himmelblau.Broker1.Maintenance.Enter
+gdbus call --session --dest com.microsoft.identity.broker1 \
+    --object-path /com/microsoft/identity/broker1 \
+    --method org.himmelblau.Broker1.Maintenance.Enter \
+    0 ''
+
+# org.himmelblau.Broker1.Maintenance.Leave
+gdbus call --session --dest com.microsoft.identity.broker1 \
+    --object-path /com/microsoft/identity/broker1 \
+    --method org.himmelblau.Broker1.Maintenance.Leave
+
+# org.himmelblau.Broker1.Ssh.getSshCert
+gdbus call --session --dest com.microsoft.identity.broker1 \
+    --object-path /com/microsoft/identity/broker1 \
+    --method org.himmelblau.Broker1.Ssh.getSshCert \
+    '0.0' "$(cat /proc/sys/kernel/random/uuid)" '{}'
+
+# org.himmelblau.BrokerExt1.getCapabilities
+gdbus call --session --dest com.microsoft.identity.broker1 \
+    --object-path /com/microsoft/identity/broker1 \
+    --method org.himmelblau.BrokerExt1.getCapabilities
+
+# org.himmelblau.TokenBroker.AcquirePrtSsoCookie
+gdbus call --session --dest com.microsoft.identity.broker1 \
+    --object-path /com/microsoft/identity/broker1 \
+    --method org.himmelblau.TokenBroker.AcquirePrtSsoCookie \
+    [] []
+
+# org.himmelblau.TokenBroker.AcquireTokenInteractively
+gdbus call --session --dest com.microsoft.identity.broker1 \
+    --object-path /com/microsoft/identity/broker1 \
+    --method org.himmelblau.TokenBroker.AcquireTokenInteractively \
+    [] []
+
+# org.himmelblau.TokenBroker.AcquireTokenSilently
+gdbus call --session --dest com.microsoft.identity.broker1 \
+    --object-path /com/microsoft/identity/broker1 \
+    --method org.himmelblau.TokenBroker.AcquireTokenSilently \
+    [] []
+
+# org.himmelblau.TokenBroker.CancelInteractiveFlow
+gdbus call --session --dest com.microsoft.identity.broker1 \
+    --object-path /com/microsoft/identity/broker1 \
+    --method org.himmelblau.TokenBroker.CancelInteractiveFlow \
+    [] []
+
+# org.himmelblau.TokenBroker.GenerateSignedHttpRequest
+gdbus call --session --dest com.microsoft.identity.broker1 \
+    --object-path /com/microsoft/identity/broker1 \
+    --method org.himmelblau.TokenBroker.GenerateSignedHttpRequest \
+    [] []
+
+# org.himmelblau.TokenBroker.GetAccounts
+gdbus call --session --dest com.microsoft.identity.broker1 \
+    --object-path /com/microsoft/identity/broker1 \
+    --method org.himmelblau.TokenBroker.GetAccounts \
+    [] []
+
+# org.himmelblau.TokenBroker.GetLinuxBrokerVersion
+gdbus call --session --dest com.microsoft.identity.broker1 \
+    --object-path /com/microsoft/identity/broker1 \
+    --method org.himmelblau.TokenBroker.GetLinuxBrokerVersion \
+    [] []
+
+# org.himmelblau.TokenBroker.RemoveAccount
+gdbus call --session --dest com.microsoft.identity.broker1 \
+    --object-path /com/microsoft/identity/broker1 \
+    --method org.himmelblau.TokenBroker.RemoveAccount \
+    [] []
+
+# The properties of org.himmelblau.Broker1.Diagnostics
+gdbus call --session --dest com.microsoft.identity.broker1 \
+    --object-path /com/microsoft/identity/broker1 \
+    --method org.freedesktop.DBus.Properties.GetAll org.himmelblau.Broker1.Diagnostics
+
+# The properties of org.himmelblau.Broker1.Maintenance
+gdbus call --session --dest com.microsoft.identity.broker1 \
+    --object-path /com/microsoft/identity/broker1 \
+    --method org.freedesktop.DBus.Properties.GetAll org.himmelblau.Broker1.Maintenance
+
+# Signals
+gdbus monitor --session --dest com.microsoft.identity.broker1 --object-path /com/microsoft/identity/broker1
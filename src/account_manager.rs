@@ -0,0 +1,254 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::accounts::{Account, DefaultAccount};
+use crate::caller::CallerInfo;
+use dbus::arg::{self, PropMap, RefArg, Variant};
+use dbus::blocking::Connection;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// The account management interface, for desktop settings panels.
+pub const ACCOUNT_MANAGER_INTERFACE: &str =
+    "org.himmelblau.Broker1.AccountManager";
+
+/// The polkit action which removing accounts and setting the default
+/// account require by default.
+pub const MANAGE_ACCOUNTS_ACTION: &str =
+    "org.himmelblau.broker.manage-accounts";
+
+/* The account manager offers getAccounts, removeAccount and the default
+ * account with typed arguments in place of JSON strings, so that settings
+ * panels can bind them directly. Each call is made through the broker's
+ * own methods, and so passes the same caller and method policies. Changes
+ * additionally require the caller to be authorized by polkit, which asks
+ * the user's agent for authentication when the action's policy says so.
+ */
+/// Authorization of the account manager's changes (see
+/// `ServeOptions::account_manager`).
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct AccountManagerConfig {
+    /// The polkit action a caller must be authorized for to remove an
+    /// account or set the default account. Unset allows every caller the
+    /// caller policy admits.
+    pub polkit_action_id: Option<String>,
+    /// How long to wait for polkit, including the user's authentication.
+    pub polkit_timeout: u64,
+}
+
+impl Default for AccountManagerConfig {
+    fn default() -> Self {
+        AccountManagerConfig {
+            polkit_action_id: Some(MANAGE_ACCOUNTS_ACTION.to_string()),
+            polkit_timeout: 120,
+        }
+    }
+}
+
+impl AccountManagerConfig {
+    /// Check that `caller` may make the change `method`.
+    pub(crate) async fn authorize(
+        &self,
+        method: &str,
+        caller: Option<&CallerInfo>,
+    ) -> Result<(), dbus::MethodErr> {
+        let action = match &self.polkit_action_id {
+            Some(action) => action.clone(),
+            None => return Ok(()),
+        };
+        let caller = match caller {
+            Some(caller) if caller.pid.is_some() && caller.uid.is_some() => {
+                caller.clone()
+            }
+            _ => {
+                warn!("Denying {}: the caller could not be identified", method);
+                return Err(access_denied("Caller could not be identified"));
+            }
+        };
+        let timeout = Duration::from_secs(self.polkit_timeout);
+        let a = action.clone();
+        let c = caller.clone();
+        let authorized =
+            tokio::task::spawn_blocking(move || polkit_check(&a, &c, timeout))
+                .await
+                .map_err(|e| dbus::MethodErr::failed(&e))?
+                .map_err(|e| {
+                    dbus::MethodErr::failed(&format!(
+                        "Failed to check polkit authorization: {}",
+                        e
+                    ))
+                })?;
+        if !authorized {
+            warn!(
+                "Denying {} to {}: not authorized for {}",
+                method, caller.bus_name, action
+            );
+            return Err(access_denied(&format!(
+                "Not authorized for {}",
+                action
+            )));
+        }
+        info!(
+            "Authorized {} for {} via {}",
+            method, caller.bus_name, action
+        );
+        Ok(())
+    }
+}
+
+fn access_denied(msg: &str) -> dbus::MethodErr {
+    dbus::MethodErr::from(("org.freedesktop.DBus.Error.AccessDenied", msg))
+}
+
+/* The caller is named to polkit as a unix-process subject. polkit looks up
+ * the start time itself when it is given as zero, and the uid guards
+ * against the pid having been reused by another user's process.
+ */
+fn polkit_check(
+    action: &str,
+    caller: &CallerInfo,
+    timeout: Duration,
+) -> Result<bool, dbus::Error> {
+    let c = Connection::new_system()?;
+    let mut details: PropMap = HashMap::new();
+    details.insert(
+        "pid".to_string(),
+        Variant(Box::new(caller.pid.unwrap_or_default()) as Box<dyn RefArg>),
+    );
+    details.insert(
+        "start-time".to_string(),
+        Variant(Box::new(0u64) as Box<dyn RefArg>),
+    );
+    details.insert(
+        "uid".to_string(),
+        Variant(
+            Box::new(caller.uid.unwrap_or_default() as i32) as Box<dyn RefArg>
+        ),
+    );
+    let subject = ("unix-process", details);
+    let proxy = c.with_proxy(
+        "org.freedesktop.PolicyKit1",
+        "/org/freedesktop/PolicyKit1/Authority",
+        timeout,
+    );
+    // Flag 0x1 is AllowUserInteraction, which invokes the polkit agent.
+    let ((authorized, _challenge, _),): ((
+        bool,
+        bool,
+        HashMap<String, String>,
+    ),) = proxy.method_call(
+        "org.freedesktop.PolicyKit1.Authority",
+        "CheckAuthorization",
+        (subject, action, HashMap::<String, String>::new(), 1u32, ""),
+    )?;
+    Ok(authorized)
+}
+
+/// The error a broker answered in its response JSON, if it did.
+fn response_error(response_json: &str) -> Option<dbus::MethodErr> {
+    let resp: Value = serde_json::from_str(response_json).ok()?;
+    let error = resp.get("error")?;
+    let message = match error {
+        Value::String(message) => message.clone(),
+        error => error
+            .get("message")
+            .and_then(Value::as_str)
+            .map(str::to_string)
+            .unwrap_or_else(|| error.to_string()),
+    };
+    Some(dbus::MethodErr::failed(&message))
+}
+
+/// Fail with the error a broker answered in its response JSON.
+pub(crate) fn check_response(
+    response_json: String,
+) -> Result<String, dbus::MethodErr> {
+    match response_error(&response_json) {
+        Some(e) => Err(e),
+        None => Ok(response_json),
+    }
+}
+
+#[derive(Deserialize)]
+struct Accounts {
+    #[serde(default)]
+    accounts: Vec<Account>,
+}
+
+/// The home account id of the default account in a getDefaultAccount
+/// response, or an empty string.
+pub(crate) fn default_account_id(response_json: &str) -> String {
+    serde_json::from_str::<DefaultAccount<Account>>(response_json)
+        .ok()
+        .and_then(|default| default.account)
+        .map(|account| account.home_account_id)
+        .unwrap_or_default()
+}
+
+/// The accounts of a getAccounts response as ListAccounts returns them,
+/// one dictionary per account.
+pub(crate) fn account_entries(
+    response_json: &str,
+    default_account: &str,
+) -> Result<Vec<PropMap>, dbus::MethodErr> {
+    let accounts: Accounts = serde_json::from_str(response_json)
+        .map_err(|e| dbus::MethodErr::failed(&e))?;
+    Ok(accounts
+        .accounts
+        .iter()
+        .map(|account| {
+            let mut entry = PropMap::new();
+            let mut insert = |key: &str, value: Box<dyn RefArg>| {
+                entry.insert(key.to_string(), arg::Variant(value));
+            };
+            insert("homeAccountId", Box::new(account.home_account_id.clone()));
+            insert("username", Box::new(account.username.clone()));
+            if let Some(name) =
+                account.extra.get("name").and_then(Value::as_str)
+            {
+                insert("name", Box::new(name.to_string()));
+            }
+            insert("tenantId", Box::new(account.home_tenant().to_string()));
+            insert("environment", Box::new(account.environment.clone()));
+            insert(
+                "isDefault",
+                Box::new(account.home_account_id == default_account),
+            );
+            entry
+        })
+        .collect())
+}
+
+/// The removeAccount request for an account.
+pub(crate) fn remove_account_request(home_account_id: &str) -> String {
+    serde_json::json!({ "account": { "homeAccountId": home_account_id } })
+        .to_string()
+}
+
+/// The setDefaultAccount request for an account, or to clear the default
+/// when `account` is empty.
+pub(crate) fn set_default_account_request(account: &str) -> String {
+    let default = DefaultAccount {
+        account: (!account.is_empty()).then(|| account.to_string()),
+    };
+    serde_json::to_string(&default).unwrap_or_default()
+}
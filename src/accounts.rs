@@ -0,0 +1,617 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+/// An account as returned by getAccounts, using MSAL's field names.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Account {
+    pub home_account_id: String,
+    #[serde(default)]
+    pub environment: String,
+    /// The tenant id which issued this account.
+    #[serde(default)]
+    pub realm: String,
+    #[serde(default)]
+    pub local_account_id: String,
+    #[serde(default)]
+    pub username: String,
+    #[serde(flatten)]
+    pub extra: HashMap<String, Value>,
+}
+
+impl Account {
+    /// The home tenant of the account. The home_account_id has the form
+    /// `<object id>.<tenant id>`, which is preferred over the realm since
+    /// guest accounts carry the realm of the resource tenant.
+    pub fn home_tenant(&self) -> &str {
+        match self.home_account_id.split_once('.') {
+            Some((_, tid)) if !tid.is_empty() => tid,
+            _ => &self.realm,
+        }
+    }
+
+    /// Whether `name` is this account's home account id or (ignoring case)
+    /// its username.
+    pub fn is_named(&self, name: &str) -> bool {
+        self.home_account_id == name
+            || (!self.username.is_empty()
+                && self.username.eq_ignore_ascii_case(name))
+    }
+}
+
+#[derive(Deserialize)]
+struct GetAccountsResponse {
+    #[serde(default)]
+    accounts: Vec<Account>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct RoutingConfig {
+    /// Tenant used when a request neither names an account nor matches a
+    /// per-app override.
+    pub default_tenant: Option<String>,
+    /// Tenant overrides keyed by the requesting application's client id.
+    pub app_overrides: HashMap<String, String>,
+    /// The home account id or username of the account used when a request
+    /// names none, as Windows uses the signed-in user's account. Replaced
+    /// for the session with setDefaultAccount (see `DefaultAccount`).
+    pub default_account: Option<String>,
+}
+
+/* The request_json of setDefaultAccount, and the response of
+ * getDefaultAccount, on the org.himmelblau.Broker1.Accounts interface:
+ *
+ *   {"account": "<home account id or username>"}
+ *
+ * A null account clears the session's default, falling back to
+ * `RoutingConfig::default_account`. getDefaultAccount answers with the
+ * default account as getAccounts would return it, or null while it is not
+ * among the known accounts.
+ */
+/// The default account of the session.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct DefaultAccount<T = String> {
+    pub account: Option<T>,
+}
+
+/// The accounts known to the session broker, populated from getAccounts
+/// responses and used to route token requests to the right home account.
+#[derive(Debug, Default)]
+pub struct AccountRegistry {
+    accounts: HashMap<String, Account>,
+    /// The home account ids last returned to each client id, and when.
+    clients: HashMap<String, (HashSet<String>, Instant)>,
+    /// The default account set with setDefaultAccount.
+    default_account: Option<String>,
+}
+
+impl AccountRegistry {
+    pub fn update_from_get_accounts(
+        &mut self,
+        response_json: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        let resp: GetAccountsResponse = serde_json::from_str(response_json)?;
+        self.accounts = resp
+            .accounts
+            .into_iter()
+            .map(|account| (account.home_account_id.clone(), account))
+            .collect();
+        debug!("Account registry holds {} accounts", self.accounts.len());
+        Ok(())
+    }
+
+    /// Record the accounts returned for a getAccounts request from
+    /// `client_id`, in addition to updating the registry.
+    pub fn update_for_client(
+        &mut self,
+        client_id: &str,
+        response_json: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        self.update_from_get_accounts(response_json)?;
+        let ids = self.accounts.keys().cloned().collect();
+        self.clients
+            .insert(client_id.to_string(), (ids, Instant::now()));
+        Ok(())
+    }
+
+    /// The accounts last returned to `client_id`, if they were recorded
+    /// within `ttl`.
+    pub fn cached_for_client(
+        &self,
+        client_id: &str,
+        ttl: Duration,
+    ) -> Option<Vec<&Account>> {
+        let (ids, fetched) = self.clients.get(client_id)?;
+        if fetched.elapsed() >= ttl {
+            return None;
+        }
+        Some(ids.iter().filter_map(|id| self.accounts.get(id)).collect())
+    }
+
+    /// Whether `account` has been returned to `client_id`.
+    pub fn known_to_client(&self, client_id: &str, account: &Account) -> bool {
+        self.clients
+            .get(client_id)
+            .is_some_and(|(ids, _)| ids.contains(&account.home_account_id))
+    }
+
+    pub fn remove(&mut self, home_account_id: &str) {
+        self.accounts.remove(home_account_id);
+    }
+
+    /// Forget which accounts were returned to each client.
+    pub fn forget_clients(&mut self) {
+        self.clients.clear();
+    }
+
+    pub fn insert(&mut self, account: Account) {
+        self.accounts
+            .insert(account.home_account_id.clone(), account);
+    }
+
+    pub fn get(&self, home_account_id: &str) -> Option<&Account> {
+        self.accounts.get(home_account_id)
+    }
+
+    pub fn accounts(&self) -> impl Iterator<Item = &Account> {
+        self.accounts.values()
+    }
+
+    /// Make the account named `name` (see `Account::is_named`) the
+    /// default, in place of `RoutingConfig::default_account`.
+    pub fn set_default(&mut self, name: Option<String>) {
+        self.default_account = name;
+    }
+
+    /// The default account, if it is known.
+    pub fn default_account(&self, config: &RoutingConfig) -> Option<&Account> {
+        let name = self
+            .default_account
+            .as_ref()
+            .or(config.default_account.as_ref())?;
+        self.accounts
+            .values()
+            .find(|account| account.is_named(name))
+    }
+
+    pub fn accounts_for_tenant<'a>(
+        &'a self,
+        tenant: &'a str,
+    ) -> impl Iterator<Item = &'a Account> {
+        self.accounts
+            .values()
+            .filter(move |account| account.home_tenant() == tenant)
+    }
+
+    /* Route a token request to a home account. If the request already names
+     * a known account, any missing account fields are filled in from the
+     * registry. Otherwise a per-app override for the request's clientId
+     * chooses the tenant, then the default account is used, and failing
+     * that the default tenant is chosen. A request routed to a tenant is
+     * bound to the single registered account in that tenant (if there is
+     * exactly one). In all cases a `common` or `organizations` authority is
+     * pinned to the account's home tenant.
+     */
+    pub fn route(
+        &self,
+        config: &RoutingConfig,
+        request_json: &str,
+    ) -> Result<String, Box<dyn Error>> {
+        let mut request: Value = serde_json::from_str(request_json)?;
+        if !request.get("authParameters").is_some_and(Value::is_object) {
+            return Ok(request_json.to_string());
+        }
+
+        let named = request["authParameters"]
+            .get("account")
+            .and_then(|a| a.get("homeAccountId"))
+            .and_then(|id| id.as_str())
+            .map(|id| id.to_string());
+        let account = match named {
+            Some(id) => self.get(&id),
+            None => {
+                let client_id = request["authParameters"]
+                    .get("clientId")
+                    .and_then(|id| id.as_str());
+                let app_tenant =
+                    client_id.and_then(|id| config.app_overrides.get(id));
+                let default = match app_tenant {
+                    Some(_) => None,
+                    None => self.default_account(config),
+                };
+                let tenant = app_tenant.or(config.default_tenant.as_ref());
+                match (default, tenant) {
+                    (Some(account), _) => Some(account),
+                    (None, Some(tenant)) => {
+                        let mut matches = self.accounts_for_tenant(tenant);
+                        match (matches.next(), matches.next()) {
+                            (Some(account), None) => Some(account),
+                            _ => None,
+                        }
+                    }
+                    (None, None) => None,
+                }
+            }
+        };
+        let account = match account {
+            Some(account) => account,
+            None => return Ok(request_json.to_string()),
+        };
+        debug!(
+            "Routing request to account {} in tenant {}",
+            account.home_account_id,
+            account.home_tenant()
+        );
+
+        let account_value = serde_json::to_value(account)?;
+        request["authParameters"]["account"] = account_value.clone();
+        if request.get("account").is_some() {
+            request["account"] = account_value;
+        }
+        let authority = request["authParameters"]
+            .get("authority")
+            .and_then(|a| a.as_str())
+            .map(|a| a.trim_end_matches('/').to_string());
+        if let Some(authority) = authority {
+            if let Some((base, tenant)) = authority.rsplit_once('/') {
+                if tenant == "common" || tenant == "organizations" {
+                    request["authParameters"]["authority"] = Value::String(
+                        format!("{}/{}", base, account.home_tenant()),
+                    );
+                }
+            }
+        }
+        Ok(serde_json::to_string(&request)?)
+    }
+
+    /// The accounts a request naming no account could be meant for: those
+    /// in the tenant it is routed to (see `route`), or every account when
+    /// no tenant is configured for it, ordered by username.
+    pub fn candidates(
+        &self,
+        config: &RoutingConfig,
+        request_json: &str,
+    ) -> Vec<&Account> {
+        let client_id = request_client_id(request_json);
+        let tenant = client_id
+            .as_deref()
+            .and_then(|id| config.app_overrides.get(id))
+            .or(config.default_tenant.as_ref());
+        let mut accounts: Vec<&Account> = self
+            .accounts
+            .values()
+            .filter(|account| {
+                tenant.is_none_or(|tenant| account.home_tenant() == tenant)
+            })
+            .collect();
+        accounts.sort_by(|a, b| a.username.cmp(&b.username));
+        accounts
+    }
+
+    /// `request_json` bound to the account `home_account_id`, which is
+    /// then routed as a request naming it would be.
+    pub fn bind(
+        &self,
+        config: &RoutingConfig,
+        request_json: &str,
+        home_account_id: &str,
+    ) -> Result<String, Box<dyn Error>> {
+        let mut request: Value = serde_json::from_str(request_json)?;
+        let params = request
+            .get_mut("authParameters")
+            .and_then(Value::as_object_mut)
+            .ok_or("The request has no authParameters")?;
+        params.insert(
+            "account".to_string(),
+            serde_json::json!({ "homeAccountId": home_account_id }),
+        );
+        self.route(config, &serde_json::to_string(&request)?)
+    }
+
+    /// `request_json` with the default account as its account hint, when
+    /// it names no account of its own.
+    pub fn hint_default(
+        &self,
+        config: &RoutingConfig,
+        request_json: &str,
+    ) -> Result<String, Box<dyn Error>> {
+        let mut request: Value = serde_json::from_str(request_json)?;
+        if request.get("account").is_some() {
+            return Ok(request_json.to_string());
+        }
+        let params = match request
+            .get_mut("authParameters")
+            .and_then(Value::as_object_mut)
+        {
+            Some(params) if !params.contains_key("account") => params,
+            _ => return Ok(request_json.to_string()),
+        };
+        let account = match self.default_account(config) {
+            Some(account) => account,
+            None => return Ok(request_json.to_string()),
+        };
+        debug!("Hinting default account {}", account.home_account_id);
+        params.insert("account".to_string(), serde_json::to_value(account)?);
+        Ok(serde_json::to_string(&request)?)
+    }
+}
+
+fn is_error(response_json: &str) -> bool {
+    serde_json::from_str::<Value>(response_json)
+        .map(|resp| resp.get("error").is_some())
+        .unwrap_or(false)
+}
+
+/* A removeAccounts request carries removeAccount's parameters, with a list
+ * of `accounts` in place of the single `account`. It is answered with one
+ * result per account, in request order:
+ *
+ *   {"results": [{"homeAccountId": "...", "removed": true}, ...]}
+ *
+ * where a failed removal carries the daemon's error (or the failure) as
+ * `error`.
+ */
+
+/// Split a removeAccounts request into removeAccount requests, returning
+/// each with the home account id it removes.
+pub fn split_remove_accounts(
+    request_json: &str,
+) -> Result<Vec<(String, String)>, Box<dyn Error>> {
+    let mut request: serde_json::Map<String, Value> =
+        serde_json::from_str(request_json)?;
+    let accounts = match request.remove("accounts") {
+        Some(Value::Array(accounts)) => accounts,
+        _ => return Err("removeAccounts requires a list of accounts".into()),
+    };
+    accounts
+        .into_iter()
+        .map(|account| {
+            let id = account
+                .get("homeAccountId")
+                .and_then(Value::as_str)
+                .ok_or("Account is missing a homeAccountId")?
+                .to_string();
+            let mut single = request.clone();
+            single.insert("account".to_string(), account);
+            Ok((id, Value::Object(single).to_string()))
+        })
+        .collect()
+}
+
+/// Build the removeAccounts response from the outcome of each removal.
+pub fn remove_accounts_response<E: std::fmt::Display>(
+    results: Vec<(String, Result<String, E>)>,
+) -> String {
+    let results: Vec<Value> = results
+        .into_iter()
+        .map(|(id, res)| {
+            let mut result = serde_json::Map::new();
+            result.insert("homeAccountId".to_string(), Value::String(id));
+            let error = match res {
+                Ok(resp) if is_error(&resp) => {
+                    serde_json::from_str::<Value>(&resp).ok().and_then(
+                        |mut resp| resp.get_mut("error").map(Value::take),
+                    )
+                }
+                Ok(_) => None,
+                Err(e) => Some(Value::String(e.to_string())),
+            };
+            result.insert("removed".to_string(), Value::Bool(error.is_none()));
+            if let Some(error) = error {
+                result.insert("error".to_string(), error);
+            }
+            Value::Object(result)
+        })
+        .collect();
+    serde_json::json!({ "results": results }).to_string()
+}
+
+/// Paging parameters of a getAccountsPage request. The page token is
+/// opaque to clients, and is returned as `nextPageToken` while further
+/// accounts remain.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AccountPage {
+    pub page_size: Option<usize>,
+    pub page_token: Option<String>,
+}
+
+impl AccountPage {
+    /// Remove the paging parameters (`pageSize` and `pageToken`) from a
+    /// getAccountsPage request, leaving a getAccounts request.
+    pub fn take_from_request(
+        request_json: &str,
+    ) -> Result<(String, Self), Box<dyn Error>> {
+        let mut request: serde_json::Map<String, Value> =
+            serde_json::from_str(request_json)?;
+        let page_size = match request.remove("pageSize") {
+            Some(size) => Some(
+                size.as_u64()
+                    .filter(|size| *size > 0)
+                    .ok_or("pageSize must be a positive integer")?
+                    as usize,
+            ),
+            None => None,
+        };
+        let page_token = match request.remove("pageToken") {
+            Some(Value::String(token)) => Some(token),
+            Some(Value::Null) | None => None,
+            Some(_) => return Err("pageToken must be a string".into()),
+        };
+        Ok((
+            Value::Object(request).to_string(),
+            AccountPage {
+                page_size,
+                page_token,
+            },
+        ))
+    }
+
+    /// Cut this page from a getAccounts response.
+    pub fn apply(&self, response_json: &str) -> Result<String, Box<dyn Error>> {
+        let mut response: serde_json::Map<String, Value> =
+            serde_json::from_str(response_json)?;
+        let accounts = match response.remove("accounts") {
+            Some(Value::Array(accounts)) => accounts,
+            // Errors are passed through untouched.
+            _ => return Ok(response_json.to_string()),
+        };
+        let start = match &self.page_token {
+            Some(token) => token
+                .parse::<usize>()
+                .map_err(|_| format!("Invalid pageToken {:?}", token))?,
+            None => 0,
+        };
+        let end = match self.page_size {
+            Some(size) => start.saturating_add(size).min(accounts.len()),
+            None => accounts.len(),
+        };
+        let more = end < accounts.len();
+        let page: Vec<Value> = accounts
+            .into_iter()
+            .skip(start)
+            .take(end.saturating_sub(start))
+            .collect();
+        response.insert("accounts".to_string(), Value::Array(page));
+        if more {
+            response.insert(
+                "nextPageToken".to_string(),
+                Value::String(end.to_string()),
+            );
+        }
+        Ok(Value::Object(response).to_string())
+    }
+}
+
+/// Criteria for the accounts returned by getAccounts, given as the `filter`
+/// object of the request. Every criterion which is set must match.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default, rename_all = "camelCase")]
+pub struct AccountFilter {
+    /// Only accounts which have been returned to this client id.
+    pub client_id: Option<String>,
+    /// Only accounts whose home tenant is this tenant id.
+    pub tenant: Option<String>,
+    /// Only accounts whose username starts with this prefix, ignoring case.
+    pub username_prefix: Option<String>,
+    /// Only accounts of this MSAL `accountType` (eg. `AAD` or `MSA`).
+    pub account_type: Option<String>,
+}
+
+impl AccountFilter {
+    /// Remove the `filter` from a getAccounts request, leaving the request
+    /// the daemon expects.
+    pub fn take_from_request(
+        request_json: &str,
+    ) -> Result<(String, Option<Self>), Box<dyn Error>> {
+        let mut request: serde_json::Map<String, Value> =
+            serde_json::from_str(request_json)?;
+        match request.remove("filter") {
+            Some(Value::Null) | None => Ok((request_json.to_string(), None)),
+            Some(filter) => Ok((
+                Value::Object(request).to_string(),
+                Some(serde_json::from_value(filter)?),
+            )),
+        }
+    }
+
+    /* The client id criterion is satisfied either by the response having
+     * been fetched for that client (`requesting_client`), or by the registry
+     * having seen the account returned to it.
+     */
+    pub fn matches(
+        &self,
+        account: &Account,
+        requesting_client: Option<&str>,
+        registry: Option<&AccountRegistry>,
+    ) -> bool {
+        if let Some(client_id) = &self.client_id {
+            let known = requesting_client == Some(client_id.as_str())
+                || registry
+                    .is_some_and(|r| r.known_to_client(client_id, account));
+            if !known {
+                return false;
+            }
+        }
+        if let Some(tenant) = &self.tenant {
+            if !account.home_tenant().eq_ignore_ascii_case(tenant) {
+                return false;
+            }
+        }
+        if let Some(prefix) = &self.username_prefix {
+            let username = account.username.to_lowercase();
+            if !username.starts_with(&prefix.to_lowercase()) {
+                return false;
+            }
+        }
+        if let Some(account_type) = &self.account_type {
+            let matched = account
+                .extra
+                .get("accountType")
+                .and_then(Value::as_str)
+                .is_some_and(|t| t.eq_ignore_ascii_case(account_type));
+            if !matched {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Remove non-matching accounts from a getAccounts response. Error
+    /// responses are passed through untouched.
+    pub fn apply(
+        &self,
+        response_json: &str,
+        requesting_client: Option<&str>,
+        registry: Option<&AccountRegistry>,
+    ) -> Result<String, Box<dyn Error>> {
+        let mut response: serde_json::Map<String, Value> =
+            serde_json::from_str(response_json)?;
+        let accounts = match response.remove("accounts") {
+            Some(Value::Array(accounts)) => accounts,
+            _ => return Ok(response_json.to_string()),
+        };
+        let accounts: Vec<Value> = accounts
+            .into_iter()
+            .filter(|value| {
+                serde_json::from_value::<Account>(value.clone())
+                    .map(|a| self.matches(&a, requesting_client, registry))
+                    .unwrap_or(false)
+            })
+            .collect();
+        response.insert("accounts".to_string(), Value::Array(accounts));
+        Ok(Value::Object(response).to_string())
+    }
+}
+
+/// The clientId of a Broker1 request, either at the top level or within
+/// its authParameters.
+pub fn request_client_id(request_json: &str) -> Option<String> {
+    let request: Value = serde_json::from_str(request_json).ok()?;
+    request
+        .get("clientId")
+        .or_else(|| request.get("authParameters")?.get("clientId"))
+        .and_then(Value::as_str)
+        .map(String::from)
+}
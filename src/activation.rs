@@ -0,0 +1,147 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::freedesktop::DBus;
+use dbus::blocking::Connection;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::debug;
+
+/// Where session bus activation files are installed.
+pub const SESSION_SERVICES_DIR: &str = "/usr/share/dbus-1/services";
+/// Where system bus activation files are installed.
+pub const SYSTEM_SERVICES_DIR: &str = "/usr/share/dbus-1/system-services";
+
+/// Environment variables an activated session broker needs in order to open
+/// a browser in the user's graphical session.
+pub const ACTIVATION_ENVIRONMENT: [&str; 3] =
+    ["DISPLAY", "WAYLAND_DISPLAY", "XDG_RUNTIME_DIR"];
+
+/// A D-Bus service activation file, allowing the bus to start the broker
+/// the first time its name is called.
+#[derive(Debug, Clone)]
+pub struct ActivationFile {
+    name: String,
+    exec: PathBuf,
+    user: Option<String>,
+    systemd_service: Option<String>,
+}
+
+impl ActivationFile {
+    pub fn new(name: &str, exec: impl Into<PathBuf>) -> Self {
+        ActivationFile {
+            name: name.to_string(),
+            exec: exec.into(),
+            user: None,
+            systemd_service: None,
+        }
+    }
+
+    /// An activation file for the session broker,
+    /// com.microsoft.identity.broker1.
+    pub fn session_broker(exec: impl Into<PathBuf>) -> Self {
+        ActivationFile::new("com.microsoft.identity.broker1", exec)
+    }
+
+    /// An activation file for the device broker,
+    /// com.microsoft.identity.DeviceBroker1, which the system bus requires
+    /// to name the `user` it runs as.
+    pub fn device_broker(exec: impl Into<PathBuf>, user: &str) -> Self {
+        ActivationFile::new("com.microsoft.identity.DeviceBroker1", exec)
+            .user(user)
+    }
+
+    /// The user the service runs as. Only used on the system bus.
+    pub fn user(mut self, user: &str) -> Self {
+        self.user = Some(user.to_string());
+        self
+    }
+
+    /// Delegate activation to this systemd unit, so that the broker runs
+    /// under the service manager rather than as a child of the bus.
+    pub fn systemd_service(mut self, unit: &str) -> Self {
+        self.systemd_service = Some(unit.to_string());
+        self
+    }
+
+    pub fn file_name(&self) -> String {
+        format!("{}.service", self.name)
+    }
+
+    pub fn render(&self) -> String {
+        let mut file = format!(
+            "[D-BUS Service]\nName={}\nExec={}\n",
+            self.name,
+            self.exec.display()
+        );
+        if let Some(user) = &self.user {
+            file.push_str(&format!("User={}\n", user));
+        }
+        if let Some(unit) = &self.systemd_service {
+            file.push_str(&format!("SystemdService={}\n", unit));
+        }
+        file
+    }
+
+    /// Write the activation file into `dir` (such as `SESSION_SERVICES_DIR`
+    /// under a packaging root), returning its path.
+    pub fn install(&self, dir: impl AsRef<Path>) -> io::Result<PathBuf> {
+        fs::create_dir_all(dir.as_ref())?;
+        let path = dir.as_ref().join(self.file_name());
+        fs::write(&path, self.render())?;
+        Ok(path)
+    }
+}
+
+/* Services activated by the session bus inherit the bus's environment, not
+ * the environment of the graphical session, which is typically set up after
+ * the bus starts. Copy the named variables from the calling process into
+ * the bus's activation environment. Unset variables are skipped.
+ */
+/// Propagate `vars` from this process's environment to services activated
+/// by the session bus, returning the names of the variables propagated.
+pub fn update_activation_environment(
+    vars: &[&str],
+) -> Result<Vec<String>, dbus::Error> {
+    let values: Vec<(&str, String)> = vars
+        .iter()
+        .filter_map(|var| env::var(var).ok().map(|value| (*var, value)))
+        .collect();
+    if values.is_empty() {
+        return Ok(vec![]);
+    }
+    let c = Connection::new_session()?;
+    let proxy = c.with_proxy(
+        "org.freedesktop.DBus",
+        "/org/freedesktop/DBus",
+        Duration::from_secs(5),
+    );
+    proxy.update_activation_environment(
+        values
+            .iter()
+            .map(|(var, value)| (*var, value.as_str()))
+            .collect::<HashMap<_, _>>(),
+    )?;
+    let names: Vec<String> =
+        values.into_iter().map(|(var, _)| var.to_string()).collect();
+    debug!("Updated activation environment: {}", names.join(", "));
+    Ok(names)
+}
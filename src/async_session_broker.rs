@@ -0,0 +1,1266 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::account_manager::{
+    account_entries, check_response, default_account_id,
+    remove_account_request, set_default_account_request, AccountManagerConfig,
+    ACCOUNT_MANAGER_INTERFACE,
+};
+use crate::accounts::{
+    remove_accounts_response, split_remove_accounts, AccountPage,
+};
+use crate::broker_ext::{
+    not_supported, register_broker_ext, Capabilities, BROKER1_METHODS,
+};
+use crate::caller::{BusType, CallerInfo};
+use crate::diagnostics::{register_diagnostics, BrokerStats};
+use crate::error::MethodErrExt;
+use crate::events::{AccountSignals, ACCOUNTS_INTERFACE};
+use crate::freedesktop::{DBusNameLost, DBusNameOwnerChanged};
+use crate::maintenance::register_maintenance;
+use crate::name_watch::{check_name_reply, NameWatch, TakeoverAction};
+use crate::peer::register_peer;
+use crate::protocol_version::ProtocolVersion;
+use crate::serve::ServeOptions;
+use crate::session_broker::{
+    call_span, panicked, Broker1Call, Dispatcher, PortalArgs, PortalRequest,
+    PORTAL_BUS_NAME, PORTAL_INTERFACE, PORTAL_OBJECT_PATH,
+};
+use crate::token_broker::{
+    self, TokenBrokerArgs, TOKEN_BROKER_INTERFACE, TOKEN_BROKER_METHODS,
+};
+#[cfg(feature = "varlink")]
+use crate::varlink::serve_varlink;
+use async_trait::async_trait;
+use dbus::arg::{AppendAll, ArgAll, ReadAll};
+use dbus::channel::MatchingReceiver;
+use dbus::message::MatchRule;
+use dbus::nonblock::SyncConnection;
+use dbus_crossroads as crossroads;
+use futures::FutureExt;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, Instrument};
+
+/// An asynchronous variant of `SessionBroker`, served on the tokio D-Bus
+/// backend. Method calls are dispatched concurrently, so the broker is
+/// shared rather than borrowed mutably, and the identity of the D-Bus
+/// caller (when it could be resolved) is passed to each method.
+#[async_trait]
+pub trait AsyncSessionBroker: Send + Sync {
+    async fn acquire_token_interactively(
+        &self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        caller: Option<CallerInfo>,
+    ) -> Result<String, dbus::MethodErr>;
+    async fn acquire_token_silently(
+        &self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        caller: Option<CallerInfo>,
+    ) -> Result<String, dbus::MethodErr>;
+    async fn get_accounts(
+        &self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        caller: Option<CallerInfo>,
+    ) -> Result<String, dbus::MethodErr>;
+    async fn remove_account(
+        &self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        caller: Option<CallerInfo>,
+    ) -> Result<String, dbus::MethodErr>;
+    async fn acquire_prt_sso_cookie(
+        &self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        caller: Option<CallerInfo>,
+    ) -> Result<String, dbus::MethodErr>;
+    async fn generate_signed_http_request(
+        &self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        caller: Option<CallerInfo>,
+    ) -> Result<String, dbus::MethodErr>;
+    async fn cancel_interactive_flow(
+        &self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        caller: Option<CallerInfo>,
+    ) -> Result<String, dbus::MethodErr>;
+    async fn get_linux_broker_version(
+        &self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        caller: Option<CallerInfo>,
+    ) -> Result<String, dbus::MethodErr>;
+
+    /// Remove several accounts in one call, see `split_remove_accounts`.
+    /// Served on the org.himmelblau.Broker1.Accounts interface. By default
+    /// each account is removed with `remove_account`.
+    async fn remove_accounts(
+        &self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        caller: Option<CallerInfo>,
+    ) -> Result<String, dbus::MethodErr> {
+        let removals = split_remove_accounts(&request_json)
+            .map_err(|e| dbus::MethodErr::invalid_arg(&e.to_string()))?;
+        let mut results = Vec::new();
+        for (id, request_json) in removals {
+            let res = self
+                .remove_account(
+                    protocol_version.clone(),
+                    correlation_id.clone(),
+                    request_json,
+                    caller.clone(),
+                )
+                .await
+                .map_err(|e| e.description().to_string());
+            results.push((id, res));
+        }
+        Ok(remove_accounts_response(results))
+    }
+
+    /// A page of the accounts returned by `get_accounts`, see
+    /// `AccountPage`. Served on the org.himmelblau.Broker1.Accounts
+    /// interface.
+    async fn get_accounts_page(
+        &self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        caller: Option<CallerInfo>,
+    ) -> Result<String, dbus::MethodErr> {
+        let (request_json, page) =
+            AccountPage::take_from_request(&request_json)
+                .map_err(|e| dbus::MethodErr::invalid_arg(&e.to_string()))?;
+        let resp = self
+            .get_accounts(
+                protocol_version,
+                correlation_id,
+                request_json,
+                caller,
+            )
+            .await?;
+        page.apply(&resp).or_failed()
+    }
+
+    /// The session's default account, as a JSON `DefaultAccount`. Served on
+    /// the org.himmelblau.Broker1.Accounts interface, as is
+    /// `set_default_account`. Unsupported by default.
+    async fn get_default_account(
+        &self,
+        _protocol_version: String,
+        _correlation_id: String,
+        _request_json: String,
+        _caller: Option<CallerInfo>,
+    ) -> Result<String, dbus::MethodErr> {
+        Err(not_supported("getDefaultAccount"))
+    }
+
+    /// Set the account used when a request names none, given a
+    /// `DefaultAccount`, returning the new default as
+    /// `get_default_account` does.
+    async fn set_default_account(
+        &self,
+        _protocol_version: String,
+        _correlation_id: String,
+        _request_json: String,
+        _caller: Option<CallerInfo>,
+    ) -> Result<String, dbus::MethodErr> {
+        Err(not_supported("setDefaultAccount"))
+    }
+
+    /// The device's Intune enrollment and compliance, as a JSON
+    /// `DeviceComplianceStatus`. Served on the
+    /// org.himmelblau.Broker1.Compliance interface. Unsupported by default.
+    async fn get_device_compliance_status(
+        &self,
+        _protocol_version: String,
+        _correlation_id: String,
+        _request_json: String,
+        _caller: Option<CallerInfo>,
+    ) -> Result<String, dbus::MethodErr> {
+        Err(not_supported("getDeviceComplianceStatus"))
+    }
+
+    /// An OpenSSH certificate for the calling user's public key, as a JSON
+    /// `SshCertificate`, given an `SshCertRequest`. Served on the
+    /// org.himmelblau.Broker1.Ssh interface. Unsupported by default.
+    async fn get_ssh_cert(
+        &self,
+        _protocol_version: String,
+        _correlation_id: String,
+        _request_json: String,
+        _caller: Option<CallerInfo>,
+    ) -> Result<String, dbus::MethodErr> {
+        Err(not_supported("getSshCert"))
+    }
+
+    /// Finish a flow which acquireTokenInteractively delegated to a browser
+    /// outside the session (see `InteractionMode`), given a
+    /// `CompleteInteractiveFlowRequest`, returning the token response.
+    /// Served on the org.himmelblau.Broker1.Interactive interface.
+    /// Unsupported by default.
+    async fn complete_interactive_flow(
+        &self,
+        _protocol_version: String,
+        _correlation_id: String,
+        _request_json: String,
+        _caller: Option<CallerInfo>,
+    ) -> Result<String, dbus::MethodErr> {
+        Err(not_supported("completeInteractiveFlow"))
+    }
+
+    /// Start a device code flow, given the same request_json as
+    /// acquireTokenInteractively, returning a JSON `DeviceCode` to show to
+    /// the user. Served on the org.himmelblau.Broker1.DeviceCode
+    /// interface, as are the methods below. Unsupported by default.
+    async fn start_device_code_flow(
+        &self,
+        _protocol_version: String,
+        _correlation_id: String,
+        _request_json: String,
+        _caller: Option<CallerInfo>,
+    ) -> Result<String, dbus::MethodErr> {
+        Err(not_supported("startDeviceCodeFlow"))
+    }
+
+    /// The JSON `DeviceCodeStatus` of the flow named by a
+    /// `DeviceCodeFlowRequest`.
+    async fn poll_device_code_flow(
+        &self,
+        _protocol_version: String,
+        _correlation_id: String,
+        _request_json: String,
+        _caller: Option<CallerInfo>,
+    ) -> Result<String, dbus::MethodErr> {
+        Err(not_supported("pollDeviceCodeFlow"))
+    }
+
+    /// The token response of an authorized flow, named by a
+    /// `DeviceCodeFlowRequest`, as acquireTokenInteractively would return
+    /// it.
+    async fn complete_device_code_flow(
+        &self,
+        _protocol_version: String,
+        _correlation_id: String,
+        _request_json: String,
+        _caller: Option<CallerInfo>,
+    ) -> Result<String, dbus::MethodErr> {
+        Err(not_supported("completeDeviceCodeFlow"))
+    }
+
+    /// The methods this broker implements, served by getCapabilities.
+    /// Implementations of optional methods (such as
+    /// `get_device_compliance_status`) should add them here.
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::new(BROKER1_METHODS)
+            .with("removeAccounts")
+            .with("getAccountsPage")
+    }
+
+    /// Watch for changes to the user's accounts, announcing each through
+    /// `signals`. Spawned once when serving starts. By default nothing is
+    /// watched.
+    async fn watch_accounts(self: Arc<Self>, _signals: AccountSignals) {}
+
+    /// Counters published on the Diagnostics interface. Implementations
+    /// which record their own statistics (such as cache hits) should return
+    /// a shared handle here.
+    fn stats(&self) -> Option<Arc<BrokerStats>> {
+        None
+    }
+}
+
+/* Each method resolves the caller and applies the caller policy on the
+ * blocking pool (the resolver uses its own blocking bus connection), before
+ * handing the call to the broker. A panic in the broker is reported to the
+ * caller as a failure rather than leaving the call unanswered.
+ */
+fn async_method<T, F, R>(
+    b: &mut crossroads::IfaceBuilder<Arc<T>>,
+    dispatcher: Arc<Dispatcher>,
+    method: &'static str,
+    f: F,
+) where
+    T: AsyncSessionBroker + 'static,
+    F: Fn(Arc<T>, String, String, String, Option<CallerInfo>) -> R
+        + Send
+        + Sync
+        + 'static,
+    R: Future<Output = Result<String, dbus::MethodErr>> + Send + 'static,
+{
+    let f = Arc::new(f);
+    b.method_with_cr_async(
+        method,
+        ("protocol_version", "correlation_id", "request_json"),
+        ("result",),
+        move |mut ctx,
+              cr,
+              (protocol_version, correlation_id, request_json): (
+            String,
+            String,
+            String,
+        )| {
+            let t = cr.data_mut::<Arc<T>>(ctx.path()).cloned();
+            let sender = ctx.message().sender().map(|s| s.to_string());
+            let d = dispatcher.clone();
+            let f = f.clone();
+            let span = call_span(method, ctx.message());
+            async move {
+                let t = match t {
+                    Some(t) => t,
+                    None => {
+                        let e = dbus::MethodErr::no_path(ctx.path());
+                        return ctx.reply(Err(e));
+                    }
+                };
+                let (version, id, request) = (
+                    protocol_version.clone(),
+                    correlation_id.clone(),
+                    request_json.clone(),
+                );
+                let call = Broker1Call {
+                    method,
+                    protocol_version: &version,
+                    correlation_id: &id,
+                    request_json: &request,
+                    app_id: None,
+                };
+                let res = run_async(d, sender, call, move |caller| {
+                    f(t, protocol_version, correlation_id, request_json, caller)
+                })
+                .await;
+                ctx.reply(res.map(|x| (x,)))
+            }
+            .instrument(span)
+        },
+    );
+}
+
+async fn run_async<F, R>(
+    d: Arc<Dispatcher>,
+    sender: Option<String>,
+    call: Broker1Call<'_>,
+    f: F,
+) -> Result<String, dbus::MethodErr>
+where
+    F: FnOnce(Option<CallerInfo>) -> R,
+    R: Future<Output = Result<String, dbus::MethodErr>>,
+{
+    let caller = match sender.clone() {
+        Some(sender) => {
+            let d = d.clone();
+            tokio::task::spawn_blocking(move || d.resolve(&sender))
+                .await
+                .unwrap_or(None)
+        }
+        None => None,
+    };
+    dispatch_async(&d, &call, sender.as_deref(), caller, f).await
+}
+
+/// Handle a call from an identified `caller`. `sender` names the
+/// connection the call arrived on, for replay protection.
+pub(crate) async fn dispatch_async<F, R>(
+    d: &Dispatcher,
+    call: &Broker1Call<'_>,
+    sender: Option<&str>,
+    caller: Option<CallerInfo>,
+    f: F,
+) -> Result<String, dbus::MethodErr>
+where
+    F: FnOnce(Option<CallerInfo>) -> R,
+    R: Future<Output = Result<String, dbus::MethodErr>>,
+{
+    let Broker1Call {
+        method,
+        correlation_id,
+        request_json,
+        ..
+    } = *call;
+    let caller = call.attribute(caller);
+    d.admit(method, caller.as_ref())?;
+    let _busy = d.busy();
+    if let Some(resp) =
+        d.check_replay(sender, method, correlation_id, request_json)?
+    {
+        return Ok(resp);
+    }
+    let started = Instant::now();
+    let res = AssertUnwindSafe(f(caller.clone()))
+        .catch_unwind()
+        .await
+        .unwrap_or_else(|panic| Err(panicked(method, correlation_id, &*panic)));
+    let res = d.shape_response(call, caller.as_ref(), res);
+    if let Some(delay) = d.error_delay(method, started, &res) {
+        tokio::time::sleep(delay).await;
+    }
+    d.complete_replay(sender, method, correlation_id, &res);
+    d.capture(call, caller.as_ref(), started, &res);
+    d.record_latency(call, caller.as_ref(), started);
+    d.finish(method, &res);
+    res
+}
+
+fn register_async_session_broker<T>(
+    cr: &mut crossroads::Crossroads,
+    dispatcher: Arc<Dispatcher>,
+) -> crossroads::IfaceToken<Arc<T>>
+where
+    T: AsyncSessionBroker + 'static,
+{
+    cr.register("com.microsoft.identity.Broker1", move |b| {
+        let stats = dispatcher.stats.clone();
+        async_method(
+            b,
+            dispatcher.clone(),
+            "acquireTokenInteractively",
+            move |t: Arc<T>, a, b, c, caller| {
+                let stats = stats.clone();
+                async move {
+                    stats.interactive_flow_started();
+                    let res =
+                        t.acquire_token_interactively(a, b, c, caller).await;
+                    stats.interactive_flow_finished();
+                    res
+                }
+            },
+        );
+        async_method(
+            b,
+            dispatcher.clone(),
+            "acquireTokenSilently",
+            |t: Arc<T>, a, b, c, caller| async move {
+                t.acquire_token_silently(a, b, c, caller).await
+            },
+        );
+        async_method(
+            b,
+            dispatcher.clone(),
+            "getAccounts",
+            |t: Arc<T>, a, b, c, caller| async move {
+                t.get_accounts(a, b, c, caller).await
+            },
+        );
+        async_method(
+            b,
+            dispatcher.clone(),
+            "removeAccount",
+            |t: Arc<T>, a, b, c, caller| async move {
+                t.remove_account(a, b, c, caller).await
+            },
+        );
+        async_method(
+            b,
+            dispatcher.clone(),
+            "acquirePrtSsoCookie",
+            |t: Arc<T>, a, b, c, caller| async move {
+                t.acquire_prt_sso_cookie(a, b, c, caller).await
+            },
+        );
+        async_method(
+            b,
+            dispatcher.clone(),
+            "generateSignedHttpRequest",
+            |t: Arc<T>, a, b, c, caller| async move {
+                t.generate_signed_http_request(a, b, c, caller).await
+            },
+        );
+        async_method(
+            b,
+            dispatcher.clone(),
+            "cancelInteractiveFlow",
+            |t: Arc<T>, a, b, c, caller| async move {
+                t.cancel_interactive_flow(a, b, c, caller).await
+            },
+        );
+        async_method(
+            b,
+            dispatcher.clone(),
+            "getLinuxBrokerVersion",
+            |t: Arc<T>, a, b, c, caller| async move {
+                t.get_linux_broker_version(a, b, c, caller).await
+            },
+        );
+    })
+}
+
+/* Himmelblau extensions to Broker1 are served on their own interface, so
+ * that Broker1 itself matches Microsoft's broker.
+ */
+fn register_accounts_extension<T>(
+    cr: &mut crossroads::Crossroads,
+    dispatcher: Arc<Dispatcher>,
+) -> crossroads::IfaceToken<Arc<T>>
+where
+    T: AsyncSessionBroker + 'static,
+{
+    cr.register(ACCOUNTS_INTERFACE, move |b| {
+        b.signal::<(String,), _>("AccountAdded", ("home_account_id",));
+        b.signal::<(String,), _>("AccountRemoved", ("home_account_id",));
+        b.signal::<(String,), _>("TokensChanged", ("home_account_id",));
+        b.signal::<(String, String), _>(
+            "TokenRevoked",
+            ("home_account_id", "reason"),
+        );
+        b.signal::<(String, String), _>(
+            "ReauthRequired",
+            ("home_account_id", "reason"),
+        );
+        b.signal::<(), _>("AccountsChanged", ());
+        async_method(
+            b,
+            dispatcher.clone(),
+            "removeAccounts",
+            |t: Arc<T>, a, b, c, caller| async move {
+                t.remove_accounts(a, b, c, caller).await
+            },
+        );
+        async_method(
+            b,
+            dispatcher.clone(),
+            "getAccountsPage",
+            |t: Arc<T>, a, b, c, caller| async move {
+                t.get_accounts_page(a, b, c, caller).await
+            },
+        );
+        async_method(
+            b,
+            dispatcher.clone(),
+            "getDefaultAccount",
+            |t: Arc<T>, a, b, c, caller| async move {
+                t.get_default_account(a, b, c, caller).await
+            },
+        );
+        async_method(
+            b,
+            dispatcher.clone(),
+            "setDefaultAccount",
+            |t: Arc<T>, a, b, c, caller| async move {
+                t.set_default_account(a, b, c, caller).await
+            },
+        );
+    })
+}
+
+/// Register a method of the account manager, which takes and returns
+/// typed arguments rather than JSON strings.
+fn manager_method<T, IA, OA, F, R>(
+    b: &mut crossroads::IfaceBuilder<Arc<T>>,
+    dispatcher: Arc<Dispatcher>,
+    method: &'static str,
+    input_args: IA::strs,
+    output_args: OA::strs,
+    f: F,
+) where
+    T: AsyncSessionBroker + 'static,
+    IA: ArgAll + ReadAll + Send + 'static,
+    OA: ArgAll + AppendAll + 'static,
+    F: Fn(Arc<T>, Arc<Dispatcher>, Option<String>, IA) -> R
+        + Send
+        + Sync
+        + 'static,
+    R: Future<Output = Result<OA, dbus::MethodErr>> + Send + 'static,
+{
+    let f = Arc::new(f);
+    b.method_with_cr_async(
+        method,
+        input_args,
+        output_args,
+        move |mut ctx, cr, args: IA| {
+            let t = cr.data_mut::<Arc<T>>(ctx.path()).cloned();
+            let sender = ctx.message().sender().map(|s| s.to_string());
+            let d = dispatcher.clone();
+            let f = f.clone();
+            let span = call_span(method, ctx.message());
+            async move {
+                let t = match t {
+                    Some(t) => t,
+                    None => {
+                        let e = dbus::MethodErr::no_path(ctx.path());
+                        return ctx.reply(Err(e));
+                    }
+                };
+                let res = f(t, d, sender, args).await;
+                ctx.reply(res)
+            }
+            .instrument(span)
+        },
+    );
+}
+
+/// Make a Broker1 call on behalf of the account manager, dispatched as the
+/// call would be had the client made it.
+async fn manager_call<F, R>(
+    d: Arc<Dispatcher>,
+    sender: Option<String>,
+    method: &'static str,
+    request_json: String,
+    f: F,
+) -> Result<String, dbus::MethodErr>
+where
+    F: FnOnce(String, String, String, Option<CallerInfo>) -> R,
+    R: Future<Output = Result<String, dbus::MethodErr>>,
+{
+    let protocol_version = ProtocolVersion::default().to_string();
+    let correlation_id = uuid::Uuid::new_v4().to_string();
+    let call = Broker1Call {
+        method,
+        protocol_version: &protocol_version,
+        correlation_id: &correlation_id,
+        request_json: &request_json,
+        app_id: None,
+    };
+    let (a, b, c) = (
+        protocol_version.clone(),
+        correlation_id.clone(),
+        request_json.clone(),
+    );
+    run_async(d, sender, call, move |caller| f(a, b, c, caller)).await
+}
+
+fn register_account_manager<T>(
+    cr: &mut crossroads::Crossroads,
+    dispatcher: Arc<Dispatcher>,
+    config: AccountManagerConfig,
+) -> crossroads::IfaceToken<Arc<T>>
+where
+    T: AsyncSessionBroker + 'static,
+{
+    let config = Arc::new(config);
+    cr.register(ACCOUNT_MANAGER_INTERFACE, move |b| {
+        manager_method(
+            b,
+            dispatcher.clone(),
+            "ListAccounts",
+            (),
+            ("accounts",),
+            |t: Arc<T>, d, sender, ()| async move {
+                let t2 = t.clone();
+                let resp = manager_call(
+                    d.clone(),
+                    sender.clone(),
+                    "getAccounts",
+                    "{}".to_string(),
+                    |a, b, c, caller| t.get_accounts(a, b, c, caller),
+                )
+                .await
+                .and_then(check_response)?;
+                // Brokers without a default account list none as default.
+                let default = manager_call(
+                    d,
+                    sender,
+                    "getDefaultAccount",
+                    "{}".to_string(),
+                    |a, b, c, caller| t2.get_default_account(a, b, c, caller),
+                )
+                .await
+                .map(|resp| default_account_id(&resp))
+                .unwrap_or_default();
+                Ok((account_entries(&resp, &default)?,))
+            },
+        );
+        manager_method(
+            b,
+            dispatcher.clone(),
+            "GetDefaultAccount",
+            (),
+            ("home_account_id",),
+            |t: Arc<T>, d, sender, ()| async move {
+                let resp = manager_call(
+                    d,
+                    sender,
+                    "getDefaultAccount",
+                    "{}".to_string(),
+                    |a, b, c, caller| t.get_default_account(a, b, c, caller),
+                )
+                .await
+                .and_then(check_response)?;
+                Ok((default_account_id(&resp),))
+            },
+        );
+        let cfg = config.clone();
+        manager_method(
+            b,
+            dispatcher.clone(),
+            "SetDefaultAccount",
+            ("account",),
+            ("home_account_id",),
+            move |t: Arc<T>, d, sender, (account,): (String,)| {
+                let cfg = cfg.clone();
+                async move {
+                    let resp = manager_call(
+                        d,
+                        sender,
+                        "setDefaultAccount",
+                        set_default_account_request(&account),
+                        |a, b, c, caller| async move {
+                            cfg.authorize("SetDefaultAccount", caller.as_ref())
+                                .await?;
+                            t.set_default_account(a, b, c, caller).await
+                        },
+                    )
+                    .await
+                    .and_then(check_response)?;
+                    Ok((default_account_id(&resp),))
+                }
+            },
+        );
+        let cfg = config.clone();
+        manager_method(
+            b,
+            dispatcher.clone(),
+            "RemoveAccount",
+            ("home_account_id",),
+            (),
+            move |t: Arc<T>, d, sender, (id,): (String,)| {
+                let cfg = cfg.clone();
+                async move {
+                    manager_call(
+                        d,
+                        sender,
+                        "removeAccount",
+                        remove_account_request(&id),
+                        |a, b, c, caller| async move {
+                            cfg.authorize("RemoveAccount", caller.as_ref())
+                                .await?;
+                            t.remove_account(a, b, c, caller).await
+                        },
+                    )
+                    .await
+                    .and_then(check_response)?;
+                    Ok(())
+                }
+            },
+        );
+    })
+}
+
+pub(crate) async fn call_by_name<T>(
+    t: Arc<T>,
+    stats: Arc<BrokerStats>,
+    req: PortalRequest,
+    caller: Option<CallerInfo>,
+) -> Result<String, dbus::MethodErr>
+where
+    T: AsyncSessionBroker + 'static,
+{
+    let (a, b, c) =
+        (req.protocol_version, req.correlation_id, req.request_json);
+    match req.method {
+        "acquireTokenInteractively" => {
+            stats.interactive_flow_started();
+            let res = t.acquire_token_interactively(a, b, c, caller).await;
+            stats.interactive_flow_finished();
+            res
+        }
+        "acquireTokenSilently" => {
+            t.acquire_token_silently(a, b, c, caller).await
+        }
+        "getAccounts" => t.get_accounts(a, b, c, caller).await,
+        "removeAccount" => t.remove_account(a, b, c, caller).await,
+        "acquirePrtSsoCookie" => {
+            t.acquire_prt_sso_cookie(a, b, c, caller).await
+        }
+        "generateSignedHttpRequest" => {
+            t.generate_signed_http_request(a, b, c, caller).await
+        }
+        "cancelInteractiveFlow" => {
+            t.cancel_interactive_flow(a, b, c, caller).await
+        }
+        "getLinuxBrokerVersion" => {
+            t.get_linux_broker_version(a, b, c, caller).await
+        }
+        method => Err(dbus::MethodErr::no_method(&method)),
+    }
+}
+
+fn register_portal_frontend<T>(
+    cr: &mut crossroads::Crossroads,
+    dispatcher: Arc<Dispatcher>,
+) -> crossroads::IfaceToken<Arc<T>>
+where
+    T: AsyncSessionBroker + 'static,
+{
+    cr.register(PORTAL_INTERFACE, move |b| {
+        let d = dispatcher.clone();
+        b.method_with_cr_async(
+            "Request",
+            ("handle", "app_id", "method", "request_json", "options"),
+            ("response", "results"),
+            move |mut ctx, cr, args: PortalArgs| {
+                let t = cr.data_mut::<Arc<T>>(ctx.path()).cloned();
+                let sender = ctx.message().sender().map(|s| s.to_string());
+                let d = d.clone();
+                let req = PortalRequest::parse(args);
+                let span = match &req {
+                    Ok(req) => call_span(req.method, ctx.message()),
+                    Err(_) => call_span("Request", ctx.message()),
+                };
+                async move {
+                    let (t, req) = match (t, req) {
+                        (Some(t), Ok(req)) => (t, req),
+                        (None, _) => {
+                            let e = dbus::MethodErr::no_path(ctx.path());
+                            return ctx.reply(Err(e));
+                        }
+                        (_, Err(e)) => return ctx.reply(Err(e)),
+                    };
+                    let method = req.method;
+                    let protocol_version = req.protocol_version.clone();
+                    let correlation_id = req.correlation_id.clone();
+                    let request_json = req.request_json.clone();
+                    let app_id = req.app_id.clone();
+                    let call = Broker1Call {
+                        method,
+                        protocol_version: &protocol_version,
+                        correlation_id: &correlation_id,
+                        request_json: &request_json,
+                        app_id: app_id.as_deref(),
+                    };
+                    let stats = d.stats.clone();
+                    let res = run_async(d, sender, call, |caller| {
+                        call_by_name(t, stats, req, caller)
+                    })
+                    .await;
+                    ctx.reply(Ok(PortalRequest::reply(res)))
+                }
+                .instrument(span)
+            },
+        );
+    })
+}
+
+fn register_token_broker<T>(
+    cr: &mut crossroads::Crossroads,
+    dispatcher: Arc<Dispatcher>,
+) -> crossroads::IfaceToken<Arc<T>>
+where
+    T: AsyncSessionBroker + 'static,
+{
+    cr.register(TOKEN_BROKER_INTERFACE, move |b| {
+        for &(name, method) in TOKEN_BROKER_METHODS {
+            let d = dispatcher.clone();
+            b.method_with_cr_async(
+                name,
+                ("request", "options"),
+                ("response",),
+                move |mut ctx, cr, (request, options): TokenBrokerArgs| {
+                    let t = cr.data_mut::<Arc<T>>(ctx.path()).cloned();
+                    let sender = ctx.message().sender().map(|s| s.to_string());
+                    let d = d.clone();
+                    let req = PortalRequest::new(
+                        method,
+                        token_broker::request_json(&request),
+                        &options,
+                    );
+                    let span = call_span(method, ctx.message());
+                    async move {
+                        let t = match t {
+                            Some(t) => t,
+                            None => {
+                                let e = dbus::MethodErr::no_path(ctx.path());
+                                return ctx.reply(Err(e));
+                            }
+                        };
+                        let protocol_version = req.protocol_version.clone();
+                        let correlation_id = req.correlation_id.clone();
+                        let request_json = req.request_json.clone();
+                        let call = Broker1Call {
+                            method,
+                            protocol_version: &protocol_version,
+                            correlation_id: &correlation_id,
+                            request_json: &request_json,
+                            app_id: None,
+                        };
+                        let stats = d.stats.clone();
+                        let res = run_async(d, sender, call, |caller| {
+                            call_by_name(t, stats, req, caller)
+                        })
+                        .await;
+                        ctx.reply(token_broker::reply(res))
+                    }
+                    .instrument(span)
+                },
+            );
+        }
+    })
+}
+
+fn register_compliance_extension<T>(
+    cr: &mut crossroads::Crossroads,
+    dispatcher: Arc<Dispatcher>,
+) -> crossroads::IfaceToken<Arc<T>>
+where
+    T: AsyncSessionBroker + 'static,
+{
+    cr.register("org.himmelblau.Broker1.Compliance", move |b| {
+        async_method(
+            b,
+            dispatcher.clone(),
+            "getDeviceComplianceStatus",
+            |t: Arc<T>, a, b, c, caller| async move {
+                t.get_device_compliance_status(a, b, c, caller).await
+            },
+        );
+    })
+}
+
+fn register_ssh_extension<T>(
+    cr: &mut crossroads::Crossroads,
+    dispatcher: Arc<Dispatcher>,
+) -> crossroads::IfaceToken<Arc<T>>
+where
+    T: AsyncSessionBroker + 'static,
+{
+    cr.register("org.himmelblau.Broker1.Ssh", move |b| {
+        async_method(
+            b,
+            dispatcher.clone(),
+            "getSshCert",
+            |t: Arc<T>, a, b, c, caller| async move {
+                t.get_ssh_cert(a, b, c, caller).await
+            },
+        );
+    })
+}
+
+fn register_interactive_extension<T>(
+    cr: &mut crossroads::Crossroads,
+    dispatcher: Arc<Dispatcher>,
+) -> crossroads::IfaceToken<Arc<T>>
+where
+    T: AsyncSessionBroker + 'static,
+{
+    cr.register("org.himmelblau.Broker1.Interactive", move |b| {
+        async_method(
+            b,
+            dispatcher.clone(),
+            "completeInteractiveFlow",
+            |t: Arc<T>, a, b, c, caller| async move {
+                t.complete_interactive_flow(a, b, c, caller).await
+            },
+        );
+    })
+}
+
+fn register_device_code_extension<T>(
+    cr: &mut crossroads::Crossroads,
+    dispatcher: Arc<Dispatcher>,
+) -> crossroads::IfaceToken<Arc<T>>
+where
+    T: AsyncSessionBroker + 'static,
+{
+    cr.register("org.himmelblau.Broker1.DeviceCode", move |b| {
+        async_method(
+            b,
+            dispatcher.clone(),
+            "startDeviceCodeFlow",
+            |t: Arc<T>, a, b, c, caller| async move {
+                t.start_device_code_flow(a, b, c, caller).await
+            },
+        );
+        async_method(
+            b,
+            dispatcher.clone(),
+            "pollDeviceCodeFlow",
+            |t: Arc<T>, a, b, c, caller| async move {
+                t.poll_device_code_flow(a, b, c, caller).await
+            },
+        );
+        async_method(
+            b,
+            dispatcher.clone(),
+            "completeDeviceCodeFlow",
+            |t: Arc<T>, a, b, c, caller| async move {
+                t.complete_device_code_flow(a, b, c, caller).await
+            },
+        );
+    })
+}
+
+pub async fn async_session_broker_serve<T>(
+    broker: T,
+) -> Result<(), dbus::MethodErr>
+where
+    T: AsyncSessionBroker + 'static,
+{
+    async_session_broker_serve_with_options(broker, ServeOptions::default())
+        .await
+}
+
+pub async fn async_session_broker_serve_with_options<T>(
+    broker: T,
+    options: ServeOptions,
+) -> Result<(), dbus::MethodErr>
+where
+    T: AsyncSessionBroker + 'static,
+{
+    // Start up a connection to the session bus and request a name
+    let (resource, c) = dbus_tokio::connection::new_session_sync()?;
+    let connection = tokio::spawn(resource);
+    let lost = async move {
+        let e = connection.await.or_failed()?;
+        Err(dbus::MethodErr::failed(&format!(
+            "Lost connection to D-Bus: {}",
+            e
+        )))
+    };
+    serve_async(c, Box::pin(lost), broker, options).await
+}
+
+/// Serve an async session broker on a connection the application has
+/// already established, such as one to a private or test bus, or one shared
+/// with other services. The application drives the connection (spawning
+/// the `IOResource` returned alongside it), and the broker serves until
+/// the future returned here is dropped, or a takeover or idle exit ends
+/// it. Set `ServeOptions::bus` when the connection is not to the session
+/// bus, so that callers can be identified.
+pub async fn async_session_broker_serve_on<T>(
+    c: Arc<SyncConnection>,
+    broker: T,
+    options: ServeOptions,
+) -> Result<(), dbus::MethodErr>
+where
+    T: AsyncSessionBroker + 'static,
+{
+    serve_async(c, Box::pin(futures::future::pending()), broker, options).await
+}
+
+/* Every interface of the session broker is registered here, both for
+ * serving and for generating its introspection data (see
+ * `session_broker_introspection`), so that the two cannot drift apart.
+ */
+pub(crate) fn mount_async_session_broker<T>(
+    cr: &mut crossroads::Crossroads,
+    broker: Arc<T>,
+    dispatcher: &Arc<Dispatcher>,
+    options: &ServeOptions,
+) where
+    T: AsyncSessionBroker + 'static,
+{
+    let token = register_async_session_broker::<T>(cr, dispatcher.clone());
+    let accounts_token =
+        register_accounts_extension::<T>(cr, dispatcher.clone());
+    let compliance_token =
+        register_compliance_extension::<T>(cr, dispatcher.clone());
+    let ssh_token = register_ssh_extension::<T>(cr, dispatcher.clone());
+    let interactive_token =
+        register_interactive_extension::<T>(cr, dispatcher.clone());
+    let device_code_token =
+        register_device_code_extension::<T>(cr, dispatcher.clone());
+    let diag_token =
+        register_diagnostics::<Arc<T>>(cr, dispatcher.stats.clone());
+    let peer_token = register_peer::<Arc<T>>(cr);
+    let maintenance_token =
+        register_maintenance::<Arc<T>>(cr, options.maintenance.clone());
+    let ext_token = register_broker_ext(cr, |t: &Arc<T>| t.capabilities());
+    let manager_token = register_account_manager::<T>(
+        cr,
+        dispatcher.clone(),
+        options.account_manager.clone(),
+    );
+    let typed_token = register_token_broker::<T>(cr, dispatcher.clone());
+    let tokens = [
+        token,
+        typed_token,
+        accounts_token,
+        manager_token,
+        compliance_token,
+        ssh_token,
+        interactive_token,
+        device_code_token,
+        diag_token,
+        maintenance_token,
+        ext_token,
+        peer_token,
+    ];
+
+    cr.insert("/com/microsoft/identity/broker1", &tokens, broker.clone());
+    for path in options.extra_object_paths() {
+        cr.insert(path, &tokens, broker.clone());
+    }
+    if options.portal_frontend {
+        let portal_token =
+            register_portal_frontend::<T>(cr, dispatcher.clone());
+        cr.insert(PORTAL_OBJECT_PATH, &[portal_token, peer_token], broker);
+    }
+}
+
+/* The serve loop ends with an error when `lost` completes, which only
+ * happens for connections the crate established (and so drives) itself.
+ */
+async fn serve_async<T>(
+    c: Arc<SyncConnection>,
+    mut lost: Pin<Box<dyn Future<Output = Result<(), dbus::MethodErr>> + Send>>,
+    broker: T,
+    options: ServeOptions,
+) -> Result<(), dbus::MethodErr>
+where
+    T: AsyncSessionBroker + 'static,
+{
+    options.check()?;
+    let mut names = options.all_bus_names("com.microsoft.identity.broker1");
+    if options.portal_frontend {
+        names.push(PORTAL_BUS_NAME.to_string());
+    }
+    let (allow_replacement, replace_existing, do_not_queue) =
+        options.name_policy.flags();
+    for name in &names {
+        let reply = c
+            .request_name(
+                name.as_str(),
+                allow_replacement,
+                replace_existing,
+                do_not_queue,
+            )
+            .await?;
+        check_name_reply(name, reply as u32)?;
+    }
+
+    let stats = broker.stats().unwrap_or_default();
+    let dispatcher = Arc::new(Dispatcher::new(stats.clone(), &options));
+    let broker = Arc::new(broker);
+    let mut cr = crossroads::Crossroads::new();
+    cr.set_async_support(Some((
+        c.clone(),
+        Box::new(|x| {
+            tokio::spawn(x);
+        }),
+    )));
+    mount_async_session_broker(&mut cr, broker.clone(), &dispatcher, &options);
+    let mut paths = vec![dbus::Path::from("/com/microsoft/identity/broker1")];
+    paths.extend(options.extra_object_paths());
+    tokio::spawn(
+        broker
+            .clone()
+            .watch_accounts(AccountSignals::new(c.clone(), paths)),
+    );
+    #[cfg(feature = "varlink")]
+    let _varlink = match &options.varlink {
+        Some(address) => Some(
+            serve_varlink(address, broker.clone(), dispatcher.clone())
+                .map_err(|e| {
+                    error!("Failed to serve varlink on {} -> {:?}", address, e);
+                    e
+                })
+                .or_failed()?,
+        ),
+        None => None,
+    };
+
+    c.start_receive(
+        MatchRule::new_method_call(),
+        Box::new(move |msg, conn| {
+            if cr.handle_message(msg, conn).is_err() {
+                error!("Failed to dispatch a method call");
+            }
+            true
+        }),
+    );
+
+    let watch = Arc::new(
+        NameWatch::new(
+            names,
+            options.on_takeover,
+            options.on_name_lost.clone(),
+            options.bus_type(BusType::Session),
+        )
+        .idle_exit(dispatcher.idle_timer()),
+    );
+    let (taken_tx, mut taken) = tokio::sync::mpsc::unbounded_channel();
+    let unique_name = c.unique_name().to_string();
+    let w = watch.clone();
+    let _name_watch = c.add_match(NameWatch::match_rule()).await?.cb(
+        move |_, sig: DBusNameOwnerChanged| {
+            if let Some(name) = w.taken_over(&sig, &unique_name) {
+                let _ = taken_tx.send((name, sig.arg2));
+            }
+            true
+        },
+    );
+
+    let w = watch.clone();
+    let _name_lost = c.add_match(NameWatch::name_lost_rule()).await?.cb(
+        move |_, sig: DBusNameLost| {
+            w.name_lost(&sig);
+            true
+        },
+    );
+
+    options.apply_seccomp()?;
+
+    // Serve clients until the bus connection is lost, a takeover asks us to
+    // stop, or the broker has been idle long enough to exit.
+    let mut idle_check = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        tokio::select! {
+            _ = idle_check.tick() => {
+                if watch.idle_expired() {
+                    for name in watch.names() {
+                        let reply = c.release_name(name.as_str()).await?;
+                        debug!("Released {} -> {:?}", name, reply);
+                    }
+                    // Serve calls which reached us before the names were
+                    // released.
+                    while !watch.idle_settled() {
+                        idle_check.tick().await;
+                    }
+                    return Ok(());
+                }
+            }
+            res = &mut lost => return res,
+            Some((name, new_owner)) = taken.recv() => {
+                let w = watch.clone();
+                let n = name.clone();
+                tokio::task::spawn_blocking(move || w.report(&n, &new_owner))
+                    .await
+                    .or_failed()?;
+                match watch.action() {
+                    TakeoverAction::Log => {}
+                    TakeoverAction::Reacquire => {
+                        let reply = c
+                            .request_name(name.as_str(), false, true, false)
+                            .await?;
+                        info!("Requested {} again -> {:?}", name, reply);
+                    }
+                    TakeoverAction::Exit => {
+                        info!("Exiting after losing {}", name);
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+}
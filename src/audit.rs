@@ -0,0 +1,234 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::caller::CallerInfo;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::error;
+
+/// Fields of a DeviceBroker1 request which may name the key in use.
+const THUMBPRINT_FIELDS: &[&str] = &["thumbprint", "keyThumbprint", "kid"];
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum KeyOperation {
+    Sign,
+    Decrypt,
+}
+
+/// A single use of a device key, as recorded in the key usage log.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct KeyUsageRecord {
+    /// Seconds since the Unix epoch.
+    pub timestamp: u64,
+    pub session_id: String,
+    pub bus_name: String,
+    pub uid: Option<u32>,
+    /// The key thumbprint named by the request, if any.
+    pub thumbprint: Option<String>,
+    pub operation: KeyOperation,
+    pub success: bool,
+    /// The error returned to the caller when the operation failed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// The key thumbprint named by a sign or decrypt request.
+pub fn key_thumbprint(request_json: &str) -> Option<String> {
+    let request: Value = serde_json::from_str(request_json).ok()?;
+    THUMBPRINT_FIELDS
+        .iter()
+        .find_map(|field| request.get(field)?.as_str())
+        .map(String::from)
+}
+
+fn open_log(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .append(true)
+        .create(true)
+        .mode(0o600)
+        .open(path)
+}
+
+fn append_record(file: &mut File, record: &impl Serialize) -> io::Result<()> {
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+    // A single write keeps concurrent appends from interleaving.
+    file.write_all(&line)?;
+    file.sync_data()
+}
+
+/* The key usage log is a file of JSON lines, opened for appending only (and
+ * readable only by its owner), so that records are never rewritten. Rotation
+ * is left to the system, eg. logrotate with copytruncate.
+ */
+pub struct KeyUsageLog {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl KeyUsageLog {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = open_log(path.as_ref())?;
+        Ok(KeyUsageLog {
+            path: path.as_ref().to_path_buf(),
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn append(&self, record: &KeyUsageRecord) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap_or_else(PoisonError::into_inner);
+        append_record(&mut file, record)
+    }
+
+    /// Record an operation, logging (rather than failing the operation) if
+    /// the record could not be written.
+    pub(crate) fn record<R>(
+        &self,
+        operation: KeyOperation,
+        session_id: &str,
+        caller: &CallerInfo,
+        request_json: &str,
+        res: &Result<R, dbus::MethodErr>,
+    ) {
+        let record = KeyUsageRecord {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            session_id: session_id.to_string(),
+            bus_name: caller.bus_name.clone(),
+            uid: caller.uid,
+            thumbprint: key_thumbprint(request_json),
+            operation,
+            success: res.is_ok(),
+            error: res.as_ref().err().map(|e| e.description().to_string()),
+        };
+        if let Err(e) = self.append(&record) {
+            error!(
+                "Failed to write to key usage log {} -> {:?}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+
+    /// The most recent `limit` records, oldest first, optionally only those
+    /// for the key with `thumbprint`. Lines which cannot be parsed are
+    /// skipped.
+    pub fn recent(
+        &self,
+        thumbprint: Option<&str>,
+        limit: usize,
+    ) -> io::Result<Vec<KeyUsageRecord>> {
+        let reader = BufReader::new(File::open(&self.path)?);
+        let mut records = Vec::new();
+        for line in reader.lines() {
+            let record: KeyUsageRecord = match serde_json::from_str(&line?) {
+                Ok(record) => record,
+                Err(_) => continue,
+            };
+            if thumbprint.is_some()
+                && record.thumbprint.as_deref() != thumbprint
+            {
+                continue;
+            }
+            records.push(record);
+        }
+        let skip = records.len().saturating_sub(limit);
+        Ok(records.split_off(skip))
+    }
+}
+
+/// A token request refused by the `CallerPolicy`, as recorded in its
+/// denial log.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct PolicyDenialRecord {
+    /// Seconds since the Unix epoch.
+    pub timestamp: u64,
+    pub method: String,
+    pub bus_name: Option<String>,
+    pub uid: Option<u32>,
+    pub pid: Option<u32>,
+    pub executable: Option<PathBuf>,
+    /// The caller's LSM label, such as its SELinux context.
+    pub security_label: Option<String>,
+    /// The sandboxed application a portal frontend call was made for.
+    #[serde(default)]
+    pub app_id: Option<String>,
+    /// Why the caller was refused.
+    pub reason: String,
+    /// False when the policy is permissive, and the call went ahead.
+    pub enforced: bool,
+}
+
+impl PolicyDenialRecord {
+    pub(crate) fn new(
+        method: &str,
+        caller: Option<&CallerInfo>,
+        reason: &str,
+        enforced: bool,
+    ) -> Self {
+        PolicyDenialRecord {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            method: method.to_string(),
+            bus_name: caller.map(|c| c.bus_name.clone()),
+            uid: caller.and_then(|c| c.uid),
+            pid: caller.and_then(|c| c.pid),
+            executable: caller.and_then(CallerInfo::executable),
+            security_label: caller.and_then(|c| c.security_label.clone()),
+            app_id: caller.and_then(|c| c.app_id.clone()),
+            reason: reason.to_string(),
+            enforced,
+        }
+    }
+
+    /* Denials are rare, so the log is opened for each one rather than held
+     * open by the policy, which is plain configuration.
+     */
+    /// Append the record to the denial log at `path`, a file of JSON lines
+    /// readable only by its owner.
+    pub fn append_to(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        append_record(&mut open_log(path.as_ref())?, self)
+    }
+}
+
+/// The records in the denial log at `path`, oldest first. Lines which
+/// cannot be parsed are skipped.
+pub fn read_policy_denials(
+    path: impl AsRef<Path>,
+) -> io::Result<Vec<PolicyDenialRecord>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut records = Vec::new();
+    for line in reader.lines() {
+        if let Ok(record) = serde_json::from_str(&line?) {
+            records.push(record);
+        }
+    }
+    Ok(records)
+}
@@ -0,0 +1,105 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use identity_dbus_broker::{
+    device_broker_examples, device_broker_introspection,
+    session_broker_examples, session_broker_introspection,
+};
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process::ExitCode;
+
+const USAGE: &str = "\
+Usage: himmelblau-broker-introspect [--device] [--examples]
+       himmelblau-broker-introspect --write <dir>
+
+Print the introspection XML of the session broker object (or with --device,
+of the device broker object), generated from the same registration code the
+brokers serve with. With --examples, print example gdbus invocations of its
+methods instead. With --write, write all of them into <dir>, as kept in the
+crate's interfaces/ directory.
+";
+
+type Generator = fn() -> String;
+
+/// The generated files, as named in the interfaces/ directory.
+const FILES: [(&str, Generator); 4] = [
+    (
+        "com.microsoft.identity.broker1.xml",
+        session_broker_introspection,
+    ),
+    ("com.microsoft.identity.broker1.sh", session_broker_examples),
+    (
+        "com.microsoft.identity.DeviceBroker1.xml",
+        device_broker_introspection,
+    ),
+    (
+        "com.microsoft.identity.DeviceBroker1.sh",
+        device_broker_examples,
+    ),
+];
+
+fn main() -> ExitCode {
+    let mut device = false;
+    let mut examples = false;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--device" => device = true,
+            "--examples" => examples = true,
+            "--write" => {
+                let dir = match args.next() {
+                    Some(dir) => dir,
+                    None => {
+                        eprint!("{}", USAGE);
+                        return ExitCode::FAILURE;
+                    }
+                };
+                return write(Path::new(&dir));
+            }
+            "--help" | "-h" => {
+                eprint!("{}", USAGE);
+                return ExitCode::SUCCESS;
+            }
+            _ => {
+                eprint!("Unknown argument {}\n\n{}", arg, USAGE);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    let out = match (device, examples) {
+        (false, false) => session_broker_introspection(),
+        (false, true) => session_broker_examples(),
+        (true, false) => device_broker_introspection(),
+        (true, true) => device_broker_examples(),
+    };
+    print!("{}", out);
+    ExitCode::SUCCESS
+}
+
+fn write(dir: &Path) -> ExitCode {
+    for (name, generate) in FILES {
+        let path = dir.join(name);
+        if let Err(e) = fs::write(&path, generate()) {
+            eprintln!("Failed to write {}: {}", path.display(), e);
+            return ExitCode::FAILURE;
+        }
+    }
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,65 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use identity_dbus_broker::{native_messaging_serve, Broker1Client};
+use std::process::ExitCode;
+use std::time::Duration;
+
+/* Interactive flows wait on the user, so calls are given ten minutes,
+ * leaving the session broker's own timeouts to end them sooner.
+ */
+const CALL_TIMEOUT: Duration = Duration::from_secs(600);
+
+/* Browsers start the host with arguments naming the calling extension
+ * (its origin, or the manifest path and extension id), which are not
+ * needed: the host manifest's allowed_origins or allowed_extensions decide
+ * which extensions may start it.
+ */
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> ExitCode {
+    // Standard output carries the protocol, so logs go to the browser's
+    // log through standard error.
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            tracing_subscriber::EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| "warn".into()),
+        )
+        .with_writer(std::io::stderr)
+        .init();
+
+    let client = match Broker1Client::session(CALL_TIMEOUT) {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("Failed to connect to the session bus: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    match native_messaging_serve(
+        client,
+        tokio::io::stdin(),
+        tokio::io::stdout(),
+    )
+    .await
+    {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Native messaging failed: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,150 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use identity_dbus_broker::{
+    broker_proxy_serve, ProxyConfig, ProxyUpstream, SocketAddress, UidRange,
+};
+use std::env;
+use std::process::ExitCode;
+use tokio::signal::unix::{signal, SignalKind};
+
+const USAGE: &str = "\
+Usage: himmelblau-broker-proxy --listen <socket> (--daemon <socket> | --session-bus)
+                               [--uid <uid>[:<count>]]... [--timeout <seconds>]
+
+Serve Entra ID brokering to a container on <socket>, forwarding requests to
+the host's himmelblau daemon or session broker. Run it as the host user whose
+accounts the container should use. Prefix a socket with @ for the abstract
+namespace.
+
+  --listen <socket>       the socket container session brokers connect to
+  --daemon <socket>       relay connections to the daemon socket
+  --session-bus           call the session broker on the host's session bus
+  --uid <uid>[:<count>]   also serve these host uids, such as the uid the
+                          container's user is mapped to
+  --timeout <seconds>     how long to wait for the session broker (300)
+";
+
+fn address(arg: &str) -> SocketAddress {
+    match arg.strip_prefix('@') {
+        Some(name) => SocketAddress::Abstract(name.to_string()),
+        None => SocketAddress::Path(arg.into()),
+    }
+}
+
+fn uid_range(arg: &str) -> Option<UidRange> {
+    let (start, count) = match arg.split_once(':') {
+        Some((start, count)) => (start, count.parse().ok()?),
+        None => (arg, 1),
+    };
+    Some(UidRange {
+        start: start.parse().ok()?,
+        count,
+    })
+}
+
+fn parse_args() -> Result<ProxyConfig, String> {
+    let mut args = env::args().skip(1);
+    let mut listen = None;
+    let mut upstream = None;
+    let mut uid_map = vec![];
+    let mut timeout = None;
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--listen" => listen = Some(address(&value()?)),
+            "--daemon" => {
+                upstream = Some(ProxyUpstream::Socket(address(&value()?)))
+            }
+            "--session-bus" => upstream = Some(ProxyUpstream::SessionBus),
+            "--uid" => {
+                let value = value()?;
+                uid_map.push(
+                    uid_range(&value)
+                        .ok_or(format!("Invalid uid range {}", value))?,
+                );
+            }
+            "--timeout" => {
+                let value = value()?;
+                timeout = Some(
+                    value
+                        .parse()
+                        .map_err(|_| format!("Invalid timeout {}", value))?,
+                );
+            }
+            "--help" | "-h" => return Err(String::new()),
+            _ => return Err(format!("Unknown argument {}", arg)),
+        }
+    }
+    let mut config = ProxyConfig::new(
+        listen.ok_or("--listen is required")?,
+        upstream.ok_or("--daemon or --session-bus is required")?,
+    );
+    config.uid_map = uid_map;
+    if let Some(timeout) = timeout {
+        config.timeout = timeout;
+    }
+    Ok(config)
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> ExitCode {
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            tracing_subscriber::EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| "info".into()),
+        )
+        .with_writer(std::io::stderr)
+        .init();
+
+    let config = match parse_args() {
+        Ok(config) => config,
+        Err(e) => {
+            if !e.is_empty() {
+                eprintln!("{}\n", e);
+            }
+            eprint!("{}", USAGE);
+            return ExitCode::FAILURE;
+        }
+    };
+    let (shutdown_tx, shutdown_rx) = tokio::sync::broadcast::channel(1);
+    let server = match broker_proxy_serve(config, shutdown_rx).await {
+        Ok(server) => server,
+        Err(e) => {
+            eprintln!("Failed to start the proxy: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let (mut sigterm, mut sigint) = match (
+        signal(SignalKind::terminate()),
+        signal(SignalKind::interrupt()),
+    ) {
+        (Ok(sigterm), Ok(sigint)) => (sigterm, sigint),
+        _ => {
+            eprintln!("Failed to install signal handlers");
+            return ExitCode::FAILURE;
+        }
+    };
+    tokio::select! {
+        _ = sigterm.recv() => {}
+        _ = sigint.recv() => {}
+    }
+    let _ = shutdown_tx.send(true);
+    let _ = server.await;
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,143 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use identity_dbus_broker::{
+    async_session_broker_serve, read_capture, replay_capture, FaultConfig,
+    MockSessionBroker,
+};
+use std::env;
+use std::process::ExitCode;
+use std::sync::Arc;
+
+const USAGE: &str = "\
+Usage: himmelblau-broker-replay [--serve] [--faults <json>] <bundle>
+
+Replay the requests recorded in a capture bundle against a mock session
+broker answering with the captured responses, printing each call and whether
+its outcome matched the capture. With --serve, the mock is instead served on
+the session bus, so the client which reported the issue can be run against
+it.
+
+--faults makes the mock misbehave as a FaultConfig given as JSON, such as
+'{\"timeout\": 0.1, \"reset\": 0.05, \"seed\": 1}', to see how the
+client copes with a broker which times out, answers with malformed or
+truncated responses, or drops off the bus.
+";
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> ExitCode {
+    let mut serve = false;
+    let mut faults = None;
+    let mut bundle = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--serve" => serve = true,
+            "--faults" => {
+                let config = args.next().unwrap_or_default();
+                match serde_json::from_str::<FaultConfig>(&config) {
+                    Ok(config) => faults = Some(config),
+                    Err(e) => {
+                        eprint!(
+                            "Invalid --faults {}: {}\n\n{}",
+                            config, e, USAGE
+                        );
+                        return ExitCode::FAILURE;
+                    }
+                }
+            }
+            "--help" | "-h" => {
+                eprint!("{}", USAGE);
+                return ExitCode::SUCCESS;
+            }
+            _ if bundle.is_none() && !arg.starts_with('-') => {
+                bundle = Some(arg)
+            }
+            _ => {
+                eprint!("Unknown argument {}\n\n{}", arg, USAGE);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    let bundle = match bundle {
+        Some(bundle) => bundle,
+        None => {
+            eprint!("{}", USAGE);
+            return ExitCode::FAILURE;
+        }
+    };
+    let calls = match read_capture(&bundle) {
+        Ok(calls) => calls,
+        Err(e) => {
+            eprintln!("Failed to read {}: {}", bundle, e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mock = MockSessionBroker::from_capture(&calls);
+    if let Some(faults) = faults {
+        mock.inject_faults(faults);
+    }
+    if serve {
+        return match async_session_broker_serve(mock).await {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                eprintln!("Failed to serve the mock broker: {:?}", e);
+                ExitCode::FAILURE
+            }
+        };
+    }
+
+    let mock = Arc::new(mock);
+    let replayed = replay_capture(mock, &calls).await;
+    let mut mismatches = 0;
+    for call in &replayed {
+        let outcome = match &call.result {
+            Ok(_) => "ok".to_string(),
+            Err(e) => e.name.clone(),
+        };
+        let verdict = if call.matches() {
+            "matched"
+        } else {
+            mismatches += 1;
+            "MISMATCHED"
+        };
+        println!(
+            "{} {} {} ({} ms, uid {}) -> {} [{}]",
+            call.captured.timestamp,
+            call.captured.correlation_id,
+            call.captured.method,
+            call.captured.duration,
+            call.captured
+                .uid
+                .map_or_else(|| "unknown".to_string(), |u| u.to_string()),
+            outcome,
+            verdict
+        );
+    }
+    println!(
+        "{} calls replayed, {} mismatched",
+        replayed.len(),
+        mismatches
+    );
+    if mismatches == 0 {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
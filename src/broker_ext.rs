@@ -0,0 +1,131 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use dbus_crossroads as crossroads;
+use serde::{Deserialize, Serialize};
+
+/// The interface on which `getCapabilities` is served, alongside Broker1
+/// and DeviceBroker1.
+pub const BROKER_EXT_INTERFACE: &str = "org.himmelblau.BrokerExt1";
+
+/// The revision of the broker traits, bumped whenever methods are added.
+pub const BROKER_EXT_VERSION: u32 = 1;
+
+/// The Broker1 methods every `SessionBroker` implements.
+pub const BROKER1_METHODS: [&str; 8] = [
+    "acquireTokenInteractively",
+    "acquireTokenSilently",
+    "getAccounts",
+    "removeAccount",
+    "acquirePrtSsoCookie",
+    "generateSignedHttpRequest",
+    "cancelInteractiveFlow",
+    "getLinuxBrokerVersion",
+];
+
+/// The DeviceBroker1 methods every `DeviceBroker` implements.
+pub const DEVICE_BROKER1_METHODS: [&str; 18] = [
+    "sign",
+    "generateKeyPair",
+    "loadKeyPair",
+    "persistKey",
+    "generateDerivedKey",
+    "deleteKey",
+    "decrypt",
+    "generatePKCS10CertSigningRequest",
+    "asymmetricKeyExists",
+    "asymmetricKeyWithThumbprintExists",
+    "getAsymmetricKeyThumbprint",
+    "generateAsymmetricKey",
+    "getAsymmetricKeyCreationDate",
+    "clearAsymmetricKey",
+    "getRequestConfirmation",
+    "mintSignedAccessToken",
+    "mintSignedHttpRequest",
+    "makeHttpRequestWithClientTls",
+];
+
+/* Methods are added to the broker traits over time, as Microsoft's broker
+ * grows new ones. So that existing implementations keep compiling, every
+ * method added after a trait's first release has a default implementation
+ * failing with `not_supported`, and clients ask getCapabilities which
+ * methods a broker really implements before calling them.
+ */
+
+/// The methods a broker implements, as returned (in JSON) by
+/// getCapabilities.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Capabilities {
+    /// The `BROKER_EXT_VERSION` the broker was built against.
+    pub version: u32,
+    /// The D-Bus names of the implemented methods.
+    pub methods: Vec<String>,
+}
+
+impl Capabilities {
+    pub fn new<'a>(methods: impl IntoIterator<Item = &'a str>) -> Self {
+        Capabilities {
+            version: BROKER_EXT_VERSION,
+            methods: methods.into_iter().map(str::to_string).collect(),
+        }
+    }
+
+    /// Add an optional method the broker implements.
+    pub fn with(mut self, method: &str) -> Self {
+        if !self.supports(method) {
+            self.methods.push(method.to_string());
+        }
+        self
+    }
+
+    pub fn supports(&self, method: &str) -> bool {
+        self.methods.iter().any(|m| m == method)
+    }
+}
+
+/// The error returned by the default implementation of an optional method.
+pub fn not_supported(method: &str) -> dbus::MethodErr {
+    dbus::MethodErr::from((
+        "org.freedesktop.DBus.Error.NotSupported",
+        format!("{} is not supported by this broker", method),
+    ))
+}
+
+pub(crate) fn register_broker_ext<T, F>(
+    cr: &mut crossroads::Crossroads,
+    capabilities: F,
+) -> crossroads::IfaceToken<T>
+where
+    T: Send + 'static,
+    F: Fn(&T) -> Capabilities + Clone + Send + 'static,
+{
+    cr.register(BROKER_EXT_INTERFACE, move |b| {
+        let capabilities = capabilities.clone();
+        b.method(
+            "getCapabilities",
+            (),
+            ("capabilities",),
+            move |_, t: &mut T, ()| {
+                serde_json::to_string(&capabilities(t))
+                    .map(|x| (x,))
+                    .map_err(|e| dbus::MethodErr::failed(&e))
+            },
+        );
+    })
+}
@@ -16,10 +16,21 @@
    along with this program. If not, see <https://www.gnu.org/licenses/>.
 */
 
-use serde::{Deserialize, Serialize};
+use crate::caller::CallerInfo;
+use crate::diagnostics::LogLevelChange;
+use crate::error::BrokerError;
+use crate::events::AccountEvent;
+use crate::federation::{FederationRequest, FederationResponse};
+use crate::prompt::{FdHandoff, PromptRequest, PromptResponse};
+use crate::wire::WireFormatKind;
+use serde::{de, Deserialize, Deserializer, Serialize};
+use std::cell::Cell;
+use std::error::Error;
+use std::fmt;
 
 #[allow(non_camel_case_types)]
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
 pub enum ClientRequest {
     acquireTokenInteractively(String, String, String),
     acquireTokenSilently(String, String, String),
@@ -29,4 +40,202 @@ pub enum ClientRequest {
     generateSignedHttpRequest(String, String, String),
     cancelInteractiveFlow(String, String, String),
     getLinuxBrokerVersion(String, String, String),
+    refreshPrt(String, String, String),
+    removeAccounts(String, String, String),
+    getAccountsPage(String, String, String),
+    getDeviceComplianceStatus(String, String, String),
+    getSshCert(String, String, String),
+    completeInteractiveFlow(String, String, String),
+    startDeviceCodeFlow(String, String, String),
+    pollDeviceCodeFlow(String, String, String),
+    completeDeviceCodeFlow(String, String, String),
+    promptResponse(PromptResponse),
+    federationResponse(FederationResponse),
+    /// Ask the daemon to switch the connection to one of the given wire
+    /// formats, in order of preference.
+    negotiateFormat(Vec<WireFormatKind>),
+    /// Change the daemon's log level. Only root may send this.
+    setLogLevel(LogLevelChange),
+    /// Turn the connection into a subscription to the user's account
+    /// events. No further requests are read from it.
+    subscribe,
+    /// A request made on behalf of a D-Bus caller. The request within may
+    /// not itself be a withCaller.
+    withCaller(
+        CallerInfo,
+        #[serde(deserialize_with = "forwarded_request")] Box<ClientRequest>,
+    ),
 }
+
+thread_local! {
+    static FORWARDING: Cell<bool> = const { Cell::new(false) };
+}
+
+/* Requests are decoded recursively, and bincode, unlike serde_json and
+ * ciborium, does not limit the depth, so a small frame of nested
+ * withCaller requests could exhaust the stack. The request within a
+ * withCaller is decoded with FORWARDING set, and a withCaller within it
+ * fails before its own request is reached.
+ */
+fn forwarded_request<'de, D>(d: D) -> Result<Box<ClientRequest>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    if FORWARDING.with(|forwarding| forwarding.replace(true)) {
+        return Err(de::Error::custom(
+            "Nested caller requests are not accepted",
+        ));
+    }
+    let req = ClientRequest::deserialize(d);
+    FORWARDING.with(|forwarding| forwarding.set(false));
+    req.map(Box::new)
+}
+
+impl ClientRequest {
+    /// The D-Bus method name of the request.
+    pub fn method(&self) -> &'static str {
+        match self {
+            ClientRequest::acquireTokenInteractively(..) => {
+                "acquireTokenInteractively"
+            }
+            ClientRequest::acquireTokenSilently(..) => "acquireTokenSilently",
+            ClientRequest::getAccounts(..) => "getAccounts",
+            ClientRequest::removeAccount(..) => "removeAccount",
+            ClientRequest::acquirePrtSsoCookie(..) => "acquirePrtSsoCookie",
+            ClientRequest::generateSignedHttpRequest(..) => {
+                "generateSignedHttpRequest"
+            }
+            ClientRequest::cancelInteractiveFlow(..) => "cancelInteractiveFlow",
+            ClientRequest::getLinuxBrokerVersion(..) => "getLinuxBrokerVersion",
+            ClientRequest::refreshPrt(..) => "refreshPrt",
+            ClientRequest::removeAccounts(..) => "removeAccounts",
+            ClientRequest::getAccountsPage(..) => "getAccountsPage",
+            ClientRequest::getDeviceComplianceStatus(..) => {
+                "getDeviceComplianceStatus"
+            }
+            ClientRequest::getSshCert(..) => "getSshCert",
+            ClientRequest::completeInteractiveFlow(..) => {
+                "completeInteractiveFlow"
+            }
+            ClientRequest::startDeviceCodeFlow(..) => "startDeviceCodeFlow",
+            ClientRequest::pollDeviceCodeFlow(..) => "pollDeviceCodeFlow",
+            ClientRequest::completeDeviceCodeFlow(..) => {
+                "completeDeviceCodeFlow"
+            }
+            ClientRequest::promptResponse(..) => "promptResponse",
+            ClientRequest::federationResponse(..) => "federationResponse",
+            ClientRequest::negotiateFormat(..) => "negotiateFormat",
+            ClientRequest::setLogLevel(..) => "setLogLevel",
+            ClientRequest::subscribe => "subscribe",
+            ClientRequest::withCaller(_, req) => req.method(),
+        }
+    }
+
+    /// The (protocol_version, correlation_id, request_json) arguments of a
+    /// Broker1 method request.
+    pub fn args(&self) -> Option<(&String, &String, &String)> {
+        match self {
+            ClientRequest::acquireTokenInteractively(a, b, c)
+            | ClientRequest::acquireTokenSilently(a, b, c)
+            | ClientRequest::getAccounts(a, b, c)
+            | ClientRequest::removeAccount(a, b, c)
+            | ClientRequest::acquirePrtSsoCookie(a, b, c)
+            | ClientRequest::generateSignedHttpRequest(a, b, c)
+            | ClientRequest::cancelInteractiveFlow(a, b, c)
+            | ClientRequest::getLinuxBrokerVersion(a, b, c)
+            | ClientRequest::refreshPrt(a, b, c)
+            | ClientRequest::removeAccounts(a, b, c)
+            | ClientRequest::getAccountsPage(a, b, c)
+            | ClientRequest::getDeviceComplianceStatus(a, b, c)
+            | ClientRequest::getSshCert(a, b, c)
+            | ClientRequest::completeInteractiveFlow(a, b, c)
+            | ClientRequest::startDeviceCodeFlow(a, b, c)
+            | ClientRequest::pollDeviceCodeFlow(a, b, c)
+            | ClientRequest::completeDeviceCodeFlow(a, b, c) => Some((a, b, c)),
+            ClientRequest::promptResponse(_)
+            | ClientRequest::federationResponse(_)
+            | ClientRequest::negotiateFormat(_)
+            | ClientRequest::setLogLevel(_)
+            | ClientRequest::subscribe => None,
+            ClientRequest::withCaller(_, req) => req.args(),
+        }
+    }
+
+    /// Mutable access to the Broker1 method arguments.
+    pub fn args_mut(
+        &mut self,
+    ) -> Option<(&mut String, &mut String, &mut String)> {
+        match self {
+            ClientRequest::acquireTokenInteractively(a, b, c)
+            | ClientRequest::acquireTokenSilently(a, b, c)
+            | ClientRequest::getAccounts(a, b, c)
+            | ClientRequest::removeAccount(a, b, c)
+            | ClientRequest::acquirePrtSsoCookie(a, b, c)
+            | ClientRequest::generateSignedHttpRequest(a, b, c)
+            | ClientRequest::cancelInteractiveFlow(a, b, c)
+            | ClientRequest::getLinuxBrokerVersion(a, b, c)
+            | ClientRequest::refreshPrt(a, b, c)
+            | ClientRequest::removeAccounts(a, b, c)
+            | ClientRequest::getAccountsPage(a, b, c)
+            | ClientRequest::getDeviceComplianceStatus(a, b, c)
+            | ClientRequest::getSshCert(a, b, c)
+            | ClientRequest::completeInteractiveFlow(a, b, c)
+            | ClientRequest::startDeviceCodeFlow(a, b, c)
+            | ClientRequest::pollDeviceCodeFlow(a, b, c)
+            | ClientRequest::completeDeviceCodeFlow(a, b, c) => Some((a, b, c)),
+            ClientRequest::promptResponse(_)
+            | ClientRequest::federationResponse(_)
+            | ClientRequest::negotiateFormat(_)
+            | ClientRequest::setLogLevel(_)
+            | ClientRequest::subscribe => None,
+            ClientRequest::withCaller(_, req) => req.args_mut(),
+        }
+    }
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub enum ClientResponse {
+    result(String),
+    /// The request failed. The connection remains usable.
+    error(BrokerError),
+    promptRequest(PromptRequest),
+    federationRequest(FederationRequest),
+    /// The wire format used for the rest of the connection, sent in reply
+    /// to negotiateFormat.
+    formatSelected(WireFormatKind),
+    /// Sent in reply to subscribe, before any events.
+    subscribed,
+    event(AccountEvent),
+    /// Sent when the daemon shuts down, once any request in progress on the
+    /// connection has completed. The daemon then closes the connection.
+    shutdown,
+    protocolError(ProtocolError),
+    /// Carries a file descriptor as ancillary data.
+    fdHandoff(FdHandoff),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub enum ProtocolErrorKind {
+    FrameTooLarge,
+    MalformedFrame,
+}
+
+/// Sent by the server when a client frame is rejected, immediately before
+/// the connection is closed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub struct ProtocolError {
+    pub kind: ProtocolErrorKind,
+    pub message: String,
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}: {}", self.kind, self.message)
+    }
+}
+
+impl Error for ProtocolError {}
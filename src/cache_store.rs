@@ -0,0 +1,284 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+use linux_keyutils::{
+    KeyPermissionsBuilder, KeyRing, KeyRingIdentifier, Permission,
+};
+use openssl::rand::rand_bytes;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use tracing::{debug, error};
+
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+const KEY_DESCRIPTION_PREFIX: &str = "identity_dbus_broker:";
+
+/// Where the key encrypting an on-disk cache is kept.
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum CacheKeyStore {
+    /// A file alongside the cache, `<cache path>.key`, readable only by its
+    /// owner.
+    #[default]
+    File,
+    /// A `user` key in the kernel's per-user keyring. The key lives only as
+    /// long as the user has running processes, so the cache is discarded
+    /// once the user has fully logged out.
+    KernelKeyring,
+    /// An item in the default collection of the freedesktop Secret Service
+    /// (such as GNOME Keyring or KWallet). Requires the `secret-service`
+    /// feature.
+    SecretService,
+}
+
+/// A 256-bit key for encrypting cached data at rest.
+pub struct CacheKey([u8; 32]);
+
+impl CacheKey {
+    pub fn generate() -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let mut key = [0u8; 32];
+        rand_bytes(&mut key)?;
+        Ok(CacheKey(key))
+    }
+
+    pub fn from_bytes(key: [u8; 32]) -> Self {
+        CacheKey(key)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    fn from_slice(data: &[u8]) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        Ok(CacheKey(
+            data.try_into().map_err(|_| "Malformed cache key")?,
+        ))
+    }
+
+    /// Read the key from `path`, or create it (readable only by its owner)
+    /// if it does not yet exist.
+    pub fn load_or_create(
+        path: impl AsRef<Path>,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        match fs::read(path.as_ref()) {
+            Ok(data) => CacheKey::from_slice(&data),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let key = CacheKey::generate()?;
+                write_private(path.as_ref(), &key.0)?;
+                Ok(key)
+            }
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+
+    /// Fetch the key for the cache at `cache_path` from `store`, creating
+    /// it there if it does not yet exist.
+    pub fn for_cache(
+        store: CacheKeyStore,
+        cache_path: impl AsRef<Path>,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let cache_path = cache_path.as_ref();
+        match store {
+            CacheKeyStore::File => {
+                let mut key_path = cache_path.as_os_str().to_owned();
+                key_path.push(".key");
+                CacheKey::load_or_create(PathBuf::from(key_path))
+            }
+            CacheKeyStore::KernelKeyring => CacheKey::from_kernel_keyring(
+                &format!("{}{}", KEY_DESCRIPTION_PREFIX, cache_path.display()),
+            ),
+            #[cfg(feature = "secret-service")]
+            CacheKeyStore::SecretService => {
+                CacheKey::from_secret_service(cache_path)
+            }
+            #[cfg(not(feature = "secret-service"))]
+            CacheKeyStore::SecretService => Err(
+                "Secret Service cache keys require the secret-service feature"
+                    .into(),
+            ),
+        }
+    }
+
+    fn from_kernel_keyring(
+        description: &str,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let keyring = KeyRing::from_special_id(KeyRingIdentifier::User, false)?;
+        if let Ok(key) = keyring.search(description) {
+            debug!("Using cache key {} from the user keyring", description);
+            return CacheKey::from_slice(&key.read_to_vec()?);
+        }
+        let key = CacheKey::generate()?;
+        let stored = keyring.add_key(description, &key.0)?;
+        // By default only a possessor of the key may read it, which a
+        // restarted broker (in a new session keyring) would not be.
+        stored.set_perms(
+            KeyPermissionsBuilder::builder()
+                .posessor(Permission::ALL)
+                .user(Permission::ALL)
+                .build(),
+        )?;
+        Ok(key)
+    }
+
+    #[cfg(feature = "secret-service")]
+    fn from_secret_service(
+        cache_path: &Path,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        use dbus_secret_service::{EncryptionType, SecretService};
+        use std::collections::HashMap;
+
+        let cache = cache_path.display().to_string();
+        let attributes = HashMap::from([
+            ("application", "identity_dbus_broker"),
+            ("cache", cache.as_str()),
+        ]);
+        let service = SecretService::connect(EncryptionType::Plain)?;
+        let found = service.search_items(attributes.clone())?;
+        if let Some(item) = found.unlocked.first() {
+            return CacheKey::from_slice(&item.get_secret()?);
+        }
+        if let Some(item) = found.locked.first() {
+            item.unlock()?;
+            return CacheKey::from_slice(&item.get_secret()?);
+        }
+        let key = CacheKey::generate()?;
+        let collection = service.get_any_collection()?;
+        collection.ensure_unlocked()?;
+        collection.create_item(
+            &format!("Identity broker cache key for {}", cache),
+            attributes,
+            &key.0,
+            true,
+            "application/octet-stream",
+        )?;
+        Ok(key)
+    }
+
+    /* Sealed data is laid out as nonce || tag || ciphertext, encrypted with
+     * AES-256-GCM under a fresh random nonce.
+     */
+    fn seal(
+        &self,
+        plaintext: &[u8],
+    ) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+        let mut nonce = [0u8; NONCE_LEN];
+        rand_bytes(&mut nonce)?;
+        let mut tag = [0u8; TAG_LEN];
+        let ciphertext = encrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.0,
+            Some(&nonce),
+            &[],
+            plaintext,
+            &mut tag,
+        )?;
+        let mut sealed =
+            Vec::with_capacity(NONCE_LEN + TAG_LEN + ciphertext.len());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&tag);
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    fn unseal(
+        &self,
+        sealed: &[u8],
+    ) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+        if sealed.len() < NONCE_LEN + TAG_LEN {
+            return Err("Sealed data is truncated".into());
+        }
+        let (nonce, rest) = sealed.split_at(NONCE_LEN);
+        let (tag, ciphertext) = rest.split_at(TAG_LEN);
+        Ok(decrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.0,
+            Some(nonce),
+            &[],
+            ciphertext,
+            tag,
+        )?)
+    }
+}
+
+pub(crate) fn write_private(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)
+}
+
+/// A JSON value persisted to a file, encrypted with a `CacheKey`.
+pub struct EncryptedFile {
+    path: PathBuf,
+    key: CacheKey,
+}
+
+impl EncryptedFile {
+    pub fn new(path: impl AsRef<Path>, key: CacheKey) -> Self {
+        EncryptedFile {
+            path: path.as_ref().to_path_buf(),
+            key,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The stored value, or `None` if the file is missing. A file which
+    /// cannot be decrypted (for example after its key was lost) is treated
+    /// as missing.
+    pub fn load<T: DeserializeOwned>(&self) -> Option<T> {
+        let sealed = fs::read(&self.path).ok()?;
+        self.key
+            .unseal(&sealed)
+            .and_then(|data| Ok(serde_json::from_slice(&data)?))
+            .map_err(|e| {
+                error!(
+                    "Discarding unreadable cache {} -> {:?}",
+                    self.path.display(),
+                    e
+                )
+            })
+            .ok()
+    }
+
+    /// Atomically replace the file's contents with `value`.
+    pub fn store<T: Serialize>(
+        &self,
+        value: &T,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let sealed = self.key.seal(&serde_json::to_vec(value)?)?;
+        write_private(&self.path, &sealed)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,164 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::freedesktop::DBus;
+use crate::logind::{SessionInfo, SessionResolver};
+use dbus::arg::RefArg;
+use dbus::blocking::Connection;
+use dbus::channel::Channel;
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::{debug, error};
+
+/// Identity of the D-Bus peer which made a broker method call, as reported
+/// by the bus daemon's GetConnectionCredentials.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub struct CallerInfo {
+    /// The unique bus name of the caller (e.g. `:1.42`).
+    pub bus_name: String,
+    pub uid: Option<u32>,
+    pub pid: Option<u32>,
+    /// The caller's LSM label (e.g. an SELinux context).
+    pub security_label: Option<String>,
+    /// The caller's logind session (see `ServeOptions::logind_sessions`).
+    #[serde(default)]
+    pub session: Option<SessionInfo>,
+    /// The sandboxed application a call through the portal frontend was
+    /// made for, as named by xdg-desktop-portal. The other fields then
+    /// describe the portal, not the application.
+    #[serde(default)]
+    pub app_id: Option<String>,
+}
+
+/// The bus a broker is served on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BusType {
+    Session,
+    System,
+    /// Any other bus, such as a private or test bus, by its address.
+    Address(String),
+}
+
+/* The resolver keeps its own connection to the bus, since method handlers
+ * running inside Crossroads do not have access to the serving connection.
+ * The connection is opened lazily on the first lookup.
+ */
+pub struct CallerResolver {
+    bus: BusType,
+    conn: Mutex<Option<Connection>>,
+    sessions: Option<SessionResolver>,
+}
+
+impl CallerResolver {
+    pub fn new(bus: BusType) -> Self {
+        CallerResolver {
+            bus,
+            conn: Mutex::new(None),
+            sessions: None,
+        }
+    }
+
+    /// Also resolve the logind session of each caller (see `SessionInfo`).
+    pub fn track_sessions(mut self) -> Self {
+        self.sessions = Some(SessionResolver::new());
+        self
+    }
+
+    /// Whether org.freedesktop.ScreenSaver reports the screen as locked on
+    /// the bus the broker is served on.
+    pub fn screensaver_active(&self) -> Result<bool, dbus::Error> {
+        self.with_connection(|c| {
+            let (active,): (bool,) = c
+                .with_proxy(
+                    "org.freedesktop.ScreenSaver",
+                    "/org/freedesktop/ScreenSaver",
+                    Duration::from_secs(5),
+                )
+                .method_call("org.freedesktop.ScreenSaver", "GetActive", ())?;
+            Ok(active)
+        })
+    }
+
+    fn with_connection<F, R>(&self, f: F) -> Result<R, dbus::Error>
+    where
+        F: FnOnce(&Connection) -> Result<R, dbus::Error>,
+    {
+        let mut conn = self
+            .conn
+            .lock()
+            .map_err(|_| dbus::Error::new_failed("Caller resolver poisoned"))?;
+        if conn.is_none() {
+            *conn = Some(match &self.bus {
+                BusType::Session => Connection::new_session()?,
+                BusType::System => Connection::new_system()?,
+                BusType::Address(address) => {
+                    let mut channel = Channel::open_private(address)?;
+                    channel.register()?;
+                    Connection::from(channel)
+                }
+            });
+        }
+        let c = conn
+            .as_ref()
+            .ok_or_else(|| dbus::Error::new_failed("No bus connection"))?;
+        f(c)
+    }
+
+    pub fn resolve(&self, sender: &str) -> Result<CallerInfo, dbus::Error> {
+        let creds = self.with_connection(|c| {
+            c.with_proxy(
+                "org.freedesktop.DBus",
+                "/org/freedesktop/DBus",
+                Duration::from_secs(5),
+            )
+            .get_connection_credentials(sender)
+        })?;
+
+        let mut caller = CallerInfo {
+            bus_name: sender.to_string(),
+            uid: creds
+                .get("UnixUserID")
+                .and_then(|v| v.as_u64())
+                .map(|v| v as u32),
+            pid: creds
+                .get("ProcessID")
+                .and_then(|v| v.as_u64())
+                .map(|v| v as u32),
+            security_label: creds.get("LinuxSecurityLabel").and_then(|v| {
+                let label: Vec<u8> =
+                    v.0.as_iter()?
+                        .filter_map(|b| b.as_u64().map(|b| b as u8))
+                        .take_while(|b| *b != 0)
+                        .collect();
+                String::from_utf8(label).ok()
+            }),
+            session: None,
+            app_id: None,
+        };
+        if let (Some(sessions), Some(pid)) = (&self.sessions, caller.pid) {
+            caller.session =
+                sessions.resolve(pid, caller.uid).unwrap_or_else(|e| {
+                    error!("Failed to resolve the session of {} -> {}", pid, e);
+                    None
+                });
+        }
+        debug!("Resolved caller {:?}", caller);
+        Ok(caller)
+    }
+}
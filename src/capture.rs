@@ -0,0 +1,164 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::caller::CallerInfo;
+use crate::redact::redact_json;
+use crate::session_broker::Broker1Call;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tracing::error;
+
+/// The error a captured call failed with.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CapturedError {
+    /// The D-Bus error name, such as
+    /// `com.microsoft.identity.Broker1.Error.InteractionRequired`.
+    pub name: String,
+    pub message: String,
+}
+
+impl From<&dbus::MethodErr> for CapturedError {
+    fn from(e: &dbus::MethodErr) -> Self {
+        CapturedError {
+            name: e.errorname().to_string(),
+            message: e.description().to_string(),
+        }
+    }
+}
+
+/// A Broker1 call, as recorded in a capture bundle. Tokens and cookies in
+/// the request and response are redacted (see `redact_json`).
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct CapturedCall {
+    /// Milliseconds since the Unix epoch at which the call arrived.
+    pub timestamp: u64,
+    /// Milliseconds taken to answer the call.
+    pub duration: u64,
+    pub method: String,
+    pub protocol_version: String,
+    pub correlation_id: String,
+    pub request_json: String,
+    /// The calling process's executable, when it could be identified.
+    #[serde(default)]
+    pub executable: Option<PathBuf>,
+    #[serde(default)]
+    pub uid: Option<u32>,
+    /// The response of a successful call.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<CapturedError>,
+}
+
+impl CapturedCall {
+    /// The outcome of the call, as it was returned to the caller.
+    pub fn result(&self) -> Result<String, CapturedError> {
+        match &self.error {
+            Some(e) => Err(e.clone()),
+            None => Ok(self.response.clone().unwrap_or_default()),
+        }
+    }
+}
+
+fn millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/* Like the key usage log, a capture bundle is a file of JSON lines which is
+ * only appended to, and readable only by its owner. Capturing is meant to be
+ * switched on while an issue is reproduced, and the bundle then attached to
+ * a support request.
+ */
+pub struct CaptureBundle {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl CaptureBundle {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .mode(0o600)
+            .open(path.as_ref())?;
+        Ok(CaptureBundle {
+            path: path.as_ref().to_path_buf(),
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn append(&self, call: &CapturedCall) -> io::Result<()> {
+        let mut line = serde_json::to_vec(call)?;
+        line.push(b'\n');
+        // A single write keeps concurrent appends from interleaving.
+        let mut file = self.file.lock().unwrap_or_else(PoisonError::into_inner);
+        file.write_all(&line)
+    }
+
+    /// Record a call which arrived at `started`, logging (rather than
+    /// failing the call) if the record could not be written.
+    pub(crate) fn record(
+        &self,
+        call: &Broker1Call<'_>,
+        caller: Option<&CallerInfo>,
+        started: Instant,
+        res: &Result<String, dbus::MethodErr>,
+    ) {
+        let duration = started.elapsed();
+        let record = CapturedCall {
+            timestamp: millis(SystemTime::now() - duration),
+            duration: duration.as_millis() as u64,
+            method: call.method.to_string(),
+            protocol_version: call.protocol_version.to_string(),
+            correlation_id: call.correlation_id.to_string(),
+            request_json: redact_json(call.request_json),
+            executable: caller.and_then(CallerInfo::executable),
+            uid: caller.and_then(|caller| caller.uid),
+            response: res.as_ref().ok().map(|resp| redact_json(resp)),
+            error: res.as_ref().err().map(CapturedError::from),
+        };
+        if let Err(e) = self.append(&record) {
+            error!(
+                "Failed to write to capture bundle {} -> {:?}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+/// The calls recorded in the capture bundle at `path`, in the order they
+/// completed. Lines which cannot be parsed are skipped.
+pub fn read_capture(path: impl AsRef<Path>) -> io::Result<Vec<CapturedCall>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut calls = Vec::new();
+    for line in reader.lines() {
+        if let Ok(call) = serde_json::from_str(&line?) {
+            calls.push(call);
+        }
+    }
+    Ok(calls)
+}
@@ -0,0 +1,159 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use openssl::base64;
+use serde_json::{json, Map, Value};
+
+/* Resources enforcing Continuous Access Evaluation or Conditional Access
+ * reject tokens with a claims challenge, which the application passes to
+ * its next token request as the `claims` authentication parameter. Entra ID
+ * answers requests it cannot satisfy silently with an interaction_required
+ * error carrying the claims to step up to. The claims are opaque JSON to
+ * the broker, and are passed on unchanged.
+ */
+/// A claims challenge: the claims request (JSON) a new token must satisfy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClaimsChallenge {
+    pub claims: String,
+}
+
+impl ClaimsChallenge {
+    pub fn new(claims: impl Into<String>) -> Self {
+        ClaimsChallenge {
+            claims: claims.into(),
+        }
+    }
+
+    /// The challenge in a resource's `WWW-Authenticate` header, such as
+    /// `Bearer error="insufficient_claims", claims="eyJhY2Nlc3..."`, where
+    /// the claims are base64 encoded.
+    pub fn from_www_authenticate(www_authenticate: &str) -> Option<Self> {
+        let (scheme, params) = www_authenticate.trim().split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("Bearer") {
+            return None;
+        }
+        let mut error = None;
+        let mut claims = None;
+        for (name, value) in auth_params(params) {
+            if name.eq_ignore_ascii_case("error") {
+                error = Some(value);
+            } else if name.eq_ignore_ascii_case("claims") {
+                claims = Some(value);
+            }
+        }
+        if error.as_deref() != Some("insufficient_claims") {
+            return None;
+        }
+        let mut b64 = claims?
+            .trim_end_matches('=')
+            .replace('-', "+")
+            .replace('_', "/");
+        while !b64.len().is_multiple_of(4) {
+            b64.push('=');
+        }
+        let claims =
+            String::from_utf8(base64::decode_block(&b64).ok()?).ok()?;
+        Some(ClaimsChallenge::new(claims))
+    }
+
+    /// The `claims` authentication parameter of a token request. Claims
+    /// given as a JSON object rather than a string are accepted.
+    pub fn from_request(request_json: &str) -> Option<Self> {
+        let request: Value = serde_json::from_str(request_json).ok()?;
+        claims_value(request.get("authParameters")?.get("claims")?)
+    }
+
+    /// `request_json` with its `claims` authentication parameter set to
+    /// this challenge.
+    pub fn apply(&self, request_json: &str) -> Result<String, String> {
+        let mut request: Map<String, Value> =
+            serde_json::from_str(request_json).map_err(|e| e.to_string())?;
+        let params = request
+            .entry("authParameters")
+            .or_insert_with(|| Value::Object(Map::new()))
+            .as_object_mut()
+            .ok_or("authParameters is not an object")?;
+        params.insert("claims".to_string(), Value::String(self.claims.clone()));
+        Ok(Value::Object(request).to_string())
+    }
+
+    /// The challenge carried by an error response, either a broker error
+    /// (`{"error": {"status": ..., "claims": ...}}`) or an Entra ID OAuth
+    /// error (`{"error": "interaction_required", "claims": ...}`).
+    pub fn from_response(response_json: &str) -> Option<Self> {
+        let response: Value = serde_json::from_str(response_json).ok()?;
+        match response.get("error")? {
+            Value::Object(error) => claims_value(error.get("claims")?),
+            Value::String(_) => claims_value(response.get("claims")?),
+            _ => None,
+        }
+    }
+
+    /// An InteractionRequired broker error response carrying this
+    /// challenge, for brokers which need the user to satisfy it.
+    pub fn error_response(&self, context: &str) -> String {
+        json!({
+            "error": {
+                "status": "InteractionRequired",
+                "context": context,
+                "claims": self.claims,
+            }
+        })
+        .to_string()
+    }
+
+    /// The claims request, parsed.
+    pub fn value(&self) -> Result<Value, serde_json::Error> {
+        serde_json::from_str(&self.claims)
+    }
+}
+
+fn claims_value(value: &Value) -> Option<ClaimsChallenge> {
+    match value {
+        Value::String(claims) if !claims.is_empty() => {
+            Some(ClaimsChallenge::new(claims.as_str()))
+        }
+        Value::Object(_) => Some(ClaimsChallenge::new(value.to_string())),
+        _ => None,
+    }
+}
+
+/* Parameters are comma separated `name=value` pairs, where quoted values
+ * may themselves contain commas.
+ */
+fn auth_params(params: &str) -> Vec<(String, String)> {
+    let mut parsed = Vec::new();
+    let mut rest = params.trim();
+    while let Some((name, value)) = rest.split_once('=') {
+        let name = name.trim().trim_start_matches(',').trim().to_string();
+        let value = value.trim_start();
+        let (value, tail) = match value.strip_prefix('"') {
+            Some(quoted) => match quoted.find('"') {
+                Some(end) => (&quoted[..end], &quoted[end + 1..]),
+                None => (quoted, ""),
+            },
+            None => match value.find(',') {
+                Some(end) => (&value[..end], &value[end..]),
+                None => (value, ""),
+            },
+        };
+        parsed.push((name, value.to_string()));
+        rest = tail.trim_start();
+    }
+    parsed
+}
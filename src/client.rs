@@ -0,0 +1,471 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::accounts::{Account, DefaultAccount};
+use crate::broker_ext::BROKER1_METHODS;
+use crate::delegated_flow::{CompleteInteractiveFlowRequest, DelegatedFlow};
+use crate::device_code::{
+    DeviceCode, DeviceCodeFlowRequest, DeviceCodeState, DeviceCodeStatus,
+};
+use crate::error::BrokerError;
+use crate::pop::{
+    RequestConfirmationRequest, RequestConfirmationResponse,
+    SignedHttpRequestRequest, SignedHttpRequestResponse,
+};
+use crate::ssh_cert::{SshCertRequest, SshCertificate};
+use dbus::nonblock::{Proxy, SyncConnection};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::error;
+
+/// A client of the session broker's Broker1 interface, for callers within
+/// the user's session such as the container proxy and native messaging
+/// host.
+#[derive(Clone)]
+pub struct Broker1Client {
+    c: Arc<SyncConnection>,
+    timeout: Duration,
+}
+
+/// Parse the description of a `BrokerError::Maintenance`.
+fn maintenance(msg: &str) -> Option<BrokerError> {
+    let rest = msg.strip_prefix("Under maintenance, retry after ")?;
+    let (retry_after, reason) = rest.split_once(" seconds: ")?;
+    Some(BrokerError::Maintenance {
+        retry_after: retry_after.parse().ok()?,
+        reason: reason.to_string(),
+    })
+}
+
+/* The session broker reports broker errors under their Broker1 error names,
+ * with the BrokerError's description as the message, which are turned back
+ * into the matching BrokerError.
+ */
+fn broker_error(e: dbus::Error) -> BrokerError {
+    let msg = e.message().unwrap_or_default();
+    let name = e.name().unwrap_or_default();
+    let detail = |prefix: &str| {
+        msg.strip_prefix(prefix)
+            .and_then(|msg| msg.strip_prefix(": "))
+            .unwrap_or(msg)
+            .to_string()
+    };
+    match name.strip_prefix("com.microsoft.identity.Broker1.Error.") {
+        Some("InteractionRequired") => {
+            BrokerError::InteractionRequired(detail("Interaction required"))
+        }
+        Some("Timeout") => BrokerError::Timeout(detail("Timed out")),
+        Some("InvalidRequest") => {
+            BrokerError::InvalidRequest(detail("Invalid request"))
+        }
+        Some("Cancelled") => BrokerError::Cancelled,
+        Some("Unavailable") => maintenance(msg).unwrap_or_else(|| {
+            BrokerError::Transport(detail("Transport error"))
+        }),
+        Some("Disabled") => {
+            BrokerError::Disabled(detail("Operation disabled by policy"))
+        }
+        Some(status) => BrokerError::backend(status, detail(status)),
+        None if name.starts_with("org.freedesktop.DBus.Error.") => match name {
+            "org.freedesktop.DBus.Error.Failed" => BrokerError::unexpected(msg),
+            _ => BrokerError::Transport(format!("{}: {}", name, msg)),
+        },
+        None => BrokerError::unexpected(msg),
+    }
+}
+
+impl Broker1Client {
+    /// Connect to the session bus, waiting up to `timeout` for each call.
+    /// The connection is driven by a task on the current tokio runtime.
+    pub fn session(timeout: Duration) -> Result<Self, dbus::Error> {
+        let (resource, c) = dbus_tokio::connection::new_session_sync()?;
+        tokio::spawn(async {
+            let e = resource.await;
+            error!("Lost connection to D-Bus: {}", e);
+        });
+        Ok(Broker1Client::new(c, timeout))
+    }
+
+    /// A client calling over an existing connection.
+    pub fn new(c: Arc<SyncConnection>, timeout: Duration) -> Self {
+        Broker1Client { c, timeout }
+    }
+
+    /// Call the Broker1 `method` (one of `BROKER1_METHODS`), returning its
+    /// result JSON.
+    pub async fn call(
+        &self,
+        method: &str,
+        protocol_version: &str,
+        correlation_id: &str,
+        request_json: &str,
+    ) -> Result<String, BrokerError> {
+        if !BROKER1_METHODS.contains(&method) {
+            return Err(BrokerError::InvalidRequest(format!(
+                "Unknown broker method {}",
+                method
+            )));
+        }
+        self.call_interface(
+            "com.microsoft.identity.Broker1",
+            method,
+            (protocol_version, correlation_id, request_json),
+        )
+        .await
+    }
+
+    /// Request an OpenSSH certificate for `request.public_key` from the
+    /// session broker's org.himmelblau.Broker1.Ssh extension.
+    pub async fn get_ssh_cert(
+        &self,
+        protocol_version: &str,
+        correlation_id: &str,
+        request: &SshCertRequest,
+    ) -> Result<SshCertificate, BrokerError> {
+        let request_json = serde_json::to_string(request)?;
+        let resp = self
+            .call_interface(
+                "org.himmelblau.Broker1.Ssh",
+                "getSshCert",
+                (protocol_version, correlation_id, request_json.as_str()),
+            )
+            .await?;
+        serde_json::from_str(&resp).map_err(BrokerError::unexpected)
+    }
+
+    /// The account the session broker uses for requests naming none.
+    pub async fn get_default_account(
+        &self,
+        protocol_version: &str,
+        correlation_id: &str,
+    ) -> Result<Option<Account>, BrokerError> {
+        let resp = self
+            .call_interface(
+                "org.himmelblau.Broker1.Accounts",
+                "getDefaultAccount",
+                (protocol_version, correlation_id, "{}"),
+            )
+            .await?;
+        serde_json::from_str::<DefaultAccount<Account>>(&resp)
+            .map(|default| default.account)
+            .map_err(BrokerError::unexpected)
+    }
+
+    /// Make the account with this home account id or username the
+    /// session's default, or with None restore the configured default.
+    pub async fn set_default_account(
+        &self,
+        protocol_version: &str,
+        correlation_id: &str,
+        account: Option<&str>,
+    ) -> Result<Option<Account>, BrokerError> {
+        let request_json = serde_json::to_string(&DefaultAccount {
+            account: account.map(str::to_string),
+        })?;
+        let resp = self
+            .call_interface(
+                "org.himmelblau.Broker1.Accounts",
+                "setDefaultAccount",
+                (protocol_version, correlation_id, request_json.as_str()),
+            )
+            .await?;
+        serde_json::from_str::<DefaultAccount<Account>>(&resp)
+            .map(|default| default.account)
+            .map_err(BrokerError::unexpected)
+    }
+
+    /// Start an acquireTokenInteractively flow for a browser outside the
+    /// session, whose `auth_url` the caller presents to the user, for
+    /// example as a QR code. `request_json` is as for
+    /// acquireTokenInteractively, without an `interactionMode`.
+    pub async fn acquire_token_delegated(
+        &self,
+        protocol_version: &str,
+        correlation_id: &str,
+        request_json: &str,
+    ) -> Result<DelegatedFlow, BrokerError> {
+        let mut request: serde_json::Map<String, serde_json::Value> =
+            serde_json::from_str(request_json)?;
+        request.insert("interactionMode".to_string(), "delegated".into());
+        let resp = self
+            .call(
+                "acquireTokenInteractively",
+                protocol_version,
+                correlation_id,
+                &serde_json::Value::Object(request).to_string(),
+            )
+            .await?;
+        DelegatedFlow::from_response(&resp).ok_or_else(|| {
+            BrokerError::unexpected(format!(
+                "acquireTokenInteractively did not delegate the flow: {}",
+                resp
+            ))
+        })
+    }
+
+    /// The acquireTokenInteractively response of a delegated flow, from the
+    /// session broker's org.himmelblau.Broker1.Interactive extension.
+    pub async fn complete_interactive_flow(
+        &self,
+        protocol_version: &str,
+        correlation_id: &str,
+        request: &CompleteInteractiveFlowRequest,
+    ) -> Result<String, BrokerError> {
+        let request_json = serde_json::to_string(request)?;
+        self.call_interface(
+            "org.himmelblau.Broker1.Interactive",
+            "completeInteractiveFlow",
+            (protocol_version, correlation_id, request_json.as_str()),
+        )
+        .await
+    }
+
+    /// Start a device code flow with the session broker's
+    /// org.himmelblau.Broker1.DeviceCode extension. `request_json` is as
+    /// for acquireTokenInteractively. The returned user code and
+    /// verification URI should be shown to the user straight away.
+    pub async fn start_device_code_flow(
+        &self,
+        protocol_version: &str,
+        correlation_id: &str,
+        request_json: &str,
+    ) -> Result<DeviceCode, BrokerError> {
+        let resp = self
+            .call_interface(
+                "org.himmelblau.Broker1.DeviceCode",
+                "startDeviceCodeFlow",
+                (protocol_version, correlation_id, request_json),
+            )
+            .await?;
+        serde_json::from_str(&resp).map_err(BrokerError::unexpected)
+    }
+
+    /// Whether the user has entered the code of the flow `flow_id`.
+    pub async fn poll_device_code_flow(
+        &self,
+        protocol_version: &str,
+        correlation_id: &str,
+        flow_id: &str,
+    ) -> Result<DeviceCodeStatus, BrokerError> {
+        let request_json = serde_json::to_string(&DeviceCodeFlowRequest {
+            flow_id: flow_id.to_string(),
+        })?;
+        let resp = self
+            .call_interface(
+                "org.himmelblau.Broker1.DeviceCode",
+                "pollDeviceCodeFlow",
+                (protocol_version, correlation_id, request_json.as_str()),
+            )
+            .await?;
+        serde_json::from_str(&resp).map_err(BrokerError::unexpected)
+    }
+
+    /// The acquireTokenInteractively response of the authorized flow
+    /// `flow_id`.
+    pub async fn complete_device_code_flow(
+        &self,
+        protocol_version: &str,
+        correlation_id: &str,
+        flow_id: &str,
+    ) -> Result<String, BrokerError> {
+        let request_json = serde_json::to_string(&DeviceCodeFlowRequest {
+            flow_id: flow_id.to_string(),
+        })?;
+        self.call_interface(
+            "org.himmelblau.Broker1.DeviceCode",
+            "completeDeviceCodeFlow",
+            (protocol_version, correlation_id, request_json.as_str()),
+        )
+        .await
+    }
+
+    /// Poll the flow started as `code` at its interval until the user
+    /// signs in, then complete it.
+    pub async fn wait_for_device_code_flow(
+        &self,
+        protocol_version: &str,
+        correlation_id: &str,
+        code: &DeviceCode,
+    ) -> Result<String, BrokerError> {
+        let mut interval = code.interval.max(1);
+        loop {
+            tokio::time::sleep(Duration::from_secs(interval)).await;
+            let status = self
+                .poll_device_code_flow(
+                    protocol_version,
+                    correlation_id,
+                    &code.flow_id,
+                )
+                .await?;
+            match status.state {
+                DeviceCodeState::Pending => {
+                    interval = status.interval.unwrap_or(interval).max(1);
+                }
+                DeviceCodeState::Authorized => break,
+                DeviceCodeState::Declined => {
+                    return Err(BrokerError::Cancelled);
+                }
+                DeviceCodeState::Expired => {
+                    return Err(BrokerError::Timeout(
+                        "The device code expired".to_string(),
+                    ));
+                }
+            }
+        }
+        self.complete_device_code_flow(
+            protocol_version,
+            correlation_id,
+            &code.flow_id,
+        )
+        .await
+    }
+
+    async fn call_interface(
+        &self,
+        interface: &str,
+        method: &str,
+        args: (&str, &str, &str),
+    ) -> Result<String, BrokerError> {
+        let proxy = Proxy::new(
+            "com.microsoft.identity.broker1",
+            "/com/microsoft/identity/broker1",
+            self.timeout,
+            self.c.clone(),
+        );
+        let (resp,): (String,) = proxy
+            .method_call(interface, method, args)
+            .await
+            .map_err(broker_error)?;
+        Ok(resp)
+    }
+}
+
+/// A client of the device broker's DeviceBroker1 interface on the system
+/// bus, used by the session broker for the key operations of PoP tokens.
+#[derive(Clone)]
+pub struct DeviceBroker1Client {
+    c: Arc<SyncConnection>,
+    timeout: Duration,
+}
+
+impl DeviceBroker1Client {
+    /// Connect to the system bus, waiting up to `timeout` for each call.
+    /// The connection is driven by a task on the current tokio runtime.
+    pub fn system(timeout: Duration) -> Result<Self, dbus::Error> {
+        let (resource, c) = dbus_tokio::connection::new_system_sync()?;
+        tokio::spawn(async {
+            let e = resource.await;
+            error!("Lost connection to D-Bus: {}", e);
+        });
+        Ok(DeviceBroker1Client::new(c, timeout))
+    }
+
+    /// A client calling over an existing connection.
+    pub fn new(c: Arc<SyncConnection>, timeout: Duration) -> Self {
+        DeviceBroker1Client { c, timeout }
+    }
+
+    fn proxy(&self) -> Proxy<'_, Arc<SyncConnection>> {
+        Proxy::new(
+            "com.microsoft.identity.DeviceBroker1",
+            "/com/microsoft/identity/devicebroker1",
+            self.timeout,
+            self.c.clone(),
+        )
+    }
+
+    /// Open a session, whose id is passed to each call made in it.
+    pub async fn open_session(&self) -> Result<String, BrokerError> {
+        let (session_id,): (String,) = self
+            .proxy()
+            .method_call(
+                "com.microsoft.identity.DeviceBroker1",
+                "openSession",
+                (),
+            )
+            .await
+            .map_err(broker_error)?;
+        Ok(session_id)
+    }
+
+    pub async fn close_session(
+        &self,
+        session_id: &str,
+    ) -> Result<(), BrokerError> {
+        self.proxy()
+            .method_call(
+                "com.microsoft.identity.DeviceBroker1",
+                "closeSession",
+                (session_id,),
+            )
+            .await
+            .map_err(broker_error)
+    }
+
+    /// Call the DeviceBroker1 `method` in the session `session_id`,
+    /// returning its result JSON.
+    pub async fn call(
+        &self,
+        method: &str,
+        session_id: &str,
+        request_json: &str,
+    ) -> Result<String, BrokerError> {
+        let (resp,): (String,) = self
+            .proxy()
+            .method_call(
+                "com.microsoft.identity.DeviceBroker1",
+                method,
+                (session_id, request_json),
+            )
+            .await
+            .map_err(broker_error)?;
+        Ok(resp)
+    }
+
+    /// The `req_cnf` of the device's PoP key for the account of
+    /// `request`.
+    pub async fn get_request_confirmation(
+        &self,
+        session_id: &str,
+        request: &RequestConfirmationRequest,
+    ) -> Result<String, BrokerError> {
+        let request_json = serde_json::to_string(request)?;
+        let resp = self
+            .call("getRequestConfirmation", session_id, &request_json)
+            .await?;
+        serde_json::from_str::<RequestConfirmationResponse>(&resp)
+            .map(|resp| resp.req_cnf)
+            .map_err(BrokerError::unexpected)
+    }
+
+    /// An SHR presenting `request.access_token`, signed by the key it is
+    /// bound to.
+    pub async fn mint_signed_http_request(
+        &self,
+        session_id: &str,
+        request: &SignedHttpRequestRequest,
+    ) -> Result<String, BrokerError> {
+        let request_json = serde_json::to_string(request)?;
+        let resp = self
+            .call("mintSignedHttpRequest", session_id, &request_json)
+            .await?;
+        serde_json::from_str::<SignedHttpRequestResponse>(&resp)
+            .map(|resp| resp.signed_http_request)
+            .map_err(BrokerError::unexpected)
+    }
+}
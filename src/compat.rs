@@ -0,0 +1,96 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::broker_proto::ClientRequest;
+use crate::protocol_version::ProtocolVersion;
+use serde_json::{Map, Value};
+use tracing::debug;
+
+/* Older MSAL clients advertising a 0.x protocol version send the auth
+ * parameters flat at the top level of request_json (with scopes as a space
+ * separated string), and expect the token response unwrapped rather than
+ * nested in a brokerTokenResponse envelope. Newer 0.x clients already use
+ * the nested shape, so the shim is only engaged when the flat shape is
+ * actually detected.
+ */
+pub(crate) struct LegacyShim;
+
+impl LegacyShim {
+    fn is_legacy_version(protocol_version: &str) -> bool {
+        protocol_version
+            .parse::<ProtocolVersion>()
+            .ok()
+            .and_then(|version| version.capabilities())
+            .is_some_and(|caps| caps.flat_requests)
+    }
+
+    /// Upgrade a legacy request in place, returning a shim for downgrading
+    /// the matching response when the request was legacy shaped.
+    pub(crate) fn apply(req: &mut ClientRequest) -> Option<LegacyShim> {
+        let (protocol_version, _, request_json) = req.args_mut()?;
+        if !Self::is_legacy_version(protocol_version) {
+            return None;
+        }
+        let mut request: Map<String, Value> =
+            serde_json::from_str(request_json).ok()?;
+        if request.contains_key("authParameters")
+            || !request.contains_key("clientId")
+        {
+            return None;
+        }
+        debug!("Upgrading legacy {} request", protocol_version);
+
+        let mut auth_params = Map::new();
+        let account = request.remove("account");
+        for (key, value) in std::mem::take(&mut request) {
+            match (key.as_str(), value) {
+                ("scopes", Value::String(scopes)) => {
+                    auth_params.insert(
+                        "requestedScopes".to_string(),
+                        Value::Array(
+                            scopes
+                                .split_whitespace()
+                                .map(|s| Value::String(s.to_string()))
+                                .collect(),
+                        ),
+                    );
+                }
+                (_, value) => {
+                    auth_params.insert(key, value);
+                }
+            }
+        }
+        if let Some(account) = account {
+            auth_params.insert("account".to_string(), account.clone());
+            request.insert("account".to_string(), account);
+        }
+        request
+            .insert("authParameters".to_string(), Value::Object(auth_params));
+        *request_json = Value::Object(request).to_string();
+        Some(LegacyShim)
+    }
+
+    pub(crate) fn downgrade_response(&self, response_json: String) -> String {
+        match serde_json::from_str::<Map<String, Value>>(&response_json) {
+            Ok(mut resp) => match resp.remove("brokerTokenResponse") {
+                Some(Value::Object(token)) => Value::Object(token).to_string(),
+                _ => response_json,
+            },
+            Err(_) => response_json,
+        }
+    }
+}
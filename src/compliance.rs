@@ -0,0 +1,82 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::error::BrokerError;
+use async_trait::async_trait;
+use libc::uid_t;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+/// The Intune compliance state of the device.
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default,
+)]
+#[serde(rename_all = "camelCase")]
+pub enum ComplianceState {
+    Compliant,
+    NonCompliant,
+    /// Non-compliant, but still within the policy's grace period.
+    InGracePeriod,
+    #[default]
+    Unknown,
+}
+
+/// The response to getDeviceComplianceStatus.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct DeviceComplianceStatus {
+    /// Whether the device is enrolled in Intune.
+    pub enrolled: bool,
+    pub compliance: ComplianceState,
+    /// The Entra ID device id, when the device is joined.
+    pub device_id: Option<String>,
+    /// When compliance was last evaluated, in seconds since the epoch.
+    pub last_checked: Option<u64>,
+    /// Descriptions of the policies the device does not comply with.
+    pub noncompliance_reasons: Vec<String>,
+}
+
+impl DeviceComplianceStatus {
+    pub fn is_compliant(&self) -> bool {
+        self.enrolled && self.compliance == ComplianceState::Compliant
+    }
+}
+
+/* Compliance reporting needs an Intune integration which not every broker
+ * has, so it is a separate trait. A HimmelblauBroker which provides it
+ * returns itself from `HimmelblauBroker::compliance`.
+ */
+#[async_trait]
+pub trait ComplianceBroker {
+    async fn get_device_compliance_status(
+        &mut self,
+        correlation_id: String,
+        request_json: String,
+        uid: uid_t,
+    ) -> Result<DeviceComplianceStatus, BrokerError>;
+}
+
+/// The response sent when the broker does not report compliance.
+pub(crate) fn compliance_unsupported() -> String {
+    json!({
+        "error": {
+            "status": "NotSupported",
+            "context": "Device compliance is not reported by this broker",
+        }
+    })
+    .to_string()
+}
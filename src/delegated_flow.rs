@@ -0,0 +1,146 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::error::BrokerError;
+use async_trait::async_trait;
+use libc::uid_t;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// How an acquireTokenInteractively request is to be completed, given as
+/// `interactionMode` in its request_json.
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default,
+)]
+#[serde(rename_all = "camelCase")]
+pub enum InteractionMode {
+    /// The broker signs the user in within the session, for example by
+    /// launching a browser.
+    #[default]
+    Local,
+    /// The broker returns a `DelegatedFlow` instead, for the caller to
+    /// present (for instance as a QR code) to a browser elsewhere. Suited
+    /// to remote desktop sessions, where a local browser is out of reach.
+    Delegated,
+}
+
+impl InteractionMode {
+    /// Remove the `interactionMode` from an acquireTokenInteractively
+    /// request, leaving the request the broker expects. Requests which are
+    /// not JSON objects are left for the broker to reject.
+    pub fn take_from_request(
+        request_json: &str,
+    ) -> Result<(String, Self), BrokerError> {
+        let mut request: serde_json::Map<String, Value> =
+            match serde_json::from_str(request_json) {
+                Ok(request) => request,
+                Err(_) => {
+                    return Ok((
+                        request_json.to_string(),
+                        InteractionMode::Local,
+                    ))
+                }
+            };
+        match request.remove("interactionMode") {
+            Some(Value::Null) | None => {
+                Ok((request_json.to_string(), InteractionMode::Local))
+            }
+            Some(mode) => Ok((
+                Value::Object(request).to_string(),
+                serde_json::from_value(mode)?,
+            )),
+        }
+    }
+}
+
+/// Returned by acquireTokenInteractively in place of a token when the
+/// request asked for `InteractionMode::Delegated`, as
+/// `{"delegatedFlow": {...}}`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct DelegatedFlow {
+    /// Identifies the flow to completeInteractiveFlow and
+    /// cancelInteractiveFlow.
+    pub flow_id: String,
+    /// The URL the user signs in at, on any device.
+    pub auth_url: String,
+    /// Seconds until the flow can no longer be completed.
+    pub expires_in: u64,
+}
+
+impl DelegatedFlow {
+    /// The acquireTokenInteractively response carrying this flow.
+    pub fn to_response(&self) -> String {
+        json!({ "delegatedFlow": self }).to_string()
+    }
+
+    /// The flow carried by an acquireTokenInteractively response, if it
+    /// has one.
+    pub fn from_response(response_json: &str) -> Option<Self> {
+        let mut response: Value = serde_json::from_str(response_json).ok()?;
+        serde_json::from_value(response.get_mut("delegatedFlow")?.take()).ok()
+    }
+}
+
+/// The request_json of completeInteractiveFlow.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct CompleteInteractiveFlowRequest {
+    pub flow_id: String,
+    /// The URL the user's browser was redirected to after signing in, when
+    /// the user copied it back. Otherwise the broker completes the flow
+    /// from its own redirect handling.
+    #[serde(default)]
+    pub redirect_uri: Option<String>,
+}
+
+/* A HimmelblauBroker which can hand its interactive flows to a browser
+ * outside the session returns itself from `HimmelblauBroker::delegation`.
+ * Delegated flows are cancelled with cancelInteractiveFlow, like any other.
+ */
+#[async_trait]
+pub trait DelegatedFlowBroker {
+    /// Begin the flow for an acquireTokenInteractively request, with its
+    /// `interactionMode` removed, without presenting anything to the user.
+    async fn start_delegated_flow(
+        &mut self,
+        correlation_id: String,
+        request_json: String,
+        uid: uid_t,
+    ) -> Result<DelegatedFlow, BrokerError>;
+
+    /// The acquireTokenInteractively response of a flow the user has
+    /// completed, waiting for them to do so when necessary.
+    async fn complete_interactive_flow(
+        &mut self,
+        correlation_id: String,
+        request: CompleteInteractiveFlowRequest,
+        uid: uid_t,
+    ) -> Result<String, BrokerError>;
+}
+
+/// The response sent when the broker cannot delegate interactive flows.
+pub(crate) fn delegation_unsupported() -> String {
+    json!({
+        "error": {
+            "status": "NotSupported",
+            "context": "Interactive flows cannot be delegated by this broker",
+        }
+    })
+    .to_string()
+}
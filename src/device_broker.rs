@@ -15,12 +15,31 @@
    You should have received a copy of the GNU Lesser General Public License
    along with this program. If not, see <https://www.gnu.org/licenses/>.
 */
+use crate::audit::{KeyOperation, KeyUsageLog};
+use crate::broker_ext::{
+    register_broker_ext, Capabilities, DEVICE_BROKER1_METHODS,
+};
+use crate::caller::{BusType, CallerInfo, CallerResolver};
+use crate::device_session::DeviceSessions;
+use crate::error::MethodErrExt;
+use crate::freedesktop::DBusNameOwnerChanged;
+use crate::name_watch::{request_names, serve_watching_names, NameWatch};
+use crate::peer::register_peer;
+use crate::policy::MethodPolicy;
+use crate::replay::ReplayGuard;
+use crate::serve::ServeOptions;
+use crate::session_broker::call_span;
 #[allow(unused_imports)]
 use dbus::arg;
 use dbus::blocking::Connection;
+use dbus::message::SignalArgs;
 use dbus_crossroads as crossroads;
+use std::sync::{Arc, Mutex, PoisonError};
+use tracing::span::EnteredSpan;
 
 pub trait DeviceBroker {
+    /// During a key rollover, sign and decrypt should use the key named by
+    /// the request, see `DeviceKeyStore::select_key`.
     fn sign(
         &mut self,
         session_id: String,
@@ -41,6 +60,8 @@ pub trait DeviceBroker {
         session_id: String,
         request_json: String,
     ) -> Result<String, dbus::MethodErr>;
+    /// Derived keys are produced with the SP 800-108 counter mode KDF, see
+    /// `derive_key`.
     fn generate_derived_key(
         &mut self,
         session_id: String,
@@ -56,6 +77,8 @@ pub trait DeviceBroker {
         session_id: String,
         request_json: String,
     ) -> Result<String, dbus::MethodErr>;
+    /// See `build_device_csr`, and `DeviceKeyStore` for keeping the issued
+    /// certificate chain with its key.
     fn generate_pkcs10_cert_signing_request(
         &mut self,
         session_id: String,
@@ -71,6 +94,7 @@ pub trait DeviceBroker {
         session_id: String,
         request_json: String,
     ) -> Result<String, dbus::MethodErr>;
+    /// Thumbprints must match those computed on Windows, see `Thumbprint`.
     fn get_asymmetric_key_thumbprint(
         &mut self,
         session_id: String,
@@ -111,191 +135,971 @@ pub trait DeviceBroker {
         session_id: String,
         request_json: String,
     ) -> Result<String, dbus::MethodErr>;
+
+    /// Called with the identity of the D-Bus caller (including its uid)
+    /// which opened the session, before each method is dispatched. A single
+    /// system broker serves every user's session broker, so
+    /// implementations should use this to attribute requests to a user.
+    fn set_caller(&mut self, _caller: CallerInfo) {}
+
+    /// The methods this broker implements, served by getCapabilities.
+    /// Implementations of optional methods should add them here.
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::new(DEVICE_BROKER1_METHODS)
+    }
+}
+
+/* Each call is checked against the method policy and the caller its session
+ * was issued to, and that caller is then handed to the broker. The returned
+ * span should be held while the broker handles the call.
+ */
+fn admit<T: DeviceBroker>(
+    sessions: &DeviceSessions,
+    methods: Option<&MethodPolicy>,
+    ctx: &crossroads::Context,
+    t: &mut T,
+    session_id: &str,
+    method: &str,
+) -> Result<(CallerInfo, EnteredSpan), dbus::MethodErr> {
+    let span = call_span(method, ctx.message()).entered();
+    if let Some(methods) = methods {
+        methods.check(method)?;
+    }
+    let caller =
+        sessions.check(ctx.message().sender().as_deref(), session_id)?;
+    t.set_caller(caller.clone());
+    Ok((caller, span))
 }
 
+/* Replay protection wraps the broker call rather than admission, as a
+ * deduped call is answered without reaching the broker and the outcome of
+ * any other is recorded. Device requests carry their correlation id in the
+ * request itself; requests without one are not checked.
+ */
+fn guarded<F>(
+    replay: Option<&ReplayGuard>,
+    ctx: &crossroads::Context,
+    method: &str,
+    request_json: String,
+    f: F,
+) -> Result<(String,), dbus::MethodErr>
+where
+    F: FnOnce(String) -> Result<String, dbus::MethodErr>,
+{
+    let replay = match replay {
+        Some(replay) => replay,
+        None => return f(request_json).map(|x| (x,)),
+    };
+    let sender = ctx.message().sender();
+    let sender = sender.as_deref().unwrap_or_default();
+    let correlation_id =
+        serde_json::from_str::<serde_json::Value>(&request_json)
+            .ok()
+            .and_then(|req| req["correlationId"].as_str().map(str::to_string))
+            .unwrap_or_default();
+    if let Some(resp) =
+        replay.check(sender, method, &correlation_id, &request_json)?
+    {
+        return Ok((resp,));
+    }
+    let res = f(request_json);
+    replay.complete(sender, method, &correlation_id, &res);
+    res.map(|x| (x,))
+}
+
+/// Register the DeviceBroker1 interface. Session ids must first be obtained
+/// from `openSession`, and are only accepted from the caller they were issued
+/// to. `T` is the object data of the paths the interface is inserted at, and
+/// must itself implement `DeviceBroker`, as `Arc<Mutex<B>>` does for any
+/// broker `B`. Sessions are only released when closed, so applications
+/// should prefer `device_broker_add_to_crossroads`, which also releases
+/// them when their caller leaves the bus.
 pub fn register_device_broker<T>(
     cr: &mut crossroads::Crossroads,
 ) -> crossroads::IfaceToken<T>
 where
     T: DeviceBroker + Send + 'static,
 {
-    cr.register("com.microsoft.identity.DeviceBroker1", |b| {
+    register_device_broker_with_sessions(
+        cr,
+        Arc::new(DeviceSessions::new(CallerResolver::new(BusType::System))),
+        None,
+        None,
+        None,
+    )
+}
+
+fn register_device_broker_with_sessions<T>(
+    cr: &mut crossroads::Crossroads,
+    sessions: Arc<DeviceSessions>,
+    audit: Option<Arc<KeyUsageLog>>,
+    methods: Option<Arc<MethodPolicy>>,
+    replay: Option<Arc<ReplayGuard>>,
+) -> crossroads::IfaceToken<T>
+where
+    T: DeviceBroker + Send + 'static,
+{
+    cr.register("com.microsoft.identity.DeviceBroker1", move |b| {
+        let s = sessions.clone();
+        b.method(
+            "openSession",
+            (),
+            ("session_id",),
+            move |ctx, _: &mut T, ()| {
+                s.open(ctx.message().sender().as_deref()).map(|x| (x,))
+            },
+        );
+        let s = sessions.clone();
+        b.method(
+            "closeSession",
+            ("session_id",),
+            (),
+            move |ctx, _: &mut T, (session_id,): (String,)| {
+                s.close(ctx.message().sender().as_deref(), &session_id)
+            },
+        );
+        let s = sessions.clone();
+        let p = methods.clone();
+        let r = replay.clone();
+        let a = audit.clone();
         b.method(
             "sign",
             ("session_id", "request_json"),
             ("result",),
-            |_, t: &mut T, (session_id, request_json)| {
-                t.sign(session_id, request_json).map(|x| (x,))
+            move |ctx,
+                  t: &mut T,
+                  (session_id, request_json): (String, String)| {
+                let (caller, _span) =
+                    admit(&s, p.as_deref(), ctx, t, &session_id, "sign")?;
+                guarded(r.as_deref(), ctx, "sign", request_json, |json| {
+                    let res = t.sign(session_id.clone(), json.clone());
+                    if let Some(audit) = &a {
+                        audit.record(
+                            KeyOperation::Sign,
+                            &session_id,
+                            &caller,
+                            &json,
+                            &res,
+                        );
+                    }
+                    res
+                })
             },
         );
+        let s = sessions.clone();
+        let p = methods.clone();
+        let r = replay.clone();
         b.method(
             "generateKeyPair",
             ("session_id", "request_json"),
             ("result",),
-            |_, t: &mut T, (session_id, request_json)| {
-                t.generate_key_pair(session_id, request_json).map(|x| (x,))
+            move |ctx,
+                  t: &mut T,
+                  (session_id, request_json): (String, String)| {
+                let (_, _span) = admit(
+                    &s,
+                    p.as_deref(),
+                    ctx,
+                    t,
+                    &session_id,
+                    "generateKeyPair",
+                )?;
+                guarded(
+                    r.as_deref(),
+                    ctx,
+                    "generateKeyPair",
+                    request_json,
+                    |json| t.generate_key_pair(session_id, json),
+                )
             },
         );
+        let s = sessions.clone();
+        let p = methods.clone();
+        let r = replay.clone();
         b.method(
             "loadKeyPair",
             ("session_id", "request_json"),
             ("result",),
-            |_, t: &mut T, (session_id, request_json)| {
-                t.load_key_pair(session_id, request_json).map(|x| (x,))
+            move |ctx,
+                  t: &mut T,
+                  (session_id, request_json): (String, String)| {
+                let (_, _span) = admit(
+                    &s,
+                    p.as_deref(),
+                    ctx,
+                    t,
+                    &session_id,
+                    "loadKeyPair",
+                )?;
+                guarded(
+                    r.as_deref(),
+                    ctx,
+                    "loadKeyPair",
+                    request_json,
+                    |json| t.load_key_pair(session_id, json),
+                )
             },
         );
+        let s = sessions.clone();
+        let p = methods.clone();
+        let r = replay.clone();
         b.method(
             "persistKey",
             ("session_id", "request_json"),
             ("result",),
-            |_, t: &mut T, (session_id, request_json)| {
-                t.persist_key(session_id, request_json).map(|x| (x,))
+            move |ctx,
+                  t: &mut T,
+                  (session_id, request_json): (String, String)| {
+                let (_, _span) =
+                    admit(&s, p.as_deref(), ctx, t, &session_id, "persistKey")?;
+                guarded(r.as_deref(), ctx, "persistKey", request_json, |json| {
+                    t.persist_key(session_id, json)
+                })
             },
         );
+        let s = sessions.clone();
+        let p = methods.clone();
+        let r = replay.clone();
         b.method(
             "generateDerivedKey",
             ("session_id", "request_json"),
             ("result",),
-            |_, t: &mut T, (session_id, request_json)| {
-                t.generate_derived_key(session_id, request_json)
-                    .map(|x| (x,))
+            move |ctx,
+                  t: &mut T,
+                  (session_id, request_json): (String, String)| {
+                let (_, _span) = admit(
+                    &s,
+                    p.as_deref(),
+                    ctx,
+                    t,
+                    &session_id,
+                    "generateDerivedKey",
+                )?;
+                guarded(
+                    r.as_deref(),
+                    ctx,
+                    "generateDerivedKey",
+                    request_json,
+                    |json| t.generate_derived_key(session_id, json),
+                )
             },
         );
+        let s = sessions.clone();
+        let p = methods.clone();
+        let r = replay.clone();
         b.method(
             "deleteKey",
             ("session_id", "request_json"),
             ("result",),
-            |_, t: &mut T, (session_id, request_json)| {
-                t.delete_key(session_id, request_json).map(|x| (x,))
+            move |ctx,
+                  t: &mut T,
+                  (session_id, request_json): (String, String)| {
+                let (_, _span) =
+                    admit(&s, p.as_deref(), ctx, t, &session_id, "deleteKey")?;
+                guarded(r.as_deref(), ctx, "deleteKey", request_json, |json| {
+                    t.delete_key(session_id, json)
+                })
             },
         );
+        let s = sessions.clone();
+        let p = methods.clone();
+        let r = replay.clone();
+        let a = audit.clone();
         b.method(
             "decrypt",
             ("session_id", "request_json"),
             ("result",),
-            |_, t: &mut T, (session_id, request_json)| {
-                t.decrypt(session_id, request_json).map(|x| (x,))
+            move |ctx,
+                  t: &mut T,
+                  (session_id, request_json): (String, String)| {
+                let (caller, _span) =
+                    admit(&s, p.as_deref(), ctx, t, &session_id, "decrypt")?;
+                guarded(r.as_deref(), ctx, "decrypt", request_json, |json| {
+                    let res = t.decrypt(session_id.clone(), json.clone());
+                    if let Some(audit) = &a {
+                        audit.record(
+                            KeyOperation::Decrypt,
+                            &session_id,
+                            &caller,
+                            &json,
+                            &res,
+                        );
+                    }
+                    res
+                })
             },
         );
+        let s = sessions.clone();
+        let p = methods.clone();
+        let r = replay.clone();
         b.method(
             "generatePKCS10CertSigningRequest",
             ("session_id", "request_json"),
             ("result",),
-            |_, t: &mut T, (session_id, request_json)| {
-                t.generate_pkcs10_cert_signing_request(session_id, request_json)
-                    .map(|x| (x,))
+            move |ctx,
+                  t: &mut T,
+                  (session_id, request_json): (String, String)| {
+                let _span = admit(
+                    &s,
+                    p.as_deref(),
+                    ctx,
+                    t,
+                    &session_id,
+                    "generatePKCS10CertSigningRequest",
+                )?
+                .1;
+                guarded(
+                    r.as_deref(),
+                    ctx,
+                    "generatePKCS10CertSigningRequest",
+                    request_json,
+                    |json| {
+                        t.generate_pkcs10_cert_signing_request(session_id, json)
+                    },
+                )
             },
         );
+        let s = sessions.clone();
+        let p = methods.clone();
+        let r = replay.clone();
         b.method(
             "asymmetricKeyExists",
             ("session_id", "request_json"),
             ("result",),
-            |_, t: &mut T, (session_id, request_json)| {
-                t.asymmetric_key_exists(session_id, request_json)
-                    .map(|x| (x,))
+            move |ctx,
+                  t: &mut T,
+                  (session_id, request_json): (String, String)| {
+                let (_, _span) = admit(
+                    &s,
+                    p.as_deref(),
+                    ctx,
+                    t,
+                    &session_id,
+                    "asymmetricKeyExists",
+                )?;
+                guarded(
+                    r.as_deref(),
+                    ctx,
+                    "asymmetricKeyExists",
+                    request_json,
+                    |json| t.asymmetric_key_exists(session_id, json),
+                )
             },
         );
+        let s = sessions.clone();
+        let p = methods.clone();
+        let r = replay.clone();
         b.method(
             "asymmetricKeyWithThumbprintExists",
             ("session_id", "request_json"),
             ("result",),
-            |_, t: &mut T, (session_id, request_json)| {
-                t.asymmetric_key_with_thumbprint_exists(
-                    session_id,
+            move |ctx,
+                  t: &mut T,
+                  (session_id, request_json): (String, String)| {
+                let _span = admit(
+                    &s,
+                    p.as_deref(),
+                    ctx,
+                    t,
+                    &session_id,
+                    "asymmetricKeyWithThumbprintExists",
+                )?
+                .1;
+                guarded(
+                    r.as_deref(),
+                    ctx,
+                    "asymmetricKeyWithThumbprintExists",
                     request_json,
+                    |json| {
+                        t.asymmetric_key_with_thumbprint_exists(
+                            session_id, json,
+                        )
+                    },
                 )
-                .map(|x| (x,))
             },
         );
+        let s = sessions.clone();
+        let p = methods.clone();
+        let r = replay.clone();
         b.method(
             "getAsymmetricKeyThumbprint",
             ("session_id", "request_json"),
             ("result",),
-            |_, t: &mut T, (session_id, request_json)| {
-                t.get_asymmetric_key_thumbprint(session_id, request_json)
-                    .map(|x| (x,))
+            move |ctx,
+                  t: &mut T,
+                  (session_id, request_json): (String, String)| {
+                let _span = admit(
+                    &s,
+                    p.as_deref(),
+                    ctx,
+                    t,
+                    &session_id,
+                    "getAsymmetricKeyThumbprint",
+                )?
+                .1;
+                guarded(
+                    r.as_deref(),
+                    ctx,
+                    "getAsymmetricKeyThumbprint",
+                    request_json,
+                    |json| t.get_asymmetric_key_thumbprint(session_id, json),
+                )
             },
         );
+        let s = sessions.clone();
+        let p = methods.clone();
+        let r = replay.clone();
         b.method(
             "generateAsymmetricKey",
             ("session_id", "request_json"),
             ("result",),
-            |_, t: &mut T, (session_id, request_json)| {
-                t.generate_asymmetric_key(session_id, request_json)
-                    .map(|x| (x,))
+            move |ctx,
+                  t: &mut T,
+                  (session_id, request_json): (String, String)| {
+                let (_, _span) = admit(
+                    &s,
+                    p.as_deref(),
+                    ctx,
+                    t,
+                    &session_id,
+                    "generateAsymmetricKey",
+                )?;
+                guarded(
+                    r.as_deref(),
+                    ctx,
+                    "generateAsymmetricKey",
+                    request_json,
+                    |json| t.generate_asymmetric_key(session_id, json),
+                )
             },
         );
+        let s = sessions.clone();
+        let p = methods.clone();
+        let r = replay.clone();
         b.method(
             "getAsymmetricKeyCreationDate",
             ("session_id", "request_json"),
             ("result",),
-            |_, t: &mut T, (session_id, request_json)| {
-                t.get_asymmetric_key_creation_date(session_id, request_json)
-                    .map(|x| (x,))
+            move |ctx,
+                  t: &mut T,
+                  (session_id, request_json): (String, String)| {
+                let _span = admit(
+                    &s,
+                    p.as_deref(),
+                    ctx,
+                    t,
+                    &session_id,
+                    "getAsymmetricKeyCreationDate",
+                )?
+                .1;
+                guarded(
+                    r.as_deref(),
+                    ctx,
+                    "getAsymmetricKeyCreationDate",
+                    request_json,
+                    |json| t.get_asymmetric_key_creation_date(session_id, json),
+                )
             },
         );
+        let s = sessions.clone();
+        let p = methods.clone();
+        let r = replay.clone();
         b.method(
             "clearAsymmetricKey",
             ("session_id", "request_json"),
             ("result",),
-            |_, t: &mut T, (session_id, request_json)| {
-                t.clear_asymmetric_key(session_id, request_json)
-                    .map(|x| (x,))
+            move |ctx,
+                  t: &mut T,
+                  (session_id, request_json): (String, String)| {
+                let (_, _span) = admit(
+                    &s,
+                    p.as_deref(),
+                    ctx,
+                    t,
+                    &session_id,
+                    "clearAsymmetricKey",
+                )?;
+                guarded(
+                    r.as_deref(),
+                    ctx,
+                    "clearAsymmetricKey",
+                    request_json,
+                    |json| t.clear_asymmetric_key(session_id, json),
+                )
             },
         );
+        let s = sessions.clone();
+        let p = methods.clone();
+        let r = replay.clone();
         b.method(
             "getRequestConfirmation",
             ("session_id", "request_json"),
             ("result",),
-            |_, t: &mut T, (session_id, request_json)| {
-                t.get_request_confirmation(session_id, request_json)
-                    .map(|x| (x,))
+            move |ctx,
+                  t: &mut T,
+                  (session_id, request_json): (String, String)| {
+                let (_, _span) = admit(
+                    &s,
+                    p.as_deref(),
+                    ctx,
+                    t,
+                    &session_id,
+                    "getRequestConfirmation",
+                )?;
+                guarded(
+                    r.as_deref(),
+                    ctx,
+                    "getRequestConfirmation",
+                    request_json,
+                    |json| t.get_request_confirmation(session_id, json),
+                )
             },
         );
+        let s = sessions.clone();
+        let p = methods.clone();
+        let r = replay.clone();
         b.method(
             "mintSignedAccessToken",
             ("session_id", "request_json"),
             ("result",),
-            |_, t: &mut T, (session_id, request_json)| {
-                t.mint_signed_access_token(session_id, request_json)
-                    .map(|x| (x,))
+            move |ctx,
+                  t: &mut T,
+                  (session_id, request_json): (String, String)| {
+                let (_, _span) = admit(
+                    &s,
+                    p.as_deref(),
+                    ctx,
+                    t,
+                    &session_id,
+                    "mintSignedAccessToken",
+                )?;
+                guarded(
+                    r.as_deref(),
+                    ctx,
+                    "mintSignedAccessToken",
+                    request_json,
+                    |json| t.mint_signed_access_token(session_id, json),
+                )
             },
         );
+        let s = sessions.clone();
+        let p = methods.clone();
+        let r = replay.clone();
         b.method(
             "mintSignedHttpRequest",
             ("session_id", "request_json"),
             ("result",),
-            |_, t: &mut T, (session_id, request_json)| {
-                t.mint_signed_http_request(session_id, request_json)
-                    .map(|x| (x,))
+            move |ctx,
+                  t: &mut T,
+                  (session_id, request_json): (String, String)| {
+                let (_, _span) = admit(
+                    &s,
+                    p.as_deref(),
+                    ctx,
+                    t,
+                    &session_id,
+                    "mintSignedHttpRequest",
+                )?;
+                guarded(
+                    r.as_deref(),
+                    ctx,
+                    "mintSignedHttpRequest",
+                    request_json,
+                    |json| t.mint_signed_http_request(session_id, json),
+                )
             },
         );
+        let s = sessions.clone();
+        let p = methods.clone();
+        let r = replay.clone();
         b.method(
             "makeHttpRequestWithClientTls",
             ("session_id", "request_json"),
             ("result",),
-            |_, t: &mut T, (session_id, request_json)| {
-                t.make_http_request_with_client_tls(session_id, request_json)
-                    .map(|x| (x,))
+            move |ctx,
+                  t: &mut T,
+                  (session_id, request_json): (String, String)| {
+                let _span = admit(
+                    &s,
+                    p.as_deref(),
+                    ctx,
+                    t,
+                    &session_id,
+                    "makeHttpRequestWithClientTls",
+                )?
+                .1;
+                guarded(
+                    r.as_deref(),
+                    ctx,
+                    "makeHttpRequestWithClientTls",
+                    request_json,
+                    |json| {
+                        t.make_http_request_with_client_tls(session_id, json)
+                    },
+                )
             },
         );
     })
 }
 
+/// Allows a single broker to be shared between several object paths.
+impl<T> DeviceBroker for Arc<Mutex<T>>
+where
+    T: DeviceBroker,
+{
+    fn sign(
+        &mut self,
+        session_id: String,
+        request_json: String,
+    ) -> Result<String, dbus::MethodErr> {
+        self.lock()
+            .map_err(|_| {
+                dbus::MethodErr::failed("Device broker lock poisoned")
+            })?
+            .sign(session_id, request_json)
+    }
+
+    fn generate_key_pair(
+        &mut self,
+        session_id: String,
+        request_json: String,
+    ) -> Result<String, dbus::MethodErr> {
+        self.lock()
+            .map_err(|_| {
+                dbus::MethodErr::failed("Device broker lock poisoned")
+            })?
+            .generate_key_pair(session_id, request_json)
+    }
+
+    fn load_key_pair(
+        &mut self,
+        session_id: String,
+        request_json: String,
+    ) -> Result<String, dbus::MethodErr> {
+        self.lock()
+            .map_err(|_| {
+                dbus::MethodErr::failed("Device broker lock poisoned")
+            })?
+            .load_key_pair(session_id, request_json)
+    }
+
+    fn persist_key(
+        &mut self,
+        session_id: String,
+        request_json: String,
+    ) -> Result<String, dbus::MethodErr> {
+        self.lock()
+            .map_err(|_| {
+                dbus::MethodErr::failed("Device broker lock poisoned")
+            })?
+            .persist_key(session_id, request_json)
+    }
+
+    fn generate_derived_key(
+        &mut self,
+        session_id: String,
+        request_json: String,
+    ) -> Result<String, dbus::MethodErr> {
+        self.lock()
+            .map_err(|_| {
+                dbus::MethodErr::failed("Device broker lock poisoned")
+            })?
+            .generate_derived_key(session_id, request_json)
+    }
+
+    fn delete_key(
+        &mut self,
+        session_id: String,
+        request_json: String,
+    ) -> Result<String, dbus::MethodErr> {
+        self.lock()
+            .map_err(|_| {
+                dbus::MethodErr::failed("Device broker lock poisoned")
+            })?
+            .delete_key(session_id, request_json)
+    }
+
+    fn decrypt(
+        &mut self,
+        session_id: String,
+        request_json: String,
+    ) -> Result<String, dbus::MethodErr> {
+        self.lock()
+            .map_err(|_| {
+                dbus::MethodErr::failed("Device broker lock poisoned")
+            })?
+            .decrypt(session_id, request_json)
+    }
+
+    fn generate_pkcs10_cert_signing_request(
+        &mut self,
+        session_id: String,
+        request_json: String,
+    ) -> Result<String, dbus::MethodErr> {
+        self.lock()
+            .map_err(|_| {
+                dbus::MethodErr::failed("Device broker lock poisoned")
+            })?
+            .generate_pkcs10_cert_signing_request(session_id, request_json)
+    }
+
+    fn asymmetric_key_exists(
+        &mut self,
+        session_id: String,
+        request_json: String,
+    ) -> Result<String, dbus::MethodErr> {
+        self.lock()
+            .map_err(|_| {
+                dbus::MethodErr::failed("Device broker lock poisoned")
+            })?
+            .asymmetric_key_exists(session_id, request_json)
+    }
+
+    fn asymmetric_key_with_thumbprint_exists(
+        &mut self,
+        session_id: String,
+        request_json: String,
+    ) -> Result<String, dbus::MethodErr> {
+        self.lock()
+            .map_err(|_| {
+                dbus::MethodErr::failed("Device broker lock poisoned")
+            })?
+            .asymmetric_key_with_thumbprint_exists(session_id, request_json)
+    }
+
+    fn get_asymmetric_key_thumbprint(
+        &mut self,
+        session_id: String,
+        request_json: String,
+    ) -> Result<String, dbus::MethodErr> {
+        self.lock()
+            .map_err(|_| {
+                dbus::MethodErr::failed("Device broker lock poisoned")
+            })?
+            .get_asymmetric_key_thumbprint(session_id, request_json)
+    }
+
+    fn generate_asymmetric_key(
+        &mut self,
+        session_id: String,
+        request_json: String,
+    ) -> Result<String, dbus::MethodErr> {
+        self.lock()
+            .map_err(|_| {
+                dbus::MethodErr::failed("Device broker lock poisoned")
+            })?
+            .generate_asymmetric_key(session_id, request_json)
+    }
+
+    fn get_asymmetric_key_creation_date(
+        &mut self,
+        session_id: String,
+        request_json: String,
+    ) -> Result<String, dbus::MethodErr> {
+        self.lock()
+            .map_err(|_| {
+                dbus::MethodErr::failed("Device broker lock poisoned")
+            })?
+            .get_asymmetric_key_creation_date(session_id, request_json)
+    }
+
+    fn clear_asymmetric_key(
+        &mut self,
+        session_id: String,
+        request_json: String,
+    ) -> Result<String, dbus::MethodErr> {
+        self.lock()
+            .map_err(|_| {
+                dbus::MethodErr::failed("Device broker lock poisoned")
+            })?
+            .clear_asymmetric_key(session_id, request_json)
+    }
+
+    fn get_request_confirmation(
+        &mut self,
+        session_id: String,
+        request_json: String,
+    ) -> Result<String, dbus::MethodErr> {
+        self.lock()
+            .map_err(|_| {
+                dbus::MethodErr::failed("Device broker lock poisoned")
+            })?
+            .get_request_confirmation(session_id, request_json)
+    }
+
+    fn mint_signed_access_token(
+        &mut self,
+        session_id: String,
+        request_json: String,
+    ) -> Result<String, dbus::MethodErr> {
+        self.lock()
+            .map_err(|_| {
+                dbus::MethodErr::failed("Device broker lock poisoned")
+            })?
+            .mint_signed_access_token(session_id, request_json)
+    }
+
+    fn mint_signed_http_request(
+        &mut self,
+        session_id: String,
+        request_json: String,
+    ) -> Result<String, dbus::MethodErr> {
+        self.lock()
+            .map_err(|_| {
+                dbus::MethodErr::failed("Device broker lock poisoned")
+            })?
+            .mint_signed_http_request(session_id, request_json)
+    }
+
+    fn make_http_request_with_client_tls(
+        &mut self,
+        session_id: String,
+        request_json: String,
+    ) -> Result<String, dbus::MethodErr> {
+        self.lock()
+            .map_err(|_| {
+                dbus::MethodErr::failed("Device broker lock poisoned")
+            })?
+            .make_http_request_with_client_tls(session_id, request_json)
+    }
+
+    fn set_caller(&mut self, caller: CallerInfo) {
+        self.lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .set_caller(caller);
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .capabilities()
+    }
+}
+
 pub async fn device_broker_serve<T>(broker: T) -> Result<(), dbus::MethodErr>
+where
+    T: DeviceBroker + Send + 'static,
+{
+    device_broker_serve_with_options(broker, ServeOptions::default()).await
+}
+
+pub async fn device_broker_serve_with_options<T>(
+    broker: T,
+    options: ServeOptions,
+) -> Result<(), dbus::MethodErr>
 where
     T: DeviceBroker + Send + 'static,
 {
     // Start up a connection to the system bus and request a name
-    let c = Connection::new_system()?;
-    c.request_name("com.microsoft.identity.DeviceBroker1", false, true, false)?;
+    device_broker_serve_on(Connection::new_system()?, broker, options).await
+}
+
+/// Serve a device broker on a connection the application has already
+/// established, such as one to a private or test bus, or one shared with
+/// other services. Set `ServeOptions::bus` when the connection is not to
+/// the system bus, so that callers can be identified.
+pub async fn device_broker_serve_on<T>(
+    c: Connection,
+    broker: T,
+    options: ServeOptions,
+) -> Result<(), dbus::MethodErr>
+where
+    T: DeviceBroker + Send + 'static,
+{
+    options.check()?;
+    let names = options.all_bus_names("com.microsoft.identity.DeviceBroker1");
+    request_names(&c, &names, options.name_policy)?;
 
     let mut cr = crossroads::Crossroads::new();
-    let token = register_device_broker::<T>(&mut cr);
+    device_broker_add_to_crossroads(&c, &mut cr, broker, &options)?;
+
+    let watch = NameWatch::new(
+        names,
+        options.on_takeover,
+        options.on_name_lost.clone(),
+        options.bus_type(BusType::System),
+    );
+    options.apply_seccomp()?;
+    serve_watching_names(&c, cr, watch)
+}
+
+/// Mount a device broker on a Crossroads instance the application already
+/// serves on `c`, for daemons with D-Bus objects of their own. The
+/// DeviceBroker1, capabilities and Peer interfaces are inserted at
+/// `/com/microsoft/identity/devicebroker1` and any
+/// `ServeOptions::object_path`s. Requesting the broker's well-known names
+/// is left to the application, and the options acting on the serve loop
+/// (names and takeovers) are not applied. Returns the handle the broker is
+/// shared through.
+pub fn device_broker_add_to_crossroads<T>(
+    c: &Connection,
+    cr: &mut crossroads::Crossroads,
+    broker: T,
+    options: &ServeOptions,
+) -> Result<Arc<Mutex<T>>, dbus::MethodErr>
+where
+    T: DeviceBroker + Send + 'static,
+{
+    options.check()?;
+    // Sessions end when the connection which opened them leaves the bus.
+    let sessions = Arc::new(DeviceSessions::new(
+        options.caller_resolver(BusType::System),
+    ));
+    let s = sessions.clone();
+    c.add_match(
+        DBusNameOwnerChanged::match_rule(None, None),
+        move |sig: DBusNameOwnerChanged, _, _| {
+            if sig.arg2.is_empty() {
+                s.release(&sig.arg0);
+            }
+            true
+        },
+    )?;
+
+    let audit = match &options.key_usage_log {
+        Some(path) => Some(Arc::new(KeyUsageLog::open(path).or_failed()?)),
+        None => None,
+    };
+    Ok(mount_device_broker(cr, broker, sessions, audit, options))
+}
+
+/* As for the session broker, serving and the introspection data (see
+ * `device_broker_introspection`) share this registration.
+ */
+pub(crate) fn mount_device_broker<T>(
+    cr: &mut crossroads::Crossroads,
+    broker: T,
+    sessions: Arc<DeviceSessions>,
+    audit: Option<Arc<KeyUsageLog>>,
+    options: &ServeOptions,
+) -> Arc<Mutex<T>>
+where
+    T: DeviceBroker + Send + 'static,
+{
+    let broker = Arc::new(Mutex::new(broker));
+    let token = register_device_broker_with_sessions::<Arc<Mutex<T>>>(
+        cr,
+        sessions,
+        audit,
+        options.method_policy.clone().map(Arc::new),
+        options
+            .replay_protection
+            .as_ref()
+            .map(|config| Arc::new(ReplayGuard::new(config))),
+    );
 
-    cr.insert("/com/microsoft/identity/devicebroker1", &[token], broker);
+    let ext_token =
+        register_broker_ext(cr, |t: &Arc<Mutex<T>>| t.capabilities());
+    let peer_token = register_peer::<Arc<Mutex<T>>>(cr);
+    let tokens = [token, ext_token, peer_token];
 
-    // Serve clients forever.
-    cr.serve(&c)?;
-    unreachable!()
+    cr.insert(
+        "/com/microsoft/identity/devicebroker1",
+        &tokens,
+        broker.clone(),
+    );
+    for path in options.extra_object_paths() {
+        cr.insert(path, &tokens, broker.clone());
+    }
+    broker
 }
@@ -0,0 +1,120 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::error::BrokerError;
+use async_trait::async_trait;
+use libc::uid_t;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+/// The response to startDeviceCodeFlow, which the caller shows to the user
+/// so they can sign in on another device.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct DeviceCode {
+    /// Identifies the flow to pollDeviceCodeFlow and
+    /// completeDeviceCodeFlow.
+    pub flow_id: String,
+    pub user_code: String,
+    pub verification_uri: String,
+    /// The verification URI with the user code already filled in, when
+    /// Entra ID provides one.
+    pub verification_uri_complete: Option<String>,
+    /// Entra ID's instructions to the user, naming the code and URI.
+    pub message: String,
+    /// Seconds until the user code expires.
+    pub expires_in: u64,
+    /// Seconds to wait between polls.
+    pub interval: u64,
+}
+
+/// The request_json of pollDeviceCodeFlow and completeDeviceCodeFlow.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceCodeFlowRequest {
+    pub flow_id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceCodeState {
+    /// The user has not yet entered the code.
+    Pending,
+    /// The user signed in, and completeDeviceCodeFlow will return the
+    /// token response.
+    Authorized,
+    /// The user declined the sign in.
+    Declined,
+    /// The user code expired before it was entered.
+    Expired,
+}
+
+/// The response to pollDeviceCodeFlow.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceCodeStatus {
+    pub state: DeviceCodeState,
+    /// A longer polling interval, when Entra ID asked for polls to slow
+    /// down.
+    #[serde(default)]
+    pub interval: Option<u64>,
+}
+
+/* Headless machines have no browser for acquireTokenInteractively, so the
+ * device code flow is split into steps which hand the user code to the
+ * caller as soon as it is issued. A HimmelblauBroker which supports it
+ * returns itself from `HimmelblauBroker::device_code`.
+ */
+#[async_trait]
+pub trait DeviceCodeBroker {
+    /// Request a user code, given the same request_json as
+    /// acquireTokenInteractively.
+    async fn start_device_code_flow(
+        &mut self,
+        correlation_id: String,
+        request_json: String,
+        uid: uid_t,
+    ) -> Result<DeviceCode, BrokerError>;
+
+    /// Check whether the user has entered the code for `flow_id`.
+    async fn poll_device_code_flow(
+        &mut self,
+        correlation_id: String,
+        flow_id: String,
+        uid: uid_t,
+    ) -> Result<DeviceCodeStatus, BrokerError>;
+
+    /// The acquireTokenInteractively response for an authorized flow,
+    /// which is then forgotten.
+    async fn complete_device_code_flow(
+        &mut self,
+        correlation_id: String,
+        flow_id: String,
+        uid: uid_t,
+    ) -> Result<String, BrokerError>;
+}
+
+/// The response sent when the broker has no device code flow.
+pub(crate) fn device_code_unsupported() -> String {
+    json!({
+        "error": {
+            "status": "NotSupported",
+            "context": "The device code flow is not supported by this broker",
+        }
+    })
+    .to_string()
+}
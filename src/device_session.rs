@@ -0,0 +1,138 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::caller::{CallerInfo, CallerResolver};
+use std::collections::HashMap;
+use std::sync::{Mutex, PoisonError};
+use tracing::{debug, error, info, warn};
+
+// The most sessions a single bus connection may hold open at once.
+const MAX_SESSIONS_PER_CALLER: usize = 64;
+
+fn access_denied(msg: &str) -> dbus::MethodErr {
+    dbus::MethodErr::from(("org.freedesktop.DBus.Error.AccessDenied", msg))
+}
+
+/* DeviceBroker1 session ids are minted by openSession and bound to the
+ * unique bus name of the caller which opened them. Unique names are never
+ * reused for the lifetime of the bus, so a session id cannot be presented by
+ * any other connection, even one belonging to the same user.
+ */
+/// The DeviceBroker1 sessions opened by each caller.
+pub struct DeviceSessions {
+    resolver: CallerResolver,
+    sessions: Mutex<HashMap<String, CallerInfo>>,
+}
+
+impl DeviceSessions {
+    pub fn new(resolver: CallerResolver) -> Self {
+        DeviceSessions {
+            resolver,
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Open a session for `sender`, returning its id.
+    pub fn open(
+        &self,
+        sender: Option<&str>,
+    ) -> Result<String, dbus::MethodErr> {
+        let sender = sender.ok_or_else(|| {
+            warn!("Refusing a session to an unidentified caller");
+            access_denied("Caller could not be identified")
+        })?;
+        let caller = self.resolver.resolve(sender).unwrap_or_else(|e| {
+            error!("Failed to resolve caller {} -> {:?}", sender, e);
+            CallerInfo {
+                bus_name: sender.to_string(),
+                ..Default::default()
+            }
+        });
+
+        let mut sessions =
+            self.sessions.lock().unwrap_or_else(PoisonError::into_inner);
+        let open = sessions
+            .values()
+            .filter(|owner| owner.bus_name == sender)
+            .count();
+        if open >= MAX_SESSIONS_PER_CALLER {
+            warn!("{} has too many open sessions", sender);
+            return Err(dbus::MethodErr::failed("Too many open sessions"));
+        }
+        let session_id = uuid::Uuid::new_v4().to_string();
+        info!(
+            "Opened session {} for {} (uid {:?})",
+            session_id, caller.bus_name, caller.uid
+        );
+        sessions.insert(session_id.clone(), caller);
+        Ok(session_id)
+    }
+
+    /// The caller which opened `session_id`, provided it is also `sender`.
+    pub fn check(
+        &self,
+        sender: Option<&str>,
+        session_id: &str,
+    ) -> Result<CallerInfo, dbus::MethodErr> {
+        let sessions =
+            self.sessions.lock().unwrap_or_else(PoisonError::into_inner);
+        match (sessions.get(session_id), sender) {
+            (Some(owner), Some(sender)) if owner.bus_name == sender => {
+                Ok(owner.clone())
+            }
+            (owner, sender) => {
+                warn!(
+                    "Rejecting session {} from {:?} (issued to {:?})",
+                    session_id,
+                    sender,
+                    owner.map(|owner| &owner.bus_name)
+                );
+                Err(access_denied("Session was not issued to this caller"))
+            }
+        }
+    }
+
+    /// Close `session_id`, provided `sender` opened it.
+    pub fn close(
+        &self,
+        sender: Option<&str>,
+        session_id: &str,
+    ) -> Result<(), dbus::MethodErr> {
+        self.check(sender, session_id)?;
+        self.sessions
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(session_id);
+        debug!("Closed session {}", session_id);
+        Ok(())
+    }
+
+    /// Forget every session opened by `bus_name`, once it leaves the bus.
+    pub fn release(&self, bus_name: &str) {
+        let mut sessions =
+            self.sessions.lock().unwrap_or_else(PoisonError::into_inner);
+        let before = sessions.len();
+        sessions.retain(|_, owner| owner.bus_name != bus_name);
+        if sessions.len() != before {
+            debug!(
+                "Released {} sessions of {}",
+                before - sessions.len(),
+                bus_name
+            );
+        }
+    }
+}
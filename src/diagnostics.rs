@@ -0,0 +1,312 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::error::BrokerError;
+use crate::latency::{LatencyHistogram, LATENCY_BUCKETS};
+use dbus_crossroads as crossroads;
+use libc::{pid_t, uid_t};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const DIAGNOSTICS_INTERFACE: &str = "org.himmelblau.Broker1.Diagnostics";
+
+/// A change to the level a running broker logs at, made with the
+/// Diagnostics SetLogLevel method or the daemon's setLogLevel message.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub struct LogLevelChange {
+    /// off, error, warn, info, debug or trace.
+    pub level: String,
+    /// The module path to change, such as `identity_dbus_broker::pam`.
+    /// When unset, every module without a level of its own is changed.
+    pub module: Option<String>,
+}
+
+impl LogLevelChange {
+    /// Apply the change to the subscriber installed by `init_logging`.
+    /// Without the logging feature, log levels cannot be changed.
+    pub fn apply(&self) -> Result<(), BrokerError> {
+        #[cfg(feature = "logging")]
+        {
+            crate::logging::set_log_level(&self.level, self.module.as_deref())
+                .map_err(BrokerError::InvalidRequest)
+        }
+        #[cfg(not(feature = "logging"))]
+        {
+            Err(BrokerError::InvalidRequest(
+                "Changing log levels requires the logging feature".to_string(),
+            ))
+        }
+    }
+}
+
+/// A client connected to the broker's socket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectedClient {
+    /// Identifies the connection in the broker's logs.
+    pub connection: u64,
+    pub uid: uid_t,
+    pub pid: pid_t,
+    /// Requests received on the connection.
+    pub requests: u64,
+}
+
+/// Runtime counters for a broker service, exposed over D-Bus on the
+/// org.himmelblau.Broker1.Diagnostics interface.
+#[derive(Default)]
+pub struct BrokerStats {
+    requests: Mutex<HashMap<String, u64>>,
+    failures: Mutex<HashMap<String, u64>>,
+    latencies: Mutex<HashMap<String, LatencyHistogram>>,
+    cache_hits: AtomicU64,
+    malformed_frames: AtomicU64,
+    active_interactive_flows: AtomicU32,
+    socket_connected: AtomicBool,
+    sso_cookie_expires_at: AtomicU64,
+    clients: Mutex<BTreeMap<u64, ConnectedClient>>,
+}
+
+impl BrokerStats {
+    pub fn record_request(&self, method: &str) {
+        if let Ok(mut requests) = self.requests.lock() {
+            *requests.entry(method.to_string()).or_insert(0) += 1;
+        }
+    }
+
+    pub fn record_failure(&self, method: &str) {
+        if let Ok(mut failures) = self.failures.lock() {
+            *failures.entry(method.to_string()).or_insert(0) += 1;
+        }
+    }
+
+    /// Record the time taken to answer a call to `method`.
+    pub fn record_latency(&self, method: &str, elapsed: Duration) {
+        if let Ok(mut latencies) = self.latencies.lock() {
+            latencies
+                .entry(method.to_string())
+                .or_default()
+                .record(elapsed);
+        }
+    }
+
+    pub fn record_cache_hit(&self) {
+        self.cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_malformed_frame(&self) {
+        self.malformed_frames.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn interactive_flow_started(&self) {
+        self.active_interactive_flows
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn interactive_flow_finished(&self) {
+        let _ = self.active_interactive_flows.fetch_update(
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+            |x| x.checked_sub(1),
+        );
+    }
+
+    pub fn set_socket_connected(&self, connected: bool) {
+        self.socket_connected.store(connected, Ordering::Relaxed);
+    }
+
+    /// Record the expiry (seconds since the epoch) of the latest PRT SSO
+    /// cookie handed out.
+    pub fn set_sso_cookie_expiry(&self, expires_at: u64) {
+        self.sso_cookie_expires_at
+            .store(expires_at, Ordering::Relaxed);
+    }
+
+    pub fn client_connected(&self, connection: u64, uid: uid_t, pid: pid_t) {
+        if let Ok(mut clients) = self.clients.lock() {
+            clients.insert(
+                connection,
+                ConnectedClient {
+                    connection,
+                    uid,
+                    pid,
+                    requests: 0,
+                },
+            );
+        }
+    }
+
+    pub fn record_client_request(&self, connection: u64) {
+        if let Ok(mut clients) = self.clients.lock() {
+            if let Some(client) = clients.get_mut(&connection) {
+                client.requests += 1;
+            }
+        }
+    }
+
+    pub fn client_disconnected(&self, connection: u64) {
+        if let Ok(mut clients) = self.clients.lock() {
+            clients.remove(&connection);
+        }
+    }
+
+    pub fn request_counts(&self) -> HashMap<String, u64> {
+        self.requests
+            .lock()
+            .map(|requests| requests.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn failure_counts(&self) -> HashMap<String, u64> {
+        self.failures
+            .lock()
+            .map(|failures| failures.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn latency_histograms(&self) -> HashMap<String, LatencyHistogram> {
+        self.latencies
+            .lock()
+            .map(|latencies| latencies.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn cache_hits(&self) -> u64 {
+        self.cache_hits.load(Ordering::Relaxed)
+    }
+
+    pub fn malformed_frames(&self) -> u64 {
+        self.malformed_frames.load(Ordering::Relaxed)
+    }
+
+    pub fn active_interactive_flows(&self) -> u32 {
+        self.active_interactive_flows.load(Ordering::Relaxed)
+    }
+
+    pub fn socket_connected(&self) -> bool {
+        self.socket_connected.load(Ordering::Relaxed)
+    }
+
+    /// The clients currently connected, in the order they connected.
+    pub fn connected_clients(&self) -> Vec<ConnectedClient> {
+        self.clients
+            .lock()
+            .map(|clients| clients.values().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// The expiry of the latest PRT SSO cookie, or 0 if none is known.
+    pub fn sso_cookie_expires_at(&self) -> u64 {
+        self.sso_cookie_expires_at.load(Ordering::Relaxed)
+    }
+
+    pub fn sso_cookie_fresh(&self) -> bool {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        now < self.sso_cookie_expires_at()
+    }
+}
+
+/* The Diagnostics interface is Himmelblau specific, and is registered
+ * alongside the broker interface on the same object. It is generic over the
+ * object data so that it can share an object path with any broker, but it
+ * only ever reads from the shared `BrokerStats`.
+ */
+pub fn register_diagnostics<T>(
+    cr: &mut crossroads::Crossroads,
+    stats: Arc<BrokerStats>,
+) -> crossroads::IfaceToken<T>
+where
+    T: Send + 'static,
+{
+    cr.register(DIAGNOSTICS_INTERFACE, move |b| {
+        // An empty module_filter changes the default level.
+        b.method(
+            "SetLogLevel",
+            ("level", "module_filter"),
+            (),
+            |_, _: &mut T, (level, module): (String, String)| {
+                LogLevelChange {
+                    level,
+                    module: Some(module).filter(|module| !module.is_empty()),
+                }
+                .apply()
+                .map_err(dbus::MethodErr::from)
+            },
+        );
+        let s = stats.clone();
+        b.property("RequestCounts")
+            .emits_changed_false()
+            .get(move |_, _: &mut T| Ok(s.request_counts()));
+        let s = stats.clone();
+        b.property("FailureCounts")
+            .emits_changed_false()
+            .get(move |_, _: &mut T| Ok(s.failure_counts()));
+        b.property("LatencyBuckets")
+            .emits_changed_false()
+            .get(|_, _: &mut T| Ok(LATENCY_BUCKETS.to_vec()));
+        // Each method's (count, total ms, max ms, calls per bucket).
+        let s = stats.clone();
+        b.property("LatencyHistograms").emits_changed_false().get(
+            move |_, _: &mut T| {
+                Ok(s.latency_histograms()
+                    .into_iter()
+                    .map(|(method, h)| {
+                        (method, (h.count, h.total, h.max, h.buckets.to_vec()))
+                    })
+                    .collect::<HashMap<String, (u64, u64, u64, Vec<u64>)>>())
+            },
+        );
+        let s = stats.clone();
+        b.property("CacheHits")
+            .emits_changed_false()
+            .get(move |_, _: &mut T| Ok(s.cache_hits()));
+        let s = stats.clone();
+        b.property("MalformedFrames")
+            .emits_changed_false()
+            .get(move |_, _: &mut T| Ok(s.malformed_frames()));
+        let s = stats.clone();
+        b.property("ActiveInteractiveFlows")
+            .emits_changed_false()
+            .get(move |_, _: &mut T| Ok(s.active_interactive_flows()));
+        let s = stats.clone();
+        b.property("SocketConnected")
+            .emits_changed_false()
+            .get(move |_, _: &mut T| Ok(s.socket_connected()));
+        let s = stats.clone();
+        b.property("SsoCookieExpiresAt")
+            .emits_changed_false()
+            .get(move |_, _: &mut T| Ok(s.sso_cookie_expires_at()));
+        let s = stats.clone();
+        b.property("SsoCookieFresh")
+            .emits_changed_false()
+            .get(move |_, _: &mut T| Ok(s.sso_cookie_fresh()));
+        let s = stats.clone();
+        b.property("ConnectedClients").emits_changed_false().get(
+            move |_, _: &mut T| {
+                Ok(s.connected_clients()
+                    .into_iter()
+                    .map(|c| (c.connection, c.uid, c.pid, c.requests))
+                    .collect::<Vec<(u64, u32, i32, u64)>>())
+            },
+        );
+    })
+}
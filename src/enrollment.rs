@@ -0,0 +1,80 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+use openssl::base64;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{HasPrivate, PKeyRef};
+use openssl::x509::{X509NameBuilder, X509Req, X509ReqBuilder, X509};
+use std::error::Error;
+
+/// The subject common name Entra ID device registration expects on device
+/// certificate signing requests. The issued certificate is named after the
+/// device id instead.
+pub const DEVICE_CSR_COMMON_NAME: &str = "7E980AD9-B86D-4306-9425-9AC066FB014A";
+
+/// Build the PKCS#10 request for a device certificate, as returned by
+/// generatePKCS10CertSigningRequest, signed with SHA-256 by `key`.
+pub fn build_device_csr<T: HasPrivate>(
+    key: &PKeyRef<T>,
+) -> Result<X509Req, Box<dyn Error + Send + Sync>> {
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_text("CN", DEVICE_CSR_COMMON_NAME)?;
+    let name = name.build();
+
+    let mut req = X509ReqBuilder::new()?;
+    req.set_version(0)?;
+    req.set_subject_name(&name)?;
+    req.set_pubkey(key)?;
+    req.sign(key, MessageDigest::sha256())?;
+    Ok(req.build())
+}
+
+/// The base64 DER encoding of a request, as sent in the CertificateRequest
+/// of a device registration.
+pub fn csr_to_base64(
+    csr: &X509Req,
+) -> Result<String, Box<dyn Error + Send + Sync>> {
+    Ok(base64::encode_block(&csr.to_der()?))
+}
+
+/* The registration service returns the issued certificate as base64 DER,
+ * while other enrollment paths hand back a PEM bundle. Either is accepted,
+ * and the leaf certificate is first in the returned chain.
+ */
+pub fn parse_issued_certificate(
+    data: &str,
+) -> Result<Vec<X509>, Box<dyn Error + Send + Sync>> {
+    let data = data.trim();
+    let chain = if data.starts_with("-----BEGIN") {
+        X509::stack_from_pem(data.as_bytes())?
+    } else {
+        let der: String = data.split_whitespace().collect();
+        vec![X509::from_der(&base64::decode_block(&der)?)?]
+    };
+    if chain.is_empty() {
+        return Err("No certificate was issued".into());
+    }
+    Ok(chain)
+}
+
+/// Whether `cert` was issued for the public half of `key`.
+pub fn certificate_matches_key<T: HasPrivate>(
+    cert: &X509,
+    key: &PKeyRef<T>,
+) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    Ok(cert.public_key()?.public_eq(key))
+}
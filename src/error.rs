@@ -0,0 +1,251 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// The result of a broker method.
+pub type BrokerResult<T = String> = Result<T, dbus::MethodErr>;
+
+/// Broker failures which are reported to D-Bus clients under their own
+/// error name, `com.microsoft.identity.Broker1.Error.<name>`, rather than
+/// the generic org.freedesktop.DBus.Error.Failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BrokerErrorName {
+    InteractionRequired,
+    NoNetwork,
+    Timeout,
+    InvalidRequest,
+    AccountNotFound,
+    Cancelled,
+    Unavailable,
+    Disabled,
+}
+
+impl BrokerErrorName {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            BrokerErrorName::InteractionRequired => {
+                "com.microsoft.identity.Broker1.Error.InteractionRequired"
+            }
+            BrokerErrorName::NoNetwork => {
+                "com.microsoft.identity.Broker1.Error.NoNetwork"
+            }
+            BrokerErrorName::Timeout => {
+                "com.microsoft.identity.Broker1.Error.Timeout"
+            }
+            BrokerErrorName::InvalidRequest => {
+                "com.microsoft.identity.Broker1.Error.InvalidRequest"
+            }
+            BrokerErrorName::AccountNotFound => {
+                "com.microsoft.identity.Broker1.Error.AccountNotFound"
+            }
+            BrokerErrorName::Cancelled => {
+                "com.microsoft.identity.Broker1.Error.Cancelled"
+            }
+            BrokerErrorName::Unavailable => {
+                "com.microsoft.identity.Broker1.Error.Unavailable"
+            }
+            BrokerErrorName::Disabled => {
+                "com.microsoft.identity.Broker1.Error.Disabled"
+            }
+        }
+    }
+
+    /// The error name for an MSAL broker error status, as found in
+    /// `{"error": {"status": ...}}` responses.
+    pub fn from_status(status: &str) -> Option<Self> {
+        match status {
+            "InteractionRequired" | "PrtExpired" => {
+                Some(BrokerErrorName::InteractionRequired)
+            }
+            "NoNetwork" => Some(BrokerErrorName::NoNetwork),
+            "Timeout" => Some(BrokerErrorName::Timeout),
+            "InvalidRequest" | "IncorrectConfiguration" => {
+                Some(BrokerErrorName::InvalidRequest)
+            }
+            "AccountNotFound" | "AccountUnusable" => {
+                Some(BrokerErrorName::AccountNotFound)
+            }
+            "UserCanceled" | "Cancelled" => Some(BrokerErrorName::Cancelled),
+            "ServiceUnavailable" => Some(BrokerErrorName::Unavailable),
+            "Disabled" => Some(BrokerErrorName::Disabled),
+            _ => None,
+        }
+    }
+
+    /// A `MethodErr` with this name and `msg`.
+    pub fn error(self, msg: impl fmt::Display) -> dbus::MethodErr {
+        dbus::MethodErr::from((self.as_str(), msg.to_string()))
+    }
+}
+
+impl fmt::Display for BrokerErrorName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// An error returned by a `HimmelblauBroker`. Errors are sent to the session
+/// broker intact, which reports them to D-Bus clients under the error name
+/// of `BrokerError::name`.
+#[derive(
+    Serialize, Deserialize, Debug, Clone, PartialEq, Eq, thiserror::Error,
+)]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub enum BrokerError {
+    /// The broker could not reach Entra ID (or another service it relies
+    /// on), or lost its connection to the client.
+    #[error("Transport error: {0}")]
+    Transport(String),
+    #[error("Timed out: {0}")]
+    Timeout(String),
+    /// The request cannot be completed without the user's involvement.
+    #[error("Interaction required: {0}")]
+    InteractionRequired(String),
+    #[error("Invalid request: {0}")]
+    InvalidRequest(String),
+    /// Any other failure, with an MSAL error status or AADSTS code.
+    #[error("{code}: {msg}")]
+    Backend { code: String, msg: String },
+    #[error("Cancelled")]
+    Cancelled,
+    /// A signed HTTP request was made with a missing or stale server
+    /// nonce. The session broker retries the request once with `nonce`,
+    /// when the broker has a fresh one (see `NonceCache`).
+    #[error("A fresh server nonce is required")]
+    NonceRequired { nonce: Option<String> },
+    /// The method has been turned off for this deployment (see
+    /// `MethodPolicy`).
+    #[error("Operation disabled by policy: {0}")]
+    Disabled(String),
+    /// The broker is in maintenance (see `MaintenanceMode`), and the
+    /// request should be retried after `retry_after` seconds.
+    #[error("Under maintenance, retry after {retry_after} seconds: {reason}")]
+    Maintenance { retry_after: u64, reason: String },
+}
+
+impl BrokerError {
+    pub fn backend(code: impl Into<String>, msg: impl Into<String>) -> Self {
+        BrokerError::Backend {
+            code: code.into(),
+            msg: msg.into(),
+        }
+    }
+
+    /// A `Backend` error with no more specific code.
+    pub fn unexpected(msg: impl fmt::Display) -> Self {
+        BrokerError::backend("Unexpected", msg.to_string())
+    }
+
+    /// The error name this is reported under. Backend errors are named
+    /// after their code when it is a known MSAL status, and are otherwise
+    /// reported as org.freedesktop.DBus.Error.Failed.
+    pub fn name(&self) -> Option<BrokerErrorName> {
+        match self {
+            BrokerError::Transport(_) => Some(BrokerErrorName::Unavailable),
+            BrokerError::Timeout(_) => Some(BrokerErrorName::Timeout),
+            BrokerError::InteractionRequired(_) => {
+                Some(BrokerErrorName::InteractionRequired)
+            }
+            BrokerError::InvalidRequest(_) => {
+                Some(BrokerErrorName::InvalidRequest)
+            }
+            BrokerError::Backend { code, .. } => {
+                BrokerErrorName::from_status(code)
+            }
+            BrokerError::Cancelled => Some(BrokerErrorName::Cancelled),
+            BrokerError::NonceRequired { .. } => {
+                Some(BrokerErrorName::InvalidRequest)
+            }
+            BrokerError::Disabled(_) => Some(BrokerErrorName::Disabled),
+            BrokerError::Maintenance { .. } => {
+                Some(BrokerErrorName::Unavailable)
+            }
+        }
+    }
+}
+
+impl From<BrokerError> for dbus::MethodErr {
+    fn from(e: BrokerError) -> Self {
+        match e.name() {
+            Some(name) => name.error(&e),
+            None => dbus::MethodErr::failed(&e),
+        }
+    }
+}
+
+impl From<io::Error> for BrokerError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::TimedOut => BrokerError::Timeout(e.to_string()),
+            _ => BrokerError::Transport(e.to_string()),
+        }
+    }
+}
+
+/* Brokers mostly fail to (de)serialize the request_json they were handed. */
+impl From<serde_json::Error> for BrokerError {
+    fn from(e: serde_json::Error) -> Self {
+        BrokerError::InvalidRequest(e.to_string())
+    }
+}
+
+impl From<String> for BrokerError {
+    fn from(msg: String) -> Self {
+        BrokerError::unexpected(msg)
+    }
+}
+
+impl From<&str> for BrokerError {
+    fn from(msg: &str) -> Self {
+        BrokerError::unexpected(msg)
+    }
+}
+
+impl From<Box<dyn Error>> for BrokerError {
+    fn from(e: Box<dyn Error>) -> Self {
+        BrokerError::unexpected(e)
+    }
+}
+
+impl From<Box<dyn Error + Send + Sync>> for BrokerError {
+    fn from(e: Box<dyn Error + Send + Sync>) -> Self {
+        BrokerError::unexpected(e)
+    }
+}
+
+/// Conversions from any displayable error to a `MethodErr`, in place of
+/// `.map_err(|e| dbus::MethodErr::failed(&e))`.
+pub trait MethodErrExt<T> {
+    /// Report the error as org.freedesktop.DBus.Error.Failed.
+    fn or_failed(self) -> BrokerResult<T>;
+    /// Report the error under the named broker error.
+    fn or_broker_error(self, name: BrokerErrorName) -> BrokerResult<T>;
+}
+
+impl<T, E: fmt::Display> MethodErrExt<T> for Result<T, E> {
+    fn or_failed(self) -> BrokerResult<T> {
+        self.map_err(|e| dbus::MethodErr::failed(&e))
+    }
+
+    fn or_broker_error(self, name: BrokerErrorName) -> BrokerResult<T> {
+        self.map_err(|e| name.error(e))
+    }
+}
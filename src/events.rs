@@ -0,0 +1,158 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use dbus::channel::Sender;
+use dbus::nonblock::SyncConnection;
+use libc::uid_t;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::broadcast;
+use tracing::error;
+
+/// The interface on which account change signals are emitted.
+pub const ACCOUNTS_INTERFACE: &str = "org.himmelblau.Broker1.Accounts";
+
+/// A change to a user's accounts or tokens, pushed by the daemon to
+/// subscribed session brokers.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub enum AccountEvent {
+    /// An account was added, by its home account id.
+    AccountAdded(String),
+    AccountRemoved(String),
+    /// Tokens were issued for or revoked from the account.
+    TokensChanged(String),
+    /// The account's tokens were revoked, for example by a Continuous
+    /// Access Evaluation event, and tokens already held should be dropped.
+    TokenRevoked {
+        home_account_id: String,
+        reason: String,
+    },
+    /// The user must sign in to the account again, for example after a
+    /// password change.
+    ReauthRequired {
+        home_account_id: String,
+        reason: String,
+    },
+    /// Events were missed, so every account should be considered changed.
+    Resync,
+}
+
+impl AccountEvent {
+    /// The D-Bus signal announcing the event, and its home account id.
+    pub fn signal(&self) -> (&'static str, Option<&str>) {
+        match self {
+            AccountEvent::AccountAdded(id) => ("AccountAdded", Some(id)),
+            AccountEvent::AccountRemoved(id) => ("AccountRemoved", Some(id)),
+            AccountEvent::TokensChanged(id) => ("TokensChanged", Some(id)),
+            AccountEvent::TokenRevoked {
+                home_account_id, ..
+            } => ("TokenRevoked", Some(home_account_id)),
+            AccountEvent::ReauthRequired {
+                home_account_id, ..
+            } => ("ReauthRequired", Some(home_account_id)),
+            AccountEvent::Resync => ("AccountsChanged", None),
+        }
+    }
+
+    /// Why the account's tokens were revoked or it must be signed in to
+    /// again, sent as the second argument of its signal.
+    pub fn reason(&self) -> Option<&str> {
+        match self {
+            AccountEvent::TokenRevoked { reason, .. }
+            | AccountEvent::ReauthRequired { reason, .. } => Some(reason),
+            _ => None,
+        }
+    }
+}
+
+/* A daemon publishes account events through a shared BrokerEvents handle,
+ * returned from `HimmelblauBroker::events`. Each subscribed session broker
+ * connection receives the events of its own user.
+ */
+/// Publishes account events to the daemon's subscribers.
+#[derive(Clone)]
+pub struct BrokerEvents {
+    tx: broadcast::Sender<(uid_t, AccountEvent)>,
+}
+
+impl Default for BrokerEvents {
+    fn default() -> Self {
+        BrokerEvents::new(64)
+    }
+}
+
+impl BrokerEvents {
+    /// A publisher buffering up to `capacity` events for slow subscribers.
+    /// Subscribers which fall further behind are sent `Resync`.
+    pub fn new(capacity: usize) -> Self {
+        let (tx, _) = broadcast::channel(capacity);
+        BrokerEvents { tx }
+    }
+
+    /// Announce `event` to the session brokers of `uid`.
+    pub fn publish(&self, uid: uid_t, event: AccountEvent) {
+        // Having no subscribers is not an error.
+        let _ = self.tx.send((uid, event));
+    }
+
+    pub(crate) fn subscribe(
+        &self,
+    ) -> broadcast::Receiver<(uid_t, AccountEvent)> {
+        self.tx.subscribe()
+    }
+}
+
+/// Emits account events as signals on the Accounts interface of each of
+/// the broker's object paths, see `AsyncSessionBroker::watch_accounts`.
+#[derive(Clone)]
+pub struct AccountSignals {
+    conn: Arc<SyncConnection>,
+    paths: Vec<dbus::Path<'static>>,
+}
+
+impl AccountSignals {
+    pub(crate) fn new(
+        conn: Arc<SyncConnection>,
+        paths: Vec<dbus::Path<'static>>,
+    ) -> Self {
+        AccountSignals { conn, paths }
+    }
+
+    pub fn emit(&self, event: &AccountEvent) {
+        let (member, home_account_id) = event.signal();
+        for path in &self.paths {
+            let msg = dbus::Message::signal(
+                path,
+                &ACCOUNTS_INTERFACE.into(),
+                &member.into(),
+            );
+            let msg = match home_account_id {
+                Some(id) => msg.append1(id),
+                None => msg,
+            };
+            let msg = match event.reason() {
+                Some(reason) => msg.append1(reason),
+                None => msg,
+            };
+            if self.conn.send(msg).is_err() {
+                error!("Failed to emit the {} signal", member);
+            }
+        }
+    }
+}
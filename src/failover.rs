@@ -0,0 +1,189 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::socket::SocketAddress;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use tokio::net::UnixStream;
+use tracing::{debug, info};
+
+/// Standby daemons for the session broker to fail over to while the
+/// primary daemon's socket is unavailable.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct FailoverConfig {
+    /// Sockets of standby daemons, in order of preference after the
+    /// primary.
+    pub standby: Vec<SocketAddress>,
+    /// Seconds between health checks of the more preferred sockets while
+    /// failed over.
+    pub health_check_interval: u64,
+    /// Consecutive passed health checks needed before failing back to a
+    /// more preferred socket.
+    pub fail_back_after: u32,
+}
+
+impl Default for FailoverConfig {
+    fn default() -> Self {
+        FailoverConfig {
+            standby: vec![],
+            health_check_interval: 10,
+            fail_back_after: 3,
+        }
+    }
+}
+
+struct FailoverState {
+    active: usize,
+    checked: Instant,
+    /// A more preferred socket which passed its recent health checks, and
+    /// how many in a row.
+    recovering: Option<(usize, u32)>,
+}
+
+/* Connections go to the active socket, which starts as the primary. When it
+ * refuses a connection the others are tried in order, and the first to
+ * accept becomes active, so the broker stays on a standby rather than
+ * retrying a dead primary for every request. While failed over, the more
+ * preferred sockets are probed at most once per health check interval, and
+ * the broker only fails back once one has stayed up for `fail_back_after`
+ * checks, so that a flapping primary does not bounce clients between
+ * daemons.
+ */
+pub(crate) struct SocketSet {
+    addresses: Vec<SocketAddress>,
+    health_check_interval: Duration,
+    fail_back_after: u32,
+    state: Mutex<FailoverState>,
+}
+
+impl SocketSet {
+    pub(crate) fn new(
+        primary: SocketAddress,
+        config: &FailoverConfig,
+    ) -> io::Result<Self> {
+        let mut addresses = vec![primary];
+        for standby in &config.standby {
+            addresses.push(standby.expand_specifiers()?);
+        }
+        Ok(SocketSet {
+            addresses,
+            health_check_interval: Duration::from_secs(
+                config.health_check_interval,
+            ),
+            fail_back_after: config.fail_back_after.max(1),
+            state: Mutex::new(FailoverState {
+                active: 0,
+                checked: Instant::now(),
+                recovering: None,
+            }),
+        })
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, FailoverState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The index of the socket connections are made to.
+    pub(crate) fn active(&self) -> usize {
+        self.state().active
+    }
+
+    pub(crate) fn address(&self, index: usize) -> &SocketAddress {
+        &self.addresses[index]
+    }
+
+    /// Connect to the active socket, failing over to the others in order of
+    /// preference. Returns the index of the socket connected to.
+    pub(crate) async fn connect(&self) -> io::Result<(UnixStream, usize)> {
+        self.check_preferred().await;
+        let active = self.active();
+        let order = std::iter::once(active)
+            .chain((0..self.addresses.len()).filter(|i| *i != active));
+        let mut last_err = None;
+        for index in order {
+            match self.addresses[index].connect().await {
+                Ok(stream) => {
+                    if index != active {
+                        info!(
+                            "Failing over from {} to {}",
+                            self.addresses[active], self.addresses[index]
+                        );
+                        let mut state = self.state();
+                        state.active = index;
+                        state.checked = Instant::now();
+                        state.recovering = None;
+                    }
+                    return Ok((stream, index));
+                }
+                Err(e) => {
+                    debug!(
+                        "Failed to connect to {} -> {}",
+                        self.addresses[index], e
+                    );
+                    last_err = Some(e);
+                }
+            }
+        }
+        Err(last_err.unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "No daemon sockets")
+        }))
+    }
+
+    async fn check_preferred(&self) {
+        let active = {
+            let mut state = self.state();
+            if state.active == 0
+                || state.checked.elapsed() < self.health_check_interval
+            {
+                return;
+            }
+            state.checked = Instant::now();
+            state.active
+        };
+        let mut healthy = None;
+        for index in 0..active {
+            if self.addresses[index].connect().await.is_ok() {
+                healthy = Some(index);
+                break;
+            }
+        }
+        let mut state = self.state();
+        state.recovering = match (healthy, state.recovering) {
+            (Some(index), Some((recovering, passed)))
+                if index == recovering =>
+            {
+                Some((index, passed + 1))
+            }
+            (Some(index), _) => Some((index, 1)),
+            (None, _) => None,
+        };
+        if let Some((index, passed)) = state.recovering {
+            if passed >= self.fail_back_after {
+                info!(
+                    "Failing back from {} to {}",
+                    self.addresses[state.active], self.addresses[index]
+                );
+                state.active = index;
+                state.recovering = None;
+            }
+        }
+    }
+}
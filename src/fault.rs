@@ -0,0 +1,252 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::broadcast::Receiver;
+use tokio::task::JoinHandle;
+use tracing::{debug, error};
+
+/// Bytes sent in place of a response by a `Fault::Malformed`.
+pub const MALFORMED_RESPONSE: &str = "\u{fffd}malformed response\u{fffd}";
+
+/// A misbehaviour injected by a mock broker (see `FaultConfig`).
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum Fault {
+    /// The response is held back for `FaultConfig::stall` seconds.
+    Timeout,
+    /// The response is replaced by `MALFORMED_RESPONSE`.
+    Malformed,
+    /// Only the first half of the response is sent.
+    PartialWrite,
+    /// The connection is dropped without a response.
+    Reset,
+}
+
+/// How often mock brokers misbehave. Each field is the probability, from
+/// 0.0 to 1.0, of the fault being injected into a call. At most one fault
+/// is injected per call.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, rename_all = "camelCase")]
+pub struct FaultConfig {
+    pub timeout: f64,
+    pub malformed: f64,
+    pub partial_write: f64,
+    pub reset: f64,
+    /// Seconds for which a `Fault::Timeout` stalls the call, longer than
+    /// the default D-Bus method call timeout of 25 seconds.
+    pub stall: u64,
+    /// Seed for the sequence of faults, so a failing run can be repeated.
+    /// Unset seeds it randomly.
+    pub seed: Option<u64>,
+}
+
+impl Default for FaultConfig {
+    fn default() -> Self {
+        FaultConfig {
+            timeout: 0.0,
+            malformed: 0.0,
+            partial_write: 0.0,
+            reset: 0.0,
+            stall: 30,
+            seed: None,
+        }
+    }
+}
+
+/* Faults are drawn from a xorshift generator rather than a system RNG, so
+ * that a seeded configuration injects the same faults on every run.
+ */
+/// Draws the faults described by a `FaultConfig`, one per call.
+#[derive(Debug)]
+pub struct FaultInjector {
+    config: FaultConfig,
+    state: Mutex<u64>,
+}
+
+impl FaultInjector {
+    pub fn new(config: FaultConfig) -> Self {
+        let seed = config
+            .seed
+            .unwrap_or_else(|| uuid::Uuid::new_v4().as_u64_pair().0);
+        FaultInjector {
+            config,
+            // Xorshift never leaves zero.
+            state: Mutex::new(seed | 1),
+        }
+    }
+
+    pub fn config(&self) -> &FaultConfig {
+        &self.config
+    }
+
+    /// How long a `Fault::Timeout` stalls the call.
+    pub fn stall(&self) -> Duration {
+        Duration::from_secs(self.config.stall)
+    }
+
+    /// The fault to inject into the next call, if any.
+    pub fn next_fault(&self) -> Option<Fault> {
+        let roll = {
+            let mut state =
+                self.state.lock().unwrap_or_else(PoisonError::into_inner);
+            *state ^= *state << 13;
+            *state ^= *state >> 7;
+            *state ^= *state << 17;
+            (*state >> 11) as f64 / (1u64 << 53) as f64
+        };
+        let mut threshold = 0.0;
+        for (probability, fault) in [
+            (self.config.timeout, Fault::Timeout),
+            (self.config.malformed, Fault::Malformed),
+            (self.config.partial_write, Fault::PartialWrite),
+            (self.config.reset, Fault::Reset),
+        ] {
+            threshold += probability;
+            if roll < threshold {
+                return Some(fault);
+            }
+        }
+        None
+    }
+}
+
+/// The first half of `resp`, as sent by a `Fault::PartialWrite`.
+pub(crate) fn truncate_response(resp: &str) -> String {
+    let mut end = resp.len() / 2;
+    while !resp.is_char_boundary(end) {
+        end -= 1;
+    }
+    resp[..end].to_string()
+}
+
+/* The proxy draws a fault as each request arrives from the client, and
+ * injects it into the daemon's reply. It forwards bytes without decoding
+ * them, so it works with every wire format, but file descriptors passed by
+ * the daemon are not forwarded.
+ */
+/// Listen on `listen_path`, forwarding each connection to the daemon socket
+/// at `daemon_path` while injecting the faults described by `config`. This
+/// puts any daemon behind the same misbehaviour as `MockSessionBroker`, to
+/// test the session broker, or another daemon client, against it. The
+/// proxy stops, removing its socket, when `shutdown_rx` is signalled.
+pub async fn serve_fault_proxy(
+    listen_path: impl AsRef<Path>,
+    daemon_path: impl AsRef<Path>,
+    config: FaultConfig,
+    mut shutdown_rx: Receiver<bool>,
+) -> Result<JoinHandle<()>, Box<dyn Error>> {
+    let listen_path = listen_path.as_ref().to_path_buf();
+    let daemon_path = daemon_path.as_ref().to_path_buf();
+    let listener = UnixListener::bind(&listen_path).map_err(|e| {
+        error!("Failed to bind fault proxy at {}", listen_path.display());
+        Box::new(e)
+    })?;
+    let faults = Arc::new(FaultInjector::new(config));
+    Ok(tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = shutdown_rx.recv() => {
+                    let _ = std::fs::remove_file(&listen_path);
+                    break;
+                }
+                accept_res = listener.accept() => {
+                    match accept_res {
+                        Ok((client, _)) => {
+                            let daemon_path = daemon_path.clone();
+                            let faults = faults.clone();
+                            tokio::spawn(async move {
+                                if let Err(e) =
+                                    proxy_connection(client, daemon_path, faults).await
+                                {
+                                    debug!("Fault proxy connection closed -> {:?}", e);
+                                }
+                            });
+                        }
+                        Err(e) => {
+                            error!("Fault proxy accept error -> {:?}", e);
+                        }
+                    }
+                }
+            }
+        }
+    }))
+}
+
+async fn proxy_connection(
+    client: UnixStream,
+    daemon_path: PathBuf,
+    faults: Arc<FaultInjector>,
+) -> io::Result<()> {
+    let daemon = UnixStream::connect(&daemon_path).await?;
+    let (mut client_rx, mut client_tx) = client.into_split();
+    let (mut daemon_rx, mut daemon_tx) = daemon.into_split();
+    let mut request = vec![0; 64 * 1024];
+    let mut response = vec![0; 64 * 1024];
+    let mut pending = None;
+    loop {
+        tokio::select! {
+            count = client_rx.read(&mut request) => {
+                let count = count?;
+                if count == 0 {
+                    return Ok(());
+                }
+                match faults.next_fault() {
+                    Some(Fault::Reset) => {
+                        debug!("Injecting a connection reset");
+                        return Ok(());
+                    }
+                    Some(Fault::Timeout) => {
+                        debug!("Injecting a timeout");
+                        tokio::time::sleep(faults.stall()).await;
+                        return Ok(());
+                    }
+                    fault => pending = fault,
+                }
+                daemon_tx.write_all(&request[..count]).await?;
+            }
+            count = daemon_rx.read(&mut response) => {
+                let count = count?;
+                if count == 0 {
+                    return Ok(());
+                }
+                match pending.take() {
+                    Some(Fault::Malformed) => {
+                        debug!("Injecting a malformed response");
+                        client_tx
+                            .write_all(MALFORMED_RESPONSE.as_bytes())
+                            .await?;
+                    }
+                    Some(Fault::PartialWrite) => {
+                        debug!("Injecting a partial write");
+                        client_tx.write_all(&response[..count / 2]).await?;
+                        return Ok(());
+                    }
+                    _ => client_tx.write_all(&response[..count]).await?,
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,121 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::error::BrokerError;
+use crate::prompt::Prompter;
+use async_trait::async_trait;
+use libc::uid_t;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub enum FederationRequestKind {
+    /// Fetch the federation metadata (MEX) document of the identity
+    /// provider.
+    MetadataExchange,
+    /// Post a WS-Trust RequestSecurityToken envelope to the identity
+    /// provider, authenticated as the user (for example with Kerberos).
+    WsTrust,
+}
+
+/// Sent from the himmelblau daemon to the session broker while a federated
+/// interactive flow is in progress, asking for a request to be made to the
+/// federated identity provider (such as ADFS) from the user's session,
+/// where the user's intranet access and credentials are available.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub struct FederationRequest {
+    pub kind: FederationRequestKind,
+    pub correlation_id: String,
+    pub url: String,
+    /// The request body. Requests without a body are sent as a GET.
+    pub body: Option<String>,
+    pub headers: Vec<(String, String)>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub struct FederationResponse {
+    /// The HTTP status, or 0 if no response was received.
+    pub status: u16,
+    pub body: String,
+    /// Why the request could not be made, if it could not.
+    pub error: Option<String>,
+}
+
+impl FederationResponse {
+    pub fn failed(error: impl Into<String>) -> Self {
+        FederationResponse {
+            status: 0,
+            body: String::new(),
+            error: Some(error.into()),
+        }
+    }
+}
+
+/// Handed to `FederatedBroker::acquire_token_interactively_federated`, so
+/// that the daemon can reach the federated identity provider through the
+/// caller's session, as well as prompt the user.
+#[async_trait]
+pub trait FederationExchange: Prompter {
+    async fn exchange(
+        &mut self,
+        request: FederationRequest,
+    ) -> Result<FederationResponse, BrokerError>;
+}
+
+/* Tenants federated to ADFS need extra round trips to the identity provider
+ * during an interactive flow. Brokers which support them implement this
+ * trait and return themselves from `HimmelblauBroker::federation`, in which
+ * case their interactive flows are run here rather than by
+ * `acquire_token_interactively_with_prompt`. Flows for users in managed
+ * domains may simply call that method.
+ */
+#[async_trait]
+pub trait FederatedBroker {
+    async fn acquire_token_interactively_federated(
+        &mut self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        uid: uid_t,
+        session: &mut dyn FederationExchange,
+    ) -> Result<String, BrokerError>;
+}
+
+/// Session side handler which makes a `FederationRequest` on the daemon's
+/// behalf.
+pub trait FederationHandler: Send + Sync {
+    fn exchange(
+        &self,
+        request: &FederationRequest,
+    ) -> Result<FederationResponse, Box<dyn Error>>;
+}
+
+/// A `FederationHandler` which declines every request.
+#[derive(Debug, Default)]
+pub struct NoFederationHandler;
+
+impl FederationHandler for NoFederationHandler {
+    fn exchange(
+        &self,
+        _request: &FederationRequest,
+    ) -> Result<FederationResponse, Box<dyn Error>> {
+        Err("Federated requests are not supported in this session".into())
+    }
+}
@@ -0,0 +1,123 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+/* Entry points into the private wire protocol for the fuzz targets and the
+ * protocol property tests. Nothing here is a stable API.
+ */
+pub use crate::broker_proto::{
+    ClientRequest, ClientResponse, ProtocolError, ProtocolErrorKind,
+};
+use crate::himmelblau_broker::{ClientCodec, CodecError, Frame};
+use crate::wire::{FrameDecode, WireFormat, WireFormatKind};
+use bytes::BytesMut;
+use tokio_util::codec::{Decoder, Encoder};
+
+/// The outcome of decoding one frame from the socket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Decoded {
+    Request(ClientRequest),
+    /// A frame which was discarded, the connection stays open.
+    Malformed(ProtocolError),
+    /// An error which closes the connection.
+    Fatal(String),
+}
+
+/// Feed `chunks` to the server codec one read at a time, as they would
+/// arrive on the socket, returning every frame decoded. Decoding stops at
+/// the first fatal error.
+pub fn decode_chunks(max_frame_size: usize, chunks: &[&[u8]]) -> Vec<Decoded> {
+    decode_chunks_in(WireFormatKind::Json, max_frame_size, chunks)
+}
+
+/// `decode_chunks` for a connection which negotiated `format`.
+pub fn decode_chunks_in(
+    format: WireFormatKind,
+    max_frame_size: usize,
+    chunks: &[&[u8]],
+) -> Vec<Decoded> {
+    let mut codec = ClientCodec {
+        max_frame_size,
+        format,
+    };
+    let mut buf = BytesMut::new();
+    let mut frames = vec![];
+    for chunk in chunks {
+        buf.extend_from_slice(chunk);
+        loop {
+            match codec.decode(&mut buf) {
+                Ok(Some(Frame::Request(req))) => {
+                    frames.push(Decoded::Request(req))
+                }
+                Ok(Some(Frame::Malformed(e))) => {
+                    frames.push(Decoded::Malformed(e))
+                }
+                Ok(None) => break,
+                Err(CodecError::Protocol(e)) => {
+                    frames.push(Decoded::Fatal(e.to_string()));
+                    return frames;
+                }
+                Err(CodecError::Io(e)) => {
+                    frames.push(Decoded::Fatal(e.to_string()));
+                    return frames;
+                }
+            }
+        }
+    }
+    frames
+}
+
+/// Encode a response with the server codec.
+pub fn encode_response(
+    resp: ClientResponse,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    encode_response_in(WireFormatKind::Json, resp)
+}
+
+/// `encode_response` for a connection which negotiated `format`.
+pub fn encode_response_in(
+    format: WireFormatKind,
+    resp: ClientResponse,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut codec = ClientCodec {
+        max_frame_size: usize::MAX,
+        format,
+    };
+    let mut buf = BytesMut::new();
+    codec.encode(resp, &mut buf)?;
+    Ok(buf.to_vec())
+}
+
+/// Encode a request as the session broker sends it in `format`.
+pub fn encode_request_in(
+    format: WireFormatKind,
+    req: &ClientRequest,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    Ok(format.encode(req)?)
+}
+
+/// Decode a response as the session broker reads it in `format`.
+pub fn decode_response_in(
+    format: WireFormatKind,
+    data: &[u8],
+) -> Result<ClientResponse, Box<dyn std::error::Error>> {
+    match format.decode(data) {
+        FrameDecode::Complete(resp, _) => Ok(resp),
+        FrameDecode::Incomplete => Err("Incomplete response".into()),
+        FrameDecode::Malformed(e, _) => Err(e.into()),
+    }
+}
@@ -0,0 +1,178 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use dbus::Message;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/* A local process may probe for accounts by asking for tokens (or removing
+ * accounts) it has no business with. An unknown account fails at once,
+ * while a known one reaches the daemon or Entra ID first, and the size of
+ * getAccounts responses reveals how many accounts exist. Hardened methods
+ * therefore fail no sooner than `error_floor` after they were called, and
+ * their responses are padded to a multiple of `bucket_size`.
+ */
+/// Hardening against account enumeration by timing or response size. Only
+/// applies to the session broker.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct HardeningConfig {
+    /// The Broker1 methods hardened.
+    pub methods: Vec<String>,
+    /// Responses are padded with trailing whitespace, which JSON parsers
+    /// ignore, to a multiple of this many bytes. Zero disables padding.
+    pub bucket_size: usize,
+    /// Milliseconds after a call arrived before it may fail. Brokers
+    /// mounted on an application's own Crossroads instance wait no longer
+    /// than `MAX_BLOCKING_ERROR_DELAY`.
+    pub error_floor: u64,
+}
+
+impl Default for HardeningConfig {
+    fn default() -> Self {
+        HardeningConfig {
+            methods: [
+                "acquireTokenSilently",
+                "getAccounts",
+                "removeAccount",
+                "acquirePrtSsoCookie",
+                "generateSignedHttpRequest",
+            ]
+            .map(str::to_string)
+            .to_vec(),
+            bucket_size: 4096,
+            error_floor: 500,
+        }
+    }
+}
+
+impl HardeningConfig {
+    pub fn applies_to(&self, method: &str) -> bool {
+        self.methods.iter().any(|m| m == method)
+    }
+
+    /// `resp` padded to the next multiple of `bucket_size`.
+    pub fn pad(&self, mut resp: String) -> String {
+        if self.bucket_size > 0 {
+            let padded =
+                resp.len().div_ceil(self.bucket_size).max(1) * self.bucket_size;
+            resp.extend(std::iter::repeat_n(' ', padded - resp.len()));
+        }
+        resp
+    }
+
+    /// How much longer a failed call which arrived at `started` must wait
+    /// before its error is returned.
+    pub fn error_delay(&self, started: Instant) -> Duration {
+        Duration::from_millis(self.error_floor)
+            .saturating_sub(started.elapsed())
+    }
+}
+
+/// The longest a hardened error is held back by blocking the thread which
+/// dispatches calls, when the broker is mounted on a Crossroads instance
+/// the application serves (see `session_broker_add_to_crossroads`).
+pub const MAX_BLOCKING_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+/* The blocking serve loop dispatches one call at a time, so sleeping out
+ * the error floor in a method handler would hold up every other caller.
+ * Instead the handler marks the call as deferred, and the loop sends its
+ * replies through `hold`, which keeps the error reply back until it is
+ * due. Calls are told apart by their sender and serial, serials only
+ * being unique per connection.
+ */
+#[derive(Default)]
+pub(crate) struct DeferredReplies {
+    serving: AtomicBool,
+    due: Mutex<HashMap<(String, u32), Instant>>,
+    held: Mutex<Vec<(Instant, Message)>>,
+}
+
+impl DeferredReplies {
+    /// Called by the serve loop before serving, after which replies are
+    /// deferred rather than delayed.
+    pub(crate) fn serve(&self) {
+        self.serving.store(true, Ordering::Relaxed);
+    }
+
+    /// Hold back the reply to `call` for `delay`. Outside the serve loop,
+    /// the calling thread sleeps instead, for no longer than
+    /// `MAX_BLOCKING_ERROR_DELAY`.
+    pub(crate) fn defer(&self, call: &Message, delay: Duration) {
+        let key = match (call.sender(), call.get_serial()) {
+            (Some(sender), Some(serial))
+                if self.serving.load(Ordering::Relaxed) =>
+            {
+                (sender.to_string(), serial)
+            }
+            _ => {
+                std::thread::sleep(delay.min(MAX_BLOCKING_ERROR_DELAY));
+                return;
+            }
+        };
+        self.due
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(key, Instant::now() + delay);
+    }
+
+    /// Keep `reply` back if it answers a deferred call, otherwise return it
+    /// to be sent.
+    pub(crate) fn hold(&self, reply: Message) -> Option<Message> {
+        let key = match (reply.destination(), reply.get_reply_serial()) {
+            (Some(dest), Some(serial)) => (dest.to_string(), serial),
+            _ => return Some(reply),
+        };
+        let due = self
+            .due
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&key);
+        match due {
+            Some(due) => {
+                self.held
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .push((due, reply));
+                None
+            }
+            None => Some(reply),
+        }
+    }
+
+    /// How long until the next held reply is due.
+    pub(crate) fn next_due(&self) -> Option<Duration> {
+        let held = self.held.lock().unwrap_or_else(PoisonError::into_inner);
+        let due = held.iter().map(|(due, _)| *due).min()?;
+        Some(due.saturating_duration_since(Instant::now()))
+    }
+
+    /// Take the held replies which are due, or every held reply when
+    /// `all`.
+    pub(crate) fn take_due(&self, all: bool) -> Vec<Message> {
+        let now = Instant::now();
+        let mut held = self.held.lock().unwrap_or_else(PoisonError::into_inner);
+        let (due, later) =
+            held.drain(..).partition(|(due, _)| all || *due <= now);
+        *held = later;
+        due.into_iter().map(|(_, reply)| reply).collect()
+    }
+}
@@ -15,18 +15,70 @@
    You should have received a copy of the GNU Lesser General Public License
    along with this program. If not, see <https://www.gnu.org/licenses/>.
 */
-use crate::broker_proto::ClientRequest;
+use crate::accounts::{
+    remove_accounts_response, split_remove_accounts, Account, AccountPage,
+};
+use crate::broker_proto::{
+    ClientRequest, ClientResponse, ProtocolError, ProtocolErrorKind,
+};
+use crate::caller::CallerInfo;
+use crate::compliance::{compliance_unsupported, ComplianceBroker};
+use crate::delegated_flow::{
+    delegation_unsupported, DelegatedFlowBroker, InteractionMode,
+};
+use crate::device_code::{
+    device_code_unsupported, DeviceCodeBroker, DeviceCodeFlowRequest,
+};
+use crate::diagnostics::BrokerStats;
+use crate::error::BrokerError;
+use crate::events::{AccountEvent, BrokerEvents};
+use crate::fdpass::send_with_fd;
+use crate::federation::{
+    FederatedBroker, FederationExchange, FederationRequest, FederationResponse,
+};
+use crate::idle::IdleTimer;
+use crate::interactive::InteractiveFlows;
+use crate::ownership::AccountOwnership;
+use crate::pam::{self, handle_pam_connection, PamBroker};
+use crate::policy::MethodPolicy;
+use crate::prompt::{
+    FdHandoff, PromptKind, PromptRequest, PromptResponse, PromptVerification,
+    PromptVerifier, Prompter,
+};
+use crate::redact::summarize_response;
+use crate::refresh::{
+    run_refresh_scheduler, RefreshBroker, RefreshSchedulerConfig,
+};
+use crate::sandbox::Sandbox;
+use crate::singleflight::SingleFlight;
+use crate::socket::{SocketAddress, SocketPermissions, SocketTakeover};
+use crate::ssh_cert::{ssh_cert_unsupported, SshCertBroker};
+use crate::system_events::{watch_system_events, SystemEvent};
+use crate::wire::{select_format, FrameDecode, WireFormat, WireFormatKind};
 use async_trait::async_trait;
-use bytes::{BufMut, BytesMut};
+use bytes::{Buf, BufMut, BytesMut};
 use futures::{SinkExt, StreamExt};
-use libc::{uid_t, umask};
+use libc::{gid_t, pid_t, uid_t};
+use serde_json::json;
 use std::error::Error;
+use std::fmt;
+use std::fs::DirBuilder;
 use std::io;
-use tokio::net::{UnixListener, UnixStream};
-use tokio::sync::broadcast::Receiver;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::os::unix::fs::DirBuilderExt;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncWriteExt, Interest};
+use tokio::net::unix::UCred;
+use tokio::net::UnixStream;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::{self, Receiver};
+use tokio::sync::Notify;
 use tokio::task::JoinHandle;
 use tokio_util::codec::{Decoder, Encoder, Framed};
-use tracing::{debug, error, trace};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{debug, error, info_span, trace, warn, Instrument};
 
 #[async_trait]
 pub trait HimmelblauBroker {
@@ -36,225 +88,1112 @@ pub trait HimmelblauBroker {
         correlation_id: String,
         request_json: String,
         uid: uid_t,
-    ) -> Result<String, Box<dyn Error>>;
+    ) -> Result<String, BrokerError>;
+    /// Variant of `acquire_token_interactively` which may present secure
+    /// prompts (PIN, biometric or presence confirmation) in the caller's
+    /// session via the supplied `Prompter`. The default implementation
+    /// ignores the prompter.
+    async fn acquire_token_interactively_with_prompt(
+        &mut self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        uid: uid_t,
+        _prompter: &mut dyn Prompter,
+    ) -> Result<String, BrokerError> {
+        self.acquire_token_interactively(
+            protocol_version,
+            correlation_id,
+            request_json,
+            uid,
+        )
+        .await
+    }
     async fn acquire_token_silently(
         &mut self,
         protocol_version: String,
         correlation_id: String,
         request_json: String,
         uid: uid_t,
-    ) -> Result<String, Box<dyn Error>>;
+    ) -> Result<String, BrokerError>;
     async fn get_accounts(
         &mut self,
         protocol_version: String,
         correlation_id: String,
         request_json: String,
         uid: uid_t,
-    ) -> Result<String, Box<dyn Error>>;
+    ) -> Result<String, BrokerError>;
     async fn remove_account(
         &mut self,
         protocol_version: String,
         correlation_id: String,
         request_json: String,
         uid: uid_t,
-    ) -> Result<String, Box<dyn Error>>;
+    ) -> Result<String, BrokerError>;
     async fn acquire_prt_sso_cookie(
         &mut self,
         protocol_version: String,
         correlation_id: String,
         request_json: String,
         uid: uid_t,
-    ) -> Result<String, Box<dyn Error>>;
+    ) -> Result<String, BrokerError>;
     async fn generate_signed_http_request(
         &mut self,
         protocol_version: String,
         correlation_id: String,
         request_json: String,
         uid: uid_t,
-    ) -> Result<String, Box<dyn Error>>;
+    ) -> Result<String, BrokerError>;
     async fn cancel_interactive_flow(
         &mut self,
         protocol_version: String,
         correlation_id: String,
         request_json: String,
         uid: uid_t,
-    ) -> Result<String, Box<dyn Error>>;
+    ) -> Result<String, BrokerError>;
     async fn get_linux_broker_version(
         &mut self,
         protocol_version: String,
         correlation_id: String,
         request_json: String,
         uid: uid_t,
-    ) -> Result<String, Box<dyn Error>>;
+    ) -> Result<String, BrokerError>;
+
+    /// Refresh the user's Primary Refresh Token. The session broker sends
+    /// this before retrying an acquireTokenSilently which failed because
+    /// the PRT had expired. Refreshing is unsupported by default.
+    async fn refresh_prt(
+        &mut self,
+        _protocol_version: String,
+        _correlation_id: String,
+        _request_json: String,
+        _uid: uid_t,
+    ) -> Result<String, BrokerError> {
+        Err("refreshPrt is not supported".into())
+    }
+
+    /// Remove several accounts at once, see `split_remove_accounts`. By
+    /// default each account is removed with `remove_account`, and one
+    /// failure does not stop the remaining removals.
+    async fn remove_accounts(
+        &mut self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        uid: uid_t,
+    ) -> Result<String, BrokerError> {
+        let removals = split_remove_accounts(&request_json)
+            .map_err(|e| BrokerError::InvalidRequest(e.to_string()))?;
+        let mut results = Vec::new();
+        for (id, request_json) in removals {
+            let res = self
+                .remove_account(
+                    protocol_version.clone(),
+                    correlation_id.clone(),
+                    request_json,
+                    uid,
+                )
+                .await
+                .map_err(|e| e.to_string());
+            results.push((id, res));
+        }
+        Ok(remove_accounts_response(results))
+    }
+
+    /// A page of the accounts returned by `get_accounts`, see
+    /// `AccountPage`.
+    async fn get_accounts_page(
+        &mut self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        uid: uid_t,
+    ) -> Result<String, BrokerError> {
+        let (request_json, page) =
+            AccountPage::take_from_request(&request_json)
+                .map_err(|e| BrokerError::InvalidRequest(e.to_string()))?;
+        let resp = self
+            .get_accounts(protocol_version, correlation_id, request_json, uid)
+            .await?;
+        Ok(page.apply(&resp)?)
+    }
+
+    /// The broker's device compliance reporting, if it has any. Brokers
+    /// implementing `ComplianceBroker` return `Some(self)`.
+    fn compliance(&mut self) -> Option<&mut (dyn ComplianceBroker + Send)> {
+        None
+    }
+
+    /// The broker's handling of PAM events, if it has any. Brokers
+    /// implementing `PamBroker` return `Some(self)`.
+    fn pam(&mut self) -> Option<&mut (dyn PamBroker + Send)> {
+        None
+    }
+
+    /// The broker's SSH certificate minting, if it has any. Brokers
+    /// implementing `SshCertBroker` return `Some(self)`.
+    fn ssh_cert(&mut self) -> Option<&mut (dyn SshCertBroker + Send)> {
+        None
+    }
+
+    /// The broker's delegation of interactive flows to browsers outside the
+    /// session, if it has any. Brokers implementing `DelegatedFlowBroker`
+    /// return `Some(self)`.
+    fn delegation(&mut self) -> Option<&mut (dyn DelegatedFlowBroker + Send)> {
+        None
+    }
+
+    /// The broker's device code flow, if it has one. Brokers implementing
+    /// `DeviceCodeBroker` return `Some(self)`.
+    fn device_code(&mut self) -> Option<&mut (dyn DeviceCodeBroker + Send)> {
+        None
+    }
+
+    /// The broker's federated interactive flows, if it has any. Brokers
+    /// implementing `FederatedBroker` return `Some(self)`.
+    fn federation(&mut self) -> Option<&mut (dyn FederatedBroker + Send)> {
+        None
+    }
+
+    /// The broker's refreshing of cached artifacts ahead of expiry, if it
+    /// has any. Brokers implementing `RefreshBroker` return `Some(self)`.
+    fn refresh(&mut self) -> Option<&mut (dyn RefreshBroker + Send)> {
+        None
+    }
+
+    /// Counters for the socket server, such as malformed frames received.
+    /// Implementations which also publish diagnostics should return a
+    /// shared handle here.
+    fn stats(&self) -> Option<Arc<BrokerStats>> {
+        None
+    }
+
+    /// The publisher of account events, for brokers which announce changes
+    /// to their accounts to subscribed session brokers. Implementations
+    /// should return a shared handle here.
+    fn events(&self) -> Option<BrokerEvents> {
+        None
+    }
+
+    /// Called when the device suspends or resumes, or its network
+    /// connectivity changes (see `HimmelblauBrokerConfig::system_events`),
+    /// after which cached cookies and tokens may be stale. Brokers may drop
+    /// or revalidate them here. Ignored by default.
+    async fn system_event(
+        &mut self,
+        _event: SystemEvent,
+    ) -> Result<(), BrokerError> {
+        Ok(())
+    }
+
+    /// Called before dispatching a request forwarded with the identity of
+    /// the D-Bus caller (uid, pid, security label and, if tracked, logind
+    /// session), allowing the daemon to apply per-application policy and
+    /// to place interactive flows. Returning an error rejects the request.
+    /// The caller has been checked against the connection (see
+    /// `CallerInfo::check_peer`), but unless `uid` is root its pid, label
+    /// and session are only the forwarder's claim. They may restrict what
+    /// a caller gets, but must not grant it more than `uid` may have.
+    async fn check_caller(
+        &mut self,
+        _method: &str,
+        _caller: &CallerInfo,
+        _uid: uid_t,
+    ) -> Result<(), BrokerError> {
+        Ok(())
+    }
+}
+
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 1024 * 1024;
+
+/// Behaviour options for the Himmelblau broker socket server.
+#[derive(Debug, Clone)]
+pub struct HimmelblauBrokerConfig {
+    /// The largest request frame (in bytes) accepted from a client. Clients
+    /// exceeding this are sent a FrameTooLarge error and disconnected.
+    pub max_frame_size: usize,
+    /// The number of consecutive malformed frames tolerated from a client
+    /// before it is disconnected.
+    pub max_malformed_frames: u32,
+    /// Ownership and permissions of the daemon socket.
+    pub socket: SocketPermissions,
+    /// Whether an existing socket at the daemon's path may be replaced.
+    pub socket_takeover: SocketTakeover,
+    /// Run each user's acquireTokenInteractively requests one at a time,
+    /// answering requests identical to one already in progress with its
+    /// result, so that the user is not prompted twice.
+    pub serialize_interactive_flows: bool,
+    /// Answer concurrent identical acquireTokenSilently requests from one
+    /// user with a single backend call.
+    pub coalesce_silent_requests: bool,
+    /// The wire formats clients may switch to (see `WireFormatKind`).
+    /// Clients may always use JSON.
+    pub wire_formats: Vec<WireFormatKind>,
+    /// Seconds to wait at shutdown for requests in progress to complete.
+    pub shutdown_timeout: u64,
+    /// Also listen here for PAM modules reporting local authentications
+    /// (see `PamRequest`). The socket is only open to the daemon's user,
+    /// and only root may use it. Unset disables it.
+    pub pam_socket: Option<SocketAddress>,
+    /// Methods refused for every client (see `MethodPolicy`).
+    pub method_policy: MethodPolicy,
+    /// Check that the accounts named by requests belong to the connecting
+    /// uid (see `AccountOwnership::check_request`). By default uids are
+    /// resolved through NSS and foreign accounts are rejected.
+    pub account_ownership: AccountOwnership,
+    /// Refresh cached artifacts ahead of their expiry while the daemon is
+    /// idle, with a broker for root (see `RefreshBroker`). Unset disables
+    /// it.
+    pub refresh: Option<RefreshSchedulerConfig>,
+    /// Watch logind and NetworkManager on the system bus, passing suspend,
+    /// resume and connectivity changes to a broker for root (see
+    /// `HimmelblauBroker::system_event`).
+    pub system_events: bool,
+    /// How PIN and biometric prompts are answered. By default the daemon
+    /// asks polkit to authenticate the user for
+    /// `org.himmelblau.broker.authenticate`, rather than trusting the
+    /// session broker's answer (see `PromptVerification`).
+    pub prompt_verification: PromptVerification,
+    /// Confinement applied by `HimmelblauBrokerConfig::apply_sandbox`, such
+    /// as `Sandbox::daemon` with the configuration directory and cache
+    /// allowed. Unset leaves the daemon unconfined.
+    pub sandbox: Option<Sandbox>,
+}
+
+impl Default for HimmelblauBrokerConfig {
+    fn default() -> Self {
+        HimmelblauBrokerConfig {
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            max_malformed_frames: 3,
+            socket: SocketPermissions::default(),
+            socket_takeover: SocketTakeover::default(),
+            serialize_interactive_flows: true,
+            coalesce_silent_requests: true,
+            wire_formats: WireFormatKind::available(),
+            shutdown_timeout: 10,
+            pam_socket: None,
+            method_policy: MethodPolicy::default(),
+            account_ownership: AccountOwnership::default(),
+            refresh: None,
+            system_events: false,
+            prompt_verification: PromptVerification::default(),
+            sandbox: None,
+        }
+    }
+}
+
+impl HimmelblauBrokerConfig {
+    /// Confine the daemon with `sandbox`, also allowing the directories of
+    /// the daemon socket at `sock_path` and of the PAM socket. Returns as
+    /// `Sandbox::apply` does, or None when no sandbox is configured. Like
+    /// `Sandbox::apply`, this must be called at the top of main, before the
+    /// tokio runtime starts.
+    pub fn apply_sandbox(
+        &self,
+        sock_path: impl Into<SocketAddress>,
+    ) -> io::Result<Option<u32>> {
+        let mut sandbox = match &self.sandbox {
+            Some(sandbox) => sandbox.clone(),
+            None => return Ok(None),
+        };
+        let addresses =
+            std::iter::once(sock_path.into()).chain(self.pam_socket.clone());
+        for address in addresses {
+            let address = address.expand_specifiers()?;
+            // Directories are only allowed if they exist, so create them
+            // now rather than when the sockets are bound.
+            if let (SocketAddress::Path(path), Some(mode)) =
+                (&address, self.socket.directory_mode)
+            {
+                if let Some(parent) = path.parent() {
+                    DirBuilder::new()
+                        .recursive(true)
+                        .mode(mode)
+                        .create(parent)?;
+                }
+            }
+            sandbox = sandbox.socket(&address);
+        }
+        sandbox.apply()
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum CodecError {
+    Io(io::Error),
+    Protocol(ProtocolError),
+}
+
+impl From<io::Error> for CodecError {
+    fn from(e: io::Error) -> Self {
+        CodecError::Io(e)
+    }
 }
 
-#[derive(Default)]
-struct ClientCodec;
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::Io(e) => write!(f, "{}", e),
+            CodecError::Protocol(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for CodecError {}
+
+pub(crate) enum Frame {
+    Request(ClientRequest),
+    Malformed(ProtocolError),
+}
+
+pub(crate) struct ClientCodec {
+    pub(crate) max_frame_size: usize,
+    pub(crate) format: WireFormatKind,
+}
 
 impl Decoder for ClientCodec {
-    type Error = io::Error;
-    type Item = ClientRequest;
+    type Error = CodecError;
+    type Item = Frame;
 
     fn decode(
         &mut self,
         src: &mut BytesMut,
     ) -> Result<Option<Self::Item>, Self::Error> {
         trace!("Attempting to decode request ...");
-        match serde_json::from_slice::<ClientRequest>(src) {
-            Ok(msg) => {
-                // Clear the buffer for the next message.
-                src.clear();
-                Ok(Some(msg))
+        if src.len() > self.max_frame_size {
+            return Err(CodecError::Protocol(ProtocolError {
+                kind: ProtocolErrorKind::FrameTooLarge,
+                message: format!(
+                    "Request exceeds the maximum frame size of {} bytes",
+                    self.max_frame_size
+                ),
+            }));
+        }
+        match self.format.decode::<ClientRequest>(src) {
+            FrameDecode::Complete(msg, len) => {
+                // Consume this message, leaving any pipelined requests.
+                src.advance(len);
+                Ok(Some(Frame::Request(msg)))
+            }
+            // An incomplete frame, wait for more data.
+            FrameDecode::Incomplete => Ok(None),
+            FrameDecode::Malformed(e, len) => {
+                // The frame can never become valid, so discard it.
+                src.advance(len);
+                Ok(Some(Frame::Malformed(ProtocolError {
+                    kind: ProtocolErrorKind::MalformedFrame,
+                    message: format!("Malformed request: {}", e),
+                })))
             }
-            _ => Ok(None),
         }
     }
 }
 
-impl Encoder<String> for ClientCodec {
-    type Error = io::Error;
+impl Encoder<ClientResponse> for ClientCodec {
+    type Error = CodecError;
 
     fn encode(
         &mut self,
-        msg: String,
+        msg: ClientResponse,
         dst: &mut BytesMut,
     ) -> Result<(), Self::Error> {
-        dst.put(msg.as_bytes());
+        dst.put(self.format.encode(&msg)?.as_slice());
         Ok(())
     }
 }
 
+fn transport_error(e: impl fmt::Display) -> BrokerError {
+    BrokerError::Transport(e.to_string())
+}
+
+/* Prompts are shuttled over the same connection as the request which caused
+ * them. The session broker answers each promptRequest with a promptResponse
+ * before the final result is sent. PIN and biometric prompts are instead
+ * verified by the daemon, unless `PromptVerification::Session` is set.
+ */
+struct SocketPrompter<'a> {
+    reqs: &'a mut Framed<UnixStream, ClientCodec>,
+    verification: &'a PromptVerification,
+}
+
+impl SocketPrompter<'_> {
+    async fn verify(
+        &self,
+        verifier: Arc<dyn PromptVerifier>,
+        request: PromptRequest,
+    ) -> Result<PromptResponse, BrokerError> {
+        let cred = self.reqs.get_ref().peer_cred()?;
+        let pid = cred.pid().ok_or_else(|| {
+            BrokerError::unexpected("The session broker's pid is unknown")
+        })?;
+        let uid = cred.uid();
+        let kind = request.kind;
+        let accepted = tokio::task::spawn_blocking(move || {
+            verifier.verify(&request, pid, uid)
+        })
+        .await
+        .map_err(BrokerError::unexpected)??;
+        debug!(
+            "{:?} prompt for uid {} verified by the daemon: {}",
+            kind, uid, accepted
+        );
+        Ok(PromptResponse { accepted })
+    }
+}
+
+#[async_trait]
+impl Prompter for SocketPrompter<'_> {
+    async fn prompt(
+        &mut self,
+        request: PromptRequest,
+    ) -> Result<PromptResponse, BrokerError> {
+        if request.kind != PromptKind::UserPresence {
+            if let PromptVerification::Daemon(verifier) = self.verification {
+                return self.verify(verifier.clone(), request).await;
+            }
+        }
+        self.reqs
+            .send(ClientResponse::promptRequest(request))
+            .await
+            .map_err(transport_error)?;
+        self.reqs.flush().await.map_err(transport_error)?;
+        match self.reqs.next().await {
+            Some(Ok(Frame::Request(ClientRequest::promptResponse(resp)))) => {
+                Ok(resp)
+            }
+            Some(Ok(Frame::Malformed(e))) => Err(transport_error(e)),
+            Some(Ok(_)) => {
+                error!("Unexpected request while awaiting prompt response");
+                Err(transport_error(
+                    "Unexpected request while awaiting prompt response",
+                ))
+            }
+            Some(Err(e)) => Err(transport_error(e)),
+            None => Err(transport_error("Client disconnected during prompt")),
+        }
+    }
+
+    async fn pass_fd(
+        &mut self,
+        handoff: FdHandoff,
+        fd: OwnedFd,
+    ) -> Result<(), BrokerError> {
+        // Everything queued in the codec must be sent before the handoff.
+        self.reqs.flush().await.map_err(transport_error)?;
+        let data = self
+            .reqs
+            .codec()
+            .format
+            .encode(&ClientResponse::fdHandoff(handoff))
+            .map_err(BrokerError::unexpected)?;
+        let stream = self.reqs.get_mut();
+        let sent = loop {
+            stream.writable().await?;
+            match stream.try_io(Interest::WRITABLE, || {
+                send_with_fd(stream.as_raw_fd(), &data, fd.as_raw_fd())
+            }) {
+                Ok(sent) => break sent,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e.into()),
+            }
+        };
+        stream.write_all(&data[sent..]).await?;
+        stream.flush().await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl FederationExchange for SocketPrompter<'_> {
+    async fn exchange(
+        &mut self,
+        request: FederationRequest,
+    ) -> Result<FederationResponse, BrokerError> {
+        self.reqs
+            .send(ClientResponse::federationRequest(request))
+            .await
+            .map_err(transport_error)?;
+        self.reqs.flush().await.map_err(transport_error)?;
+        match self.reqs.next().await {
+            Some(Ok(Frame::Request(ClientRequest::federationResponse(
+                resp,
+            )))) => Ok(resp),
+            Some(Ok(Frame::Malformed(e))) => Err(transport_error(e)),
+            Some(Ok(_)) => {
+                error!("Unexpected request while awaiting federation response");
+                Err(transport_error(
+                    "Unexpected request while awaiting federation response",
+                ))
+            }
+            Some(Err(e)) => Err(transport_error(e)),
+            None => Err(transport_error(
+                "Client disconnected during federation request",
+            )),
+        }
+    }
+}
+
+/// State shared by the connections to one daemon socket.
+#[derive(Clone)]
+struct ServerState {
+    config: HimmelblauBrokerConfig,
+    stats: Arc<BrokerStats>,
+    interactive: Option<Arc<InteractiveFlows>>,
+    silent: Option<Arc<SingleFlight>>,
+    idle: Arc<IdleTimer>,
+    shutdown: CancellationToken,
+}
+
 async fn handle_request<T>(
     sock: UnixStream,
+    uid: uid_t,
+    connection: u64,
     mut broker: T,
+    state: ServerState,
 ) -> Result<(), Box<dyn Error>>
 where
-    T: HimmelblauBroker + Send + 'static + Clone,
+    T: HimmelblauBroker + Send + 'static,
 {
-    let cred = sock.peer_cred().map_err(|e| {
-        error!("Unable to verify peer credentials: {:?}", e);
-        Box::new(e)
-    })?;
-    let uid = cred.uid();
-
-    let mut reqs = Framed::new(sock, ClientCodec);
+    let ServerState {
+        config,
+        stats,
+        interactive,
+        silent,
+        idle,
+        shutdown,
+    } = state;
+    let mut reqs = Framed::new(
+        sock,
+        ClientCodec {
+            max_frame_size: config.max_frame_size,
+            format: WireFormatKind::Json,
+        },
+    );
+    let mut malformed_frames = 0;
 
-    while let Some(Ok(req)) = reqs.next().await {
+    loop {
+        // Shutdown is only noticed between requests, so that a request in
+        // progress is completed first.
+        let frame = tokio::select! {
+            biased;
+            _ = shutdown.cancelled() => {
+                debug!("Sending shutdown notice to uid {}", uid);
+                reqs.send(ClientResponse::shutdown).await?;
+                reqs.flush().await?;
+                break;
+            }
+            frame = reqs.next() => match frame {
+                Some(frame) => frame,
+                None => break,
+            },
+        };
+        let busy = idle.busy();
+        let req = match frame {
+            Ok(Frame::Request(req)) => {
+                malformed_frames = 0;
+                req
+            }
+            Ok(Frame::Malformed(e)) => {
+                error!("Malformed request from uid {} -> {}", uid, e);
+                stats.record_malformed_frame();
+                malformed_frames += 1;
+                reqs.send(ClientResponse::protocolError(e)).await?;
+                reqs.flush().await?;
+                if malformed_frames >= config.max_malformed_frames {
+                    error!(
+                        "Disconnecting uid {} after {} malformed requests",
+                        uid, malformed_frames
+                    );
+                    break;
+                }
+                continue;
+            }
+            Err(CodecError::Protocol(e)) => {
+                error!("Rejecting request from uid {} -> {}", uid, e);
+                reqs.send(ClientResponse::protocolError(e)).await?;
+                reqs.flush().await?;
+                break;
+            }
+            Err(e) => {
+                error!("Failed reading request -> {}", e);
+                break;
+            }
+        };
+        let mut req = match req {
+            ClientRequest::withCaller(caller, req) => {
+                let method = req.method();
+                let checked = match caller.check_peer(uid) {
+                    Ok(()) => broker.check_caller(method, &caller, uid).await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = checked {
+                    error!("Rejected {} from uid {} -> {}", method, uid, e);
+                    reqs.send(ClientResponse::error(e)).await?;
+                    reqs.flush().await?;
+                    continue;
+                }
+                *req
+            }
+            req => req,
+        };
+        #[cfg(feature = "legacy-protocol")]
+        let shim = crate::compat::LegacyShim::apply(&mut req);
+        let method = req.method();
+        debug!("Received {} request from uid {}", method, uid);
+        stats.record_client_request(connection);
+        if let Err(e) = config.method_policy.check(method) {
+            reqs.send(ClientResponse::error(e)).await?;
+            reqs.flush().await?;
+            continue;
+        }
+        if let Some((protocol_version, correlation_id, request_json)) =
+            req.args()
+        {
+            let ownership = &config.account_ownership;
+            let held =
+                if ownership.needs_held_accounts(method, uid, request_json) {
+                    held_accounts(
+                        &mut broker,
+                        protocol_version,
+                        correlation_id,
+                        uid,
+                    )
+                    .await
+                } else {
+                    Vec::new()
+                };
+            if let Err(e) =
+                ownership.check_request(method, uid, request_json, &held)
+            {
+                reqs.send(ClientResponse::error(e)).await?;
+                reqs.flush().await?;
+                continue;
+            }
+        }
+        // Callers are only given their own accounts, unless an
+        // administrator asks for every account on the device.
+        let mut scope = None;
+        if let Some((_, _, request_json)) = req.args_mut() {
+            if matches!(method, "getAccounts" | "getAccountsPage") {
+                let ownership = &config.account_ownership;
+                match ownership.take_scope(uid, request_json) {
+                    Ok((stripped, account_scope)) => {
+                        *request_json = stripped;
+                        scope = Some((ownership, account_scope));
+                    }
+                    Err(e) => {
+                        reqs.send(ClientResponse::error(e)).await?;
+                        reqs.flush().await?;
+                        continue;
+                    }
+                }
+            }
+        }
         let resp = match req {
             ClientRequest::acquireTokenInteractively(
                 protocol_version,
                 correlation_id,
                 request_json,
+            ) => match InteractionMode::take_from_request(&request_json) {
+                // Delegated flows present nothing in the session, so are
+                // not serialized with the user's other interactive flows.
+                Ok((request_json, InteractionMode::Delegated)) => {
+                    match broker.delegation() {
+                        Some(delegation) => delegation
+                            .start_delegated_flow(
+                                correlation_id,
+                                request_json,
+                                uid,
+                            )
+                            .await
+                            .map(|flow| flow.to_response()),
+                        None => Ok(delegation_unsupported()),
+                    }
+                }
+                Ok((request_json, InteractionMode::Local)) => {
+                    let mut prompter = SocketPrompter {
+                        reqs: &mut reqs,
+                        verification: &config.prompt_verification,
+                    };
+                    let flow = match broker.federation() {
+                        Some(federation) => federation
+                            .acquire_token_interactively_federated(
+                                protocol_version,
+                                correlation_id,
+                                request_json.clone(),
+                                uid,
+                                &mut prompter,
+                            ),
+                        None => broker.acquire_token_interactively_with_prompt(
+                            protocol_version,
+                            correlation_id,
+                            request_json.clone(),
+                            uid,
+                            &mut prompter,
+                        ),
+                    };
+                    match &interactive {
+                        Some(flows) => {
+                            flows.run(uid, &request_json, || flow).await
+                        }
+                        None => flow.await,
+                    }
+                }
+                Err(e) => Err(e),
+            },
+            ClientRequest::acquireTokenSilently(
+                protocol_version,
+                correlation_id,
+                request_json,
+            ) => {
+                let request = broker.acquire_token_silently(
+                    protocol_version,
+                    correlation_id,
+                    request_json.clone(),
+                    uid,
+                );
+                match &silent {
+                    Some(flights) => {
+                        flights.run(uid, &request_json, || request).await
+                    }
+                    None => request.await,
+                }
+            }
+            ClientRequest::getAccounts(
+                protocol_version,
+                correlation_id,
+                request_json,
             ) => {
                 broker
-                    .acquire_token_interactively(
+                    .get_accounts(
                         protocol_version,
                         correlation_id,
                         request_json,
                         uid,
                     )
-                    .await?
+                    .await
             }
-            ClientRequest::acquireTokenSilently(
+            ClientRequest::removeAccount(
                 protocol_version,
                 correlation_id,
                 request_json,
             ) => {
                 broker
-                    .acquire_token_silently(
+                    .remove_account(
                         protocol_version,
                         correlation_id,
                         request_json,
                         uid,
                     )
-                    .await?
+                    .await
             }
-            ClientRequest::getAccounts(
+            ClientRequest::acquirePrtSsoCookie(
                 protocol_version,
                 correlation_id,
                 request_json,
             ) => {
                 broker
-                    .get_accounts(
+                    .acquire_prt_sso_cookie(
                         protocol_version,
                         correlation_id,
                         request_json,
                         uid,
                     )
-                    .await?
+                    .await
             }
-            ClientRequest::removeAccount(
+            ClientRequest::generateSignedHttpRequest(
                 protocol_version,
                 correlation_id,
                 request_json,
             ) => {
                 broker
-                    .remove_account(
+                    .generate_signed_http_request(
                         protocol_version,
                         correlation_id,
                         request_json,
                         uid,
                     )
-                    .await?
+                    .await
             }
-            ClientRequest::acquirePrtSsoCookie(
+            ClientRequest::cancelInteractiveFlow(
                 protocol_version,
                 correlation_id,
                 request_json,
             ) => {
                 broker
-                    .acquire_prt_sso_cookie(
+                    .cancel_interactive_flow(
                         protocol_version,
                         correlation_id,
                         request_json,
                         uid,
                     )
-                    .await?
+                    .await
             }
-            ClientRequest::generateSignedHttpRequest(
+            ClientRequest::getLinuxBrokerVersion(
                 protocol_version,
                 correlation_id,
                 request_json,
             ) => {
                 broker
-                    .generate_signed_http_request(
+                    .get_linux_broker_version(
                         protocol_version,
                         correlation_id,
                         request_json,
                         uid,
                     )
-                    .await?
+                    .await
             }
-            ClientRequest::cancelInteractiveFlow(
+            ClientRequest::refreshPrt(
                 protocol_version,
                 correlation_id,
                 request_json,
             ) => {
+                // A failed refresh is reported to the session broker rather
+                // than dropping the connection, so it can fall back to the
+                // original response.
                 broker
-                    .cancel_interactive_flow(
+                    .refresh_prt(
                         protocol_version,
                         correlation_id,
                         request_json,
                         uid,
                     )
-                    .await?
+                    .await
+                    .or_else(|e| {
+                        error!("PRT refresh failed -> {}", e);
+                        Ok(json!({
+                            "error": {
+                                "status": "PrtRefreshFailed",
+                                "context": e.to_string(),
+                            }
+                        })
+                        .to_string())
+                    })
             }
-            ClientRequest::getLinuxBrokerVersion(
+            ClientRequest::removeAccounts(
                 protocol_version,
                 correlation_id,
                 request_json,
             ) => {
                 broker
-                    .get_linux_broker_version(
+                    .remove_accounts(
                         protocol_version,
                         correlation_id,
                         request_json,
                         uid,
                     )
-                    .await?
+                    .await
+            }
+            ClientRequest::getAccountsPage(
+                protocol_version,
+                correlation_id,
+                request_json,
+            ) => {
+                broker
+                    .get_accounts_page(
+                        protocol_version,
+                        correlation_id,
+                        request_json,
+                        uid,
+                    )
+                    .await
+            }
+            ClientRequest::getDeviceComplianceStatus(
+                _,
+                correlation_id,
+                request_json,
+            ) => match broker.compliance() {
+                Some(compliance) => compliance
+                    .get_device_compliance_status(
+                        correlation_id,
+                        request_json,
+                        uid,
+                    )
+                    .await
+                    .and_then(|status| {
+                        serde_json::to_string(&status)
+                            .map_err(BrokerError::unexpected)
+                    }),
+                None => Ok(compliance_unsupported()),
+            },
+            ClientRequest::getSshCert(_, correlation_id, request_json) => {
+                match broker.ssh_cert() {
+                    Some(ssh_cert) => match serde_json::from_str(&request_json)
+                    {
+                        Ok(request) => ssh_cert
+                            .get_ssh_cert(correlation_id, request, uid)
+                            .await
+                            .and_then(|cert| {
+                                serde_json::to_string(&cert)
+                                    .map_err(BrokerError::unexpected)
+                            }),
+                        Err(e) => Err(e.into()),
+                    },
+                    None => Ok(ssh_cert_unsupported()),
+                }
             }
+            ClientRequest::completeInteractiveFlow(
+                _,
+                correlation_id,
+                request_json,
+            ) => match broker.delegation() {
+                Some(delegation) => match serde_json::from_str(&request_json) {
+                    Ok(request) => {
+                        delegation
+                            .complete_interactive_flow(
+                                correlation_id,
+                                request,
+                                uid,
+                            )
+                            .await
+                    }
+                    Err(e) => Err(e.into()),
+                },
+                None => Ok(delegation_unsupported()),
+            },
+            ClientRequest::startDeviceCodeFlow(
+                _,
+                correlation_id,
+                request_json,
+            ) => match broker.device_code() {
+                Some(device_code) => device_code
+                    .start_device_code_flow(correlation_id, request_json, uid)
+                    .await
+                    .and_then(|code| {
+                        serde_json::to_string(&code)
+                            .map_err(BrokerError::unexpected)
+                    }),
+                None => Ok(device_code_unsupported()),
+            },
+            ClientRequest::pollDeviceCodeFlow(
+                _,
+                correlation_id,
+                request_json,
+            ) => match broker.device_code() {
+                Some(device_code) => {
+                    match serde_json::from_str::<DeviceCodeFlowRequest>(
+                        &request_json,
+                    ) {
+                        Ok(request) => device_code
+                            .poll_device_code_flow(
+                                correlation_id,
+                                request.flow_id,
+                                uid,
+                            )
+                            .await
+                            .and_then(|status| {
+                                serde_json::to_string(&status)
+                                    .map_err(BrokerError::unexpected)
+                            }),
+                        Err(e) => Err(e.into()),
+                    }
+                }
+                None => Ok(device_code_unsupported()),
+            },
+            ClientRequest::completeDeviceCodeFlow(
+                _,
+                correlation_id,
+                request_json,
+            ) => match broker.device_code() {
+                Some(device_code) => {
+                    match serde_json::from_str::<DeviceCodeFlowRequest>(
+                        &request_json,
+                    ) {
+                        Ok(request) => {
+                            device_code
+                                .complete_device_code_flow(
+                                    correlation_id,
+                                    request.flow_id,
+                                    uid,
+                                )
+                                .await
+                        }
+                        Err(e) => Err(e.into()),
+                    }
+                }
+                None => Ok(device_code_unsupported()),
+            },
+            ClientRequest::promptResponse(_) => {
+                error!("Received a prompt response with no pending prompt");
+                continue;
+            }
+            ClientRequest::negotiateFormat(offered) => {
+                let format = select_format(&offered, &config.wire_formats);
+                debug!("Using the {:?} wire format for uid {}", format, uid);
+                reqs.send(ClientResponse::formatSelected(format)).await?;
+                reqs.flush().await?;
+                reqs.codec_mut().format = format;
+                continue;
+            }
+            ClientRequest::federationResponse(_) => {
+                error!(
+                    "Received a federation response with no pending request"
+                );
+                continue;
+            }
+            ClientRequest::setLogLevel(change) => {
+                if uid == 0 {
+                    change.apply().map(|()| "{}".to_string())
+                } else {
+                    Err(BrokerError::InvalidRequest(
+                        "Only root may change the log level".to_string(),
+                    ))
+                }
+            }
+            ClientRequest::subscribe => {
+                let mut events = match broker.events() {
+                    Some(events) => events.subscribe(),
+                    None => {
+                        reqs.send(ClientResponse::error(
+                            BrokerError::InvalidRequest(
+                                "Account events are not published".to_string(),
+                            ),
+                        ))
+                        .await?;
+                        reqs.flush().await?;
+                        continue;
+                    }
+                };
+                // A subscription is not a request in progress.
+                drop(busy);
+                debug!("uid {} subscribed to account events", uid);
+                reqs.send(ClientResponse::subscribed).await?;
+                reqs.flush().await?;
+                return serve_subscription(
+                    &mut reqs,
+                    &mut events,
+                    uid,
+                    &shutdown,
+                )
+                .await;
+            }
+            ClientRequest::withCaller(..) => {
+                error!("Received a nested caller request");
+                continue;
+            }
+        };
+        let resp = match resp {
+            Ok(resp) => resp,
+            Err(e) => {
+                error!("{} failed for uid {} -> {}", method, uid, e);
+                reqs.send(ClientResponse::error(e)).await?;
+                reqs.flush().await?;
+                continue;
+            }
+        };
+        let resp = match scope {
+            Some((ownership, scope)) => {
+                ownership.scope_response(uid, scope, resp)
+            }
+            None => resp,
+        };
+        #[cfg(feature = "legacy-protocol")]
+        let resp = match shim {
+            Some(shim) => shim.downgrade_response(resp),
+            None => resp,
         };
-        reqs.send(resp).await?;
+        debug!("{} response: {}", method, summarize_response(&resp));
+        reqs.send(ClientResponse::result(resp)).await?;
         reqs.flush().await?;
         debug!("flushed response!");
     }
@@ -263,38 +1202,271 @@ where
     Ok(())
 }
 
+/* The accounts the broker holds for uid, against which the home account
+ * ids named in its requests are verified. Should they be unavailable, no
+ * id can be verified, and the request is refused.
+ */
+async fn held_accounts<T>(
+    broker: &mut T,
+    protocol_version: &str,
+    correlation_id: &str,
+    uid: uid_t,
+) -> Vec<Account>
+where
+    T: HimmelblauBroker + Send,
+{
+    let resp = broker
+        .get_accounts(
+            protocol_version.to_string(),
+            correlation_id.to_string(),
+            "{}".to_string(),
+            uid,
+        )
+        .await;
+    let accounts = resp.map_err(|e| e.to_string()).and_then(|resp| {
+        let mut resp: serde_json::Value =
+            serde_json::from_str(&resp).map_err(|e| e.to_string())?;
+        serde_json::from_value(resp["accounts"].take())
+            .map_err(|e| e.to_string())
+    });
+    accounts.unwrap_or_else(|e| {
+        warn!("Unable to list the accounts of uid {} -> {}", uid, e);
+        Vec::new()
+    })
+}
+
+/* A subscribed connection carries only events. Reading from it just notices
+ * the session broker going away.
+ */
+async fn serve_subscription(
+    reqs: &mut Framed<UnixStream, ClientCodec>,
+    events: &mut broadcast::Receiver<(uid_t, AccountEvent)>,
+    uid: uid_t,
+    shutdown: &CancellationToken,
+) -> Result<(), Box<dyn Error>> {
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => {
+                reqs.send(ClientResponse::shutdown).await?;
+                reqs.flush().await?;
+                break;
+            }
+            event = events.recv() => {
+                let event = match event {
+                    Ok((target, event)) if target == uid => event,
+                    Ok(_) => continue,
+                    Err(RecvError::Lagged(missed)) => {
+                        debug!("uid {} missed {} account events", uid, missed);
+                        AccountEvent::Resync
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                reqs.send(ClientResponse::event(event)).await?;
+                reqs.flush().await?;
+            }
+            frame = reqs.next() => match frame {
+                Some(Ok(_)) => {
+                    error!("Ignoring a request on a subscription from uid {}", uid);
+                }
+                _ => break,
+            },
+        }
+    }
+    debug!("Ending the account event subscription of uid {}", uid);
+    Ok(())
+}
+
+/// Serve `broker` on the Himmelblau socket. Each connection is handled by
+/// its own clone of the broker, so brokers sharing one instance between
+/// connections implement `SharedHimmelblauBroker` and pass an `Arc`.
 pub async fn himmelblau_broker_serve<T>(
     broker: T,
-    sock_path: &str,
-    mut broadcast_rx: Receiver<bool>,
+    sock_path: impl Into<SocketAddress>,
+    broadcast_rx: Receiver<bool>,
+) -> Result<JoinHandle<()>, Box<dyn Error>>
+where
+    T: HimmelblauBroker + Send + 'static + Clone,
+{
+    himmelblau_broker_serve_with_config(
+        broker,
+        sock_path,
+        broadcast_rx,
+        HimmelblauBrokerConfig::default(),
+    )
+    .await
+}
+
+pub async fn himmelblau_broker_serve_with_config<T>(
+    broker: T,
+    sock_path: impl Into<SocketAddress>,
+    broadcast_rx: Receiver<bool>,
+    config: HimmelblauBrokerConfig,
 ) -> Result<JoinHandle<()>, Box<dyn Error>>
 where
     T: HimmelblauBroker + Send + 'static + Clone,
 {
-    // Set the umask while we open the path for most clients.
-    let before = unsafe { umask(0) };
-    let listener = UnixListener::bind(sock_path).map_err(|e| {
-        error!("Failed to bind UNIX socket at {}", sock_path);
-        Box::new(e)
-    })?;
-    // Undo umask changes.
-    let _ = unsafe { umask(before) };
+    let stats = broker.stats().unwrap_or_default();
+    himmelblau_broker_serve_with_factory(
+        move |_| broker.clone(),
+        stats,
+        sock_path,
+        broadcast_rx,
+        config,
+    )
+    .await
+}
+
+/// The credentials of a client connected to the daemon socket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerCred {
+    pub uid: uid_t,
+    pub gid: gid_t,
+    pub pid: Option<pid_t>,
+}
+
+impl From<UCred> for PeerCred {
+    fn from(cred: UCred) -> Self {
+        PeerCred {
+            uid: cred.uid(),
+            gid: cred.gid(),
+            pid: cred.pid(),
+        }
+    }
+}
+
+/// Serve brokers built by `factory` for each connection to the daemon
+/// socket, from the credentials of the connecting client. This lets a
+/// daemon keep per-user state, such as a user's token cache, in the broker
+/// itself. The PAM socket only accepts connections from root, which are
+/// given a broker built from the connecting process's credentials. The
+/// factory runs as connections are accepted, so it should not block.
+/// `stats` are the counters published by the daemon's diagnostics, as
+/// `HimmelblauBroker::stats` returns for `himmelblau_broker_serve`.
+pub async fn himmelblau_broker_serve_with_factory<T, F>(
+    factory: F,
+    stats: Arc<BrokerStats>,
+    sock_path: impl Into<SocketAddress>,
+    mut broadcast_rx: Receiver<bool>,
+    config: HimmelblauBrokerConfig,
+) -> Result<JoinHandle<()>, Box<dyn Error>>
+where
+    T: HimmelblauBroker + Send + 'static,
+    F: Fn(PeerCred) -> T + Send + 'static,
+{
+    let interactive = config
+        .serialize_interactive_flows
+        .then(|| Arc::new(InteractiveFlows::default()));
+    let silent = config
+        .coalesce_silent_requests
+        .then(|| Arc::new(SingleFlight::default()));
+    let sock_path = sock_path.into().expand_specifiers()?;
+
+    let (listener, socket_file) = sock_path
+        .bind(&config.socket, config.socket_takeover)
+        .map_err(|e| {
+            error!("Failed to bind UNIX socket at {}", sock_path);
+            Box::new(e)
+        })?;
+    let (pam_listener, pam_socket_file) = match &config.pam_socket {
+        Some(address) => {
+            let address = address.expand_specifiers()?;
+            let permissions = SocketPermissions {
+                mode: 0o600,
+                ..config.socket.clone()
+            };
+            let (listener, socket_file) = address
+                .bind(&permissions, config.socket_takeover)
+                .map_err(|e| {
+                    error!("Failed to bind PAM socket at {}", address);
+                    Box::new(e)
+                })?;
+            (Some(listener), socket_file)
+        }
+        None => (None, None),
+    };
+
+    let tasks = TaskTracker::new();
+    let shutdown = CancellationToken::new();
+    let idle = Arc::new(IdleTimer::new(Duration::ZERO));
+    let wake = Arc::new(Notify::new());
+    let root = PeerCred {
+        uid: 0,
+        gid: 0,
+        pid: None,
+    };
+    if let Some(refresh) = &config.refresh {
+        tasks.spawn(run_refresh_scheduler(
+            factory(root),
+            refresh.clone(),
+            idle.clone(),
+            wake.clone(),
+            shutdown.clone(),
+        ));
+    }
+    if config.system_events {
+        tasks.spawn(watch_system_events(factory(root), wake, shutdown.clone()));
+    }
+    let state = ServerState {
+        config: config.clone(),
+        stats,
+        interactive,
+        silent,
+        idle,
+        shutdown: shutdown.clone(),
+    };
+    let mut connections: u64 = 0;
 
     Ok(tokio::spawn(async move {
         loop {
             tokio::select! {
                 _ = broadcast_rx.recv() => {
+                    for socket_file in [&socket_file, &pam_socket_file].into_iter().flatten() {
+                        socket_file.remove();
+                    }
                     break;
                 }
+                accept_res = pam::accept(pam_listener.as_ref()) => {
+                    match accept_res {
+                        Ok((socket, cred)) => {
+                            let broker_ref = factory(cred.into());
+                            tasks.spawn(async move {
+                                if let Err(e) = handle_pam_connection(socket, broker_ref).await {
+                                    error!("PAM connection error -> {:?}", e);
+                                }
+                            });
+                        }
+                        Err(e) => {
+                            error!("Error while handling PAM connection -> {:?}", e);
+                        }
+                    }
+                }
                 accept_res = listener.accept() => {
                     match accept_res {
                         Ok((socket, _addr)) => {
-                            let broker_ref = broker.clone();
-                            tokio::spawn(async move {
-                                if let Err(e) = handle_request(socket, broker_ref.clone()).await {
+                            let cred = match socket.peer_cred() {
+                                Ok(cred) => cred,
+                                Err(e) => {
+                                    error!("Unable to verify peer credentials: {:?}", e);
+                                    continue;
+                                }
+                            };
+                            connections += 1;
+                            let connection = connections;
+                            let uid = cred.uid();
+                            let pid = cred.pid().unwrap_or_default();
+                            // Everything logged for the connection carries its
+                            // id and peer.
+                            let span = info_span!("connection", id = connection, uid, pid);
+                            let broker_ref = factory(cred.into());
+                            let state_ref = state.clone();
+                            tasks.spawn(async move {
+                                let stats = state_ref.stats.clone();
+                                stats.client_connected(connection, uid, pid);
+                                if let Err(e) = handle_request(socket, uid, connection, broker_ref, state_ref).await {
                                     error!("handle_request error occurred; error = {:?}", e);
                                 }
-                            });
+                                stats.client_disconnected(connection);
+                            }.instrument(span));
                         }
                         Err(e) => {
                             error!("Error while handling connection -> {:?}", e);
@@ -303,5 +1475,22 @@ where
                 }
             }
         }
+
+        /* Stop accepting, then let the connections finish the requests in
+         * progress and notify their clients, which reconnect to the next
+         * daemon to listen. Requests still running after the shutdown
+         * timeout are abandoned.
+         */
+        drop(listener);
+        drop(pam_listener);
+        shutdown.cancel();
+        tasks.close();
+        let timeout = Duration::from_secs(config.shutdown_timeout);
+        if tokio::time::timeout(timeout, tasks.wait()).await.is_err() {
+            warn!(
+                "Abandoning {} connections with requests in progress",
+                tasks.len()
+            );
+        }
     }))
 }
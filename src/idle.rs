@@ -0,0 +1,86 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/// How long a broker exiting on idle keeps serving calls which reached it
+/// before its names were released.
+pub(crate) const IDLE_EXIT_GRACE: Duration = Duration::from_secs(1);
+
+/* Tracks the session broker's Broker1 calls for `ServeOptions::idle_exit`,
+ * and the daemon's requests for its refresh scheduler. A broker is idle
+ * once no call is in progress and none has started or finished for the
+ * timeout, so that a long interactive flow never counts as idle time.
+ */
+pub(crate) struct IdleTimer {
+    timeout: Duration,
+    active: AtomicUsize,
+    last: Mutex<Instant>,
+}
+
+/// Marks a call as in progress until dropped.
+pub(crate) struct Busy<'a>(&'a IdleTimer);
+
+impl Drop for Busy<'_> {
+    fn drop(&mut self) {
+        self.0.touch();
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl IdleTimer {
+    pub(crate) fn new(timeout: Duration) -> Self {
+        IdleTimer {
+            timeout,
+            active: AtomicUsize::new(0),
+            last: Mutex::new(Instant::now()),
+        }
+    }
+
+    pub(crate) fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn touch(&self) {
+        *self.last.lock().unwrap_or_else(PoisonError::into_inner) =
+            Instant::now();
+    }
+
+    pub(crate) fn busy(&self) -> Busy<'_> {
+        self.active.fetch_add(1, Ordering::Relaxed);
+        self.touch();
+        Busy(self)
+    }
+
+    /// Whether no call has been in progress for `period`.
+    pub(crate) fn idle_for(&self, period: Duration) -> bool {
+        self.active.load(Ordering::Relaxed) == 0
+            && self
+                .last
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .elapsed()
+                >= period
+    }
+
+    pub(crate) fn expired(&self) -> bool {
+        self.idle_for(self.timeout)
+    }
+}
@@ -0,0 +1,68 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::error::BrokerError;
+use crate::singleflight::SingleFlight;
+use libc::uid_t;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex, PoisonError};
+use tracing::debug;
+
+/* Interactive flows prompt the user, so two applications asking at once
+ * would otherwise race to show two prompts. Flows are run one at a time for
+ * each user, and a request identical to one already queued or running waits
+ * for that flow's result instead of starting its own.
+ */
+#[derive(Default)]
+pub(crate) struct InteractiveFlows {
+    users: Mutex<HashMap<uid_t, Arc<tokio::sync::Mutex<()>>>>,
+    flights: SingleFlight,
+}
+
+impl InteractiveFlows {
+    pub(crate) async fn run<F, Fut>(
+        &self,
+        uid: uid_t,
+        request_json: &str,
+        flow: F,
+    ) -> Result<String, BrokerError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<String, BrokerError>>,
+    {
+        self.flights
+            .run(uid, request_json, || async {
+                let user = self
+                    .users
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .entry(uid)
+                    .or_default()
+                    .clone();
+                let _turn = match user.try_lock() {
+                    Ok(turn) => turn,
+                    Err(_) => {
+                        debug!("Queueing interactive flow for uid {}", uid);
+                        user.lock().await
+                    }
+                };
+                flow().await
+            })
+            .await
+    }
+}
@@ -0,0 +1,344 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::async_session_broker::{
+    mount_async_session_broker, AsyncSessionBroker,
+};
+use crate::broker_ext::not_supported;
+use crate::caller::{BusType, CallerInfo, CallerResolver};
+use crate::device_broker::{mount_device_broker, DeviceBroker};
+use crate::device_session::DeviceSessions;
+use crate::diagnostics::BrokerStats;
+use crate::protocol_version::ProtocolVersion;
+use crate::serve::ServeOptions;
+use crate::session_broker::Dispatcher;
+use async_trait::async_trait;
+use dbus::channel::Sender;
+use dbus::Message;
+use dbus_crossroads as crossroads;
+use std::cell::RefCell;
+use std::sync::Arc;
+
+/* The introspection data is produced by registering the brokers' interfaces
+ * exactly as serving does, on a Crossroads instance which is never
+ * connected to a bus, with a broker that answers nothing, and handing it an
+ * Introspect call.
+ */
+struct Unserved;
+
+#[async_trait]
+impl AsyncSessionBroker for Unserved {
+    async fn acquire_token_interactively(
+        &self,
+        _protocol_version: String,
+        _correlation_id: String,
+        _request_json: String,
+        _caller: Option<CallerInfo>,
+    ) -> Result<String, dbus::MethodErr> {
+        Err(not_supported("acquireTokenInteractively"))
+    }
+    async fn acquire_token_silently(
+        &self,
+        _protocol_version: String,
+        _correlation_id: String,
+        _request_json: String,
+        _caller: Option<CallerInfo>,
+    ) -> Result<String, dbus::MethodErr> {
+        Err(not_supported("acquireTokenSilently"))
+    }
+    async fn get_accounts(
+        &self,
+        _protocol_version: String,
+        _correlation_id: String,
+        _request_json: String,
+        _caller: Option<CallerInfo>,
+    ) -> Result<String, dbus::MethodErr> {
+        Err(not_supported("getAccounts"))
+    }
+    async fn remove_account(
+        &self,
+        _protocol_version: String,
+        _correlation_id: String,
+        _request_json: String,
+        _caller: Option<CallerInfo>,
+    ) -> Result<String, dbus::MethodErr> {
+        Err(not_supported("removeAccount"))
+    }
+    async fn acquire_prt_sso_cookie(
+        &self,
+        _protocol_version: String,
+        _correlation_id: String,
+        _request_json: String,
+        _caller: Option<CallerInfo>,
+    ) -> Result<String, dbus::MethodErr> {
+        Err(not_supported("acquirePrtSsoCookie"))
+    }
+    async fn generate_signed_http_request(
+        &self,
+        _protocol_version: String,
+        _correlation_id: String,
+        _request_json: String,
+        _caller: Option<CallerInfo>,
+    ) -> Result<String, dbus::MethodErr> {
+        Err(not_supported("generateSignedHttpRequest"))
+    }
+    async fn cancel_interactive_flow(
+        &self,
+        _protocol_version: String,
+        _correlation_id: String,
+        _request_json: String,
+        _caller: Option<CallerInfo>,
+    ) -> Result<String, dbus::MethodErr> {
+        Err(not_supported("cancelInteractiveFlow"))
+    }
+    async fn get_linux_broker_version(
+        &self,
+        _protocol_version: String,
+        _correlation_id: String,
+        _request_json: String,
+        _caller: Option<CallerInfo>,
+    ) -> Result<String, dbus::MethodErr> {
+        Err(not_supported("getLinuxBrokerVersion"))
+    }
+}
+
+macro_rules! unserved_device_methods {
+    ($($name:ident => $method:literal),* $(,)?) => {
+        $(
+            fn $name(
+                &mut self,
+                _session_id: String,
+                _request_json: String,
+            ) -> Result<String, dbus::MethodErr> {
+                Err(not_supported($method))
+            }
+        )*
+    };
+}
+
+impl DeviceBroker for Unserved {
+    unserved_device_methods!(
+        sign => "sign",
+        generate_key_pair => "generateKeyPair",
+        load_key_pair => "loadKeyPair",
+        persist_key => "persistKey",
+        generate_derived_key => "generateDerivedKey",
+        delete_key => "deleteKey",
+        decrypt => "decrypt",
+        generate_pkcs10_cert_signing_request =>
+            "generatePkcs10CertSigningRequest",
+        asymmetric_key_exists => "asymmetricKeyExists",
+        asymmetric_key_with_thumbprint_exists =>
+            "asymmetricKeyWithThumbprintExists",
+        get_asymmetric_key_thumbprint => "getAsymmetricKeyThumbprint",
+        generate_asymmetric_key => "generateAsymmetricKey",
+        get_asymmetric_key_creation_date => "getAsymmetricKeyCreationDate",
+        clear_asymmetric_key => "clearAsymmetricKey",
+        get_request_confirmation => "getRequestConfirmation",
+        mint_signed_access_token => "mintSignedAccessToken",
+        mint_signed_http_request => "mintSignedHttpRequest",
+        make_http_request_with_client_tls => "makeHttpRequestWithClientTls",
+    );
+}
+
+/// Keeps the reply to the Introspect call in place of a bus connection.
+#[derive(Default)]
+struct Reply(RefCell<Option<Message>>);
+
+impl Sender for Reply {
+    fn send(&self, msg: Message) -> Result<u32, ()> {
+        *self.0.borrow_mut() = Some(msg);
+        Ok(0)
+    }
+}
+
+fn introspect(cr: &mut crossroads::Crossroads, path: &str) -> String {
+    let mut msg = Message::new_method_call(
+        "org.freedesktop.DBus",
+        path,
+        "org.freedesktop.DBus.Introspectable",
+        "Introspect",
+    )
+    .expect("Invalid object path");
+    msg.set_serial(1);
+    let reply = Reply::default();
+    if cr.handle_message(msg, &reply).is_err() {
+        return String::new();
+    }
+    let reply = reply.0.borrow_mut().take();
+    reply
+        .and_then(|reply| reply.read1::<String>().ok())
+        .unwrap_or_default()
+}
+
+/// The introspection XML of the session broker object,
+/// `/com/microsoft/identity/broker1`, with every interface an
+/// `AsyncSessionBroker` serves.
+pub fn session_broker_introspection() -> String {
+    let options = ServeOptions::default();
+    let dispatcher =
+        Arc::new(Dispatcher::new(Arc::new(BrokerStats::default()), &options));
+    let mut cr = crossroads::Crossroads::new();
+    mount_async_session_broker(
+        &mut cr,
+        Arc::new(Unserved),
+        &dispatcher,
+        &options,
+    );
+    introspect(&mut cr, "/com/microsoft/identity/broker1")
+}
+
+/// The introspection XML of the device broker object,
+/// `/com/microsoft/identity/devicebroker1`.
+pub fn device_broker_introspection() -> String {
+    let mut cr = crossroads::Crossroads::new();
+    mount_device_broker(
+        &mut cr,
+        Unserved,
+        Arc::new(DeviceSessions::new(CallerResolver::new(BusType::System))),
+        None,
+        &ServeOptions::default(),
+    );
+    introspect(&mut cr, "/com/microsoft/identity/devicebroker1")
+}
+
+/// Example gdbus invocations of every session broker method, and of
+/// reading its properties and watching its signals, as a shell script.
+pub fn session_broker_examples() -> String {
+    examples(
+        &session_broker_introspection(),
+        "--session",
+        "com.microsoft.identity.broker1",
+        "",
+    )
+}
+
+/// Example gdbus invocations of every device broker method, as a shell
+/// script.
+pub fn device_broker_examples() -> String {
+    examples(
+        &device_broker_introspection(),
+        "--system",
+        "com.microsoft.identity.DeviceBroker1",
+        "\
+# Session ids are only accepted from the connection which opened them, and
+# each gdbus call makes a connection of its own, so the calls taking a
+# session_id show their signatures rather than a working sequence.
+",
+    )
+}
+
+/// The value of the attribute `name` of the XML element on `line`.
+fn attribute<'a>(line: &'a str, name: &str) -> Option<&'a str> {
+    let start = line.find(&format!(" {}=\"", name))? + name.len() + 3;
+    let len = line[start..].find('"')?;
+    Some(&line[start..start + len])
+}
+
+/// A gdbus (GVariant text) placeholder for an argument.
+fn placeholder(name: &str, signature: &str) -> String {
+    match (name, signature) {
+        ("protocol_version", _) => {
+            format!("'{}'", ProtocolVersion::default())
+        }
+        ("correlation_id", _) => {
+            "\"$(cat /proc/sys/kernel/random/uuid)\"".to_string()
+        }
+        ("request_json", _) => "'{}'".to_string(),
+        ("session_id", _) => "\"$SESSION_ID\"".to_string(),
+        (_, "s") => "''".to_string(),
+        (_, "b") => "false".to_string(),
+        (_, "d") => "0.0".to_string(),
+        (_, s) if s.starts_with('a') => "[]".to_string(),
+        _ => "0".to_string(),
+    }
+}
+
+/* The introspection XML produced by Crossroads has one element per line,
+ * which is all the parsing here relies on.
+ */
+fn examples(xml: &str, bus: &str, dest: &str, note: &str) -> String {
+    let path = xml
+        .lines()
+        .find(|line| line.trim_start().starts_with("<node "))
+        .and_then(|line| attribute(line, "name"))
+        .unwrap_or("/");
+    let call = format!(
+        "gdbus call {} --dest {} \\\n    --object-path {}",
+        bus, dest, path
+    );
+    let mut out = format!(
+        "#!/bin/sh\n# Generated by himmelblau-broker-introspect. Do not edit.\n{}",
+        note
+    );
+    let mut interface = "";
+    let mut method: Option<(&str, Vec<String>)> = None;
+    let mut properties = Vec::new();
+    let mut signals = false;
+    for line in xml.lines().map(str::trim) {
+        if line.starts_with("<interface ") {
+            interface = attribute(line, "name").unwrap_or_default();
+        }
+        if interface.starts_with("org.freedesktop.DBus.") {
+            continue;
+        }
+        if line.starts_with("<method ") {
+            method = attribute(line, "name").map(|name| (name, Vec::new()));
+        } else if line.starts_with("<arg ") {
+            if let (Some((_, args)), Some("in")) =
+                (&mut method, attribute(line, "direction"))
+            {
+                args.push(placeholder(
+                    attribute(line, "name").unwrap_or_default(),
+                    attribute(line, "type").unwrap_or_default(),
+                ));
+            }
+        } else if line.starts_with("</method>") {
+            if let Some((name, args)) = method.take() {
+                out.push_str(&format!(
+                    "\n# {}.{}\n{} \\\n    --method {}.{}",
+                    interface, name, call, interface, name
+                ));
+                if !args.is_empty() {
+                    out.push_str(&format!(" \\\n    {}", args.join(" ")));
+                }
+                out.push('\n');
+            }
+        } else if line.starts_with("<property ")
+            && properties.last() != Some(&interface)
+        {
+            properties.push(interface);
+        } else if line.starts_with("<signal ") {
+            signals = true;
+        }
+    }
+    for interface in properties {
+        out.push_str(&format!(
+            "\n# The properties of {}\n{} \\\n    --method \
+             org.freedesktop.DBus.Properties.GetAll {}\n",
+            interface, call, interface
+        ));
+    }
+    if signals {
+        out.push_str(&format!(
+            "\n# Signals\ngdbus monitor {} --dest {} --object-path {}\n",
+            bus, dest, path
+        ));
+    }
+    out
+}
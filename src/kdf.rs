@@ -0,0 +1,81 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::error::Error;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// The label Entra ID uses when deriving session keys from the session key
+/// or device transport key.
+pub const SECURE_CONVERSATION_LABEL: &[u8] = b"AzureAD-SecureConversation";
+
+/// The fixed input data of SP 800-108: `label || 0x00 || context || [L]_32`,
+/// where L is the length of the derived key in bits.
+pub fn kdf_fixed_input(label: &[u8], context: &[u8], len: usize) -> Vec<u8> {
+    let mut fixed = Vec::with_capacity(label.len() + context.len() + 5);
+    fixed.extend_from_slice(label);
+    fixed.push(0);
+    fixed.extend_from_slice(context);
+    fixed.extend_from_slice(&((len * 8) as u32).to_be_bytes());
+    fixed
+}
+
+/* NIST SP 800-108 KDF in counter mode, with HMAC-SHA256 as the PRF and a
+ * 32-bit big-endian counter (starting at 1) placed before the fixed input
+ * data. Each block is HMAC(key, [i]_32 || fixed_input), and the output is
+ * the first `len` bytes of the concatenated blocks.
+ */
+pub fn kdf_counter_hmac_sha256(
+    key: &[u8],
+    fixed_input: &[u8],
+    len: usize,
+) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    // Both the counter and L are encoded as 32-bit integers.
+    if len
+        .checked_mul(8)
+        .and_then(|l| u32::try_from(l).ok())
+        .is_none()
+    {
+        return Err(format!("Cannot derive a key of {} bytes", len).into());
+    }
+    let mut out = Vec::with_capacity(len);
+    let mut counter: u32 = 1;
+    while out.len() < len {
+        let mut mac = HmacSha256::new_from_slice(key)
+            .map_err(|e| format!("Invalid key derivation key: {}", e))?;
+        mac.update(&counter.to_be_bytes());
+        mac.update(fixed_input);
+        let block = mac.finalize().into_bytes();
+        let take = (len - out.len()).min(block.len());
+        out.extend_from_slice(&block[..take]);
+        counter += 1;
+    }
+    Ok(out)
+}
+
+/// Derive a `len` byte key from `key` for the given label and context, as
+/// for DeviceBroker1's generateDerivedKey.
+pub fn derive_key(
+    key: &[u8],
+    label: &[u8],
+    context: &[u8],
+    len: usize,
+) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    kdf_counter_hmac_sha256(key, &kdf_fixed_input(label, context, len), len)
+}
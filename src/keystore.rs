@@ -0,0 +1,322 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::audit::key_thumbprint;
+use crate::enrollment::certificate_matches_key;
+use crate::thumbprint::{Thumbprint, ThumbprintAlgorithm};
+use openssl::pkey::{PKey, Private};
+use openssl::x509::X509;
+use std::error::Error;
+use std::fs::{self, DirBuilder, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{debug, info};
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Whether `thumbprint` identifies `key`, either by its public key or by the
+/// leaf certificate issued for it.
+fn identifies(
+    thumbprint: &str,
+    key: &PKey<Private>,
+    chain: Option<&Vec<X509>>,
+) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    for alg in [ThumbprintAlgorithm::Sha1, ThumbprintAlgorithm::Sha256] {
+        if Thumbprint::of_public_key(key, alg)?.matches(thumbprint) {
+            return Ok(true);
+        }
+        if let Some(leaf) = chain.and_then(|chain| chain.first()) {
+            if Thumbprint::of_certificate(leaf, alg)?.matches(thumbprint) {
+                return Ok(true);
+            }
+        }
+    }
+    Ok(false)
+}
+
+/* Keys are stored as PEM files named `<name>.key` in the store directory,
+ * with the certificate chain issued for a key beside it in `<name>.crt`.
+ * Files are written to a temporary name and renamed into place, so a key and
+ * its certificate are never seen half written.
+ *
+ * When a key is rotated, the outgoing key and chain are kept as
+ * `<name>.key.prev` and `<name>.crt.prev` until the time (in seconds since
+ * the epoch) recorded in `<name>.rollover`, so that requests already made
+ * with the old key can still be served.
+ */
+pub struct DeviceKeyStore {
+    dir: PathBuf,
+}
+
+impl DeviceKeyStore {
+    /// Open the store at `dir`, creating it (readable only by its owner)
+    /// if necessary.
+    pub fn open(dir: impl AsRef<Path>) -> io::Result<Self> {
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir.as_ref())?;
+        Ok(DeviceKeyStore {
+            dir: dir.as_ref().to_path_buf(),
+        })
+    }
+
+    fn path(&self, name: &str, ext: &str) -> io::Result<PathBuf> {
+        if name.is_empty() || name.starts_with('.') || name.contains('/') {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid key name {:?}", name),
+            ));
+        }
+        Ok(self.dir.join(format!("{}.{}", name, ext)))
+    }
+
+    fn write(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Option<Vec<u8>>> {
+        match fs::read(path) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn store_key(
+        &self,
+        name: &str,
+        key: &PKey<Private>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let path = self.path(name, "key")?;
+        self.write(&path, &key.private_key_to_pem_pkcs8()?)?;
+        debug!("Stored key {}", name);
+        Ok(())
+    }
+
+    pub fn load_key(
+        &self,
+        name: &str,
+    ) -> Result<Option<PKey<Private>>, Box<dyn Error + Send + Sync>> {
+        match self.read(&self.path(name, "key")?)? {
+            Some(pem) => Ok(Some(PKey::private_key_from_pem(&pem)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Store the certificate chain issued for the key `name`, leaf first.
+    /// The leaf must certify the stored key.
+    pub fn store_certificate_chain(
+        &self,
+        name: &str,
+        chain: &[X509],
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let key = self
+            .load_key(name)?
+            .ok_or_else(|| format!("No key named {}", name))?;
+        let leaf = chain.first().ok_or("Empty certificate chain")?;
+        if !certificate_matches_key(leaf, &key)? {
+            return Err(format!(
+                "The certificate was not issued for key {}",
+                name
+            )
+            .into());
+        }
+        let mut pem = Vec::new();
+        for cert in chain {
+            pem.extend_from_slice(&cert.to_pem()?);
+        }
+        self.write(&self.path(name, "crt")?, &pem)?;
+        debug!(
+            "Stored a chain of {} certificates for {}",
+            chain.len(),
+            name
+        );
+        Ok(())
+    }
+
+    pub fn load_certificate_chain(
+        &self,
+        name: &str,
+    ) -> Result<Option<Vec<X509>>, Box<dyn Error + Send + Sync>> {
+        match self.read(&self.path(name, "crt")?)? {
+            Some(pem) => Ok(Some(X509::stack_from_pem(&pem)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Replace the key `name` with `key`, keeping the outgoing key and its
+    /// certificate chain usable for `grace`. The new key needs its own
+    /// certificate, see `store_certificate_chain`.
+    pub fn rotate_key(
+        &self,
+        name: &str,
+        key: &PKey<Private>,
+        grace: Duration,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let current = self.path(name, "key")?;
+        if current.exists() {
+            fs::rename(&current, self.path(name, "key.prev")?)?;
+            match fs::rename(
+                self.path(name, "crt")?,
+                self.path(name, "crt.prev")?,
+            ) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    self.remove(&self.path(name, "crt.prev")?)?
+                }
+                res => res?,
+            }
+            let retires_at = now() + grace.as_secs();
+            self.write(
+                &self.path(name, "rollover")?,
+                retires_at.to_string().as_bytes(),
+            )?;
+            info!(
+                "Rotated key {}, the old key retires at {}",
+                name, retires_at
+            );
+        }
+        self.store_key(name, key)
+    }
+
+    /// The key `name` held before the last rotation, while its grace window
+    /// lasts. Once the window has passed, the old key is deleted.
+    pub fn previous_key(
+        &self,
+        name: &str,
+    ) -> Result<Option<PKey<Private>>, Box<dyn Error + Send + Sync>> {
+        let retires_at = match self.read(&self.path(name, "rollover")?)? {
+            Some(data) => String::from_utf8(data)?.trim().parse::<u64>()?,
+            None => return Ok(None),
+        };
+        if now() >= retires_at {
+            self.retire(name)?;
+            return Ok(None);
+        }
+        match self.read(&self.path(name, "key.prev")?)? {
+            Some(pem) => Ok(Some(PKey::private_key_from_pem(&pem)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn previous_certificate_chain(
+        &self,
+        name: &str,
+    ) -> Result<Option<Vec<X509>>, Box<dyn Error + Send + Sync>> {
+        match self.read(&self.path(name, "crt.prev")?)? {
+            Some(pem) => Ok(Some(X509::stack_from_pem(&pem)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Delete the key `name` held before the last rotation.
+    pub fn retire(&self, name: &str) -> io::Result<()> {
+        for ext in ["crt.prev", "key.prev", "rollover"] {
+            self.remove(&self.path(name, ext)?)?;
+        }
+        debug!("Retired the previous key of {}", name);
+        Ok(())
+    }
+
+    /// The thumbprints of the public halves of the current key `name` and,
+    /// during a rollover, the previous key, current first.
+    pub fn key_thumbprints(
+        &self,
+        name: &str,
+        alg: ThumbprintAlgorithm,
+    ) -> Result<Vec<Thumbprint>, Box<dyn Error + Send + Sync>> {
+        let mut thumbprints = Vec::new();
+        for key in [self.load_key(name)?, self.previous_key(name)?]
+            .into_iter()
+            .flatten()
+        {
+            thumbprints.push(Thumbprint::of_public_key(&key, alg)?);
+        }
+        Ok(thumbprints)
+    }
+
+    /// The current or (during a rollover) previous key `name` identified by
+    /// `thumbprint`, in any of the encodings accepted by `Thumbprint::parse`
+    /// and either of the key or of its certificate.
+    pub fn key_for_thumbprint(
+        &self,
+        name: &str,
+        thumbprint: &str,
+    ) -> Result<Option<PKey<Private>>, Box<dyn Error + Send + Sync>> {
+        if let Some(key) = self.load_key(name)? {
+            let chain = self.load_certificate_chain(name)?;
+            if identifies(thumbprint, &key, chain.as_ref())? {
+                return Ok(Some(key));
+            }
+        }
+        if let Some(key) = self.previous_key(name)? {
+            let chain = self.previous_certificate_chain(name)?;
+            if identifies(thumbprint, &key, chain.as_ref())? {
+                debug!("Using the previous key of {}", name);
+                return Ok(Some(key));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The key `name` to use for a sign or decrypt request: the one named
+    /// by the request's thumbprint, or the current key if none is given.
+    pub fn select_key(
+        &self,
+        name: &str,
+        request_json: &str,
+    ) -> Result<Option<PKey<Private>>, Box<dyn Error + Send + Sync>> {
+        match key_thumbprint(request_json) {
+            Some(thumbprint) => self.key_for_thumbprint(name, &thumbprint),
+            None => self.load_key(name),
+        }
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Remove the key `name` and its certificate chain, along with any key
+    /// retained from a rotation.
+    pub fn delete(&self, name: &str) -> io::Result<()> {
+        self.retire(name)?;
+        for ext in ["crt", "key"] {
+            self.remove(&self.path(name, ext)?)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,131 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::caller::CallerInfo;
+use crate::session_broker::MethodClass;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::Duration;
+use tracing::warn;
+
+/// The upper bounds, in milliseconds, of the buckets of a
+/// `LatencyHistogram`. Calls taking longer fall in a final, unbounded
+/// bucket.
+pub const LATENCY_BUCKETS: [u64; 10] =
+    [10, 50, 100, 250, 500, 1000, 2000, 5000, 10000, 30000];
+
+/// The distribution of the time taken to answer calls to one method, as
+/// published in the Diagnostics interface's LatencyHistograms property.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    /// Calls per bucket of `LATENCY_BUCKETS`, followed by the calls which
+    /// took longer than the last bound.
+    pub buckets: [u64; LATENCY_BUCKETS.len() + 1],
+    pub count: u64,
+    /// Milliseconds taken by all calls together.
+    pub total: u64,
+    /// Milliseconds taken by the slowest call.
+    pub max: u64,
+}
+
+impl LatencyHistogram {
+    pub fn record(&mut self, elapsed: Duration) {
+        let ms = u64::try_from(elapsed.as_millis()).unwrap_or(u64::MAX);
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| ms <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.total = self.total.saturating_add(ms);
+        self.max = self.max.max(ms);
+    }
+
+    /// Milliseconds taken by calls on average.
+    pub fn mean(&self) -> u64 {
+        self.total.checked_div(self.count).unwrap_or_default()
+    }
+}
+
+/* Interactive flows wait on the user, so by default only calls answered
+ * without the user are expected to be quick. A silent call taking seconds
+ * usually means the tenant (or the path to it) is slow, which would
+ * otherwise only be visible as applications hanging.
+ */
+/// Thresholds, in milliseconds, above which a Broker1 call is logged as
+/// slow, with its correlation id and caller. Methods are grouped as in
+/// `MethodTimeouts`, and `methods` sets thresholds for individual methods.
+/// Unset thresholds disable the logging.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct SlowRequestConfig {
+    pub interactive: Option<u64>,
+    pub silent: Option<u64>,
+    pub metadata: Option<u64>,
+    pub methods: HashMap<String, u64>,
+}
+
+impl Default for SlowRequestConfig {
+    fn default() -> Self {
+        SlowRequestConfig {
+            interactive: None,
+            silent: Some(2000),
+            metadata: Some(1000),
+            methods: HashMap::new(),
+        }
+    }
+}
+
+impl SlowRequestConfig {
+    pub fn threshold(&self, method: &str) -> Option<Duration> {
+        let ms = match self.methods.get(method) {
+            Some(ms) => Some(*ms),
+            None => match MethodClass::of(method) {
+                MethodClass::Interactive => self.interactive,
+                MethodClass::Silent => self.silent,
+                MethodClass::Metadata => self.metadata,
+            },
+        };
+        ms.map(Duration::from_millis)
+    }
+
+    /// Log a call to `method` which took `elapsed` if it was slow.
+    pub(crate) fn check(
+        &self,
+        method: &str,
+        correlation_id: &str,
+        caller: Option<&CallerInfo>,
+        elapsed: Duration,
+    ) {
+        let threshold = match self.threshold(method) {
+            Some(threshold) if elapsed > threshold => threshold,
+            _ => return,
+        };
+        warn!(
+            "Slow {} took {} ms (threshold {} ms), correlation id {}, \
+             caller uid {:?} pid {:?} exe {:?}",
+            method,
+            elapsed.as_millis(),
+            threshold.as_millis(),
+            correlation_id,
+            caller.and_then(|c| c.uid),
+            caller.and_then(|c| c.pid),
+            caller.and_then(CallerInfo::executable),
+        );
+    }
+}
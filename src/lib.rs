@@ -1,7 +1,177 @@
 mod himmelblau_broker;
 pub use himmelblau_broker::*;
+mod shared_broker;
+pub use shared_broker::SharedHimmelblauBroker;
 mod session_broker;
 pub use session_broker::*;
+mod async_session_broker;
+pub use async_session_broker::*;
 mod device_broker;
 pub use device_broker::*;
+mod broker_ext;
+pub use broker_ext::*;
 mod broker_proto;
+mod introspection;
+pub use introspection::*;
+mod protocol_version;
+pub use protocol_version::*;
+mod diagnostics;
+pub use diagnostics::*;
+mod prompt;
+pub use prompt::*;
+mod accounts;
+pub use accounts::*;
+mod account_manager;
+pub use account_manager::{
+    AccountManagerConfig, ACCOUNT_MANAGER_INTERFACE, MANAGE_ACCOUNTS_ACTION,
+};
+#[cfg(feature = "legacy-protocol")]
+mod compat;
+mod error;
+#[cfg(feature = "fuzzing")]
+#[doc(hidden)]
+pub mod fuzzing;
+pub use error::*;
+mod serve;
+pub use serve::*;
+mod caller;
+mod freedesktop;
+pub use caller::*;
+mod logind;
+pub use logind::{SessionInfo, SessionResolver};
+mod screen_lock;
+pub use screen_lock::{LockedAction, ScreenLockPolicy};
+mod policy;
+pub use policy::*;
+mod ownership;
+pub use ownership::*;
+mod redact;
+pub use redact::*;
+mod replay;
+#[cfg(feature = "test-util")]
+pub use replay::ReplayGuard;
+pub use replay::{ReplayAction, ReplayConfig};
+mod pool;
+pub use pool::PoolConfig;
+mod socket;
+pub use socket::{SocketAddress, SocketPermissions, SocketTakeover};
+mod audit;
+pub use audit::*;
+mod kdf;
+pub use kdf::*;
+mod enrollment;
+pub use enrollment::*;
+mod keystore;
+pub use keystore::DeviceKeyStore;
+mod thumbprint;
+pub use thumbprint::*;
+mod token_cache;
+pub use token_cache::TokenCache;
+mod name_watch;
+pub use name_watch::{NameAcquisitionPolicy, TakeoverAction};
+mod activation;
+pub use activation::*;
+mod shr;
+pub use shr::*;
+mod claims;
+pub use claims::ClaimsChallenge;
+mod pop;
+pub use pop::*;
+mod sso_cookie;
+pub use sso_cookie::*;
+mod peer;
+pub use peer::{machine_id, register_peer, PEER_INTERFACE};
+mod maintenance;
+pub use maintenance::*;
+mod latency;
+pub use latency::{LatencyHistogram, SlowRequestConfig, LATENCY_BUCKETS};
+mod capture;
+pub use capture::{read_capture, CaptureBundle, CapturedCall, CapturedError};
+mod cache_store;
+pub use cache_store::{CacheKey, CacheKeyStore, EncryptedFile};
+mod compliance;
+mod delegated_flow;
+mod device_code;
+mod pam;
+pub use pam::{PamBroker, PamEvent, PamRequest, PamResponse};
+mod refresh;
+mod ssh_cert;
+mod system_events;
+pub use compliance::{
+    ComplianceBroker, ComplianceState, DeviceComplianceStatus,
+};
+pub use delegated_flow::{
+    CompleteInteractiveFlowRequest, DelegatedFlow, DelegatedFlowBroker,
+    InteractionMode,
+};
+pub use device_code::{
+    DeviceCode, DeviceCodeBroker, DeviceCodeFlowRequest, DeviceCodeState,
+    DeviceCodeStatus,
+};
+pub use refresh::{
+    ArtifactKind, ArtifactRefresh, CachedArtifact, RefreshBroker,
+    RefreshSchedulerConfig,
+};
+pub use ssh_cert::{
+    SshCertBroker, SshCertRequest, SshCertificate, SSH_CERT_SCOPE,
+};
+pub use system_events::SystemEvent;
+mod events;
+pub use events::*;
+mod failover;
+pub use failover::FailoverConfig;
+mod hardening;
+pub use hardening::{HardeningConfig, MAX_BLOCKING_ERROR_DELAY};
+mod sandbox;
+pub use sandbox::Sandbox;
+#[cfg(feature = "hardening")]
+mod seccomp;
+#[cfg(feature = "hardening")]
+pub use seccomp::{SeccompAction, SeccompFilter};
+mod federation;
+mod token_broker;
+pub use federation::*;
+pub use token_broker::TOKEN_BROKER_INTERFACE;
+mod client;
+pub use client::{Broker1Client, DeviceBroker1Client};
+mod transform;
+pub use transform::ResponseTransformer;
+mod wire;
+pub use wire::WireFormatKind;
+#[cfg(feature = "logging")]
+mod logging;
+#[cfg(feature = "logging")]
+pub use logging::*;
+#[cfg(feature = "test-util")]
+mod fault;
+#[cfg(feature = "test-util")]
+pub use fault::{
+    serve_fault_proxy, Fault, FaultConfig, FaultInjector, MALFORMED_RESPONSE,
+};
+#[cfg(feature = "test-util")]
+mod mock;
+#[cfg(feature = "test-util")]
+pub use mock::{replay_capture, MockCall, MockSessionBroker, ReplayedCall};
+#[cfg(feature = "native-messaging")]
+mod native_messaging;
+#[cfg(feature = "native-messaging")]
+pub use native_messaging::native_messaging_serve;
+#[cfg(feature = "proxy")]
+mod proxy;
+#[cfg(feature = "proxy")]
+pub use proxy::*;
+#[cfg(feature = "varlink")]
+mod varlink;
+#[cfg(feature = "varlink")]
+pub use varlink::VARLINK_INTERFACE;
+mod device_session;
+#[cfg(feature = "test-util")]
+pub use device_session::DeviceSessions;
+mod fdpass;
+mod idle;
+mod interactive;
+mod schema;
+mod singleflight;
+#[cfg(feature = "wsl")]
+mod wsl;
+pub use schema::*;
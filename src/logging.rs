@@ -0,0 +1,359 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::ffi::CString;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::{Mutex, OnceLock, PoisonError};
+use tracing::{info, Level, Metadata};
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::Layered;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{reload, EnvFilter, Layer, Registry};
+
+type InitError = Box<dyn Error + Send + Sync>;
+
+/// Where `init_logging` sends log records.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogBackend {
+    /// Formatted lines on stderr.
+    #[default]
+    Stderr,
+    /// The systemd journal. The fields of each event and its spans (such
+    /// as a request's correlation id) are kept as journal fields.
+    Journald,
+    /// The local syslog daemon, through syslog(3).
+    Syslog,
+    /// Files which are rotated as they age.
+    File(LogFile),
+}
+
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    Hourly,
+    #[default]
+    Daily,
+    Never,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LogFile {
+    pub directory: PathBuf,
+    /// The file name, before the date and `.log` suffix.
+    pub prefix: String,
+    #[serde(default)]
+    pub rotation: LogRotation,
+    /// How many rotated files to keep. Unset keeps them all.
+    #[serde(default)]
+    pub max_files: Option<usize>,
+}
+
+/// Logging options, in the form they take in a configuration file.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct LoggingConfig {
+    pub backend: LogBackend,
+    /// The level logged by modules without a level of their own.
+    pub level: String,
+    /// Levels for individual modules, such as
+    /// `"identity_dbus_broker::pam" = "debug"`.
+    pub modules: BTreeMap<String, String>,
+    /// The name records are logged under in syslog and the journal.
+    /// Defaults to the name of the program.
+    pub identifier: Option<String>,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            backend: LogBackend::default(),
+            level: "info".to_string(),
+            modules: BTreeMap::new(),
+            identifier: None,
+        }
+    }
+}
+
+impl LoggingConfig {
+    /// The configured levels. RUST_LOG, when set, takes their place, so
+    /// that a single run can be debugged without editing configuration.
+    pub fn env_filter(&self) -> Result<EnvFilter, InitError> {
+        self.levels().filter()
+    }
+
+    fn levels(&self) -> Levels {
+        match std::env::var(EnvFilter::DEFAULT_ENV) {
+            Ok(directives) if !directives.is_empty() => Levels {
+                default: directives,
+                modules: BTreeMap::new(),
+            },
+            _ => Levels {
+                default: self.level.clone(),
+                modules: self.modules.clone(),
+            },
+        }
+    }
+
+    fn identifier(&self) -> String {
+        self.identifier.clone().unwrap_or_else(|| {
+            std::env::args()
+                .next()
+                .as_deref()
+                .and_then(|arg0| arg0.rsplit('/').next())
+                .unwrap_or("identity_dbus_broker")
+                .to_string()
+        })
+    }
+}
+
+/* The filter is rebuilt from its directives whenever a level is changed at
+ * runtime (see `set_log_level`), since an EnvFilter cannot be edited.
+ */
+#[derive(Clone)]
+struct Levels {
+    default: String,
+    modules: BTreeMap<String, String>,
+}
+
+impl Levels {
+    fn filter(&self) -> Result<EnvFilter, InitError> {
+        let mut filter = EnvFilter::try_new(&self.default)?;
+        for (module, level) in &self.modules {
+            filter =
+                filter.add_directive(format!("{}={}", module, level).parse()?);
+        }
+        Ok(filter)
+    }
+}
+
+type FilterHandle = reload::Handle<EnvFilter, Registry>;
+
+static RELOAD: OnceLock<(Mutex<Levels>, FilterHandle)> = OnceLock::new();
+
+fn install<L>(config: &LoggingConfig, layer: L) -> Result<(), InitError>
+where
+    L: Layer<Layered<reload::Layer<EnvFilter, Registry>, Registry>>
+        + Send
+        + Sync
+        + 'static,
+{
+    let levels = config.levels();
+    let (filter, handle) = reload::Layer::new(levels.filter()?);
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(layer)
+        .try_init()?;
+    let _ = RELOAD.set((Mutex::new(levels), handle));
+    Ok(())
+}
+
+/// Change the level logged by `module`, or by every module without a level
+/// of its own when `module` is None, in the subscriber installed by
+/// `init_logging`.
+pub fn set_log_level(level: &str, module: Option<&str>) -> Result<(), String> {
+    let level: LevelFilter = level
+        .parse()
+        .map_err(|_| format!("Unknown log level {}", level))?;
+    let (levels, handle) = RELOAD
+        .get()
+        .ok_or("Logging was not set up by init_logging")?;
+    let mut levels = levels.lock().unwrap_or_else(PoisonError::into_inner);
+    let mut updated = levels.clone();
+    match module {
+        Some(module) => {
+            updated
+                .modules
+                .insert(module.to_string(), level.to_string());
+        }
+        None => updated.default = level.to_string(),
+    }
+    let filter = updated
+        .filter()
+        .map_err(|e| format!("Invalid log filter: {}", e))?;
+    handle.reload(filter).map_err(|e| e.to_string())?;
+    *levels = updated;
+    info!(
+        "Log level of {} set to {}",
+        module.unwrap_or("all modules"),
+        level
+    );
+    Ok(())
+}
+
+/// Returned by `init_logging`. Records written to a file are buffered
+/// until this is dropped, so it should be kept until the program exits.
+#[must_use]
+pub struct LoggingGuard {
+    _file: Option<WorkerGuard>,
+}
+
+/// Install a global tracing subscriber for `config.backend`.
+pub fn init_logging(config: &LoggingConfig) -> Result<LoggingGuard, InitError> {
+    let file = match &config.backend {
+        LogBackend::Stderr => {
+            init_stderr(config)?;
+            None
+        }
+        LogBackend::Journald => {
+            init_journald(config)?;
+            None
+        }
+        LogBackend::Syslog => {
+            init_syslog(config)?;
+            None
+        }
+        LogBackend::File(file) => Some(init_file(config, file)?),
+    };
+    Ok(LoggingGuard { _file: file })
+}
+
+pub fn init_stderr(config: &LoggingConfig) -> Result<(), InitError> {
+    install(
+        config,
+        tracing_subscriber::fmt::layer().with_writer(io::stderr),
+    )
+}
+
+pub fn init_journald(config: &LoggingConfig) -> Result<(), InitError> {
+    let journald =
+        tracing_journald::layer()?.with_syslog_identifier(config.identifier());
+    tracing_subscriber::registry()
+        .with(config.env_filter()?)
+        .with(journald)
+        .try_init()?;
+    Ok(())
+}
+
+/* syslog(3) timestamps and prioritises each record itself, so only the
+ * target and message are formatted.
+ */
+pub fn init_syslog(config: &LoggingConfig) -> Result<(), InitError> {
+    static IDENT: OnceLock<CString> = OnceLock::new();
+    let ident = IDENT.get_or_init(|| {
+        CString::new(config.identifier().replace('\0', "")).unwrap_or_default()
+    });
+    // openlog keeps the identifier pointer, hence the static.
+    unsafe {
+        libc::openlog(ident.as_ptr(), libc::LOG_PID, libc::LOG_DAEMON);
+    }
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(Syslog)
+        .with_ansi(false)
+        .with_level(false)
+        .without_time();
+    install(config, layer)
+}
+
+/// Log to `file`, rotating it as configured. Records are written by a
+/// background thread until the returned guard is dropped.
+pub fn init_file(
+    config: &LoggingConfig,
+    file: &LogFile,
+) -> Result<WorkerGuard, InitError> {
+    let rotation = match file.rotation {
+        LogRotation::Hourly => Rotation::HOURLY,
+        LogRotation::Daily => Rotation::DAILY,
+        LogRotation::Never => Rotation::NEVER,
+    };
+    let mut appender = RollingFileAppender::builder()
+        .rotation(rotation)
+        .filename_prefix(&file.prefix)
+        .filename_suffix("log");
+    if let Some(max_files) = file.max_files {
+        appender = appender.max_log_files(max_files);
+    }
+    let (writer, guard) =
+        tracing_appender::non_blocking(appender.build(&file.directory)?);
+    install(
+        config,
+        tracing_subscriber::fmt::layer()
+            .with_writer(writer)
+            .with_ansi(false),
+    )?;
+    Ok(guard)
+}
+
+struct Syslog;
+
+/// One formatted record, sent to syslog(3) when dropped.
+struct SyslogRecord {
+    priority: libc::c_int,
+    buf: Vec<u8>,
+}
+
+impl<'a> MakeWriter<'a> for Syslog {
+    type Writer = SyslogRecord;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        SyslogRecord {
+            priority: libc::LOG_INFO,
+            buf: Vec::new(),
+        }
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        let priority = match *meta.level() {
+            Level::ERROR => libc::LOG_ERR,
+            Level::WARN => libc::LOG_WARNING,
+            Level::INFO => libc::LOG_INFO,
+            Level::DEBUG | Level::TRACE => libc::LOG_DEBUG,
+        };
+        SyslogRecord {
+            priority,
+            buf: Vec::new(),
+        }
+    }
+}
+
+impl Write for SyslogRecord {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for SyslogRecord {
+    fn drop(&mut self) {
+        self.buf.retain(|b| *b != 0);
+        while self.buf.last() == Some(&b'\n') {
+            self.buf.pop();
+        }
+        if self.buf.is_empty() {
+            return;
+        }
+        if let Ok(msg) = CString::new(std::mem::take(&mut self.buf)) {
+            unsafe {
+                libc::syslog(self.priority, c"%s".as_ptr(), msg.as_ptr());
+            }
+        }
+    }
+}
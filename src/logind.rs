@@ -0,0 +1,159 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+use dbus::arg::{prop_cast, PropMap, RefArg};
+use dbus::blocking::stdintf::org_freedesktop_dbus::Properties;
+use dbus::blocking::Connection;
+use dbus::Path;
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::debug;
+
+const LOGIND: &str = "org.freedesktop.login1";
+const LOGIND_PATH: &str = "/org/freedesktop/login1";
+const LOGIND_MANAGER: &str = "org.freedesktop.login1.Manager";
+const LOGIND_SESSION: &str = "org.freedesktop.login1.Session";
+const LOGIND_USER: &str = "org.freedesktop.login1.User";
+
+/// The logind session of a D-Bus caller, so that the daemon can open
+/// interactive flows in the right graphical session when a user has several.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub struct SessionInfo {
+    /// The session id, as in XDG_SESSION_ID.
+    pub id: String,
+    /// The seat the session is attached to (e.g. `seat0`).
+    pub seat: Option<String>,
+    /// The X11 display of the session (e.g. `:0`).
+    pub display: Option<String>,
+    /// The session type, e.g. `x11`, `wayland` or `tty`.
+    pub session_type: Option<String>,
+    /// Whether the session is a remote login.
+    pub remote: bool,
+    /// Whether the session's screen is locked (see `ScreenLockPolicy`).
+    pub locked: bool,
+}
+
+/* Resolves the logind session of a process over the system bus, with a
+ * connection opened lazily on the first lookup. Callers which are not part
+ * of a session, such as applications started as systemd user services, are
+ * placed in their user's display session, which logind picks as the user's
+ * primary graphical session.
+ */
+pub struct SessionResolver {
+    conn: Mutex<Option<Connection>>,
+}
+
+impl Default for SessionResolver {
+    fn default() -> Self {
+        SessionResolver::new()
+    }
+}
+
+impl SessionResolver {
+    pub fn new() -> Self {
+        SessionResolver {
+            conn: Mutex::new(None),
+        }
+    }
+
+    /// The session of process `pid`, or else the display session of `uid`.
+    pub fn resolve(
+        &self,
+        pid: u32,
+        uid: Option<u32>,
+    ) -> Result<Option<SessionInfo>, dbus::Error> {
+        let mut conn = self.conn.lock().map_err(|_| {
+            dbus::Error::new_failed("Session resolver poisoned")
+        })?;
+        if conn.is_none() {
+            *conn = Some(Connection::new_system()?);
+        }
+        let c = conn
+            .as_ref()
+            .ok_or_else(|| dbus::Error::new_failed("No bus connection"))?;
+        let manager = c.with_proxy(LOGIND, LOGIND_PATH, Duration::from_secs(5));
+        let path = match manager.method_call::<(Path,), _, _, _>(
+            LOGIND_MANAGER,
+            "GetSessionByPID",
+            (pid,),
+        ) {
+            Ok((path,)) => path,
+            Err(e) => {
+                debug!("Process {} is not in a session -> {}", pid, e);
+                match uid {
+                    Some(uid) => match display_session(c, uid)? {
+                        Some(path) => path,
+                        None => return Ok(None),
+                    },
+                    None => return Ok(None),
+                }
+            }
+        };
+        let props = c
+            .with_proxy(LOGIND, path, Duration::from_secs(5))
+            .get_all(LOGIND_SESSION)?;
+        let session = session_info(&props);
+        debug!("Process {} is in session {:?}", pid, session);
+        Ok(session)
+    }
+}
+
+/// The object path of the display session of `uid`, if it has one.
+fn display_session(
+    c: &Connection,
+    uid: u32,
+) -> Result<Option<Path<'static>>, dbus::Error> {
+    let manager = c.with_proxy(LOGIND, LOGIND_PATH, Duration::from_secs(5));
+    let (user,): (Path,) =
+        match manager.method_call(LOGIND_MANAGER, "GetUser", (uid,)) {
+            Ok(user) => user,
+            Err(e) => {
+                debug!("User {} has no sessions -> {}", uid, e);
+                return Ok(None);
+            }
+        };
+    let (_, path): (String, Path) = c
+        .with_proxy(LOGIND, user, Duration::from_secs(5))
+        .get(LOGIND_USER, "Display")?;
+    /* logind reports "/" when the user has no graphical session */
+    Ok((&*path != "/").then_some(path))
+}
+
+fn session_info(props: &PropMap) -> Option<SessionInfo> {
+    let string = |name: &str| {
+        prop_cast::<String>(props, name)
+            .filter(|s| !s.is_empty())
+            .cloned()
+    };
+    let flag =
+        |name: &str| prop_cast::<bool>(props, name).copied().unwrap_or(false);
+    /* Seat is a (so) of the seat's name and object path */
+    let seat = props
+        .get("Seat")
+        .and_then(|seat| seat.0.as_iter()?.next()?.as_str().map(String::from))
+        .filter(|seat| !seat.is_empty());
+    Some(SessionInfo {
+        id: string("Id")?,
+        seat,
+        display: string("Display"),
+        session_type: string("Type"),
+        remote: flag("Remote"),
+        locked: flag("LockedHint"),
+    })
+}
@@ -0,0 +1,157 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::error::BrokerError;
+use crate::policy::TOKEN_METHODS;
+use dbus_crossroads as crossroads;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex, PoisonError};
+use tracing::info;
+
+pub const MAINTENANCE_INTERFACE: &str = "org.himmelblau.Broker1.Maintenance";
+
+/// What callers are told while the broker is in maintenance.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct MaintenanceNotice {
+    /// Seconds after which callers should try again.
+    pub retry_after: u64,
+    /// Why the broker is unavailable, such as a tenant migration.
+    pub reason: String,
+}
+
+impl Default for MaintenanceNotice {
+    fn default() -> Self {
+        MaintenanceNotice {
+            retry_after: 300,
+            reason: String::new(),
+        }
+    }
+}
+
+/* During migrations or key rollover windows, forwarding token requests to
+ * the daemon would only produce confusing failures (or tokens minted with
+ * a key about to be replaced). In maintenance, the session broker answers
+ * the TOKEN_METHODS itself, with BrokerError::Maintenance, while account
+ * listing and the other metadata methods keep working.
+ */
+/// A switch putting a session broker into maintenance (see
+/// `ServeOptions::maintenance`). Clones share the same state, so the
+/// embedding application can keep one to toggle maintenance, for example
+/// from a signal handler.
+#[derive(Debug, Clone, Default)]
+pub struct MaintenanceMode {
+    notice: Arc<Mutex<Option<MaintenanceNotice>>>,
+}
+
+impl MaintenanceMode {
+    pub fn new() -> Self {
+        MaintenanceMode::default()
+    }
+
+    pub fn enter(&self, notice: MaintenanceNotice) {
+        info!(
+            "Entering maintenance, retry after {} seconds: {}",
+            notice.retry_after, notice.reason
+        );
+        *self.notice.lock().unwrap_or_else(PoisonError::into_inner) =
+            Some(notice);
+    }
+
+    pub fn leave(&self) {
+        if self
+            .notice
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take()
+            .is_some()
+        {
+            info!("Leaving maintenance");
+        }
+    }
+
+    /// The notice given to callers, while in maintenance.
+    pub fn notice(&self) -> Option<MaintenanceNotice> {
+        self.notice
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Refuse `method` if it is a token method and the broker is in
+    /// maintenance.
+    pub fn check(&self, method: &str) -> Result<(), BrokerError> {
+        if !TOKEN_METHODS.contains(&method) {
+            return Ok(());
+        }
+        match self.notice() {
+            Some(notice) => Err(BrokerError::Maintenance {
+                retry_after: notice.retry_after,
+                reason: notice.reason,
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Register the Maintenance interface, with which an administrator can put
+/// the broker into maintenance and take it out again.
+pub fn register_maintenance<T>(
+    cr: &mut crossroads::Crossroads,
+    mode: MaintenanceMode,
+) -> crossroads::IfaceToken<T>
+where
+    T: Send + 'static,
+{
+    cr.register(MAINTENANCE_INTERFACE, move |b| {
+        let m = mode.clone();
+        b.method(
+            "Enter",
+            ("retry_after", "reason"),
+            (),
+            move |_, _: &mut T, (retry_after, reason): (u64, String)| {
+                m.enter(MaintenanceNotice {
+                    retry_after,
+                    reason,
+                });
+                Ok(())
+            },
+        );
+        let m = mode.clone();
+        b.method("Leave", (), (), move |_, _: &mut T, ()| {
+            m.leave();
+            Ok(())
+        });
+        let m = mode.clone();
+        b.property("Active")
+            .emits_changed_false()
+            .get(move |_, _: &mut T| Ok(m.notice().is_some()));
+        let m = mode.clone();
+        b.property("RetryAfter").emits_changed_false().get(
+            move |_, _: &mut T| {
+                Ok(m.notice().map(|n| n.retry_after).unwrap_or_default())
+            },
+        );
+        let m = mode.clone();
+        b.property("Reason")
+            .emits_changed_false()
+            .get(move |_, _: &mut T| {
+                Ok(m.notice().map(|n| n.reason).unwrap_or_default())
+            });
+    })
+}
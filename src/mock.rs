@@ -0,0 +1,380 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::async_session_broker::{call_by_name, AsyncSessionBroker};
+use crate::broker_ext::BROKER1_METHODS;
+use crate::caller::CallerInfo;
+use crate::capture::{CapturedCall, CapturedError};
+use crate::diagnostics::BrokerStats;
+use crate::fault::{
+    truncate_response, Fault, FaultConfig, FaultInjector, MALFORMED_RESPONSE,
+};
+use crate::redact::redact_json;
+use crate::session_broker::PortalRequest;
+use async_trait::async_trait;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, PoisonError};
+
+/// A call received by a `MockSessionBroker`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MockCall {
+    pub method: String,
+    pub protocol_version: String,
+    pub correlation_id: String,
+    pub request_json: String,
+    pub caller: Option<CallerInfo>,
+    /// The fault injected into the call (see `inject_faults`), if any.
+    pub fault: Option<Fault>,
+}
+
+/* Responses are queued for each method and answered in order, so a test
+ * (or a replayed capture) can script a sequence such as an
+ * interaction_required failure followed by a successful interactive flow.
+ */
+/// An `AsyncSessionBroker` which answers each Broker1 method with responses
+/// queued by the test, recording every call it receives. Calls to a method
+/// with nothing queued fail with org.freedesktop.DBus.Error.Failed.
+#[derive(Default)]
+pub struct MockSessionBroker {
+    responses: Mutex<HashMap<String, VecDeque<Result<String, CapturedError>>>>,
+    calls: Mutex<Vec<MockCall>>,
+    faults: Mutex<Option<Arc<FaultInjector>>>,
+}
+
+impl MockSessionBroker {
+    pub fn new() -> Self {
+        MockSessionBroker::default()
+    }
+
+    /// A mock answering with the outcomes recorded in a capture bundle (see
+    /// `read_capture`), in the order they were captured.
+    pub fn from_capture(calls: &[CapturedCall]) -> Self {
+        let mock = MockSessionBroker::new();
+        for call in calls {
+            mock.push(&call.method, call.result());
+        }
+        mock
+    }
+
+    /// Queue a successful response to `method`.
+    pub fn respond(&self, method: &str, response_json: impl Into<String>) {
+        self.push(method, Ok(response_json.into()));
+    }
+
+    /// Queue a failure of `method` with the D-Bus error `name`.
+    pub fn fail(&self, method: &str, name: &str, message: &str) {
+        self.push(
+            method,
+            Err(CapturedError {
+                name: name.to_string(),
+                message: message.to_string(),
+            }),
+        );
+    }
+
+    fn push(&self, method: &str, res: Result<String, CapturedError>) {
+        self.responses
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(method.to_string())
+            .or_default()
+            .push_back(res);
+    }
+
+    /* A faulted call still consumes its queued response, as a broker which
+     * misbehaves has usually done the work the call asked for.
+     */
+    /// Misbehave as `config` describes from the next call on: stall calls,
+    /// answer with malformed or truncated responses, or fail them with
+    /// org.freedesktop.DBus.Error.NoReply as if the broker had dropped off
+    /// the bus.
+    pub fn inject_faults(&self, config: FaultConfig) {
+        *self.faults.lock().unwrap_or_else(PoisonError::into_inner) =
+            Some(Arc::new(FaultInjector::new(config)));
+    }
+
+    /// The calls received so far, in order.
+    pub fn calls(&self) -> Vec<MockCall> {
+        self.calls
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    async fn answer(
+        &self,
+        method: &str,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        caller: Option<CallerInfo>,
+    ) -> Result<String, dbus::MethodErr> {
+        let faults = self
+            .faults
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+        let fault = faults.as_ref().and_then(|faults| faults.next_fault());
+        self.calls
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(MockCall {
+                method: method.to_string(),
+                protocol_version,
+                correlation_id,
+                request_json,
+                caller,
+                fault,
+            });
+        let next = self
+            .responses
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get_mut(method)
+            .and_then(VecDeque::pop_front);
+        let res = match next {
+            Some(Ok(resp)) => Ok(resp),
+            Some(Err(e)) => Err(dbus::MethodErr::from((e.name, e.message))),
+            None => Err(dbus::MethodErr::failed(&format!(
+                "No mock response queued for {}",
+                method
+            ))),
+        };
+        match (fault, faults) {
+            (Some(Fault::Timeout), Some(faults)) => {
+                tokio::time::sleep(faults.stall()).await;
+                res
+            }
+            (Some(Fault::Malformed), _) => Ok(MALFORMED_RESPONSE.to_string()),
+            (Some(Fault::PartialWrite), _) => {
+                res.map(|resp| truncate_response(&resp))
+            }
+            (Some(Fault::Reset), _) => Err(dbus::MethodErr::from((
+                "org.freedesktop.DBus.Error.NoReply",
+                "Message recipient disconnected from message bus without \
+                 replying",
+            ))),
+            _ => res,
+        }
+    }
+}
+
+#[async_trait]
+impl AsyncSessionBroker for MockSessionBroker {
+    async fn acquire_token_interactively(
+        &self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        caller: Option<CallerInfo>,
+    ) -> Result<String, dbus::MethodErr> {
+        self.answer(
+            "acquireTokenInteractively",
+            protocol_version,
+            correlation_id,
+            request_json,
+            caller,
+        )
+        .await
+    }
+
+    async fn acquire_token_silently(
+        &self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        caller: Option<CallerInfo>,
+    ) -> Result<String, dbus::MethodErr> {
+        self.answer(
+            "acquireTokenSilently",
+            protocol_version,
+            correlation_id,
+            request_json,
+            caller,
+        )
+        .await
+    }
+
+    async fn get_accounts(
+        &self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        caller: Option<CallerInfo>,
+    ) -> Result<String, dbus::MethodErr> {
+        self.answer(
+            "getAccounts",
+            protocol_version,
+            correlation_id,
+            request_json,
+            caller,
+        )
+        .await
+    }
+
+    async fn remove_account(
+        &self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        caller: Option<CallerInfo>,
+    ) -> Result<String, dbus::MethodErr> {
+        self.answer(
+            "removeAccount",
+            protocol_version,
+            correlation_id,
+            request_json,
+            caller,
+        )
+        .await
+    }
+
+    async fn acquire_prt_sso_cookie(
+        &self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        caller: Option<CallerInfo>,
+    ) -> Result<String, dbus::MethodErr> {
+        self.answer(
+            "acquirePrtSsoCookie",
+            protocol_version,
+            correlation_id,
+            request_json,
+            caller,
+        )
+        .await
+    }
+
+    async fn generate_signed_http_request(
+        &self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        caller: Option<CallerInfo>,
+    ) -> Result<String, dbus::MethodErr> {
+        self.answer(
+            "generateSignedHttpRequest",
+            protocol_version,
+            correlation_id,
+            request_json,
+            caller,
+        )
+        .await
+    }
+
+    async fn cancel_interactive_flow(
+        &self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        caller: Option<CallerInfo>,
+    ) -> Result<String, dbus::MethodErr> {
+        self.answer(
+            "cancelInteractiveFlow",
+            protocol_version,
+            correlation_id,
+            request_json,
+            caller,
+        )
+        .await
+    }
+
+    async fn get_linux_broker_version(
+        &self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        caller: Option<CallerInfo>,
+    ) -> Result<String, dbus::MethodErr> {
+        self.answer(
+            "getLinuxBrokerVersion",
+            protocol_version,
+            correlation_id,
+            request_json,
+            caller,
+        )
+        .await
+    }
+}
+
+/// A captured call replayed by `replay_capture`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplayedCall {
+    pub captured: CapturedCall,
+    pub result: Result<String, CapturedError>,
+}
+
+impl ReplayedCall {
+    /// Whether the replayed call had the captured outcome: the same
+    /// response once redacted, or the same error name. Responses replayed
+    /// from a `MockSessionBroker` seeded with the capture are already
+    /// redacted, and are compared as they are.
+    pub fn matches(&self) -> bool {
+        match (&self.result, self.captured.result()) {
+            (Ok(resp), Ok(captured)) => {
+                *resp == captured || redact_json(resp) == captured
+            }
+            (Err(e), Err(captured)) => e.name == captured.name,
+            _ => false,
+        }
+    }
+}
+
+/// Call `broker` with each captured Broker1 request, in order, as the
+/// captured caller would have. Calls to methods outside Broker1 fail with
+/// org.freedesktop.DBus.Error.UnknownMethod.
+pub async fn replay_capture<T>(
+    broker: Arc<T>,
+    calls: &[CapturedCall],
+) -> Vec<ReplayedCall>
+where
+    T: AsyncSessionBroker + 'static,
+{
+    let stats = Arc::new(BrokerStats::default());
+    let mut replayed = Vec::new();
+    for call in calls {
+        let result = match BROKER1_METHODS
+            .iter()
+            .find(|m| **m == call.method)
+            .copied()
+        {
+            Some(method) => {
+                let req = PortalRequest {
+                    method,
+                    protocol_version: call.protocol_version.clone(),
+                    correlation_id: call.correlation_id.clone(),
+                    request_json: call.request_json.clone(),
+                    app_id: None,
+                };
+                let caller = CallerInfo {
+                    uid: call.uid,
+                    ..Default::default()
+                };
+                call_by_name(broker.clone(), stats.clone(), req, Some(caller))
+                    .await
+            }
+            None => Err(dbus::MethodErr::no_method(&call.method)),
+        };
+        replayed.push(ReplayedCall {
+            captured: call.clone(),
+            result: result.map_err(|e| CapturedError::from(&e)),
+        });
+    }
+    replayed
+}
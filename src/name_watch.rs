@@ -0,0 +1,396 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::caller::{BusType, CallerResolver};
+use crate::freedesktop::{DBusNameLost, DBusNameOwnerChanged};
+use crate::hardening::DeferredReplies;
+use crate::idle::{IdleTimer, IDLE_EXIT_GRACE};
+use dbus::blocking::Connection;
+use dbus::channel::{MatchingReceiver, Sender};
+use dbus::message::{MatchRule, SignalArgs};
+use dbus_crossroads::Crossroads;
+use std::fmt;
+use std::fs;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+use tracing::{debug, error, info, warn};
+
+/// How a broker requests its well-known names from the bus.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NameAcquisitionPolicy {
+    /// Wait in the bus's queue if another connection owns the name.
+    Queue,
+    /// Replace the current owner if it allows replacement, otherwise queue.
+    /// Other connections may not replace this broker.
+    #[default]
+    ReplaceExisting,
+    /// Fail to start if another connection owns the name.
+    FailIfExists,
+    /// As `ReplaceExisting`, but also allow another connection (such as
+    /// Microsoft's own broker) to replace this one.
+    AllowReplacement,
+}
+
+impl NameAcquisitionPolicy {
+    /// The (allow_replacement, replace_existing, do_not_queue) RequestName
+    /// flags for this policy.
+    pub(crate) fn flags(self) -> (bool, bool, bool) {
+        match self {
+            NameAcquisitionPolicy::Queue => (false, false, false),
+            NameAcquisitionPolicy::ReplaceExisting => (false, true, false),
+            NameAcquisitionPolicy::FailIfExists => (false, false, true),
+            NameAcquisitionPolicy::AllowReplacement => (true, true, false),
+        }
+    }
+}
+
+/* The blocking and nonblocking connections each define their own
+ * RequestNameReply, so replies are checked by their RequestName return code.
+ */
+pub(crate) fn check_name_reply(
+    name: &str,
+    reply: u32,
+) -> Result<(), dbus::MethodErr> {
+    match reply {
+        2 => {
+            info!("Queued for {}, which is owned by another connection", name);
+            Ok(())
+        }
+        3 => Err(dbus::MethodErr::failed(&format!(
+            "{} is owned by another connection",
+            name
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// A callback invoked with a well-known name the broker has lost.
+#[derive(Clone)]
+pub(crate) struct NameLostCallback(pub(crate) Arc<dyn Fn(&str) + Send + Sync>);
+
+impl fmt::Debug for NameLostCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("NameLostCallback")
+    }
+}
+
+/// How a broker responds when another connection takes over one of its
+/// well-known names, such as when Microsoft's own broker is started.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TakeoverAction {
+    /// Log the takeover, and carry on serving under any remaining names.
+    #[default]
+    Log,
+    /// Request the name again, queueing behind the new owner if it does not
+    /// allow replacement.
+    Reacquire,
+    /// Stop serving. The serve function returns `Ok(())`.
+    Exit,
+}
+
+/* Watches NameOwnerChanged for the names a broker serves under. The bus
+ * reports every ownership change, including those of names this broker is
+ * only queued for, so takeovers are told apart by the old owner being this
+ * connection.
+ */
+pub(crate) struct NameWatch {
+    names: Vec<String>,
+    action: TakeoverAction,
+    on_name_lost: Option<NameLostCallback>,
+    resolver: CallerResolver,
+    idle: Option<Arc<IdleTimer>>,
+    deferred: Option<Arc<DeferredReplies>>,
+}
+
+impl NameWatch {
+    pub(crate) fn new(
+        names: Vec<String>,
+        action: TakeoverAction,
+        on_name_lost: Option<NameLostCallback>,
+        bus: BusType,
+    ) -> Self {
+        NameWatch {
+            names,
+            action,
+            on_name_lost,
+            resolver: CallerResolver::new(bus),
+            idle: None,
+            deferred: None,
+        }
+    }
+
+    /// Hold back the replies `deferred` asks for, rather than the broker
+    /// blocking the loop to delay them (see `DeferredReplies`).
+    pub(crate) fn deferred_replies(
+        mut self,
+        deferred: Arc<DeferredReplies>,
+    ) -> Self {
+        deferred.serve();
+        self.deferred = Some(deferred);
+        self
+    }
+
+    /// Send the held replies which are due, or all of them when `all`.
+    fn send_deferred(&self, c: &Connection, all: bool) {
+        let deferred = match &self.deferred {
+            Some(deferred) => deferred,
+            None => return,
+        };
+        for reply in deferred.take_due(all) {
+            if c.send(reply).is_err() {
+                error!("Failed to send a deferred reply");
+            }
+        }
+    }
+
+    /// How long the loop may wait for calls before a held reply is due.
+    fn process_timeout(&self) -> Duration {
+        let timeout = Duration::from_millis(1000);
+        match self.deferred.as_ref().and_then(|d| d.next_due()) {
+            Some(due) => due.min(timeout),
+            None => timeout,
+        }
+    }
+
+    /// Exit once `idle` expires (see `ServeOptions::idle_exit`).
+    pub(crate) fn idle_exit(mut self, idle: Option<Arc<IdleTimer>>) -> Self {
+        self.idle = idle;
+        self
+    }
+
+    /// Whether the broker has been idle long enough to exit.
+    pub(crate) fn idle_expired(&self) -> bool {
+        match &self.idle {
+            Some(idle) if idle.expired() => {
+                info!("Exiting after {:?} idle", idle.timeout());
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether calls which arrived before the names were released have
+    /// been served.
+    pub(crate) fn idle_settled(&self) -> bool {
+        self.idle
+            .as_ref()
+            .is_none_or(|idle| idle.idle_for(IDLE_EXIT_GRACE))
+    }
+
+    pub(crate) fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    pub(crate) fn action(&self) -> TakeoverAction {
+        self.action
+    }
+
+    /// The watched name which `sig` reports was taken from `unique_name`.
+    pub(crate) fn taken_over(
+        &self,
+        sig: &DBusNameOwnerChanged,
+        unique_name: &str,
+    ) -> Option<String> {
+        let (name, old_owner, new_owner) = (&sig.arg0, &sig.arg1, &sig.arg2);
+        if !self.names.contains(name) {
+            return None;
+        }
+        if new_owner == unique_name {
+            info!("Acquired {}", name);
+            None
+        } else if old_owner == unique_name {
+            Some(name.clone())
+        } else {
+            debug!("{} is now owned by {:?}", name, new_owner);
+            None
+        }
+    }
+
+    /// Log that `name` was taken over by `new_owner`, identifying the
+    /// process behind it where possible.
+    pub(crate) fn report(&self, name: &str, new_owner: &str) {
+        if new_owner.is_empty() {
+            warn!("Lost {}", name);
+            return;
+        }
+        let process = self
+            .resolver
+            .resolve(new_owner)
+            .ok()
+            .and_then(|caller| caller.pid)
+            .map(|pid| {
+                let exe = fs::read_link(format!("/proc/{}/exe", pid))
+                    .map(|exe| exe.display().to_string())
+                    .unwrap_or_else(|_| "unknown".to_string());
+                format!(" (pid {}, {})", pid, exe)
+            })
+            .unwrap_or_default();
+        warn!("{} was taken over by {}{}", name, new_owner, process);
+    }
+
+    /// Pass a NameLost signal on to the application.
+    pub(crate) fn name_lost(&self, sig: &DBusNameLost) {
+        debug!("NameLost {}", sig.arg0);
+        if let Some(callback) = &self.on_name_lost {
+            (callback.0)(&sig.arg0);
+        }
+    }
+
+    pub(crate) fn match_rule() -> MatchRule<'static> {
+        DBusNameOwnerChanged::match_rule(None, None).static_clone()
+    }
+
+    pub(crate) fn name_lost_rule() -> MatchRule<'static> {
+        DBusNameLost::match_rule(None, None).static_clone()
+    }
+}
+
+/* A broker exiting while idle releases its names first, so that the bus
+ * activates a new instance for the next call rather than queueing it for
+ * this one.
+ */
+pub(crate) fn release_names(
+    c: &Connection,
+    names: &[String],
+) -> Result<(), dbus::MethodErr> {
+    for name in names {
+        let reply = c.release_name(name.as_str())?;
+        debug!("Released {} -> {:?}", name, reply);
+    }
+    Ok(())
+}
+
+/// Request `names` from the bus as `policy` directs.
+pub(crate) fn request_names(
+    c: &Connection,
+    names: &[String],
+    policy: NameAcquisitionPolicy,
+) -> Result<(), dbus::MethodErr> {
+    let (allow_replacement, replace_existing, do_not_queue) = policy.flags();
+    for name in names {
+        let reply = c.request_name(
+            name.as_str(),
+            allow_replacement,
+            replace_existing,
+            do_not_queue,
+        )?;
+        check_name_reply(name, reply as u32)?;
+    }
+    Ok(())
+}
+
+/* Serve method calls on a blocking connection, as Crossroads::serve does,
+ * while acting on takeovers of the watched names between calls.
+ */
+pub(crate) fn serve_watching_names(
+    c: &Connection,
+    mut cr: Crossroads,
+    watch: NameWatch,
+) -> Result<(), dbus::MethodErr> {
+    let watch = Arc::new(watch);
+    let deferred = watch.deferred.clone();
+    c.start_receive(
+        MatchRule::new_method_call(),
+        Box::new(move |msg, conn| {
+            let dispatched = match &deferred {
+                Some(deferred) => {
+                    let sender = HoldingSender { conn, deferred };
+                    cr.handle_message(msg, &sender)
+                }
+                None => cr.handle_message(msg, conn),
+            };
+            if dispatched.is_err() {
+                error!("Failed to dispatch a method call");
+            }
+            true
+        }),
+    );
+
+    let unique_name = c.unique_name().to_string();
+    let taken: Arc<Mutex<Vec<(String, String)>>> = Arc::default();
+    let t = taken.clone();
+    let w = watch.clone();
+    c.add_match(
+        NameWatch::match_rule(),
+        move |sig: DBusNameOwnerChanged, _, _| {
+            if let Some(name) = w.taken_over(&sig, &unique_name) {
+                t.lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .push((name, sig.arg2));
+            }
+            true
+        },
+    )?;
+
+    let w = watch.clone();
+    c.add_match(
+        NameWatch::name_lost_rule(),
+        move |sig: DBusNameLost, _, _| {
+            w.name_lost(&sig);
+            true
+        },
+    )?;
+
+    // Serve clients until a takeover asks us to stop, or the broker has
+    // been idle long enough to exit.
+    loop {
+        c.process(watch.process_timeout())?;
+        watch.send_deferred(c, false);
+        if watch.idle_expired() {
+            release_names(c, watch.names())?;
+            // Serve calls which reached us before the names were released.
+            while c.process(IDLE_EXIT_GRACE)? {}
+            watch.send_deferred(c, true);
+            return Ok(());
+        }
+        let pending: Vec<_> = taken
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .drain(..)
+            .collect();
+        for (name, new_owner) in pending {
+            watch.report(&name, &new_owner);
+            match watch.action() {
+                TakeoverAction::Log => {}
+                TakeoverAction::Reacquire => {
+                    let reply = c.request_name(&name, false, true, false)?;
+                    info!("Requested {} again -> {:?}", name, reply);
+                }
+                TakeoverAction::Exit => {
+                    info!("Exiting after losing {}", name);
+                    watch.send_deferred(c, true);
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+/// Sends the replies of dispatched calls, keeping back those deferred.
+struct HoldingSender<'a> {
+    conn: &'a Connection,
+    deferred: &'a DeferredReplies,
+}
+
+impl Sender for HoldingSender<'_> {
+    fn send(&self, msg: dbus::Message) -> Result<u32, ()> {
+        match self.deferred.hold(msg) {
+            Some(msg) => self.conn.send(msg),
+            None => Ok(0),
+        }
+    }
+}
@@ -0,0 +1,159 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::client::Broker1Client;
+use crate::error::BrokerError;
+use crate::protocol_version::ProtocolVersion;
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::io;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::mpsc;
+use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
+use tracing::{debug, error};
+
+/// The largest message a browser accepts from a native messaging host.
+const MAX_RESPONSE_LEN: usize = 1024 * 1024;
+/// The largest message accepted from the browser.
+const MAX_REQUEST_LEN: usize = 64 * 1024 * 1024;
+
+/// A Broker1 call from a browser extension. The request is given as a JSON
+/// value rather than as request_json.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct NativeRequest {
+    /// Echoed in the response, so that extensions can match responses to
+    /// requests answered out of order.
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default = "default_protocol_version")]
+    protocol_version: String,
+    correlation_id: Option<String>,
+    #[serde(default = "empty_request")]
+    request: Value,
+}
+
+fn default_protocol_version() -> String {
+    ProtocolVersion::default().to_string()
+}
+
+fn empty_request() -> Value {
+    json!({})
+}
+
+fn error_response(id: Value, e: &BrokerError) -> Value {
+    let name = e
+        .name()
+        .and_then(|name| name.as_str().rsplit('.').next())
+        .unwrap_or("Failed");
+    json!({ "id": id, "error": { "name": name, "message": e.to_string() } })
+}
+
+async fn handle(client: &Broker1Client, message: &[u8]) -> Value {
+    let req: NativeRequest = match serde_json::from_slice(message) {
+        Ok(req) => req,
+        Err(e) => {
+            let id = serde_json::from_slice::<Value>(message)
+                .ok()
+                .and_then(|message| message.get("id").cloned())
+                .unwrap_or_default();
+            return error_response(id, &e.into());
+        }
+    };
+    debug!("Native messaging request {} ({})", req.method, req.id);
+    let correlation_id = req
+        .correlation_id
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    let res = client
+        .call(
+            &req.method,
+            &req.protocol_version,
+            &correlation_id,
+            &req.request.to_string(),
+        )
+        .await;
+    match res {
+        Ok(result) => {
+            /* Results are passed to the extension as JSON where they are
+             * JSON, as they always are from a conforming broker.
+             */
+            let result =
+                serde_json::from_str(&result).unwrap_or(Value::String(result));
+            json!({ "id": req.id, "result": result })
+        }
+        Err(e) => {
+            debug!("{} failed -> {}", req.method, e);
+            error_response(req.id, &e)
+        }
+    }
+}
+
+/* Browsers speak to native messaging hosts over their standard input and
+ * output, in JSON messages preceded by their length as a 32 bit integer in
+ * native byte order. Each request is answered as it completes, so that a
+ * slow interactive flow does not hold up the extension's other calls.
+ */
+/// Answer the Broker1 calls of a browser extension, read from `input` and
+/// written to `output` in the native messaging protocol, by calling the
+/// session broker through `client`. Returns once the browser has closed
+/// `input` and the calls already read have been answered.
+pub async fn native_messaging_serve<R, W>(
+    client: Broker1Client,
+    input: R,
+    output: W,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let codec = || {
+        LengthDelimitedCodec::builder()
+            .length_field_length(4)
+            .native_endian()
+            .max_frame_length(MAX_REQUEST_LEN)
+            .new_codec()
+    };
+    let mut input = FramedRead::new(input, codec());
+    let mut output = FramedWrite::new(output, codec());
+    let (tx, mut rx) = mpsc::unbounded_channel::<Value>();
+    let writer = tokio::spawn(async move {
+        while let Some(resp) = rx.recv().await {
+            let mut message = resp.to_string();
+            if message.len() > MAX_RESPONSE_LEN {
+                error!("Response of {} bytes is too large", message.len());
+                let e = BrokerError::unexpected("The response is too large");
+                message = error_response(resp["id"].clone(), &e).to_string();
+            }
+            output.send(Bytes::from(message)).await?;
+        }
+        Ok::<(), io::Error>(())
+    });
+    while let Some(message) = input.next().await {
+        let message = message?;
+        let client = client.clone();
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            let _ = tx.send(handle(&client, &message).await);
+        });
+    }
+    drop(tx);
+    writer.await.map_err(io::Error::other)?
+}
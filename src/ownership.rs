@@ -0,0 +1,558 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::accounts::Account;
+use crate::error::BrokerError;
+use crate::socket::{user_groups, user_name};
+use libc::{gid_t, uid_t};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+use tracing::{debug, warn};
+
+/// The Entra ID account a local user signs in as. Unset fields are not
+/// compared.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct AccountIdentity {
+    /// The user principal name, compared case-insensitively with an
+    /// account's username.
+    pub upn: Option<String>,
+    /// The object id, the first part of an account's home_account_id.
+    pub object_id: Option<String>,
+    /// The home tenant id, the second part of an account's
+    /// home_account_id.
+    pub tenant_id: Option<String>,
+}
+
+impl AccountIdentity {
+    pub fn upn(upn: impl Into<String>) -> Self {
+        AccountIdentity {
+            upn: Some(upn.into()),
+            ..Default::default()
+        }
+    }
+
+    /* An account is only matched on its UPN or object id, the tenant id
+     * alone being shared by every user of the tenant. Any field known on
+     * both sides must agree.
+     */
+    /// Whether `account` is this identity.
+    pub fn matches(&self, account: &Account) -> bool {
+        let (object_id, tenant_id) =
+            match account.home_account_id.split_once('.') {
+                Some((oid, tid)) => (oid, tid),
+                None => (account.home_account_id.as_str(), ""),
+            };
+        let mut identified = false;
+        if let Some(upn) = &self.upn {
+            if !account.username.is_empty() {
+                if !upn.eq_ignore_ascii_case(&account.username) {
+                    return false;
+                }
+                identified = true;
+            }
+        }
+        if let Some(oid) = &self.object_id {
+            if !object_id.is_empty() {
+                if !oid.eq_ignore_ascii_case(object_id) {
+                    return false;
+                }
+                identified = true;
+            }
+        }
+        if let Some(tid) = &self.tenant_id {
+            if !tenant_id.is_empty() && !tid.eq_ignore_ascii_case(tenant_id) {
+                return false;
+            }
+        }
+        identified
+    }
+
+    /* A UPN alone does not establish that a home account id is the user's,
+     * since a request may pair the user's own username with another
+     * user's id. Unless the object id is known, the id is only trusted
+     * when it is one of the accounts the broker holds for the user.
+     */
+    /// Whether `account` is this identity, with its home account id
+    /// verified against the object id or against `held`, the accounts the
+    /// broker holds for the user.
+    pub fn owns(&self, account: &Account, held: &[Account]) -> bool {
+        if !self.matches(account) {
+            return false;
+        }
+        if account.home_account_id.is_empty() || self.object_id.is_some() {
+            return true;
+        }
+        held.iter().any(|held| {
+            held.home_account_id
+                .eq_ignore_ascii_case(&account.home_account_id)
+                && self.matches(held)
+        })
+    }
+}
+
+/// Maps a local uid to the Entra ID account it owns. Closures taking the
+/// uid implement this, for example to query Himmelblau's idmap cache.
+pub trait AccountResolver: Send + Sync {
+    /// The account owned by `uid`, or None for a user without one.
+    fn resolve(
+        &self,
+        uid: uid_t,
+    ) -> Result<Option<AccountIdentity>, BrokerError>;
+}
+
+impl<F> AccountResolver for F
+where
+    F: Fn(uid_t) -> Result<Option<AccountIdentity>, BrokerError> + Send + Sync,
+{
+    fn resolve(
+        &self,
+        uid: uid_t,
+    ) -> Result<Option<AccountIdentity>, BrokerError> {
+        self(uid)
+    }
+}
+
+/// Resolves uids through NSS, where Himmelblau names Entra ID users by
+/// their UPN. Names without a domain are local users, unless
+/// `default_domain` is set (for Himmelblau's short name mapping).
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct NssAccountResolver {
+    pub default_domain: Option<String>,
+}
+
+impl AccountResolver for NssAccountResolver {
+    fn resolve(
+        &self,
+        uid: uid_t,
+    ) -> Result<Option<AccountIdentity>, BrokerError> {
+        let name = match user_name(uid) {
+            Ok(name) => String::from_utf8_lossy(&name).into_owned(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        if name.contains('@') {
+            return Ok(Some(AccountIdentity::upn(name)));
+        }
+        Ok(self
+            .default_domain
+            .as_ref()
+            .map(|domain| AccountIdentity::upn(format!("{}@{}", name, domain))))
+    }
+}
+
+/// A fixed mapping of uids to accounts, such as from a configuration file.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(transparent)]
+pub struct StaticAccountResolver(pub HashMap<uid_t, AccountIdentity>);
+
+impl AccountResolver for StaticAccountResolver {
+    fn resolve(
+        &self,
+        uid: uid_t,
+    ) -> Result<Option<AccountIdentity>, BrokerError> {
+        Ok(self.0.get(&uid).cloned())
+    }
+}
+
+/// Resolvers consulted in turn, the first to know the uid answering.
+#[derive(Clone, Default)]
+pub struct ResolverChain(pub Vec<Arc<dyn AccountResolver>>);
+
+impl AccountResolver for ResolverChain {
+    fn resolve(
+        &self,
+        uid: uid_t,
+    ) -> Result<Option<AccountIdentity>, BrokerError> {
+        for resolver in &self.0 {
+            if let Some(identity) = resolver.resolve(uid)? {
+                return Ok(Some(identity));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Methods whose request names accounts which must belong to the caller.
+pub const OWNED_ACCOUNT_METHODS: &[&str] = &[
+    "acquireTokenSilently",
+    "acquirePrtSsoCookie",
+    "generateSignedHttpRequest",
+    "refreshPrt",
+    "removeAccount",
+    "removeAccounts",
+];
+
+/* A request may name its account both at the top level and within its
+ * authParameters, and the two need not agree, so both are returned. An
+ * account object without a homeAccountId is still returned, with the id
+ * left empty, so that it cannot slip past the check. A loginHint or
+ * username selects an account by its username, and is returned after the
+ * account objects as an account with only its username set.
+ */
+/// The accounts named by `request_json`: its `account`, its
+/// `authParameters.account`, the `accounts` of a removeAccounts request,
+/// and the `loginHint` or `username` of either.
+pub fn requested_accounts(request_json: &str) -> Vec<Account> {
+    let request: Value = match serde_json::from_str(request_json) {
+        Ok(request) => request,
+        Err(_) => return Vec::new(),
+    };
+    let field = |account: &Value, name: &str| {
+        account
+            .get(name)
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string()
+    };
+    let named = request.get("account").into_iter().chain(
+        request
+            .get("authParameters")
+            .and_then(|params| params.get("account")),
+    );
+    let listed = request
+        .get("accounts")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten();
+    let hints = [Some(&request), request.get("authParameters")]
+        .into_iter()
+        .flatten()
+        .flat_map(|params| ["loginHint", "username"].map(|n| params.get(n)))
+        .flatten()
+        .filter_map(Value::as_str)
+        .filter(|hint| !hint.is_empty())
+        .map(|hint| Account {
+            home_account_id: String::new(),
+            environment: String::new(),
+            realm: String::new(),
+            local_account_id: String::new(),
+            username: hint.to_string(),
+            extra: HashMap::new(),
+        });
+    named
+        .chain(listed)
+        .filter(|account| account.is_object())
+        .map(|account| Account {
+            home_account_id: field(account, "homeAccountId"),
+            environment: field(account, "environment"),
+            realm: field(account, "realm"),
+            local_account_id: field(account, "localAccountId"),
+            username: field(account, "username"),
+            extra: HashMap::new(),
+        })
+        .chain(hints)
+        .collect()
+}
+
+/// The accounts a getAccounts or getAccountsPage request asks for. Callers
+/// are given their own accounts, unless the request has
+/// `"allAccounts": true`, which only root and the `AccountOwnership` admin
+/// group may ask for.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AccountScope {
+    #[default]
+    Caller,
+    Device,
+}
+
+impl AccountScope {
+    /// Remove `allAccounts` from a request, leaving the request the broker
+    /// expects.
+    pub fn take_from_request(
+        request_json: &str,
+    ) -> Result<(String, Self), Box<dyn Error>> {
+        let mut request: serde_json::Map<String, Value> =
+            serde_json::from_str(request_json)?;
+        let scope = match request.remove("allAccounts") {
+            Some(Value::Bool(true)) => AccountScope::Device,
+            Some(Value::Bool(false)) | Some(Value::Null) => {
+                AccountScope::Caller
+            }
+            None => {
+                return Ok((request_json.to_string(), AccountScope::Caller))
+            }
+            Some(_) => return Err("allAccounts must be a boolean".into()),
+        };
+        Ok((Value::Object(request).to_string(), scope))
+    }
+}
+
+/// What becomes of a request for an account its caller does not own.
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum OwnershipEnforcement {
+    /// Log the request and let it through, to try out a resolver.
+    Log,
+    /// Refuse the request as though the account did not exist.
+    #[default]
+    Reject,
+}
+
+/* Root is not checked: it can read every user's token cache regardless,
+ * and management tools running as root act on other users' accounts.
+ * A uid which cannot be resolved owns no accounts, and a request whose
+ * accounts cannot be found names none the uid owns, nor does a request
+ * whose home account id cannot be verified, so the check fails closed
+ * when enforcing. By default uids are resolved through NSS.
+ */
+/// Checks that the accounts named in requests belong to the calling uid,
+/// as resolved by an `AccountResolver`.
+#[derive(Clone)]
+pub struct AccountOwnership {
+    resolver: Arc<dyn AccountResolver>,
+    enforcement: OwnershipEnforcement,
+    admin_group: Option<gid_t>,
+}
+
+impl fmt::Debug for AccountOwnership {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AccountOwnership")
+            .field("enforcement", &self.enforcement)
+            .field("admin_group", &self.admin_group)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for AccountOwnership {
+    fn default() -> Self {
+        AccountOwnership::new(
+            Arc::new(NssAccountResolver::default()),
+            OwnershipEnforcement::default(),
+        )
+    }
+}
+
+impl AccountOwnership {
+    pub fn new(
+        resolver: Arc<dyn AccountResolver>,
+        enforcement: OwnershipEnforcement,
+    ) -> Self {
+        AccountOwnership {
+            resolver,
+            enforcement,
+            admin_group: None,
+        }
+    }
+
+    /// Members of `gid`, as listed in the group database, may list the
+    /// accounts of every user, as root may.
+    pub fn admin_group(mut self, gid: gid_t) -> Self {
+        self.admin_group = Some(gid);
+        self
+    }
+
+    /// Whether `uid` is root or a member of the admin group.
+    pub fn is_admin(&self, uid: uid_t) -> bool {
+        if uid == 0 {
+            return true;
+        }
+        let gid = match self.admin_group {
+            Some(gid) => gid,
+            None => return false,
+        };
+        match user_groups(uid) {
+            Ok(groups) => groups.contains(&gid),
+            Err(e) => {
+                warn!("Unable to read the groups of uid {} -> {}", uid, e);
+                false
+            }
+        }
+    }
+
+    pub fn enforcement(&self) -> OwnershipEnforcement {
+        self.enforcement
+    }
+
+    /// Whether `uid` owns `account`, given `held`, the accounts the broker
+    /// holds for `uid` (see `AccountIdentity::owns`). Fails with an
+    /// AccountNotFound error when it does not and ownership is enforced.
+    pub fn check(
+        &self,
+        uid: uid_t,
+        account: &Account,
+        held: &[Account],
+    ) -> Result<(), BrokerError> {
+        if uid == 0 {
+            return Ok(());
+        }
+        let reason = match self.resolver.resolve(uid) {
+            Ok(Some(identity)) if identity.owns(account, held) => {
+                debug!("uid {} owns account {}", uid, account.home_account_id);
+                return Ok(());
+            }
+            Ok(Some(identity)) if identity.matches(account) => {
+                "its home account id could not be verified".to_string()
+            }
+            Ok(Some(_)) => "it belongs to another user".to_string(),
+            Ok(None) => "the uid has no Entra ID account".to_string(),
+            Err(e) => format!("the uid could not be resolved: {}", e),
+        };
+        warn!(
+            "uid {} requested account {}, but {}",
+            uid,
+            account_name(account),
+            reason
+        );
+        self.enforce(account_name(account))
+    }
+
+    fn enforce(&self, account: &str) -> Result<(), BrokerError> {
+        match self.enforcement {
+            OwnershipEnforcement::Log => Ok(()),
+            OwnershipEnforcement::Reject => Err(BrokerError::backend(
+                "AccountNotFound",
+                format!("Account {} not found", account),
+            )),
+        }
+    }
+
+    /// Whether checking a request for `method` needs the accounts the
+    /// broker holds for `uid`: when it names a home account id, and the
+    /// object id of `uid` is not known.
+    pub fn needs_held_accounts(
+        &self,
+        method: &str,
+        uid: uid_t,
+        request_json: &str,
+    ) -> bool {
+        if uid == 0 || !OWNED_ACCOUNT_METHODS.contains(&method) {
+            return false;
+        }
+        let named_id = requested_accounts(request_json)
+            .iter()
+            .any(|account| !account.home_account_id.is_empty());
+        named_id
+            && !matches!(
+                self.resolver.resolve(uid),
+                Ok(Some(AccountIdentity {
+                    object_id: Some(_),
+                    ..
+                }))
+            )
+    }
+
+    /// Check the accounts named by a request for `method`, if it is one of
+    /// the `OWNED_ACCOUNT_METHODS`, given `held`, the accounts the broker
+    /// holds for `uid`.
+    pub fn check_request(
+        &self,
+        method: &str,
+        uid: uid_t,
+        request_json: &str,
+        held: &[Account],
+    ) -> Result<(), BrokerError> {
+        if uid == 0 || !OWNED_ACCOUNT_METHODS.contains(&method) {
+            return Ok(());
+        }
+        let accounts = requested_accounts(request_json);
+        if accounts.is_empty() {
+            warn!("uid {} sent a {} request naming no account", uid, method);
+            return self.enforce("(none)");
+        }
+        accounts
+            .iter()
+            .try_for_each(|account| self.check(uid, account, held))
+    }
+
+    /// Take the `AccountScope` from a getAccounts or getAccountsPage
+    /// request, refusing the `Device` scope to callers who are not admins.
+    pub fn take_scope(
+        &self,
+        uid: uid_t,
+        request_json: &str,
+    ) -> Result<(String, AccountScope), BrokerError> {
+        let (request_json, scope) =
+            AccountScope::take_from_request(request_json)
+                .map_err(|e| BrokerError::InvalidRequest(e.to_string()))?;
+        if scope == AccountScope::Device && !self.is_admin(uid) {
+            warn!("Refusing to list every account to uid {}", uid);
+            return Err(BrokerError::InvalidRequest(
+                "allAccounts is only permitted to administrators".to_string(),
+            ));
+        }
+        Ok((request_json, scope))
+    }
+
+    /// Remove the accounts which `uid` does not own from a getAccounts (or
+    /// getAccountsPage) response, whatever the enforcement, unless an
+    /// administrator asked for the `Device` scope. Listing is filtered for
+    /// root too, unless it requests `allAccounts`. Pages may then hold
+    /// fewer accounts than their size.
+    pub fn scope_response(
+        &self,
+        uid: uid_t,
+        scope: AccountScope,
+        response_json: String,
+    ) -> String {
+        if scope == AccountScope::Device {
+            return response_json;
+        }
+        let mut response: serde_json::Map<String, Value> =
+            match serde_json::from_str(&response_json) {
+                Ok(response) => response,
+                Err(_) => return response_json,
+            };
+        let accounts = match response.get_mut("accounts") {
+            Some(Value::Array(accounts)) => accounts,
+            _ => return response_json,
+        };
+        let identity = match self.resolver.resolve(uid) {
+            Ok(identity) => identity,
+            Err(e) => {
+                warn!("Unable to resolve the account of uid {} -> {}", uid, e);
+                None
+            }
+        };
+        let owned = |account: &Value| {
+            let account = serde_json::from_value::<Account>(account.clone());
+            match (&identity, account) {
+                (Some(identity), Ok(account)) => identity.matches(&account),
+                _ => false,
+            }
+        };
+        let foreign = accounts.iter().filter(|a| !owned(a)).count();
+        if foreign == 0 {
+            return response_json;
+        }
+        debug!(
+            "Withholding {} accounts from uid {} which are not its own",
+            foreign, uid
+        );
+        accounts.retain(owned);
+        Value::Object(response).to_string()
+    }
+}
+
+/// How an account is named in logs and errors: by its home account id, or
+/// its username when selected by a login hint.
+fn account_name(account: &Account) -> &str {
+    if account.home_account_id.is_empty() {
+        &account.username
+    } else {
+        &account.home_account_id
+    }
+}
@@ -0,0 +1,186 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::error::BrokerError;
+use crate::himmelblau_broker::HimmelblauBroker;
+use crate::socket::user_name;
+use async_trait::async_trait;
+use futures::{SinkExt, StreamExt};
+use libc::uid_t;
+use serde::{Deserialize, Serialize};
+use std::io;
+use tokio::net::unix::UCred;
+use tokio::net::{UnixListener, UnixStream};
+use tokio_util::codec::{Framed, LinesCodec};
+use tracing::{debug, error, warn};
+
+// The longest request line accepted from a PAM module.
+const MAX_LINE_LEN: usize = 64 * 1024;
+
+/// The user a PAM module reports on, as known to the PAM stack.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct PamEvent {
+    pub uid: uid_t,
+    /// The user name, which must belong to `uid`.
+    pub user: String,
+    /// The PAM service the user authenticated to, e.g. `gdm-password`.
+    pub service: String,
+    /// The remote host of the login (PAM_RHOST), if any.
+    #[serde(default)]
+    pub rhost: Option<String>,
+}
+
+/// A message from a PAM module on the daemon's PAM socket (see
+/// `HimmelblauBrokerConfig::pam_socket`), sent as a line of JSON.
+#[allow(non_camel_case_types)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum PamRequest {
+    /// The user authenticated successfully.
+    authenticated(PamEvent),
+    /// Obtain a PRT for the user, so that their session starts signed in.
+    bootstrapPrt(PamEvent),
+}
+
+/// The daemon's answer to each `PamRequest`, sent as a line of JSON.
+#[allow(non_camel_case_types)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum PamResponse {
+    ok,
+    error(BrokerError),
+}
+
+/* Like compliance reporting, PAM integration is a separate trait. A
+ * HimmelblauBroker which handles PAM events returns itself from
+ * `HimmelblauBroker::pam`.
+ */
+#[async_trait]
+pub trait PamBroker {
+    /// The user authenticated successfully to `event.service`.
+    async fn authenticated(
+        &mut self,
+        event: PamEvent,
+    ) -> Result<(), BrokerError>;
+
+    /// Obtain a PRT for the user ahead of their first request.
+    async fn bootstrap_prt(
+        &mut self,
+        event: PamEvent,
+    ) -> Result<(), BrokerError>;
+}
+
+/// Accept a connection from root on the PAM socket, if there is one,
+/// along with its credentials. Connections from other users are refused.
+pub(crate) async fn accept(
+    listener: Option<&UnixListener>,
+) -> io::Result<(UnixStream, UCred)> {
+    let listener = match listener {
+        Some(listener) => listener,
+        None => return std::future::pending().await,
+    };
+    loop {
+        let (sock, _) = listener.accept().await?;
+        let peer = sock.peer_cred()?;
+        if peer.uid() == 0 {
+            return Ok((sock, peer));
+        }
+        warn!(
+            "Refusing PAM connection from uid {} (pid {:?})",
+            peer.uid(),
+            peer.pid()
+        );
+    }
+}
+
+/* PAM modules run as root within the login process, so only root's reports
+ * are believed. The user name must also belong to the uid, so that a
+ * confused module cannot act for the wrong account.
+ */
+fn verify_user(event: &PamEvent) -> Result<(), BrokerError> {
+    match user_name(event.uid) {
+        Ok(name) if name == event.user.as_bytes() => Ok(()),
+        Ok(_) => Err(BrokerError::InvalidRequest(format!(
+            "{} is not the user name of uid {}",
+            event.user, event.uid
+        ))),
+        Err(e) => Err(BrokerError::InvalidRequest(e.to_string())),
+    }
+}
+
+async fn handle_pam_request<T>(
+    broker: &mut T,
+    req: PamRequest,
+) -> Result<(), BrokerError>
+where
+    T: HimmelblauBroker + Send,
+{
+    let event = match &req {
+        PamRequest::authenticated(event) | PamRequest::bootstrapPrt(event) => {
+            event
+        }
+    };
+    verify_user(event)?;
+    let pam = broker.pam().ok_or_else(|| {
+        BrokerError::backend(
+            "NotSupported",
+            "PAM events are not handled by this broker",
+        )
+    })?;
+    match req {
+        PamRequest::authenticated(event) => {
+            debug!(
+                "{} authenticated to {} (uid {})",
+                event.user, event.service, event.uid
+            );
+            pam.authenticated(event).await
+        }
+        PamRequest::bootstrapPrt(event) => {
+            debug!("Bootstrapping a PRT for {}", event.user);
+            pam.bootstrap_prt(event).await
+        }
+    }
+}
+
+/// Answer the requests of a PAM module connected to the PAM socket.
+pub(crate) async fn handle_pam_connection<T>(
+    sock: UnixStream,
+    mut broker: T,
+) -> io::Result<()>
+where
+    T: HimmelblauBroker + Send,
+{
+    let mut lines =
+        Framed::new(sock, LinesCodec::new_with_max_length(MAX_LINE_LEN));
+    while let Some(line) = lines.next().await {
+        let line = line.map_err(io::Error::other)?;
+        let res = match serde_json::from_str::<PamRequest>(&line) {
+            Ok(req) => handle_pam_request(&mut broker, req).await,
+            Err(e) => Err(e.into()),
+        };
+        let resp = match res {
+            Ok(()) => PamResponse::ok,
+            Err(e) => {
+                error!("PAM request failed -> {}", e);
+                PamResponse::error(e)
+            }
+        };
+        let resp = serde_json::to_string(&resp).map_err(io::Error::other)?;
+        lines.send(resp).await.map_err(io::Error::other)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,72 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use dbus_crossroads as crossroads;
+use std::fs;
+use std::io;
+
+pub const PEER_INTERFACE: &str = "org.freedesktop.DBus.Peer";
+
+// Where the machine id is kept, in order of preference.
+const MACHINE_ID_PATHS: [&str; 2] =
+    ["/etc/machine-id", "/var/lib/dbus/machine-id"];
+
+/// The machine id, as reported by org.freedesktop.DBus.Peer.GetMachineId:
+/// 32 lowercase hexadecimal digits, from /etc/machine-id (or the older
+/// /var/lib/dbus/machine-id).
+pub fn machine_id() -> io::Result<String> {
+    let mut err = None;
+    for path in MACHINE_ID_PATHS {
+        match fs::read_to_string(path) {
+            Ok(id) => {
+                let id = id.trim();
+                if id.len() == 32 && id.bytes().all(|b| b.is_ascii_hexdigit()) {
+                    return Ok(id.to_ascii_lowercase());
+                }
+                err = Some(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} is not a valid machine id", path),
+                ));
+            }
+            Err(e) => err = err.or(Some(e)),
+        }
+    }
+    Err(err.unwrap_or_else(|| io::ErrorKind::NotFound.into()))
+}
+
+/* Crossroads answers Introspectable and Properties itself, but not Peer,
+ * which some MSAL clients use to check that the broker is alive before
+ * calling it. The interface is registered on every object the brokers
+ * serve.
+ */
+/// Register org.freedesktop.DBus.Peer, with Ping and GetMachineId.
+pub fn register_peer<T>(
+    cr: &mut crossroads::Crossroads,
+) -> crossroads::IfaceToken<T>
+where
+    T: Send + 'static,
+{
+    cr.register(PEER_INTERFACE, |b| {
+        b.method("Ping", (), (), |_, _: &mut T, ()| Ok(()));
+        b.method("GetMachineId", (), ("machine_uuid",), |_, _: &mut T, ()| {
+            machine_id()
+                .map(|id| (id,))
+                .map_err(|e| dbus::MethodErr::failed(&e))
+        });
+    })
+}
@@ -0,0 +1,302 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::audit::PolicyDenialRecord;
+use crate::caller::CallerInfo;
+use crate::error::BrokerError;
+use libc::uid_t;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+use tracing::{debug, error, warn};
+
+/// Broker1 methods which return tokens or cookies to the caller.
+pub const TOKEN_METHODS: &[&str] = &[
+    "acquireTokenInteractively",
+    "acquireTokenSilently",
+    "acquirePrtSsoCookie",
+    "generateSignedHttpRequest",
+    "getSshCert",
+    "completeInteractiveFlow",
+    "completeDeviceCodeFlow",
+];
+
+/// Methods which sign users in or out, or create or delete device keys,
+/// disabled by `MethodPolicy::read_only`.
+pub const WRITE_METHODS: &[&str] = &[
+    "acquireTokenInteractively",
+    "completeInteractiveFlow",
+    "startDeviceCodeFlow",
+    "pollDeviceCodeFlow",
+    "completeDeviceCodeFlow",
+    "removeAccount",
+    "removeAccounts",
+    "generateKeyPair",
+    "persistKey",
+    "deleteKey",
+    "generateAsymmetricKey",
+    "clearAsymmetricKey",
+];
+
+impl CallerInfo {
+    /// The executable of the calling process, from /proc/<pid>/exe.
+    pub fn executable(&self) -> Option<PathBuf> {
+        fs::read_link(format!("/proc/{}/exe", self.pid?)).ok()
+    }
+
+    /// Whether the calling process is running with a real uid which differs
+    /// from its effective uid (ie. it is a setuid binary).
+    pub fn is_setuid(&self) -> Option<bool> {
+        let status =
+            fs::read_to_string(format!("/proc/{}/status", self.pid?)).ok()?;
+        let uids: Vec<&str> = status
+            .lines()
+            .find_map(|line| line.strip_prefix("Uid:"))?
+            .split_whitespace()
+            .collect();
+        Some(uids.first()? != uids.get(1)?)
+    }
+
+    /* A caller is forwarded by whoever connects to the daemon socket, so
+     * the daemon only believes it as far as the socket's peer credentials
+     * allow. A user's process must forward its own uid, and a pid running
+     * as that uid. Which of the user's processes made the call, and its
+     * security label, remain the forwarder's claim: any process of the
+     * user could make it. Only root, such as the device broker forwarding
+     * the callers of every user, is trusted to forward any caller.
+     */
+    /// Check a caller forwarded over a daemon socket connection from
+    /// `peer_uid`, failing when it claims another user or another user's
+    /// process.
+    pub fn check_peer(&self, peer_uid: uid_t) -> Result<(), BrokerError> {
+        if peer_uid == 0 {
+            return Ok(());
+        }
+        if self.uid != Some(peer_uid) {
+            warn!(
+                "uid {} forwarded a caller claiming uid {:?}",
+                peer_uid, self.uid
+            );
+            return Err(BrokerError::InvalidRequest(
+                "The caller does not match the connection".to_string(),
+            ));
+        }
+        if let Some(pid) = self.pid {
+            let owner = fs::metadata(format!("/proc/{}", pid))
+                .map(|proc| proc.uid())
+                .ok();
+            if owner != Some(peer_uid) {
+                warn!(
+                    "uid {} forwarded pid {}, which runs as {:?}",
+                    peer_uid, pid, owner
+                );
+                return Err(BrokerError::InvalidRequest(
+                    "The caller does not match the connection".to_string(),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// The SELinux domain (the type of the security context) the caller
+    /// runs in, e.g. `mozilla_t` for `unconfined_u:unconfined_r:mozilla_t:s0`.
+    pub fn selinux_domain(&self) -> Option<&str> {
+        self.security_label.as_deref()?.split(':').nth(2)
+    }
+}
+
+/* The CallerPolicy mirrors how Microsoft's broker restricts which
+ * applications may use brokered authentication. It is only consulted for
+ * TOKEN_METHODS, and fails closed when the caller cannot be identified.
+ * On hardened systems the caller's SELinux domain is harder to fake than
+ * its executable, since a process cannot leave its domain at will.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct CallerPolicy {
+    /// Executables permitted to request tokens. When unset, any executable
+    /// is permitted.
+    pub allowed_executables: Option<Vec<PathBuf>>,
+    /// Reject callers running setuid.
+    pub deny_setuid: bool,
+    /// SELinux domains permitted to request tokens (see
+    /// `CallerInfo::selinux_domain`). Callers without an SELinux context
+    /// are refused. When unset, any domain is permitted.
+    pub allowed_domains: Option<Vec<String>>,
+    /// Sandboxed applications, by their app id, permitted to request tokens
+    /// through the portal frontend (see `CallerInfo::app_id`). The portal
+    /// itself must also pass the other checks. When unset, such calls are
+    /// refused if executables or domains are restricted, as these would
+    /// only describe the portal.
+    pub allowed_app_ids: Option<Vec<String>>,
+    /// Record denials without enforcing them, to try out a policy.
+    pub permissive: bool,
+    /// Append a `PolicyDenialRecord` for each denial to this file.
+    pub denial_log: Option<PathBuf>,
+}
+
+impl CallerPolicy {
+    pub fn check(
+        &self,
+        method: &str,
+        caller: Option<&CallerInfo>,
+    ) -> Result<(), dbus::MethodErr> {
+        if !TOKEN_METHODS.contains(&method) {
+            return Ok(());
+        }
+        let reason = match self.denial(method, caller) {
+            Some(reason) => reason,
+            None => return Ok(()),
+        };
+        if let Some(path) = &self.denial_log {
+            let record = PolicyDenialRecord::new(
+                method,
+                caller,
+                reason,
+                !self.permissive,
+            );
+            if let Err(e) = record.append_to(path) {
+                error!(
+                    "Failed to write to denial log {} -> {:?}",
+                    path.display(),
+                    e
+                );
+            }
+        }
+        if self.permissive {
+            warn!("Permitting {} as the policy is permissive", method);
+            return Ok(());
+        }
+        Err(dbus::MethodErr::failed(reason))
+    }
+
+    /// Why `caller` may not call `method`, if it may not.
+    fn denial(
+        &self,
+        method: &str,
+        caller: Option<&CallerInfo>,
+    ) -> Option<&'static str> {
+        let caller = match caller {
+            Some(caller) => caller,
+            None => {
+                warn!("Denying {}: the caller could not be identified", method);
+                return Some("Caller could not be identified");
+            }
+        };
+
+        if let Some(app_id) = &caller.app_id {
+            let restricted = self.allowed_executables.is_some()
+                || self.allowed_domains.is_some();
+            match &self.allowed_app_ids {
+                Some(allowed) if allowed.contains(app_id) => {
+                    debug!("{} permitted for app {}", method, app_id);
+                }
+                None if !restricted => {}
+                _ => {
+                    warn!(
+                        "Denying {} to app {} via {}",
+                        method, app_id, caller.bus_name
+                    );
+                    return Some(
+                        "The sandboxed application may not request tokens",
+                    );
+                }
+            }
+        }
+
+        if self.deny_setuid && caller.is_setuid() != Some(false) {
+            warn!(
+                "Denying {} to setuid caller {} (pid {:?})",
+                method, caller.bus_name, caller.pid
+            );
+            return Some("Setuid callers may not request tokens");
+        }
+
+        if let Some(allowed) = &self.allowed_executables {
+            let exe = caller.executable();
+            match &exe {
+                Some(exe) if allowed.contains(exe) => {
+                    debug!("{} permitted for {}", method, exe.display());
+                }
+                _ => {
+                    warn!(
+                        "Denying {} to {} (pid {:?}, exe {:?})",
+                        method, caller.bus_name, caller.pid, exe
+                    );
+                    return Some(
+                        "The calling application may not request tokens",
+                    );
+                }
+            }
+        }
+
+        if let Some(allowed) = &self.allowed_domains {
+            match caller.selinux_domain() {
+                Some(domain) if allowed.iter().any(|d| d == domain) => {
+                    debug!("{} permitted for domain {}", method, domain);
+                }
+                _ => {
+                    warn!(
+                        "Denying {} to {} (pid {:?}, context {:?})",
+                        method,
+                        caller.bus_name,
+                        caller.pid,
+                        caller.security_label
+                    );
+                    return Some(
+                        "The caller's SELinux domain may not request tokens",
+                    );
+                }
+            }
+        }
+        None
+    }
+}
+
+/* Kiosk and shared machines may need to keep users from signing in or
+ * removing the machine's accounts. The method policy is applied to every
+ * caller, before the caller policy, by the session and device brokers and
+ * by the Himmelblau daemon.
+ */
+/// Methods turned off for a deployment. Calls to them fail with
+/// `BrokerError::Disabled`, reported as
+/// com.microsoft.identity.Broker1.Error.Disabled.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct MethodPolicy {
+    /// Methods to disable, by their D-Bus names (e.g. `removeAccount`).
+    pub disabled: Vec<String>,
+    /// Also disable the `WRITE_METHODS`, leaving existing accounts usable
+    /// but unchangeable.
+    pub read_only: bool,
+}
+
+impl MethodPolicy {
+    pub fn is_disabled(&self, method: &str) -> bool {
+        (self.read_only && WRITE_METHODS.contains(&method))
+            || self.disabled.iter().any(|m| m == method)
+    }
+
+    pub fn check(&self, method: &str) -> Result<(), BrokerError> {
+        if self.is_disabled(method) {
+            warn!("Refusing {}: disabled by policy", method);
+            return Err(BrokerError::Disabled(method.to_string()));
+        }
+        Ok(())
+    }
+}
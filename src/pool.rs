@@ -0,0 +1,102 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+use serde::{Deserialize, Serialize};
+use std::io::{self, ErrorKind};
+use std::os::fd::AsRawFd;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::net::UnixStream;
+use tracing::debug;
+
+/// Reuse of daemon socket connections by the session broker.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct PoolConfig {
+    /// The most idle connections kept open. Zero disables pooling.
+    pub max_idle: usize,
+    /// Seconds an idle connection is kept before it is closed.
+    pub idle_timeout: u64,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            max_idle: 4,
+            idle_timeout: 30,
+        }
+    }
+}
+
+/* The daemon serves requests on a connection one at a time, so a connection
+ * is taken out of the pool for the duration of a request and only returned
+ * once the final response has been read.
+ */
+pub(crate) struct ConnectionPool<S = UnixStream> {
+    config: PoolConfig,
+    idle: Mutex<Vec<(S, Instant)>>,
+}
+
+impl<S: AsRawFd> ConnectionPool<S> {
+    pub(crate) fn new(config: PoolConfig) -> Self {
+        ConnectionPool {
+            config,
+            idle: Mutex::new(vec![]),
+        }
+    }
+
+    /// Take the most recently used idle connection which is still open,
+    /// closing any which have expired or been closed by the daemon.
+    pub(crate) fn take(&self) -> Option<S> {
+        let mut idle = self.idle.lock().ok()?;
+        let idle_timeout = Duration::from_secs(self.config.idle_timeout);
+        idle.retain(|(_, since)| since.elapsed() < idle_timeout);
+        while let Some((stream, _)) = idle.pop() {
+            if is_alive(&stream) {
+                return Some(stream);
+            }
+            debug!("Dropping closed pooled connection");
+        }
+        None
+    }
+
+    /// Return a connection after its request completed.
+    pub(crate) fn put(&self, stream: S) {
+        if let Ok(mut idle) = self.idle.lock() {
+            if idle.len() < self.config.max_idle {
+                idle.push((stream, Instant::now()));
+            }
+        }
+    }
+}
+
+/* An idle connection has nothing to read. A read of zero bytes means the
+ * daemon hung up, and unsolicited data means the stream is out of step with
+ * the protocol, so either way the connection cannot be reused.
+ */
+fn is_alive(stream: &impl AsRawFd) -> bool {
+    let mut buf = [0u8; 1];
+    let res = unsafe {
+        libc::recv(
+            stream.as_raw_fd(),
+            buf.as_mut_ptr() as *mut libc::c_void,
+            buf.len(),
+            libc::MSG_PEEK | libc::MSG_DONTWAIT,
+        )
+    };
+    res < 0 && io::Error::last_os_error().kind() == ErrorKind::WouldBlock
+}
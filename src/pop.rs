@@ -0,0 +1,189 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::shr::PopParams;
+use crate::thumbprint::Thumbprint;
+use openssl::base64;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+/* A proof-of-possession (PoP) token is bound to a key held by the device.
+ * The token request names the key as `req_cnf`, the base64url encoding of
+ * `{"kid": "<key id>"}`, and Entra ID is asked for `token_type=pop`. The
+ * token is then presented to the resource inside a signed HTTP request
+ * (SHR) for the resource request described by `popParams`.
+ */
+/// An acquireTokenSilently or acquireTokenInteractively request for a PoP
+/// token, with `"tokenType": "pop"` in its authParameters.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PopTokenRequest {
+    /// The `reqCnf` authentication parameter. Unset when the key is left
+    /// to the device broker.
+    pub req_cnf: Option<String>,
+    /// The resource request the token will be presented with.
+    pub pop_params: PopParams,
+}
+
+impl PopTokenRequest {
+    pub fn from_request(request_json: &str) -> Option<Self> {
+        let request: Value = serde_json::from_str(request_json).ok()?;
+        let params = request.get("authParameters")?;
+        let token_type = params.get("tokenType")?.as_str()?;
+        if !token_type.eq_ignore_ascii_case("pop") {
+            return None;
+        }
+        let pop_params = match params.get("popParams") {
+            Some(pop_params) => {
+                serde_json::from_value(pop_params.clone()).ok()?
+            }
+            None => PopParams::default(),
+        };
+        Some(PopTokenRequest {
+            req_cnf: params
+                .get("reqCnf")
+                .and_then(Value::as_str)
+                .map(str::to_string),
+            pop_params,
+        })
+    }
+
+    /// `request_json` with its `reqCnf` and `popParams` authentication
+    /// parameters replaced by these.
+    pub fn apply(&self, request_json: &str) -> Result<String, String> {
+        let mut request: Map<String, Value> =
+            serde_json::from_str(request_json).map_err(|e| e.to_string())?;
+        let params = request
+            .entry("authParameters")
+            .or_insert_with(|| Value::Object(Map::new()))
+            .as_object_mut()
+            .ok_or("authParameters is not an object")?;
+        params.insert("tokenType".to_string(), "pop".into());
+        match &self.req_cnf {
+            Some(req_cnf) => {
+                params.insert("reqCnf".to_string(), req_cnf.as_str().into())
+            }
+            None => params.remove("reqCnf"),
+        };
+        params.insert(
+            "popParams".to_string(),
+            serde_json::to_value(&self.pop_params)
+                .map_err(|e| e.to_string())?,
+        );
+        Ok(Value::Object(request).to_string())
+    }
+}
+
+/// The key a PoP token is bound to, sent to Entra ID as `req_cnf`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RequestConfirmation {
+    pub kid: String,
+}
+
+impl RequestConfirmation {
+    pub fn new(kid: impl Into<String>) -> Self {
+        RequestConfirmation { kid: kid.into() }
+    }
+
+    /// The confirmation for the key with this (SHA-256 JWK) thumbprint.
+    pub fn for_thumbprint(thumbprint: &Thumbprint) -> Self {
+        RequestConfirmation::new(thumbprint.to_base64url())
+    }
+
+    /// The `req_cnf` parameter: this confirmation as unpadded base64url
+    /// JSON.
+    pub fn encode(&self) -> String {
+        let json = json!({ "kid": self.kid }).to_string();
+        base64::encode_block(json.as_bytes())
+            .trim_end_matches('=')
+            .replace('+', "-")
+            .replace('/', "_")
+    }
+
+    pub fn decode(req_cnf: &str) -> Option<Self> {
+        let mut b64 = req_cnf
+            .trim_end_matches('=')
+            .replace('-', "+")
+            .replace('_', "/");
+        while !b64.len().is_multiple_of(4) {
+            b64.push('=');
+        }
+        serde_json::from_slice(&base64::decode_block(&b64).ok()?).ok()
+    }
+}
+
+/// The request_json of DeviceBroker1.getRequestConfirmation, asking for
+/// the `req_cnf` of the device's PoP key.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct RequestConfirmationRequest {
+    /// The home account id of the account the token is for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub home_account_id: Option<String>,
+}
+
+/// The response to DeviceBroker1.getRequestConfirmation.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct RequestConfirmationResponse {
+    pub req_cnf: String,
+}
+
+/// The request_json of DeviceBroker1.mintSignedHttpRequest, asking for an
+/// SHR presenting `access_token` for the resource request in
+/// `pop_params`, signed by the key named by `req_cnf`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct SignedHttpRequestRequest {
+    pub access_token: String,
+    pub req_cnf: String,
+    pub pop_params: PopParams,
+}
+
+/// The response to DeviceBroker1.mintSignedHttpRequest.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct SignedHttpRequestResponse {
+    pub signed_http_request: String,
+}
+
+/// The access token of a brokerTokenResponse.
+pub fn response_access_token(response_json: &str) -> Option<String> {
+    let response: Value = serde_json::from_str(response_json).ok()?;
+    response
+        .get("brokerTokenResponse")?
+        .get("accessToken")?
+        .as_str()
+        .map(str::to_string)
+}
+
+/// A brokerTokenResponse with its access token replaced by the SHR which
+/// presents it, and its `tokenType` set to `pop`.
+pub fn pop_token_response(
+    response_json: &str,
+    signed_http_request: &str,
+) -> Result<String, String> {
+    let mut response: Map<String, Value> =
+        serde_json::from_str(response_json).map_err(|e| e.to_string())?;
+    let token = response
+        .get_mut("brokerTokenResponse")
+        .and_then(Value::as_object_mut)
+        .ok_or("The response has no brokerTokenResponse")?;
+    token.insert("accessToken".to_string(), signed_http_request.into());
+    token.insert("tokenType".to_string(), "pop".into());
+    Ok(Value::Object(response).to_string())
+}
@@ -0,0 +1,468 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::accounts::Account;
+use crate::error::BrokerError;
+use async_trait::async_trait;
+use dbus::arg::{PropMap, RefArg, Variant};
+use dbus::blocking::Connection;
+use dbus::message::MatchRule;
+use libc::{pid_t, uid_t};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::os::fd::OwnedFd;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tracing::{debug, error};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub enum PromptKind {
+    /// Simple confirmation that the user is present at the session. The
+    /// answer is advisory: any process of the user could give it.
+    UserPresence,
+    /// Verification of the user via their PIN or password.
+    Pin,
+    /// Verification of the user via a biometric device.
+    Biometric,
+}
+
+/// A prompt sent from the himmelblau daemon to the session broker while an
+/// interactive flow is in progress.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub struct PromptRequest {
+    pub kind: PromptKind,
+    pub correlation_id: String,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub struct PromptResponse {
+    pub accepted: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub enum FdKind {
+    /// A pipe, for example carrying a browser's output.
+    Pipe,
+    /// The controller side of a pseudo terminal.
+    Pty,
+    /// A connection to a display server for a browser window.
+    Display,
+}
+
+/// Sent from the himmelblau daemon to the session broker with a file
+/// descriptor attached (as SCM_RIGHTS ancillary data) while an interactive
+/// flow is in progress.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub struct FdHandoff {
+    pub kind: FdKind,
+    pub correlation_id: String,
+}
+
+/// Handed to `HimmelblauBroker::acquire_token_interactively_with_prompt` so
+/// that the daemon can drive user-facing UI in the caller's session.
+#[async_trait]
+pub trait Prompter: Send {
+    async fn prompt(
+        &mut self,
+        request: PromptRequest,
+    ) -> Result<PromptResponse, BrokerError>;
+
+    /// Pass a file descriptor to the session broker, which hands it to its
+    /// `PromptHandler`.
+    async fn pass_fd(
+        &mut self,
+        _handoff: FdHandoff,
+        _fd: OwnedFd,
+    ) -> Result<(), BrokerError> {
+        Err("File descriptor passing is not supported".into())
+    }
+}
+
+/// Sent to `PromptHandler::choose_account` when a silent token request
+/// names no account and several of the user's accounts could answer it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AccountChoiceRequest {
+    pub correlation_id: String,
+    /// The client id of the requesting application.
+    pub client_id: Option<String>,
+    pub accounts: Vec<Account>,
+}
+
+/// Session side handler which presents a `PromptRequest` to the user.
+pub trait PromptHandler: Send + Sync {
+    fn prompt(
+        &self,
+        request: &PromptRequest,
+    ) -> Result<PromptResponse, Box<dyn Error>>;
+
+    /// Ask the user which of `request.accounts` to use, returning the home
+    /// account id of their choice, or None to send the request without an
+    /// account. By default no account is chosen.
+    fn choose_account(
+        &self,
+        _request: &AccountChoiceRequest,
+    ) -> Result<Option<String>, Box<dyn Error>> {
+        Ok(None)
+    }
+
+    /// Take ownership of a file descriptor passed by the daemon. By default
+    /// the descriptor is closed.
+    fn handle_fd(
+        &self,
+        handoff: &FdHandoff,
+        _fd: OwnedFd,
+    ) -> Result<(), Box<dyn Error>> {
+        Err(format!("Unhandled {:?} file descriptor", handoff.kind).into())
+    }
+}
+
+/// The polkit action a PIN or biometric prompt authenticates the user for
+/// by default.
+pub const AUTHENTICATE_ACTION: &str = "org.himmelblau.broker.authenticate";
+
+/// Verifies the user behind a PIN or biometric prompt from within the
+/// daemon, given the pid and uid of the session broker's process. Closures
+/// taking the request, pid and uid implement this.
+pub trait PromptVerifier: Send + Sync {
+    fn verify(
+        &self,
+        request: &PromptRequest,
+        pid: pid_t,
+        uid: uid_t,
+    ) -> Result<bool, BrokerError>;
+}
+
+impl<F> PromptVerifier for F
+where
+    F: Fn(&PromptRequest, pid_t, uid_t) -> Result<bool, BrokerError>
+        + Send
+        + Sync,
+{
+    fn verify(
+        &self,
+        request: &PromptRequest,
+        pid: pid_t,
+        uid: uid_t,
+    ) -> Result<bool, BrokerError> {
+        self(request, pid, uid)
+    }
+}
+
+/* The session broker's process is named to polkit as a unix-process
+ * subject, with its uid and its start time read from /proc, so that the
+ * check cannot be transferred to another process reusing its pid. polkit
+ * then asks the user's agent in that session to authenticate the user.
+ */
+/// Verifies prompts by asking polkit to authorize the session broker's
+/// process for `action_id`.
+#[derive(Debug, Clone)]
+pub struct PolkitPromptVerifier {
+    pub action_id: String,
+    /// How long to wait for polkit, including the user's authentication.
+    pub timeout: Duration,
+}
+
+impl Default for PolkitPromptVerifier {
+    fn default() -> Self {
+        PolkitPromptVerifier {
+            action_id: AUTHENTICATE_ACTION.to_string(),
+            timeout: Duration::from_secs(120),
+        }
+    }
+}
+
+impl PromptVerifier for PolkitPromptVerifier {
+    fn verify(
+        &self,
+        _request: &PromptRequest,
+        pid: pid_t,
+        uid: uid_t,
+    ) -> Result<bool, BrokerError> {
+        let start_time = process_start_time(pid)?;
+        let c = Connection::new_system().map_err(BrokerError::unexpected)?;
+        let mut details: PropMap = HashMap::new();
+        details.insert(
+            "pid".to_string(),
+            Variant(Box::new(pid as u32) as Box<dyn RefArg>),
+        );
+        details.insert(
+            "start-time".to_string(),
+            Variant(Box::new(start_time) as Box<dyn RefArg>),
+        );
+        details.insert(
+            "uid".to_string(),
+            Variant(Box::new(uid as i32) as Box<dyn RefArg>),
+        );
+        let subject = ("unix-process", details);
+        let proxy = c.with_proxy(
+            "org.freedesktop.PolicyKit1",
+            "/org/freedesktop/PolicyKit1/Authority",
+            self.timeout,
+        );
+        // Flag 0x1 is AllowUserInteraction, which invokes the polkit agent.
+        let ((authorized, _challenge, _),): ((
+            bool,
+            bool,
+            HashMap<String, String>,
+        ),) = proxy
+            .method_call(
+                "org.freedesktop.PolicyKit1.Authority",
+                "CheckAuthorization",
+                (
+                    subject,
+                    self.action_id.as_str(),
+                    HashMap::<String, String>::new(),
+                    1u32,
+                    "",
+                ),
+            )
+            .map_err(|e| {
+                BrokerError::unexpected(format!(
+                    "Failed to check polkit authorization: {}",
+                    e
+                ))
+            })?;
+        Ok(authorized)
+    }
+}
+
+/// The start time of `pid` in clock ticks since boot, the 22nd field of
+/// /proc/<pid>/stat.
+fn process_start_time(pid: pid_t) -> Result<u64, BrokerError> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid))?;
+    // The command name may itself hold spaces and parentheses.
+    stat.rsplit_once(')')
+        .and_then(|(_, fields)| fields.split_whitespace().nth(19))
+        .and_then(|start| start.parse().ok())
+        .ok_or_else(|| {
+            BrokerError::unexpected(format!(
+                "Unable to read the start time of pid {}",
+                pid
+            ))
+        })
+}
+
+/* A promptResponse comes from the user's own session broker, so any
+ * process running as the user could answer in its place. By default the
+ * daemon therefore verifies PIN and biometric prompts itself, and only
+ * presence confirmations, which are advisory, are sent to the session.
+ */
+/// How the daemon answers PIN and biometric prompts (see
+/// `HimmelblauBrokerConfig::prompt_verification`).
+#[derive(Clone)]
+pub enum PromptVerification {
+    /// The daemon verifies the user itself, by default through polkit.
+    Daemon(Arc<dyn PromptVerifier>),
+    /// The prompts are sent to the session broker, whose answer is trusted.
+    /// This is advisory only, and no authentication of the user.
+    Session,
+}
+
+impl Default for PromptVerification {
+    fn default() -> Self {
+        PromptVerification::Daemon(Arc::new(PolkitPromptVerifier::default()))
+    }
+}
+
+impl fmt::Debug for PromptVerification {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PromptVerification::Daemon(_) => f.write_str("Daemon(..)"),
+            PromptVerification::Session => f.write_str("Session"),
+        }
+    }
+}
+
+/* The PortalPromptHandler presents prompts using the desktop's own UI. User
+ * presence is confirmed by a notification with Allow and Deny actions, and
+ * only the Allow action counts as confirmation. PIN and biometric
+ * verification are delegated to the user's polkit agent, which
+ * authenticates via PAM (and therefore fprintd, when configured). The
+ * daemon only sends these with `PromptVerification::Session`, as it
+ * otherwise verifies them itself.
+ */
+pub struct PortalPromptHandler {
+    pub polkit_action_id: String,
+    pub timeout: Duration,
+}
+
+impl Default for PortalPromptHandler {
+    fn default() -> Self {
+        PortalPromptHandler {
+            polkit_action_id: AUTHENTICATE_ACTION.to_string(),
+            timeout: Duration::from_secs(120),
+        }
+    }
+}
+
+const NOTIFICATIONS: &str = "org.freedesktop.Notifications";
+
+impl PortalPromptHandler {
+    fn notification_confirm(
+        &self,
+        request: &PromptRequest,
+    ) -> Result<PromptResponse, Box<dyn Error>> {
+        let c = Connection::new_session()?;
+        let proxy = c.with_proxy(
+            NOTIFICATIONS,
+            "/org/freedesktop/Notifications",
+            self.timeout,
+        );
+        // Without actions the notification could only be dismissed, which
+        // must not be taken as the user's confirmation.
+        let (capabilities,): (Vec<String>,) =
+            proxy.method_call(NOTIFICATIONS, "GetCapabilities", ())?;
+        if !capabilities.iter().any(|cap| cap == "actions") {
+            return Err(
+                "The notification server does not support actions".into()
+            );
+        }
+
+        // The action chosen for each notification, or None when it was
+        // closed without one.
+        let events = Arc::new(Mutex::new(Vec::<(u32, Option<String>)>::new()));
+        let invoked = events.clone();
+        c.add_match(
+            MatchRule::new_signal(NOTIFICATIONS, "ActionInvoked"),
+            move |(id, action): (u32, String), _, _| {
+                if let Ok(mut events) = invoked.lock() {
+                    events.push((id, Some(action)));
+                }
+                true
+            },
+        )?;
+        let closed = events.clone();
+        c.add_match(
+            MatchRule::new_signal(NOTIFICATIONS, "NotificationClosed"),
+            move |(id, _reason): (u32, u32), _, _| {
+                if let Ok(mut events) = closed.lock() {
+                    events.push((id, None));
+                }
+                true
+            },
+        )?;
+
+        let mut hints: PropMap = HashMap::new();
+        // Critical notifications stay on screen until the user answers.
+        hints.insert(
+            "urgency".to_string(),
+            Variant(Box::new(2u8) as Box<dyn RefArg>),
+        );
+        let expire = i32::try_from(self.timeout.as_millis()).unwrap_or(-1);
+        let (id,): (u32,) = proxy.method_call(
+            NOTIFICATIONS,
+            "Notify",
+            (
+                "Himmelblau",
+                0u32,
+                "dialog-password",
+                "Confirm sign in",
+                request.message.as_str(),
+                vec!["allow", "Allow", "deny", "Deny"],
+                hints,
+                expire,
+            ),
+        )?;
+
+        let start = SystemTime::now();
+        loop {
+            let event = events
+                .lock()
+                .map_err(|e| e.to_string())?
+                .iter()
+                .find(|(event_id, _)| *event_id == id)
+                .map(|(_, action)| action.clone());
+            if let Some(action) = event {
+                debug!("Presence prompt completed with {:?}", action);
+                return Ok(PromptResponse {
+                    accepted: action.as_deref() == Some("allow"),
+                });
+            }
+            if SystemTime::now().duration_since(start)? > self.timeout {
+                error!("Presence prompt timed out");
+                let _: Result<(), _> = proxy.method_call(
+                    NOTIFICATIONS,
+                    "CloseNotification",
+                    (id,),
+                );
+                return Ok(PromptResponse { accepted: false });
+            }
+            c.process(Duration::from_millis(500))?;
+        }
+    }
+
+    /* The session broker names itself to polkit by its system bus name, so
+     * that polkit resolves the process and its owner from the bus rather
+     * than from a pid which could since have been reused.
+     */
+    fn polkit_authenticate(&self) -> Result<PromptResponse, Box<dyn Error>> {
+        let c = Connection::new_system()?;
+        let mut details: PropMap = HashMap::new();
+        details.insert(
+            "name".to_string(),
+            Variant(Box::new(c.unique_name().to_string()) as Box<dyn RefArg>),
+        );
+        let subject = ("system-bus-name", details);
+        let proxy = c.with_proxy(
+            "org.freedesktop.PolicyKit1",
+            "/org/freedesktop/PolicyKit1/Authority",
+            self.timeout,
+        );
+        // Flag 0x1 is AllowUserInteraction, which invokes the polkit agent.
+        let ((authorized, _challenge, _),): ((
+            bool,
+            bool,
+            HashMap<String, String>,
+        ),) = proxy.method_call(
+            "org.freedesktop.PolicyKit1.Authority",
+            "CheckAuthorization",
+            (
+                subject,
+                self.polkit_action_id.as_str(),
+                HashMap::<String, String>::new(),
+                1u32,
+                "",
+            ),
+        )?;
+        Ok(PromptResponse {
+            accepted: authorized,
+        })
+    }
+}
+
+impl PromptHandler for PortalPromptHandler {
+    fn prompt(
+        &self,
+        request: &PromptRequest,
+    ) -> Result<PromptResponse, Box<dyn Error>> {
+        match request.kind {
+            PromptKind::UserPresence => self.notification_confirm(request),
+            PromptKind::Pin | PromptKind::Biometric => {
+                self.polkit_authenticate()
+            }
+        }
+    }
+}
@@ -0,0 +1,169 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::broker_ext::BROKER1_METHODS;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+use std::fmt;
+use std::str::FromStr;
+
+/// The protocol_version argument of a Broker1 call, `<major>.<minor>`. A
+/// version without a minor (`1`) is read as `<major>.0`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ProtocolVersion {
+    pub major: u32,
+    pub minor: u32,
+}
+
+impl ProtocolVersion {
+    /// The version sent by current MSAL clients, and assumed for callers
+    /// which send none (such as portal and native messaging callers).
+    pub const V0_0: ProtocolVersion = ProtocolVersion::new(0, 0);
+    pub const V1_0: ProtocolVersion = ProtocolVersion::new(1, 0);
+
+    pub const fn new(major: u32, minor: u32) -> Self {
+        ProtocolVersion { major, minor }
+    }
+
+    /// What this version supports: the latest entry of
+    /// `supported_versions` with the same major version which is not newer
+    /// than it. None for unsupported major versions.
+    pub fn capabilities(&self) -> Option<&'static VersionCapabilities> {
+        supported_versions().iter().rev().find(|caps| {
+            caps.version.major == self.major && caps.version <= *self
+        })
+    }
+
+    /// Whether this version is the one named by `prefix`, or one of the
+    /// versions it prefixes (so `0` matches `0.1`).
+    pub fn matches(&self, prefix: &str) -> bool {
+        let prefix = prefix.trim();
+        match prefix.split_once('.') {
+            Some(_) => prefix.parse() == Ok(*self),
+            None => prefix.parse() == Ok(self.major),
+        }
+    }
+}
+
+impl Default for ProtocolVersion {
+    fn default() -> Self {
+        ProtocolVersion::V0_0
+    }
+}
+
+impl fmt::Display for ProtocolVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+/// The error returned when a protocol_version cannot be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidProtocolVersion(pub String);
+
+impl fmt::Display for InvalidProtocolVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid protocol version {:?}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidProtocolVersion {}
+
+impl FromStr for ProtocolVersion {
+    type Err = InvalidProtocolVersion;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidProtocolVersion(s.to_string());
+        let number = |n: &str| match n.bytes().all(|b| b.is_ascii_digit()) {
+            true => n.parse::<u32>().map_err(|_| invalid()),
+            false => Err(invalid()),
+        };
+        let (major, minor) = match s.trim().split_once('.') {
+            Some((major, minor)) => (number(major)?, number(minor)?),
+            None => (number(s.trim())?, 0),
+        };
+        Ok(ProtocolVersion::new(major, minor))
+    }
+}
+
+impl Serialize for ProtocolVersion {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for ProtocolVersion {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        String::deserialize(d)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// What a range of protocol versions supports, from `version` up to the
+/// next entry of `supported_versions`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionCapabilities {
+    pub version: ProtocolVersion,
+    /// The Broker1 methods callers of these versions may use.
+    pub methods: &'static [&'static str],
+    /// Whether requests may carry their authentication parameters flat at
+    /// the top level of request_json (see `is_flat_request`), as older MSAL
+    /// clients send them. Such requests are upgraded by daemons built with
+    /// the `legacy-protocol` feature.
+    pub flat_requests: bool,
+}
+
+impl VersionCapabilities {
+    pub fn supports(&self, method: &str) -> bool {
+        self.methods.contains(&method)
+    }
+}
+
+/* 0.x clients include the older MSAL releases which sent flat requests;
+ * 1.0 clients always nest their parameters under authParameters.
+ */
+const SUPPORTED_VERSIONS: [VersionCapabilities; 2] = [
+    VersionCapabilities {
+        version: ProtocolVersion::V0_0,
+        methods: &BROKER1_METHODS,
+        flat_requests: true,
+    },
+    VersionCapabilities {
+        version: ProtocolVersion::V1_0,
+        methods: &BROKER1_METHODS,
+        flat_requests: false,
+    },
+];
+
+/// The protocol versions understood by this crate, oldest first.
+pub fn supported_versions() -> &'static [VersionCapabilities] {
+    &SUPPORTED_VERSIONS
+}
+
+/// Whether `request_json` has the flat shape of older MSAL clients: a
+/// top-level clientId and no authParameters.
+pub fn is_flat_request(request_json: &str) -> bool {
+    match serde_json::from_str::<Value>(request_json) {
+        Ok(Value::Object(request)) => {
+            !request.contains_key("authParameters")
+                && request.contains_key("clientId")
+        }
+        _ => false,
+    }
+}
@@ -0,0 +1,346 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::client::Broker1Client;
+use crate::error::BrokerError;
+use crate::himmelblau_broker::{
+    himmelblau_broker_serve_with_config, HimmelblauBroker,
+    HimmelblauBrokerConfig,
+};
+use crate::socket::{SocketAddress, SocketPermissions, SocketTakeover};
+use async_trait::async_trait;
+use libc::uid_t;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::Receiver;
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info};
+
+/// Where `broker_proxy_serve` forwards the requests of container clients.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum ProxyUpstream {
+    /// Relay connections to the host's himmelblau daemon socket, unchanged.
+    Socket(SocketAddress),
+    /// Answer requests by calling the session broker on the host's session
+    /// bus (com.microsoft.identity.broker1).
+    SessionBus,
+}
+
+/// A range of uids, as seen by the proxy, which are served as the proxy's
+/// own user.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UidRange {
+    pub start: uid_t,
+    pub count: u32,
+}
+
+impl UidRange {
+    pub fn contains(&self, uid: uid_t) -> bool {
+        uid >= self.start && uid - self.start < self.count
+    }
+}
+
+/// Configuration of the container proxy (see `broker_proxy_serve`).
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProxyConfig {
+    /// The socket container clients connect to, typically in a directory
+    /// shared with the container.
+    pub listen: SocketAddress,
+    pub upstream: ProxyUpstream,
+    /// Other users which may use the proxy, such as the host uid a rootless
+    /// container's user is mapped to. The proxy's own user always may.
+    pub uid_map: Vec<UidRange>,
+    /// Seconds to wait for the session broker to answer a request.
+    pub timeout: u64,
+}
+
+impl ProxyConfig {
+    pub fn new(
+        listen: impl Into<SocketAddress>,
+        upstream: ProxyUpstream,
+    ) -> Self {
+        ProxyConfig {
+            listen: listen.into(),
+            upstream,
+            uid_map: vec![],
+            timeout: 300,
+        }
+    }
+
+    fn permits(&self, uid: uid_t) -> bool {
+        uid == unsafe { libc::geteuid() }
+            || self.uid_map.iter().any(|range| range.contains(uid))
+    }
+}
+
+/* A container's users are usually mapped to uids on the host which own no
+ * Entra ID accounts, so the daemon would not recognise them. The proxy runs
+ * as the host user instead, and whichever upstream it uses acts for that
+ * user. Its socket is open to everyone (containers may not share the host
+ * user's uid or groups), so the uid map alone decides which container users
+ * may borrow that identity; anyone else is refused.
+ */
+/// Serve container clients on `config.listen`, forwarding their requests
+/// to `config.upstream`, until `shutdown_rx` fires.
+pub async fn broker_proxy_serve(
+    config: ProxyConfig,
+    shutdown_rx: Receiver<bool>,
+) -> Result<JoinHandle<()>, Box<dyn Error>> {
+    match config.upstream.clone() {
+        ProxyUpstream::Socket(upstream) => {
+            relay_serve(config, upstream.expand_specifiers()?, shutdown_rx)
+                .await
+        }
+        ProxyUpstream::SessionBus => {
+            let broker = SessionBusBroker {
+                client: Broker1Client::session(Duration::from_secs(
+                    config.timeout,
+                ))?,
+                config: Arc::new(config.clone()),
+            };
+            himmelblau_broker_serve_with_config(
+                broker,
+                config.listen,
+                shutdown_rx,
+                HimmelblauBrokerConfig::default(),
+            )
+            .await
+        }
+    }
+}
+
+/* The daemon protocol is passed through byte for byte, so container session
+ * brokers negotiate wire formats and subscriptions with the daemon itself.
+ * File descriptors handed over by the daemon are not relayed.
+ */
+async fn relay_serve(
+    config: ProxyConfig,
+    upstream: SocketAddress,
+    mut shutdown_rx: Receiver<bool>,
+) -> Result<JoinHandle<()>, Box<dyn Error>> {
+    let listen = config.listen.expand_specifiers()?;
+    let (listener, socket_file) = listen
+        .bind(&SocketPermissions::default(), SocketTakeover::default())
+        .map_err(|e| {
+            error!("Failed to bind UNIX socket at {}", listen);
+            Box::new(e)
+        })?;
+    info!("Relaying {} to {}", listen, upstream);
+    Ok(tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = shutdown_rx.recv() => {
+                    if let Some(socket_file) = &socket_file {
+                        socket_file.remove();
+                    }
+                    break;
+                }
+                accept_res = listener.accept() => {
+                    let mut client = match accept_res {
+                        Ok((client, _)) => client,
+                        Err(e) => {
+                            error!("Error while handling connection -> {:?}", e);
+                            continue;
+                        }
+                    };
+                    match client.peer_cred() {
+                        Ok(cred) if config.permits(cred.uid()) => {}
+                        Ok(cred) => {
+                            error!("Refusing connection from uid {}", cred.uid());
+                            continue;
+                        }
+                        Err(e) => {
+                            error!("Unable to verify peer credentials: {:?}", e);
+                            continue;
+                        }
+                    }
+                    let upstream = upstream.clone();
+                    tokio::spawn(async move {
+                        let mut daemon = match upstream.connect().await {
+                            Ok(daemon) => daemon,
+                            Err(e) => {
+                                error!("Failed to connect to {} -> {:?}", upstream, e);
+                                return;
+                            }
+                        };
+                        match tokio::io::copy_bidirectional(&mut client, &mut daemon).await {
+                            Ok((sent, received)) => debug!(
+                                "Relayed {} bytes to and {} bytes from the daemon",
+                                sent, received
+                            ),
+                            Err(e) => debug!("Relay ended -> {:?}", e),
+                        }
+                    });
+                }
+            }
+        }
+    }))
+}
+
+/// Answers daemon protocol requests by calling the host's session broker.
+#[derive(Clone)]
+struct SessionBusBroker {
+    client: Broker1Client,
+    config: Arc<ProxyConfig>,
+}
+
+impl SessionBusBroker {
+    async fn call(
+        &self,
+        method: &str,
+        uid: uid_t,
+        args: (String, String, String),
+    ) -> Result<String, BrokerError> {
+        if !self.config.permits(uid) {
+            error!("Refusing {} from uid {}", method, uid);
+            return Err(BrokerError::InvalidRequest(format!(
+                "uid {} may not use this proxy",
+                uid
+            )));
+        }
+        let (protocol_version, correlation_id, request_json) = args;
+        self.client
+            .call(method, &protocol_version, &correlation_id, &request_json)
+            .await
+    }
+}
+
+#[async_trait]
+impl HimmelblauBroker for SessionBusBroker {
+    async fn acquire_token_interactively(
+        &mut self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        uid: uid_t,
+    ) -> Result<String, BrokerError> {
+        self.call(
+            "acquireTokenInteractively",
+            uid,
+            (protocol_version, correlation_id, request_json),
+        )
+        .await
+    }
+
+    async fn acquire_token_silently(
+        &mut self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        uid: uid_t,
+    ) -> Result<String, BrokerError> {
+        self.call(
+            "acquireTokenSilently",
+            uid,
+            (protocol_version, correlation_id, request_json),
+        )
+        .await
+    }
+
+    async fn get_accounts(
+        &mut self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        uid: uid_t,
+    ) -> Result<String, BrokerError> {
+        self.call(
+            "getAccounts",
+            uid,
+            (protocol_version, correlation_id, request_json),
+        )
+        .await
+    }
+
+    async fn remove_account(
+        &mut self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        uid: uid_t,
+    ) -> Result<String, BrokerError> {
+        self.call(
+            "removeAccount",
+            uid,
+            (protocol_version, correlation_id, request_json),
+        )
+        .await
+    }
+
+    async fn acquire_prt_sso_cookie(
+        &mut self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        uid: uid_t,
+    ) -> Result<String, BrokerError> {
+        self.call(
+            "acquirePrtSsoCookie",
+            uid,
+            (protocol_version, correlation_id, request_json),
+        )
+        .await
+    }
+
+    async fn generate_signed_http_request(
+        &mut self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        uid: uid_t,
+    ) -> Result<String, BrokerError> {
+        self.call(
+            "generateSignedHttpRequest",
+            uid,
+            (protocol_version, correlation_id, request_json),
+        )
+        .await
+    }
+
+    async fn cancel_interactive_flow(
+        &mut self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        uid: uid_t,
+    ) -> Result<String, BrokerError> {
+        self.call(
+            "cancelInteractiveFlow",
+            uid,
+            (protocol_version, correlation_id, request_json),
+        )
+        .await
+    }
+
+    async fn get_linux_broker_version(
+        &mut self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        uid: uid_t,
+    ) -> Result<String, BrokerError> {
+        self.call(
+            "getLinuxBrokerVersion",
+            uid,
+            (protocol_version, correlation_id, request_json),
+        )
+        .await
+    }
+}
@@ -0,0 +1,144 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+use serde_json::Value;
+use std::fmt;
+
+/// Fields of Broker1 payloads which carry credentials and must never be
+/// logged.
+const SECRET_FIELDS: &[&str] = &[
+    "accessToken",
+    "access_token",
+    "refreshToken",
+    "refresh_token",
+    "idToken",
+    "id_token",
+    "clientInfo",
+    "client_info",
+    "cookieContent",
+    "signedHttpRequest",
+    "prt",
+    "sessionKey",
+    "session_key",
+    "password",
+];
+
+fn redact_value(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, v) in map.iter_mut() {
+                if SECRET_FIELDS.contains(&key.as_str()) {
+                    let len = v.as_str().map(|s| s.len()).unwrap_or(0);
+                    *v = Value::String(format!("<redacted {} bytes>", len));
+                } else {
+                    redact_value(v);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(redact_value),
+        _ => (),
+    }
+}
+
+/// Returns a copy of a Broker1 request or response JSON with tokens and
+/// cookies replaced by a placeholder. Payloads which are not JSON are not
+/// reproduced at all.
+pub fn redact_json(json: &str) -> String {
+    match serde_json::from_str::<Value>(json) {
+        Ok(mut value) => {
+            redact_value(&mut value);
+            value.to_string()
+        }
+        Err(_) => format!("<non-JSON payload, {} bytes>", json.len()),
+    }
+}
+
+/// A safe-to-log summary of a Broker1 response.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ResponseSummary {
+    pub token_type: Option<String>,
+    pub expires_on: Option<String>,
+    pub scopes: Option<String>,
+    pub client_id: Option<String>,
+    pub accounts: Option<usize>,
+    pub has_cookie: bool,
+    pub error: Option<String>,
+}
+
+fn field(value: &Value, keys: &[&str]) -> Option<String> {
+    keys.iter().find_map(|key| match value.get(key)? {
+        Value::String(s) => Some(s.clone()),
+        Value::Null => None,
+        other => Some(other.to_string()),
+    })
+}
+
+/* Token responses are nested in a brokerTokenResponse envelope, while
+ * cookie, account and error responses sit at the top level. The summary
+ * looks in both places.
+ */
+pub fn summarize_response(json: &str) -> ResponseSummary {
+    let value: Value = match serde_json::from_str(json) {
+        Ok(value) => value,
+        Err(_) => return ResponseSummary::default(),
+    };
+    let token = value.get("brokerTokenResponse").unwrap_or(&value);
+    ResponseSummary {
+        token_type: field(token, &["accessTokenType", "token_type"]),
+        expires_on: field(token, &["expiresOn", "expires_on", "expires_in"]),
+        scopes: field(token, &["grantedScopes", "scope", "scopes"]),
+        client_id: field(token, &["clientId", "client_id"]),
+        accounts: value
+            .get("accounts")
+            .and_then(|a| a.as_array())
+            .map(|a| a.len()),
+        has_cookie: value.get("cookieContent").is_some(),
+        error: field(&value, &["error"]).or_else(|| field(token, &["error"])),
+    }
+}
+
+impl fmt::Display for ResponseSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = vec![];
+        if let Some(token_type) = &self.token_type {
+            parts.push(format!("token_type={}", token_type));
+        }
+        if let Some(expires_on) = &self.expires_on {
+            parts.push(format!("expires_on={}", expires_on));
+        }
+        if let Some(scopes) = &self.scopes {
+            parts.push(format!("scopes={}", scopes));
+        }
+        if let Some(client_id) = &self.client_id {
+            parts.push(format!("client_id={}", client_id));
+        }
+        if let Some(accounts) = self.accounts {
+            parts.push(format!("accounts={}", accounts));
+        }
+        if self.has_cookie {
+            parts.push("cookie=<redacted>".to_string());
+        }
+        if let Some(error) = &self.error {
+            parts.push(format!("error={}", error));
+        }
+        if parts.is_empty() {
+            write!(f, "<no summary>")
+        } else {
+            write!(f, "{}", parts.join(" "))
+        }
+    }
+}
@@ -0,0 +1,213 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::error::BrokerError;
+use crate::himmelblau_broker::HimmelblauBroker;
+use crate::idle::IdleTimer;
+use async_trait::async_trait;
+use libc::uid_t;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn};
+
+/// A kind of artifact the daemon caches for its users.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "camelCase")]
+pub enum ArtifactKind {
+    /// A Primary Refresh Token.
+    Prt,
+    /// A PRT SSO cookie, as acquirePrtSsoCookie returns.
+    SsoCookie,
+}
+
+/// An artifact cached by the daemon, and when it expires.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CachedArtifact {
+    pub kind: ArtifactKind,
+    pub uid: uid_t,
+    /// Identifies the artifact to the broker, such as the home account id
+    /// it was issued to.
+    pub id: String,
+    pub expires_at: SystemTime,
+}
+
+/// When artifacts of one kind are refreshed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct ArtifactRefresh {
+    /// Seconds before it expires from which an artifact is refreshed.
+    pub refresh_before: u64,
+}
+
+impl Default for ArtifactRefresh {
+    fn default() -> Self {
+        ArtifactRefresh {
+            refresh_before: 30 * 60,
+        }
+    }
+}
+
+/// The refresh scheduler of the daemon (see
+/// `HimmelblauBrokerConfig::refresh`). An artifact kind left unset is not
+/// refreshed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct RefreshSchedulerConfig {
+    /// Seconds between looking for artifacts to refresh.
+    pub interval: u64,
+    /// Seconds without a request in progress after which the daemon counts
+    /// as idle. Refreshing stops as soon as requests arrive again.
+    pub idle_for: u64,
+    pub prt: Option<ArtifactRefresh>,
+    pub sso_cookie: Option<ArtifactRefresh>,
+}
+
+impl Default for RefreshSchedulerConfig {
+    fn default() -> Self {
+        RefreshSchedulerConfig {
+            interval: 60,
+            idle_for: 30,
+            prt: Some(ArtifactRefresh::default()),
+            sso_cookie: Some(ArtifactRefresh {
+                refresh_before: 5 * 60,
+            }),
+        }
+    }
+}
+
+impl RefreshSchedulerConfig {
+    /// The kinds of artifact to refresh, with how long before expiry.
+    fn artifacts(&self) -> Vec<(ArtifactKind, Duration)> {
+        [
+            (ArtifactKind::Prt, &self.prt),
+            (ArtifactKind::SsoCookie, &self.sso_cookie),
+        ]
+        .into_iter()
+        .filter_map(|(kind, refresh)| {
+            refresh
+                .as_ref()
+                .map(|r| (kind, Duration::from_secs(r.refresh_before)))
+        })
+        .collect()
+    }
+}
+
+/* Only the daemon knows what it has cached and how to renew it, so the
+ * scheduler asks the broker for both. Like PAM integration, this is a
+ * separate trait, and a HimmelblauBroker which refreshes ahead of expiry
+ * returns itself from `HimmelblauBroker::refresh`.
+ */
+#[async_trait]
+pub trait RefreshBroker {
+    /// The cached artifacts of `kind`, for every user.
+    async fn cached_artifacts(
+        &mut self,
+        kind: ArtifactKind,
+    ) -> Result<Vec<CachedArtifact>, BrokerError>;
+
+    /// Renew `artifact` and replace it in the cache.
+    async fn refresh_artifact(
+        &mut self,
+        artifact: &CachedArtifact,
+    ) -> Result<(), BrokerError>;
+}
+
+/// Whether `artifact` expires within `lead` of `now`.
+fn due(artifact: &CachedArtifact, now: SystemTime, lead: Duration) -> bool {
+    artifact
+        .expires_at
+        .duration_since(now)
+        .map(|left| left <= lead)
+        .unwrap_or(true)
+}
+
+/* Refreshing happens only while no client request is in progress and none
+ * has been for `idle_for`, so that it never competes with a user waiting
+ * on a token. Artifacts are refreshed soonest-expiring first, and a pass
+ * is abandoned when requests arrive, to be resumed on the next interval.
+ * The device resuming or reconnecting wakes the scheduler early (see
+ * `HimmelblauBrokerConfig::system_events`).
+ */
+pub(crate) async fn run_refresh_scheduler<T>(
+    mut broker: T,
+    config: RefreshSchedulerConfig,
+    idle: Arc<IdleTimer>,
+    wake: Arc<Notify>,
+    shutdown: CancellationToken,
+) where
+    T: HimmelblauBroker + Send,
+{
+    if broker.refresh().is_none() {
+        warn!(
+            "The broker does not refresh artifacts, not scheduling refreshes"
+        );
+        return;
+    }
+    let idle_for = Duration::from_secs(config.idle_for);
+    let mut interval =
+        tokio::time::interval(Duration::from_secs(config.interval.max(1)));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => break,
+            _ = interval.tick() => {}
+            _ = wake.notified() => {}
+        }
+        if !idle.idle_for(idle_for) {
+            continue;
+        }
+        let refresher = match broker.refresh() {
+            Some(refresher) => refresher,
+            None => break,
+        };
+        'pass: for (kind, lead) in config.artifacts() {
+            let mut artifacts = match refresher.cached_artifacts(kind).await {
+                Ok(artifacts) => artifacts,
+                Err(e) => {
+                    error!(
+                        "Failed to list cached {:?} artifacts -> {}",
+                        kind, e
+                    );
+                    continue;
+                }
+            };
+            let now = SystemTime::now();
+            artifacts.retain(|artifact| due(artifact, now, lead));
+            artifacts.sort_by_key(|artifact| artifact.expires_at);
+            for artifact in artifacts {
+                if shutdown.is_cancelled() || !idle.idle_for(idle_for) {
+                    debug!("Requests arrived, pausing refreshes");
+                    break 'pass;
+                }
+                match refresher.refresh_artifact(&artifact).await {
+                    Ok(()) => info!(
+                        "Refreshed {:?} {} of uid {} ahead of expiry",
+                        kind, artifact.id, artifact.uid
+                    ),
+                    Err(e) => error!(
+                        "Failed to refresh {:?} {} of uid {} -> {}",
+                        kind, artifact.id, artifact.uid, e
+                    ),
+                }
+            }
+        }
+    }
+}
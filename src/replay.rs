@@ -0,0 +1,190 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::error::BrokerError;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// What to do with a call repeating the correlation id of a recent call.
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default,
+)]
+pub enum ReplayAction {
+    /// Log the repeat and handle the call as usual.
+    #[default]
+    Log,
+    /// Answer a repeat of an identical call, from the same D-Bus
+    /// connection, with the earlier response. Other repeats are logged and
+    /// handled as usual.
+    Dedupe,
+    /// Fail the repeat with an InvalidRequest error.
+    Reject,
+}
+
+/// Detection of Broker1 calls which reuse the correlation id of an earlier
+/// call to the same method.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ReplayConfig {
+    /// Seconds for which a correlation id is remembered.
+    pub window: u64,
+    pub action: ReplayAction,
+}
+
+impl Default for ReplayConfig {
+    fn default() -> Self {
+        ReplayConfig {
+            window: 300,
+            action: ReplayAction::Log,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Hash)]
+struct CallKey {
+    method: String,
+    correlation_id: String,
+}
+
+struct SeenCall {
+    at: Instant,
+    sender: String,
+    request: u64,
+    response: Option<String>,
+}
+
+/* A call is remembered from the moment it is admitted, so that concurrent
+ * repeats are caught too, although only a completed call has a response to
+ * dedupe to. Repeats are detected whichever connection they come from, but
+ * a response is only ever handed back to the connection it was first sent
+ * to. Failed calls are forgotten, leaving clients free to retry them under
+ * the same correlation id. Responses are held for at most the window, and
+ * only when deduping.
+ */
+/// The correlation ids recently seen by a broker, checked as a
+/// `ReplayConfig` directs.
+pub struct ReplayGuard {
+    window: Duration,
+    action: ReplayAction,
+    seen: Mutex<HashMap<CallKey, SeenCall>>,
+}
+
+fn request_hash(request_json: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    request_json.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl ReplayGuard {
+    pub fn new(config: &ReplayConfig) -> Self {
+        ReplayGuard {
+            window: Duration::from_secs(config.window),
+            action: config.action,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Check a call before it is handled, returning the response to answer
+    /// it with when it is deduped.
+    pub fn check(
+        &self,
+        sender: &str,
+        method: &str,
+        correlation_id: &str,
+        request_json: &str,
+    ) -> Result<Option<String>, BrokerError> {
+        if correlation_id.is_empty() {
+            return Ok(None);
+        }
+        let mut seen = self.seen.lock().unwrap_or_else(PoisonError::into_inner);
+        seen.retain(|_, call| call.at.elapsed() < self.window);
+        let key = CallKey {
+            method: method.to_string(),
+            correlation_id: correlation_id.to_string(),
+        };
+        let request = request_hash(request_json);
+        let call = match seen.get(&key) {
+            Some(call) => call,
+            None => {
+                seen.insert(
+                    key,
+                    SeenCall {
+                        at: Instant::now(),
+                        sender: sender.to_string(),
+                        request,
+                        response: None,
+                    },
+                );
+                return Ok(None);
+            }
+        };
+        warn!(
+            "{} from {} repeats correlation id {}",
+            method, sender, correlation_id
+        );
+        match self.action {
+            ReplayAction::Log => Ok(None),
+            ReplayAction::Dedupe
+                if call.sender == sender && call.request == request =>
+            {
+                Ok(call.response.clone())
+            }
+            ReplayAction::Dedupe => Ok(None),
+            ReplayAction::Reject => Err(BrokerError::InvalidRequest(format!(
+                "Repeated correlation id {}",
+                correlation_id
+            ))),
+        }
+    }
+
+    /// Record the outcome of a call admitted by `check`.
+    pub fn complete<E>(
+        &self,
+        sender: &str,
+        method: &str,
+        correlation_id: &str,
+        res: &Result<String, E>,
+    ) {
+        if correlation_id.is_empty() {
+            return;
+        }
+        let mut seen = self.seen.lock().unwrap_or_else(PoisonError::into_inner);
+        let key = CallKey {
+            method: method.to_string(),
+            correlation_id: correlation_id.to_string(),
+        };
+        // Only the first call's outcome is recorded, not a repeat's.
+        match seen.get_mut(&key) {
+            Some(call) if call.sender == sender => match res {
+                Ok(resp) if self.action == ReplayAction::Dedupe => {
+                    call.response.get_or_insert_with(|| resp.clone());
+                }
+                Ok(_) => {}
+                Err(_) => {
+                    seen.remove(&key);
+                }
+            },
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,462 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::socket::SocketAddress;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+use tracing::{debug, info, warn};
+
+/// Capability names, indexed by their number.
+const CAPABILITIES: &[&str] = &[
+    "CAP_CHOWN",
+    "CAP_DAC_OVERRIDE",
+    "CAP_DAC_READ_SEARCH",
+    "CAP_FOWNER",
+    "CAP_FSETID",
+    "CAP_KILL",
+    "CAP_SETGID",
+    "CAP_SETUID",
+    "CAP_SETPCAP",
+    "CAP_LINUX_IMMUTABLE",
+    "CAP_NET_BIND_SERVICE",
+    "CAP_NET_BROADCAST",
+    "CAP_NET_ADMIN",
+    "CAP_NET_RAW",
+    "CAP_IPC_LOCK",
+    "CAP_IPC_OWNER",
+    "CAP_SYS_MODULE",
+    "CAP_SYS_RAWIO",
+    "CAP_SYS_CHROOT",
+    "CAP_SYS_PTRACE",
+    "CAP_SYS_PACCT",
+    "CAP_SYS_ADMIN",
+    "CAP_SYS_BOOT",
+    "CAP_SYS_NICE",
+    "CAP_SYS_RESOURCE",
+    "CAP_SYS_TIME",
+    "CAP_SYS_TTY_CONFIG",
+    "CAP_MKNOD",
+    "CAP_LEASE",
+    "CAP_AUDIT_WRITE",
+    "CAP_AUDIT_CONTROL",
+    "CAP_SETFCAP",
+    "CAP_MAC_OVERRIDE",
+    "CAP_MAC_ADMIN",
+    "CAP_SYSLOG",
+    "CAP_WAKE_ALARM",
+    "CAP_BLOCK_SUSPEND",
+    "CAP_AUDIT_READ",
+    "CAP_PERFMON",
+    "CAP_BPF",
+    "CAP_CHECKPOINT_RESTORE",
+];
+
+/* Landlock's ABI, from linux/landlock.h, which the libc crate does not
+ * carry. Each ABI version handles more filesystem access rights, and a
+ * ruleset may only handle the rights the running kernel knows of.
+ */
+const LANDLOCK_CREATE_RULESET_VERSION: libc::c_uint = 1;
+const LANDLOCK_RULE_PATH_BENEATH: libc::c_int = 1;
+const ACCESS_FS_EXECUTE: u64 = 1 << 0;
+const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+const ACCESS_FS_IOCTL_DEV: u64 = 1 << 15;
+/// Rights which apply to files, rather than to directory contents.
+const ACCESS_FS_FILE: u64 = ACCESS_FS_EXECUTE
+    | ACCESS_FS_WRITE_FILE
+    | ACCESS_FS_READ_FILE
+    | ACCESS_FS_TRUNCATE
+    | ACCESS_FS_IOCTL_DEV;
+const ACCESS_FS_READ: u64 = ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR;
+
+#[repr(C)]
+struct LandlockRulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct LandlockPathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x20080522;
+
+/// The filesystem rights handled by each Landlock ABI version.
+fn handled_access_fs(abi: u32) -> u64 {
+    match abi {
+        0 => 0,
+        // Execute through making symlinks.
+        1 => (1 << 13) - 1,
+        // Linking or renaming across directories.
+        2 => (1 << 14) - 1,
+        // Truncate. ABI 4 only added network rights.
+        3 | 4 => (1 << 15) - 1,
+        // ioctl on devices.
+        _ => (1 << 16) - 1,
+    }
+}
+
+/* Confinement is per thread: Landlock domains and capability sets only
+ * apply to the thread which sets them and the threads it starts later.
+ * `Sandbox::apply` therefore refuses to run once other threads exist, and
+ * must be called at the top of main, before the tokio runtime is built.
+ */
+/// Confinement of the broker process, limiting what a compromised broker
+/// could reach. Landlock restricts the filesystem to the paths allowed
+/// here, and every capability not kept is dropped.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct Sandbox {
+    /// Paths which may be read, such as the configuration directory.
+    pub read: Vec<PathBuf>,
+    /// Paths which may be read and written, such as the socket directory
+    /// and the cache.
+    pub read_write: Vec<PathBuf>,
+    /// Paths from which programs may be run, such as the bridge command of
+    /// a `SocketAddress::Bridge`.
+    pub execute: Vec<PathBuf>,
+    /// Capabilities kept by name (e.g. `CAP_CHOWN`). All others are dropped
+    /// from the permitted, effective, inheritable, ambient and bounding
+    /// sets.
+    pub keep_capabilities: Vec<String>,
+    /// Fail when the kernel lacks Landlock, rather than carrying on with
+    /// only the capabilities dropped.
+    pub require_landlock: bool,
+}
+
+/* What a broker daemon reads besides its own configuration: the user and
+ * group databases and the NSS modules resolving them, the dynamic loader's
+ * cache, name resolution and CA certificates for reaching Entra ID, time
+ * zones, and /proc, where callers' security labels (/proc/<pid>/attr),
+ * start times and the thread count are read.
+ */
+const DAEMON_READ: &[&str] = &[
+    "/etc/passwd",
+    "/etc/group",
+    "/etc/nsswitch.conf",
+    "/etc/ld.so.cache",
+    "/etc/hosts",
+    "/etc/resolv.conf",
+    "/etc/localtime",
+    "/etc/ssl",
+    "/etc/pki",
+    "/etc/ca-certificates",
+    "/run/systemd/resolve",
+    "/lib",
+    "/lib64",
+    "/usr/lib",
+    "/usr/lib64",
+    "/usr/share/ca-certificates",
+    "/usr/share/zoneinfo",
+    "/proc",
+    "/dev/urandom",
+];
+
+impl Sandbox {
+    pub fn new() -> Self {
+        Sandbox::default()
+    }
+
+    /// A sandbox allowing what a broker daemon reads on any system, such as
+    /// `/etc/passwd`, the NSS modules and `/proc`, to which the daemon's
+    /// configuration directory and cache should be added. Paths missing on
+    /// this system are left out.
+    pub fn daemon() -> Self {
+        Sandbox {
+            read: DAEMON_READ
+                .iter()
+                .map(PathBuf::from)
+                .filter(|path| path.exists())
+                .collect(),
+            read_write: vec![PathBuf::from("/dev/null")],
+            ..Default::default()
+        }
+    }
+
+    pub fn read(mut self, path: impl Into<PathBuf>) -> Self {
+        self.read.push(path.into());
+        self
+    }
+
+    pub fn read_write(mut self, path: impl Into<PathBuf>) -> Self {
+        self.read_write.push(path.into());
+        self
+    }
+
+    pub fn execute(mut self, path: impl Into<PathBuf>) -> Self {
+        self.execute.push(path.into());
+        self
+    }
+
+    /// Allow binding `address`, by allowing the directory of a socket
+    /// path. Other addresses need no filesystem access.
+    pub fn socket(self, address: &SocketAddress) -> Self {
+        match address {
+            SocketAddress::Path(path) => match path.parent() {
+                Some(parent) => self.read_write(parent),
+                None => self,
+            },
+            _ => self,
+        }
+    }
+
+    pub fn keep_capability(mut self, name: &str) -> Self {
+        self.keep_capabilities.push(name.to_string());
+        self
+    }
+
+    pub fn require_landlock(mut self) -> Self {
+        self.require_landlock = true;
+        self
+    }
+
+    /// Confine the calling process, returning the Landlock ABI version
+    /// enforced, or None when the kernel lacks Landlock. Fails if other
+    /// threads are already running, as they would escape confinement.
+    pub fn apply(&self) -> io::Result<Option<u32>> {
+        let threads = thread_count()?;
+        if threads > 1 {
+            return Err(io::Error::other(format!(
+                "The sandbox must be applied before other threads are \
+                 started, {} are running",
+                threads
+            )));
+        }
+        let keep = self.capability_mask()?;
+        drop_capabilities(keep)?;
+        // Keeps exec from regaining capabilities, and lets an unprivileged
+        // process enter a Landlock domain.
+        if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let abi = match landlock_abi() {
+            Some(abi) => abi,
+            None if self.require_landlock => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "The kernel does not support Landlock",
+                ))
+            }
+            None => {
+                warn!("The kernel does not support Landlock, not confining");
+                return Ok(None);
+            }
+        };
+        self.restrict(abi)?;
+        info!("Sandbox applied with Landlock ABI {}", abi);
+        Ok(Some(abi))
+    }
+
+    fn capability_mask(&self) -> io::Result<u64> {
+        let mut mask = 0;
+        for name in &self.keep_capabilities {
+            let name = name.to_ascii_uppercase();
+            let cap = CAPABILITIES
+                .iter()
+                .position(|c| *c == name || c[4..] == name)
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("Unknown capability {}", name),
+                    )
+                })?;
+            mask |= 1 << cap;
+        }
+        Ok(mask)
+    }
+
+    fn restrict(&self, abi: u32) -> io::Result<()> {
+        let handled = handled_access_fs(abi);
+        let attr = LandlockRulesetAttr {
+            handled_access_fs: handled,
+        };
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                &attr as *const LandlockRulesetAttr,
+                std::mem::size_of::<LandlockRulesetAttr>(),
+                0,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let ruleset = unsafe { OwnedFd::from_raw_fd(fd as libc::c_int) };
+        let rules = [
+            (&self.read, ACCESS_FS_READ),
+            (&self.read_write, handled),
+            (&self.execute, ACCESS_FS_READ | ACCESS_FS_EXECUTE),
+        ];
+        for (paths, access) in rules {
+            for path in paths {
+                add_path_rule(&ruleset, path, access & handled)?;
+            }
+        }
+        if unsafe {
+            libc::syscall(
+                libc::SYS_landlock_restrict_self,
+                ruleset.as_raw_fd(),
+                0,
+            )
+        } != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+fn thread_count() -> io::Result<usize> {
+    let status = fs::read_to_string("/proc/self/status")?;
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("Threads:"))
+        .and_then(|count| count.trim().parse().ok())
+        .ok_or_else(|| io::Error::other("No thread count in /proc/self/status"))
+}
+
+fn landlock_abi() -> Option<u32> {
+    let abi = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            std::ptr::null::<LandlockRulesetAttr>(),
+            0,
+            LANDLOCK_CREATE_RULESET_VERSION,
+        )
+    };
+    u32::try_from(abi).ok().filter(|abi| *abi > 0)
+}
+
+/* Paths which do not exist yet (a cache created on first use, say) are
+ * skipped, as Landlock rules are bound to the file opened here.
+ */
+fn add_path_rule(
+    ruleset: &OwnedFd,
+    path: &Path,
+    access: u64,
+) -> io::Result<()> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            warn!("Not allowing {}, it does not exist", path.display());
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+    let access = match file.metadata()?.is_dir() {
+        true => access,
+        false => access & ACCESS_FS_FILE,
+    };
+    let rule = LandlockPathBeneathAttr {
+        allowed_access: access,
+        parent_fd: file.as_raw_fd(),
+    };
+    debug!("Allowing {:#x} beneath {}", access, path.display());
+    if unsafe {
+        libc::syscall(
+            libc::SYS_landlock_add_rule,
+            ruleset.as_raw_fd(),
+            LANDLOCK_RULE_PATH_BENEATH,
+            &rule as *const LandlockPathBeneathAttr,
+            0,
+        )
+    } != 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/* The bounding set can only be reduced with CAP_SETPCAP. Without it (as an
+ * unprivileged broker) it is left alone, as no_new_privs already keeps exec
+ * from granting anything in it.
+ */
+fn drop_capabilities(keep: u64) -> io::Result<()> {
+    let last = fs::read_to_string("/proc/sys/kernel/cap_last_cap")
+        .ok()
+        .and_then(|last| last.trim().parse::<u32>().ok())
+        .unwrap_or(CAPABILITIES.len() as u32 - 1);
+    for cap in (0..=last).filter(|cap| keep & (1 << cap) == 0) {
+        let res = unsafe {
+            libc::prctl(libc::PR_CAPBSET_DROP, cap as libc::c_ulong, 0, 0, 0)
+        };
+        if res != 0 {
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() == Some(libc::EPERM) {
+                debug!("Leaving the capability bounding set -> {}", e);
+                break;
+            }
+            return Err(e);
+        }
+    }
+    // Fails only on kernels predating ambient capabilities, which then
+    // have none to clear.
+    unsafe {
+        libc::prctl(
+            libc::PR_CAP_AMBIENT,
+            libc::PR_CAP_AMBIENT_CLEAR_ALL,
+            0,
+            0,
+            0,
+        )
+    };
+
+    let mut header = CapHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let mut data = [CapData::default(); 2];
+    if unsafe {
+        libc::syscall(libc::SYS_capget, &mut header, data.as_mut_ptr())
+    } != 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+    for (i, set) in data.iter_mut().enumerate() {
+        let keep = (keep >> (32 * i)) as u32;
+        set.effective &= keep;
+        set.permitted &= keep;
+        set.inheritable &= keep;
+    }
+    if unsafe { libc::syscall(libc::SYS_capset, &mut header, data.as_ptr()) }
+        != 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -0,0 +1,124 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+/// The JSON schema of the request_json argument of a Broker1 method, as
+/// shipped in the crate's schemas/ directory.
+pub fn request_schema(method: &str) -> Option<&'static str> {
+    match method {
+        "acquireTokenInteractively" => {
+            Some(include_str!("../schemas/acquireTokenInteractively.json"))
+        }
+        "acquireTokenSilently" => {
+            Some(include_str!("../schemas/acquireTokenSilently.json"))
+        }
+        "getAccounts" => Some(include_str!("../schemas/getAccounts.json")),
+        "removeAccount" => Some(include_str!("../schemas/removeAccount.json")),
+        "acquirePrtSsoCookie" => {
+            Some(include_str!("../schemas/acquirePrtSsoCookie.json"))
+        }
+        "generateSignedHttpRequest" => {
+            Some(include_str!("../schemas/generateSignedHttpRequest.json"))
+        }
+        "cancelInteractiveFlow" => {
+            Some(include_str!("../schemas/cancelInteractiveFlow.json"))
+        }
+        "getLinuxBrokerVersion" => {
+            Some(include_str!("../schemas/getLinuxBrokerVersion.json"))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(feature = "schema-validation")]
+pub use validation::RequestValidator;
+
+#[cfg(feature = "schema-validation")]
+mod validation {
+    use super::request_schema;
+    use serde_json::Value;
+    use std::collections::HashMap;
+    use std::error::Error;
+
+    /// Validates request_json payloads against the shipped schemas.
+    pub struct RequestValidator {
+        validators: HashMap<&'static str, jsonschema::Validator>,
+    }
+
+    impl RequestValidator {
+        pub fn new() -> Result<Self, Box<dyn Error + Send + Sync>> {
+            let mut validators = HashMap::new();
+            for method in [
+                "acquireTokenInteractively",
+                "acquireTokenSilently",
+                "getAccounts",
+                "removeAccount",
+                "acquirePrtSsoCookie",
+                "generateSignedHttpRequest",
+                "cancelInteractiveFlow",
+                "getLinuxBrokerVersion",
+            ] {
+                let schema: Value = match request_schema(method) {
+                    Some(schema) => serde_json::from_str(schema)?,
+                    None => continue,
+                };
+                let validator = jsonschema::validator_for(&schema)
+                    .map_err(|e| format!("{} schema: {}", method, e))?;
+                validators.insert(method, validator);
+            }
+            Ok(RequestValidator { validators })
+        }
+
+        /// Check `request_json` for `method`, describing every violation
+        /// (with its JSON pointer) on failure. Methods without a schema are
+        /// not checked.
+        pub fn validate(
+            &self,
+            method: &str,
+            request_json: &str,
+        ) -> Result<(), String> {
+            let validator = match self.validators.get(method) {
+                Some(validator) => validator,
+                None => return Ok(()),
+            };
+            let request: Value =
+                serde_json::from_str(request_json).map_err(|e| {
+                    format!("{} request_json is not JSON: {}", method, e)
+                })?;
+            let errors: Vec<String> = validator
+                .iter_errors(&request)
+                .map(|e| {
+                    let path = e.instance_path.to_string();
+                    if path.is_empty() {
+                        e.to_string()
+                    } else {
+                        format!("{}: {}", path, e)
+                    }
+                })
+                .collect();
+            if errors.is_empty() {
+                Ok(())
+            } else {
+                Err(format!(
+                    "Invalid {} request_json: {}",
+                    method,
+                    errors.join("; ")
+                ))
+            }
+        }
+    }
+}
@@ -0,0 +1,94 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::caller::CallerInfo;
+use crate::error::BrokerError;
+use crate::policy::TOKEN_METHODS;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use tracing::warn;
+
+/// What to do with a call made while the caller's session is locked.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum LockedAction {
+    /// Fail the call with org.freedesktop.DBus.Error.AccessDenied.
+    Deny,
+    /// Fail the call with
+    /// com.microsoft.identity.Broker1.Error.InteractionRequired, so that
+    /// the application has the user sign in again.
+    Reauthenticate,
+}
+
+/* Conditional access treats tokens issued to an unattended device as a
+ * risk, so high-value tokens should not be handed out while the user has
+ * stepped away. The lock state is logind's LockedHint for the caller's
+ * session (see `SessionInfo::locked`), which the screen lockers of GNOME
+ * and KDE maintain. Callers whose session cannot be found are let through,
+ * as are calls to methods the policy does not list.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ScreenLockPolicy {
+    /// The action for each method while locked, by the method's D-Bus name
+    /// (e.g. `acquireTokenSilently`). Every method in `TOKEN_METHODS` is
+    /// denied by default.
+    pub methods: BTreeMap<String, LockedAction>,
+    /// Also ask org.freedesktop.ScreenSaver on the session bus whether the
+    /// screen is locked, for lockers which do not set the LockedHint.
+    pub screensaver: bool,
+}
+
+impl Default for ScreenLockPolicy {
+    fn default() -> Self {
+        ScreenLockPolicy {
+            methods: TOKEN_METHODS
+                .iter()
+                .map(|method| (method.to_string(), LockedAction::Deny))
+                .collect(),
+            screensaver: false,
+        }
+    }
+}
+
+impl ScreenLockPolicy {
+    pub fn check(
+        &self,
+        method: &str,
+        caller: Option<&CallerInfo>,
+    ) -> Result<(), dbus::MethodErr> {
+        let action = match self.methods.get(method) {
+            Some(action) => action,
+            None => return Ok(()),
+        };
+        let session = match caller.and_then(|caller| caller.session.as_ref()) {
+            Some(session) if session.locked => session,
+            _ => return Ok(()),
+        };
+        warn!("Refusing {} while session {} is locked", method, session.id);
+        Err(match action {
+            LockedAction::Deny => dbus::MethodErr::from((
+                "org.freedesktop.DBus.Error.AccessDenied",
+                "The session is locked",
+            )),
+            LockedAction::Reauthenticate => BrokerError::InteractionRequired(
+                "The session is locked".to_string(),
+            )
+            .into(),
+        })
+    }
+}
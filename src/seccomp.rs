@@ -0,0 +1,318 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::io;
+use tracing::info;
+
+/* The audit architecture of the running kernel, from linux/audit.h, which
+ * the libc crate does not carry. The filter refuses calls made under any
+ * other architecture, whose system call numbers mean something else.
+ */
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc00000b7;
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+compile_error!("The hardening feature supports x86_64 and aarch64");
+
+/// Offsets into `seccomp_data`.
+const DATA_NR: u32 = 0;
+const DATA_ARCH: u32 = 4;
+
+/// The system calls the broker needs to serve: the bus connection and
+/// sockets, the tokio runtime and its threads, and reading and writing its
+/// configuration, caches and logs.
+const SYSCALLS: &[libc::c_long] = &[
+    // Files
+    libc::SYS_read,
+    libc::SYS_write,
+    libc::SYS_readv,
+    libc::SYS_writev,
+    libc::SYS_pread64,
+    libc::SYS_pwrite64,
+    libc::SYS_openat,
+    libc::SYS_close,
+    libc::SYS_close_range,
+    libc::SYS_lseek,
+    libc::SYS_fstat,
+    libc::SYS_newfstatat,
+    libc::SYS_statx,
+    libc::SYS_statfs,
+    libc::SYS_fstatfs,
+    libc::SYS_faccessat,
+    libc::SYS_faccessat2,
+    libc::SYS_readlinkat,
+    libc::SYS_getdents64,
+    libc::SYS_getcwd,
+    libc::SYS_mkdirat,
+    libc::SYS_unlinkat,
+    libc::SYS_renameat,
+    libc::SYS_renameat2,
+    libc::SYS_fchmod,
+    libc::SYS_fchmodat,
+    libc::SYS_fchown,
+    libc::SYS_fchownat,
+    libc::SYS_ftruncate,
+    libc::SYS_fsync,
+    libc::SYS_fdatasync,
+    libc::SYS_flock,
+    libc::SYS_utimensat,
+    libc::SYS_fcntl,
+    libc::SYS_ioctl,
+    libc::SYS_dup,
+    libc::SYS_dup3,
+    libc::SYS_pipe2,
+    // Sockets
+    libc::SYS_socket,
+    libc::SYS_socketpair,
+    libc::SYS_connect,
+    libc::SYS_bind,
+    libc::SYS_listen,
+    libc::SYS_accept,
+    libc::SYS_accept4,
+    libc::SYS_sendmsg,
+    libc::SYS_recvmsg,
+    libc::SYS_sendto,
+    libc::SYS_recvfrom,
+    libc::SYS_getsockopt,
+    libc::SYS_setsockopt,
+    libc::SYS_getsockname,
+    libc::SYS_getpeername,
+    libc::SYS_shutdown,
+    // Polling and timers
+    libc::SYS_epoll_create1,
+    libc::SYS_epoll_ctl,
+    libc::SYS_epoll_pwait,
+    libc::SYS_epoll_pwait2,
+    libc::SYS_eventfd2,
+    libc::SYS_ppoll,
+    libc::SYS_pselect6,
+    libc::SYS_timerfd_create,
+    libc::SYS_timerfd_settime,
+    libc::SYS_clock_gettime,
+    libc::SYS_clock_getres,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_nanosleep,
+    libc::SYS_gettimeofday,
+    // Memory
+    libc::SYS_brk,
+    libc::SYS_mmap,
+    libc::SYS_munmap,
+    libc::SYS_mremap,
+    libc::SYS_mprotect,
+    libc::SYS_madvise,
+    libc::SYS_membarrier,
+    // Threads and signals
+    libc::SYS_clone,
+    libc::SYS_clone3,
+    libc::SYS_futex,
+    libc::SYS_set_robust_list,
+    libc::SYS_rseq,
+    libc::SYS_sched_yield,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+    libc::SYS_rt_sigaction,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_sigaltstack,
+    libc::SYS_restart_syscall,
+    libc::SYS_tgkill,
+    // Process information
+    libc::SYS_getpid,
+    libc::SYS_getppid,
+    libc::SYS_gettid,
+    libc::SYS_getuid,
+    libc::SYS_geteuid,
+    libc::SYS_getgid,
+    libc::SYS_getegid,
+    libc::SYS_getresuid,
+    libc::SYS_getresgid,
+    libc::SYS_getrandom,
+    libc::SYS_prctl,
+    libc::SYS_prlimit64,
+    libc::SYS_getrusage,
+    libc::SYS_uname,
+    libc::SYS_sysinfo,
+];
+
+/// System calls x86_64 keeps alongside the generic ones, which its libc
+/// may still use.
+#[cfg(target_arch = "x86_64")]
+const ARCH_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_open,
+    libc::SYS_stat,
+    libc::SYS_lstat,
+    libc::SYS_access,
+    libc::SYS_readlink,
+    libc::SYS_mkdir,
+    libc::SYS_unlink,
+    libc::SYS_rename,
+    libc::SYS_chmod,
+    libc::SYS_getdents,
+    libc::SYS_dup2,
+    libc::SYS_pipe,
+    libc::SYS_poll,
+    libc::SYS_select,
+    libc::SYS_epoll_create,
+    libc::SYS_epoll_wait,
+    libc::SYS_eventfd,
+    libc::SYS_arch_prctl,
+    libc::SYS_time,
+];
+#[cfg(not(target_arch = "x86_64"))]
+const ARCH_SYSCALLS: &[libc::c_long] = &[];
+
+/// What happens to a system call the filter does not allow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SeccompAction {
+    /// The call fails with EPERM.
+    #[default]
+    Deny,
+    /// The call is allowed, and logged by the kernel's audit subsystem, for
+    /// finding the calls an implementation needs before enforcing.
+    Log,
+    /// The process is killed.
+    Kill,
+}
+
+impl SeccompAction {
+    fn ret(self) -> u32 {
+        match self {
+            SeccompAction::Deny => libc::SECCOMP_RET_ERRNO | libc::EPERM as u32,
+            SeccompAction::Log => libc::SECCOMP_RET_LOG,
+            SeccompAction::Kill => libc::SECCOMP_RET_KILL_PROCESS,
+        }
+    }
+}
+
+/* Unlike Landlock and capabilities (see `Sandbox`), a seccomp filter can
+ * be synchronised to every thread of the process, so it is installed by
+ * the serve loop itself, once the bus connection and sockets are set up,
+ * and also covers the threads tokio has already started.
+ */
+/// A seccomp filter restricting the broker process to the system calls it
+/// needs to serve (see `ServeOptions::seccomp`). Implementations which need
+/// more, such as one starting helper processes, allow them with `allow`.
+#[derive(Debug, Clone, Default)]
+pub struct SeccompFilter {
+    allowed: Vec<libc::c_long>,
+    action: SeccompAction,
+}
+
+impl SeccompFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Also allow this system call (one of the `libc::SYS_` numbers).
+    pub fn allow(mut self, syscall: libc::c_long) -> Self {
+        self.allowed.push(syscall);
+        self
+    }
+
+    /// What happens to the system calls which are not allowed. Defaults to
+    /// failing them with EPERM.
+    pub fn action(mut self, action: SeccompAction) -> Self {
+        self.action = action;
+        self
+    }
+
+    /// The filter program: the architecture is checked, then the call
+    /// number against each allowed call in turn.
+    fn program(&self) -> Vec<libc::sock_filter> {
+        let mut syscalls: Vec<libc::c_long> = SYSCALLS
+            .iter()
+            .chain(ARCH_SYSCALLS)
+            .chain(&self.allowed)
+            .copied()
+            .collect();
+        syscalls.sort_unstable();
+        syscalls.dedup();
+
+        let mut program = vec![
+            stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, DATA_ARCH),
+            jump(AUDIT_ARCH, 1, 0),
+            stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_KILL_PROCESS),
+            stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, DATA_NR),
+        ];
+        for nr in syscalls {
+            program.push(jump(nr as u32, 0, 1));
+            program.push(stmt(
+                libc::BPF_RET | libc::BPF_K,
+                libc::SECCOMP_RET_ALLOW,
+            ));
+        }
+        program.push(stmt(libc::BPF_RET | libc::BPF_K, self.action.ret()));
+        program
+    }
+
+    /// Install the filter on every thread of the process. It cannot be
+    /// removed again, and no_new_privs is set, as seccomp requires.
+    pub fn apply(&self) -> io::Result<()> {
+        let program = self.program();
+        let prog = libc::sock_fprog {
+            len: program.len() as libc::c_ushort,
+            filter: program.as_ptr() as *mut libc::sock_filter,
+        };
+        if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let res = unsafe {
+            libc::syscall(
+                libc::SYS_seccomp,
+                libc::SECCOMP_SET_MODE_FILTER,
+                libc::SECCOMP_FILTER_FLAG_TSYNC,
+                &prog as *const libc::sock_fprog,
+            )
+        };
+        match res {
+            0 => {}
+            tid if tid > 0 => {
+                return Err(io::Error::other(format!(
+                    "Thread {} could not take the seccomp filter",
+                    tid
+                )))
+            }
+            _ => return Err(io::Error::last_os_error()),
+        }
+        info!(
+            "Installed a seccomp filter allowing {} system calls",
+            (program.len() - 5) / 2
+        );
+        Ok(())
+    }
+}
+
+fn stmt(code: u32, k: u32) -> libc::sock_filter {
+    libc::sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+fn jump(k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter {
+        code: (libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K) as u16,
+        jt,
+        jf,
+        k,
+    }
+}
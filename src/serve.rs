@@ -0,0 +1,347 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::account_manager::AccountManagerConfig;
+use crate::caller::{BusType, CallerResolver};
+use crate::hardening::HardeningConfig;
+use crate::latency::SlowRequestConfig;
+use crate::maintenance::MaintenanceMode;
+use crate::name_watch::{
+    NameAcquisitionPolicy, NameLostCallback, TakeoverAction,
+};
+use crate::policy::{CallerPolicy, MethodPolicy};
+use crate::replay::ReplayConfig;
+use crate::screen_lock::ScreenLockPolicy;
+#[cfg(feature = "hardening")]
+use crate::seccomp::SeccompFilter;
+#[cfg(feature = "varlink")]
+use crate::socket::SocketAddress;
+use crate::sso_cookie::SsoCookieShapeRule;
+use crate::transform::ResponseTransformer;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::error;
+
+/// Options for `session_broker_serve_with_options` and
+/// `device_broker_serve_with_options`.
+///
+/// The broker is always served under its Microsoft well-known name and
+/// object path. Additional object paths (for example a vendor path used for
+/// testing) and additional well-known names may be requested here, and are
+/// all backed by the same broker instance.
+#[derive(Debug, Clone, Default)]
+pub struct ServeOptions {
+    pub(crate) object_paths: Vec<String>,
+    pub(crate) bus_names: Vec<String>,
+    pub(crate) caller_policy: Option<CallerPolicy>,
+    pub(crate) method_policy: Option<MethodPolicy>,
+    pub(crate) key_usage_log: Option<PathBuf>,
+    pub(crate) on_takeover: TakeoverAction,
+    pub(crate) name_policy: NameAcquisitionPolicy,
+    pub(crate) on_name_lost: Option<NameLostCallback>,
+    pub(crate) portal_frontend: bool,
+    pub(crate) replay_protection: Option<ReplayConfig>,
+    pub(crate) idle_exit: Option<Duration>,
+    #[cfg(feature = "varlink")]
+    pub(crate) varlink: Option<SocketAddress>,
+    pub(crate) sso_cookie_shapes: Vec<SsoCookieShapeRule>,
+    pub(crate) response_transformers: Vec<Arc<dyn ResponseTransformer>>,
+    pub(crate) capture: Option<PathBuf>,
+    pub(crate) slow_requests: SlowRequestConfig,
+    pub(crate) maintenance: MaintenanceMode,
+    pub(crate) bus: Option<BusType>,
+    pub(crate) hardening: Option<HardeningConfig>,
+    #[cfg(feature = "hardening")]
+    pub(crate) seccomp: Option<SeccompFilter>,
+    pub(crate) account_manager: AccountManagerConfig,
+    pub(crate) logind_sessions: bool,
+    pub(crate) screen_lock: Option<ScreenLockPolicy>,
+}
+
+impl ServeOptions {
+    pub fn new() -> Self {
+        ServeOptions::default()
+    }
+
+    /// Also serve the broker at `path`. Invalid object paths fail serving
+    /// (see `ServeOptions::check`).
+    pub fn object_path(mut self, path: &str) -> Self {
+        self.object_paths.push(path.to_string());
+        self
+    }
+
+    /// Also request the well-known `name`. Invalid bus names fail serving
+    /// (see `ServeOptions::check`).
+    pub fn bus_name(mut self, name: &str) -> Self {
+        self.bus_names.push(name.to_string());
+        self
+    }
+
+    /// Restrict which callers may use token-returning methods. Only applies
+    /// to the session broker.
+    pub fn caller_policy(mut self, policy: CallerPolicy) -> Self {
+        self.caller_policy = Some(policy);
+        self
+    }
+
+    /// Disable methods for every caller, failing calls to them with
+    /// com.microsoft.identity.Broker1.Error.Disabled (see `MethodPolicy`).
+    pub fn method_policy(mut self, policy: MethodPolicy) -> Self {
+        self.method_policy = Some(policy);
+        self
+    }
+
+    /// Append a record of every sign and decrypt operation to the file at
+    /// `path` (see `KeyUsageLog`). Only applies to the device broker.
+    pub fn key_usage_log(mut self, path: impl Into<PathBuf>) -> Self {
+        self.key_usage_log = Some(path.into());
+        self
+    }
+
+    /// How to respond when another connection (such as Microsoft's own
+    /// broker) takes over one of the served names. Takeovers are always
+    /// logged.
+    pub fn on_takeover(mut self, action: TakeoverAction) -> Self {
+        self.on_takeover = action;
+        self
+    }
+
+    /// How the broker's well-known names are requested from the bus.
+    pub fn name_policy(mut self, policy: NameAcquisitionPolicy) -> Self {
+        self.name_policy = policy;
+        self
+    }
+
+    /// Call `f` with each well-known name the broker loses (the bus's
+    /// NameLost signal), for example when it was served with
+    /// `NameAcquisitionPolicy::AllowReplacement` and has been replaced.
+    /// `f` runs on the serve loop, so it should return promptly.
+    pub fn on_name_lost(
+        mut self,
+        f: impl Fn(&str) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_name_lost = Some(NameLostCallback(Arc::new(f)));
+        self
+    }
+
+    /// Also serve the broker as an xdg-desktop-portal style backend, under
+    /// `PORTAL_BUS_NAME` at `PORTAL_OBJECT_PATH`, for sandboxed
+    /// applications which cannot reach the broker's own name. Only applies
+    /// to the session broker.
+    pub fn portal_frontend(mut self) -> Self {
+        self.portal_frontend = true;
+        self
+    }
+
+    /// Watch for Broker1 and DeviceBroker1 calls which repeat the
+    /// correlation id of a recent call, handling them as `config` directs.
+    /// A device request's correlation id is its `correlationId` field.
+    pub fn replay_protection(mut self, config: ReplayConfig) -> Self {
+        self.replay_protection = Some(config);
+        self
+    }
+
+    /// Exit cleanly, releasing the broker's names, once no Broker1 call has
+    /// been in progress for `timeout`, leaving D-Bus activation (see
+    /// `ActivationFile`) to start the broker again when it is next called.
+    /// Only applies to the session broker.
+    pub fn idle_exit(mut self, timeout: Duration) -> Self {
+        self.idle_exit = Some(timeout);
+        self
+    }
+
+    /// Also serve the Broker1 methods over varlink (`VARLINK_INTERFACE`) at
+    /// `address`, for clients without a session bus such as CLI tools in
+    /// containers. Only the broker's own user may connect. Only applies to
+    /// the async session broker.
+    #[cfg(feature = "varlink")]
+    pub fn varlink(mut self, address: impl Into<SocketAddress>) -> Self {
+        self.varlink = Some(address.into());
+        self
+    }
+
+    /// Reshape acquirePrtSsoCookie responses for the callers matched by
+    /// `rules`, such as browser extensions expecting a particular response
+    /// shape. Only applies to the session broker.
+    pub fn sso_cookie_shapes(mut self, rules: Vec<SsoCookieShapeRule>) -> Self {
+        self.sso_cookie_shapes = rules;
+        self
+    }
+
+    /// Pass the responses of successful Broker1 calls through
+    /// `transformer` before returning them. Transformers run in the order
+    /// they were added, after any `sso_cookie_shapes`. Only applies to the
+    /// session broker.
+    pub fn response_transformer(
+        mut self,
+        transformer: Arc<dyn ResponseTransformer>,
+    ) -> Self {
+        self.response_transformers.push(transformer);
+        self
+    }
+
+    /// Append each Broker1 call, with its response or error and timing, to
+    /// a capture bundle at `path` for a support request (see
+    /// `read_capture`). Tokens and cookies are redacted, but the bundle
+    /// still names the user's accounts and applications, so capturing
+    /// should only be enabled while an issue is reproduced. Only applies to
+    /// the session broker.
+    pub fn capture(mut self, path: impl Into<PathBuf>) -> Self {
+        self.capture = Some(path.into());
+        self
+    }
+
+    /// When to log a Broker1 call as slow. By default, calls answered
+    /// without the user are logged when they take over a second or two
+    /// (see `SlowRequestConfig`). Only applies to the session broker.
+    pub fn slow_requests(mut self, config: SlowRequestConfig) -> Self {
+        self.slow_requests = config;
+        self
+    }
+
+    /// The switch putting the broker into maintenance, during which token
+    /// methods fail with `BrokerError::Maintenance`. It can also be flipped
+    /// over D-Bus (`MAINTENANCE_INTERFACE`). Only applies to the session
+    /// broker.
+    pub fn maintenance(mut self, mode: MaintenanceMode) -> Self {
+        self.maintenance = mode;
+        self
+    }
+
+    /// Pad the responses of account-enumeration-sensitive methods and delay
+    /// their errors (see `HardeningConfig`). Only applies to the session
+    /// broker.
+    pub fn hardening(mut self, config: HardeningConfig) -> Self {
+        self.hardening = Some(config);
+        self
+    }
+
+    /// How the account manager interface authorizes changes to accounts
+    /// (see `AccountManagerConfig`). Only applies to async session brokers.
+    pub fn account_manager(mut self, config: AccountManagerConfig) -> Self {
+        self.account_manager = config;
+        self
+    }
+
+    /// Install a seccomp filter once the broker's names are acquired,
+    /// before serving (see `SeccompFilter`). Requires the hardening
+    /// feature.
+    #[cfg(feature = "hardening")]
+    pub fn seccomp(mut self, filter: SeccompFilter) -> Self {
+        self.seccomp = Some(filter);
+        self
+    }
+
+    /// Look up the logind session (id, seat and display) of each caller on
+    /// the system bus, and forward it with the caller's identity (see
+    /// `CallerInfo::session`), so that interactive flows can be opened in
+    /// the caller's graphical session.
+    pub fn logind_sessions(mut self) -> Self {
+        self.logind_sessions = true;
+        self
+    }
+
+    /// Refuse calls made while the caller's session is locked, per method
+    /// (see `ScreenLockPolicy`). Implies `logind_sessions`. Only applies to
+    /// session brokers.
+    pub fn screen_lock(mut self, policy: ScreenLockPolicy) -> Self {
+        self.screen_lock = Some(policy);
+        self
+    }
+
+    /// The bus the broker is served on, which is asked to identify callers.
+    /// Only needs setting when serving on a connection the application
+    /// established itself (such as with `session_broker_serve_on`) to a
+    /// bus other than the session bus, or the system bus for the device
+    /// broker.
+    pub fn bus(mut self, bus: BusType) -> Self {
+        self.bus = Some(bus);
+        self
+    }
+
+    /// The bus the broker is served on, or `default`.
+    pub(crate) fn bus_type(&self, default: BusType) -> BusType {
+        self.bus.clone().unwrap_or(default)
+    }
+
+    /// A resolver for callers on the bus the broker is served on, or
+    /// `default`.
+    pub(crate) fn caller_resolver(&self, default: BusType) -> CallerResolver {
+        let resolver = CallerResolver::new(self.bus_type(default));
+        if self.logind_sessions || self.screen_lock.is_some() {
+            resolver.track_sessions()
+        } else {
+            resolver
+        }
+    }
+
+    /// Install the seccomp filter, if one was set.
+    pub(crate) fn apply_seccomp(&self) -> Result<(), dbus::MethodErr> {
+        #[cfg(feature = "hardening")]
+        if let Some(filter) = &self.seccomp {
+            filter.apply().map_err(|e| {
+                dbus::MethodErr::failed(&format!(
+                    "Failed to install the seccomp filter: {}",
+                    e
+                ))
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Check that the additional object paths and bus names are valid,
+    /// as the serve functions do before serving.
+    pub fn check(&self) -> Result<(), dbus::MethodErr> {
+        for path in &self.object_paths {
+            dbus::Path::new(path.as_str()).map_err(|e| {
+                dbus::MethodErr::invalid_arg(&format!(
+                    "Invalid object path {:?}: {}",
+                    path, e
+                ))
+            })?;
+        }
+        for name in &self.bus_names {
+            dbus::strings::BusName::new(name.as_str()).map_err(|e| {
+                dbus::MethodErr::invalid_arg(&format!(
+                    "Invalid bus name {:?}: {}",
+                    name, e
+                ))
+            })?;
+        }
+        Ok(())
+    }
+
+    /// The additional object paths, leaving out any which are invalid, for
+    /// mounting brokers on a Crossroads instance the application serves.
+    pub(crate) fn extra_object_paths(
+        &self,
+    ) -> impl Iterator<Item = dbus::Path<'static>> + '_ {
+        self.object_paths.iter().filter_map(|path| {
+            dbus::Path::new(path.clone())
+                .map_err(|e| error!("Invalid object path {:?} -> {}", path, e))
+                .ok()
+        })
+    }
+
+    /// The broker's well-known name followed by any additional names.
+    pub(crate) fn all_bus_names(&self, primary: &str) -> Vec<String> {
+        let mut names = vec![primary.to_string()];
+        names.extend(self.bus_names.iter().cloned());
+        names
+    }
+}
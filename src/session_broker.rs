@@ -15,17 +15,90 @@
    You should have received a copy of the GNU Lesser General Public License
    along with this program. If not, see <https://www.gnu.org/licenses/>.
 */
-use crate::broker_proto::ClientRequest;
+use crate::account_manager::AccountManagerConfig;
+use crate::accounts::{
+    request_client_id, Account, AccountFilter, AccountRegistry, DefaultAccount,
+    RoutingConfig,
+};
+use crate::async_session_broker::{
+    async_session_broker_serve_with_options, AsyncSessionBroker,
+};
+use crate::broker_ext::{register_broker_ext, Capabilities, BROKER1_METHODS};
+use crate::broker_proto::{ClientRequest, ClientResponse, ProtocolError};
+use crate::cache_store::{CacheKey, CacheKeyStore};
+use crate::caller::{BusType, CallerInfo, CallerResolver};
+use crate::capture::CaptureBundle;
+use crate::claims::ClaimsChallenge;
+use crate::client::DeviceBroker1Client;
+use crate::compliance::DeviceComplianceStatus;
+use crate::device_code::{DeviceCode, DeviceCodeStatus};
+use crate::diagnostics::{register_diagnostics, BrokerStats};
+use crate::error::{BrokerError, BrokerErrorName, MethodErrExt};
+use crate::events::{AccountEvent, AccountSignals};
+use crate::failover::{FailoverConfig, SocketSet};
+use crate::fdpass::recv_with_fds;
+use crate::federation::{
+    FederationHandler, FederationResponse, NoFederationHandler,
+};
+use crate::hardening::{DeferredReplies, HardeningConfig};
+use crate::idle::{Busy, IdleTimer};
+use crate::latency::SlowRequestConfig;
+use crate::maintenance::{
+    register_maintenance, MaintenanceMode, MaintenanceNotice,
+};
+use crate::name_watch::{request_names, serve_watching_names, NameWatch};
+use crate::ownership::requested_accounts;
+use crate::peer::register_peer;
+use crate::policy::{CallerPolicy, MethodPolicy};
+use crate::pool::{ConnectionPool, PoolConfig};
+use crate::pop::{
+    pop_token_response, response_access_token, PopTokenRequest,
+    RequestConfirmationRequest, SignedHttpRequestRequest,
+};
+use crate::prompt::{
+    AccountChoiceRequest, PortalPromptHandler, PromptHandler, PromptResponse,
+};
+#[cfg(feature = "schema-validation")]
+use crate::protocol_version::is_flat_request;
+use crate::protocol_version::ProtocolVersion;
+use crate::redact::{redact_json, summarize_response};
+use crate::replay::{ReplayConfig, ReplayGuard};
+#[cfg(feature = "schema-validation")]
+use crate::schema::RequestValidator;
+use crate::screen_lock::ScreenLockPolicy;
+use crate::serve::ServeOptions;
+use crate::shr::{NonceCache, PopParams};
+use crate::socket::SocketAddress;
+use crate::ssh_cert::SshCertificate;
+#[cfg(feature = "secret-service")]
+use crate::sso_cookie::store_sso_cookie;
+use crate::sso_cookie::{sso_cookie_shape, SsoCookie, SsoCookieShapeRule};
+use crate::token_broker::{
+    self, TokenBrokerArgs, TOKEN_BROKER_INTERFACE, TOKEN_BROKER_METHODS,
+};
+use crate::token_cache::TokenCache;
+use crate::transform::ResponseTransformer;
+use crate::wire::{FrameDecode, WireFormat, WireFormatKind};
+use async_trait::async_trait;
 #[allow(unused_imports)]
 use dbus::arg;
 use dbus::blocking::Connection;
 use dbus_crossroads as crossroads;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::any::Any;
+use std::collections::VecDeque;
 use std::error::Error;
-use std::io::{Read, Write};
-use std::os::unix::net::UnixStream;
-use std::time::Duration;
-use std::time::SystemTime;
-use tracing::{debug, error};
+use std::future::Future;
+use std::io;
+use std::os::fd::{AsRawFd, OwnedFd, RawFd};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncWriteExt, Interest};
+use tokio::net::UnixStream;
+use tracing::{debug, error, info, trace};
 
 pub trait SessionBroker {
     fn acquire_token_interactively(
@@ -76,224 +149,835 @@ pub trait SessionBroker {
         correlation_id: String,
         request_json: String,
     ) -> Result<String, dbus::MethodErr>;
+
+    /// Counters published on the org.himmelblau.Broker1.Diagnostics
+    /// interface. Implementations which track their own statistics (such as
+    /// socket or cache state) should return a shared handle here, otherwise
+    /// the serve loop allocates one for request counting.
+    fn stats(&self) -> Option<Arc<BrokerStats>> {
+        None
+    }
+
+    /// Called with the identity of the D-Bus caller before each method is
+    /// dispatched, so that implementations may apply per-application policy
+    /// or forward the caller to a backend. None when the caller could not
+    /// be resolved, replacing any caller of an earlier call.
+    fn set_caller(&mut self, _caller: Option<CallerInfo>) {}
+
+    /// The methods this broker implements, served by getCapabilities.
+    /// Implementations of optional methods should add them here.
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::new(BROKER1_METHODS)
+    }
+}
+
+/// The arguments of a Broker1 method call.
+pub(crate) struct Broker1Call<'a> {
+    pub(crate) method: &'a str,
+    pub(crate) protocol_version: &'a str,
+    pub(crate) correlation_id: &'a str,
+    pub(crate) request_json: &'a str,
+    /// The application a portal frontend call was made for, if any.
+    pub(crate) app_id: Option<&'a str>,
+}
+
+impl Broker1Call<'_> {
+    /// `caller`, naming the application the call was made for when it came
+    /// through the portal frontend.
+    pub(crate) fn attribute(
+        &self,
+        caller: Option<CallerInfo>,
+    ) -> Option<CallerInfo> {
+        caller.map(|caller| CallerInfo {
+            app_id: self.app_id.map(str::to_string),
+            ..caller
+        })
+    }
+}
+
+/* Every Broker1 method call passes through the dispatcher, which resolves
+ * the caller's identity for the broker, applies the caller policy, checks
+ * for replayed correlation ids, shapes the response for the caller and
+ * records statistics.
+ */
+pub(crate) struct Dispatcher {
+    pub(crate) stats: Arc<BrokerStats>,
+    resolver: CallerResolver,
+    policy: Option<CallerPolicy>,
+    methods: Option<MethodPolicy>,
+    maintenance: MaintenanceMode,
+    replay: Option<ReplayGuard>,
+    idle: Option<Arc<IdleTimer>>,
+    sso_cookie_shapes: Vec<SsoCookieShapeRule>,
+    transformers: Vec<Arc<dyn ResponseTransformer>>,
+    capture: Option<CaptureBundle>,
+    slow_requests: SlowRequestConfig,
+    hardening: Option<HardeningConfig>,
+    screen_lock: Option<ScreenLockPolicy>,
+    deferred: Arc<DeferredReplies>,
+}
+
+impl Dispatcher {
+    pub(crate) fn new(stats: Arc<BrokerStats>, options: &ServeOptions) -> Self {
+        Dispatcher {
+            stats,
+            resolver: options.caller_resolver(BusType::Session),
+            policy: options.caller_policy.clone(),
+            methods: options.method_policy.clone(),
+            maintenance: options.maintenance.clone(),
+            replay: options.replay_protection.as_ref().map(ReplayGuard::new),
+            idle: options
+                .idle_exit
+                .map(|timeout| Arc::new(IdleTimer::new(timeout))),
+            sso_cookie_shapes: options.sso_cookie_shapes.clone(),
+            transformers: options.response_transformers.clone(),
+            capture: options.capture.as_ref().and_then(|path| {
+                CaptureBundle::open(path)
+                    .map_err(|e| {
+                        error!(
+                            "Failed to open capture bundle {} -> {:?}",
+                            path.display(),
+                            e
+                        )
+                    })
+                    .ok()
+            }),
+            slow_requests: options.slow_requests.clone(),
+            hardening: options.hardening.clone(),
+            screen_lock: options.screen_lock.clone(),
+            deferred: Arc::default(),
+        }
+    }
+
+    /// The replies held back by the serve loop (see `DeferredReplies`).
+    pub(crate) fn deferred_replies(&self) -> Arc<DeferredReplies> {
+        self.deferred.clone()
+    }
+
+    pub(crate) fn idle_timer(&self) -> Option<Arc<IdleTimer>> {
+        self.idle.clone()
+    }
+
+    /// Mark a call as in progress, for `ServeOptions::idle_exit`.
+    pub(crate) fn busy(&self) -> Option<Busy<'_>> {
+        self.idle.as_deref().map(IdleTimer::busy)
+    }
+
+    pub(crate) fn resolve(&self, sender: &str) -> Option<CallerInfo> {
+        let mut caller = self
+            .resolver
+            .resolve(sender)
+            .map_err(|e| {
+                error!("Failed to resolve caller {} -> {:?}", sender, e)
+            })
+            .ok()?;
+        let screensaver = self
+            .screen_lock
+            .as_ref()
+            .is_some_and(|policy| policy.screensaver);
+        if let Some(session) = caller.session.as_mut() {
+            if screensaver && !session.locked {
+                session.locked =
+                    self.resolver.screensaver_active().unwrap_or_else(|e| {
+                        debug!("Failed to query the screensaver -> {}", e);
+                        false
+                    });
+            }
+        }
+        Some(caller)
+    }
+
+    /// Record a call to `method` and check it against the method policy,
+    /// maintenance, the caller policy and the screen lock policy.
+    pub(crate) fn admit(
+        &self,
+        method: &str,
+        caller: Option<&CallerInfo>,
+    ) -> Result<(), dbus::MethodErr> {
+        self.stats.record_request(method);
+        if let Some(methods) = &self.methods {
+            if let Err(e) = methods.check(method) {
+                self.stats.record_failure(method);
+                return Err(e.into());
+            }
+        }
+        if let Err(e) = self.maintenance.check(method) {
+            self.stats.record_failure(method);
+            return Err(e.into());
+        }
+        if let Some(policy) = &self.policy {
+            if let Err(e) = policy.check(method, caller) {
+                self.stats.record_failure(method);
+                return Err(e);
+            }
+        }
+        if let Some(policy) = &self.screen_lock {
+            if let Err(e) = policy.check(method, caller) {
+                self.stats.record_failure(method);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Check an admitted call for a repeated correlation id, returning the
+    /// earlier response when the call is deduped.
+    pub(crate) fn check_replay(
+        &self,
+        sender: Option<&str>,
+        method: &str,
+        correlation_id: &str,
+        request_json: &str,
+    ) -> Result<Option<String>, dbus::MethodErr> {
+        let replay = match &self.replay {
+            Some(replay) => replay,
+            None => return Ok(None),
+        };
+        replay
+            .check(
+                sender.unwrap_or_default(),
+                method,
+                correlation_id,
+                request_json,
+            )
+            .map_err(|e| {
+                self.stats.record_failure(method);
+                e.into()
+            })
+    }
+
+    /// Record the outcome of a call passed by `check_replay`.
+    pub(crate) fn complete_replay(
+        &self,
+        sender: Option<&str>,
+        method: &str,
+        correlation_id: &str,
+        res: &Result<String, dbus::MethodErr>,
+    ) {
+        if let Some(replay) = &self.replay {
+            replay.complete(
+                sender.unwrap_or_default(),
+                method,
+                correlation_id,
+                res,
+            );
+        }
+    }
+
+    /// Adapt a successful response to the shape `caller` expects, pass it
+    /// through the response transformers, then pad it if the method is
+    /// hardened.
+    pub(crate) fn shape_response(
+        &self,
+        call: &Broker1Call<'_>,
+        caller: Option<&CallerInfo>,
+        res: Result<String, dbus::MethodErr>,
+    ) -> Result<String, dbus::MethodErr> {
+        let mut resp = res?;
+        if call.method == "acquirePrtSsoCookie"
+            && !self.sso_cookie_shapes.is_empty()
+        {
+            let shape = sso_cookie_shape(
+                &self.sso_cookie_shapes,
+                call.protocol_version,
+                caller,
+            );
+            resp = shape.apply(resp);
+        }
+        for transformer in &self.transformers {
+            resp = transformer.transform(call.method, caller, resp);
+        }
+        Ok(match self.hardened(call.method) {
+            Some(hardening) => hardening.pad(resp),
+            None => resp,
+        })
+    }
+
+    fn hardened(&self, method: &str) -> Option<&HardeningConfig> {
+        self.hardening
+            .as_ref()
+            .filter(|hardening| hardening.applies_to(method))
+    }
+
+    /// How long to hold back the outcome of a call which arrived at
+    /// `started`, so that a hardened method does not fail sooner than
+    /// `HardeningConfig::error_floor`.
+    pub(crate) fn error_delay<R>(
+        &self,
+        method: &str,
+        started: Instant,
+        res: &Result<R, dbus::MethodErr>,
+    ) -> Option<Duration> {
+        match (self.hardened(method), res) {
+            (Some(hardening), Err(_)) => Some(hardening.error_delay(started)),
+            _ => None,
+        }
+    }
+
+    /// Record a call which arrived at `started` in the capture bundle, when
+    /// capturing (see `ServeOptions::capture`).
+    pub(crate) fn capture(
+        &self,
+        call: &Broker1Call<'_>,
+        caller: Option<&CallerInfo>,
+        started: Instant,
+        res: &Result<String, dbus::MethodErr>,
+    ) {
+        if let Some(capture) = &self.capture {
+            capture.record(call, caller, started, res);
+        }
+    }
+
+    /// Record the time taken by a call which arrived at `started`, logging
+    /// it if it was slow.
+    pub(crate) fn record_latency(
+        &self,
+        call: &Broker1Call<'_>,
+        caller: Option<&CallerInfo>,
+        started: Instant,
+    ) {
+        let elapsed = started.elapsed();
+        self.stats.record_latency(call.method, elapsed);
+        self.slow_requests.check(
+            call.method,
+            call.correlation_id,
+            caller,
+            elapsed,
+        );
+    }
+
+    pub(crate) fn finish<R>(
+        &self,
+        method: &str,
+        res: &Result<R, dbus::MethodErr>,
+    ) {
+        if res.is_err() {
+            self.stats.record_failure(method);
+        }
+    }
+
+    fn call<T, F>(
+        &self,
+        ctx: &crossroads::Context,
+        t: &mut T,
+        method: &str,
+        f: F,
+    ) -> Result<(String,), dbus::MethodErr>
+    where
+        T: SessionBroker,
+        F: FnOnce(&mut T) -> Result<String, dbus::MethodErr>,
+    {
+        let _span = call_span(method, ctx.message()).entered();
+        let (protocol_version, correlation_id, request_json) =
+            ctx.message().get3::<String, String, String>();
+        let call = Broker1Call {
+            method,
+            protocol_version: protocol_version.as_deref().unwrap_or_default(),
+            correlation_id: correlation_id.as_deref().unwrap_or_default(),
+            request_json: request_json.as_deref().unwrap_or_default(),
+            app_id: None,
+        };
+        self.run(ctx, t, &call, f).map(|x| (x,))
+    }
+
+    fn run<T, F>(
+        &self,
+        ctx: &crossroads::Context,
+        t: &mut T,
+        call: &Broker1Call<'_>,
+        f: F,
+    ) -> Result<String, dbus::MethodErr>
+    where
+        T: SessionBroker,
+        F: FnOnce(&mut T) -> Result<String, dbus::MethodErr>,
+    {
+        let Broker1Call {
+            method,
+            correlation_id,
+            request_json,
+            ..
+        } = *call;
+        let sender = ctx.message().sender();
+        let caller = call
+            .attribute(sender.as_ref().and_then(|sender| self.resolve(sender)));
+        self.admit(method, caller.as_ref())?;
+        let _busy = self.busy();
+        let sender = sender.as_deref();
+        if let Some(resp) =
+            self.check_replay(sender, method, correlation_id, request_json)?
+        {
+            return Ok(resp);
+        }
+        t.set_caller(caller.clone());
+        let started = Instant::now();
+        let res = supervise(method, correlation_id, || f(t));
+        let res = self.shape_response(call, caller.as_ref(), res);
+        if let Some(delay) = self.error_delay(method, started, &res) {
+            self.deferred.defer(ctx.message(), delay);
+        }
+        self.complete_replay(sender, method, correlation_id, &res);
+        self.capture(call, caller.as_ref(), started, &res);
+        self.record_latency(call, caller.as_ref(), started);
+        self.finish(method, &res);
+        res
+    }
+}
+
+/// The well-known name of the portal frontend (see
+/// `ServeOptions::portal_frontend`).
+pub const PORTAL_BUS_NAME: &str =
+    "org.freedesktop.impl.portal.desktop.himmelblau";
+/// The object path of the portal frontend.
+pub const PORTAL_OBJECT_PATH: &str = "/org/freedesktop/portal/desktop";
+/// The interface of the portal frontend.
+pub const PORTAL_INTERFACE: &str = "org.freedesktop.impl.portal.IdentityBroker";
+
+pub(crate) type PortalArgs =
+    (dbus::Path<'static>, String, String, String, arg::PropMap);
+
+/* Sandboxed applications may be unable to see the broker's own name, but
+ * xdg-desktop-portal can call backends on their behalf. The portal frontend
+ * has a single Request method in the portal backend style: it takes the
+ * request handle, the application id, the Broker1 method name and its
+ * request_json, with protocol_version and correlation_id passed in the
+ * options, and answers with a response code (0 success, 1 cancelled,
+ * 2 failed) and a results dictionary holding either the Broker1 result or
+ * the error name and message.
+ */
+pub(crate) struct PortalRequest {
+    pub(crate) method: &'static str,
+    pub(crate) protocol_version: String,
+    pub(crate) correlation_id: String,
+    pub(crate) request_json: String,
+    /// The sandboxed application the request was made for, as named by
+    /// xdg-desktop-portal.
+    pub(crate) app_id: Option<String>,
+}
+
+impl PortalRequest {
+    pub(crate) fn parse(
+        (handle, app_id, method, request_json, options): PortalArgs,
+    ) -> Result<Self, dbus::MethodErr> {
+        let method = BROKER1_METHODS
+            .iter()
+            .find(|m| **m == method)
+            .copied()
+            .ok_or_else(|| {
+                dbus::MethodErr::invalid_arg(&format!(
+                    "Unknown broker method {}",
+                    method
+                ))
+            })?;
+        debug!("Portal request {} for {} from {:?}", handle, method, app_id);
+        let mut req = PortalRequest::new(method, request_json, &options);
+        // Applications which are not sandboxed have no app id.
+        req.app_id = Some(app_id).filter(|app_id| !app_id.is_empty());
+        Ok(req)
+    }
+
+    /// A request for `method`, taking protocol_version and correlation_id
+    /// from `options`, or defaulting them.
+    pub(crate) fn new(
+        method: &'static str,
+        request_json: String,
+        options: &arg::PropMap,
+    ) -> Self {
+        let option = |key: &str, default: String| {
+            arg::prop_cast::<String>(options, key)
+                .cloned()
+                .unwrap_or(default)
+        };
+        PortalRequest {
+            method,
+            protocol_version: option(
+                "protocol_version",
+                ProtocolVersion::default().to_string(),
+            ),
+            correlation_id: option(
+                "correlation_id",
+                uuid::Uuid::new_v4().to_string(),
+            ),
+            request_json,
+            app_id: None,
+        }
+    }
+
+    pub(crate) fn reply(
+        res: Result<String, dbus::MethodErr>,
+    ) -> (u32, arg::PropMap) {
+        let mut results = arg::PropMap::new();
+        match res {
+            Ok(result) => {
+                results.insert("result".into(), arg::Variant(Box::new(result)));
+                (0, results)
+            }
+            Err(e) => {
+                let code =
+                    if e.errorname() == BrokerErrorName::Cancelled.as_str() {
+                        1
+                    } else {
+                        2
+                    };
+                results.insert(
+                    "error".into(),
+                    arg::Variant(Box::new(e.errorname().to_string())),
+                );
+                results.insert(
+                    "message".into(),
+                    arg::Variant(Box::new(e.description().to_string())),
+                );
+                (code, results)
+            }
+        }
+    }
+}
+
+fn call_by_name<T>(
+    t: &mut T,
+    stats: &BrokerStats,
+    req: PortalRequest,
+) -> Result<String, dbus::MethodErr>
+where
+    T: SessionBroker,
+{
+    let (a, b, c) =
+        (req.protocol_version, req.correlation_id, req.request_json);
+    match req.method {
+        "acquireTokenInteractively" => {
+            stats.interactive_flow_started();
+            let res = t.acquire_token_interactively(a, b, c);
+            stats.interactive_flow_finished();
+            res
+        }
+        "acquireTokenSilently" => t.acquire_token_silently(a, b, c),
+        "getAccounts" => t.get_accounts(a, b, c),
+        "removeAccount" => t.remove_account(a, b, c),
+        "acquirePrtSsoCookie" => t.acquire_prt_sso_cookie(a, b, c),
+        "generateSignedHttpRequest" => t.generate_signed_http_request(a, b, c),
+        "cancelInteractiveFlow" => t.cancel_interactive_flow(a, b, c),
+        "getLinuxBrokerVersion" => t.get_linux_broker_version(a, b, c),
+        method => Err(dbus::MethodErr::no_method(&method)),
+    }
+}
+
+fn register_portal_frontend<T>(
+    cr: &mut crossroads::Crossroads,
+    d: Arc<Dispatcher>,
+) -> crossroads::IfaceToken<T>
+where
+    T: SessionBroker + Send + 'static,
+{
+    cr.register(PORTAL_INTERFACE, move |b| {
+        b.method(
+            "Request",
+            ("handle", "app_id", "method", "request_json", "options"),
+            ("response", "results"),
+            move |ctx, t: &mut T, args: PortalArgs| {
+                let req = PortalRequest::parse(args)?;
+                let method = req.method;
+                let _span = call_span(method, ctx.message()).entered();
+                let protocol_version = req.protocol_version.clone();
+                let correlation_id = req.correlation_id.clone();
+                let request_json = req.request_json.clone();
+                let app_id = req.app_id.clone();
+                let call = Broker1Call {
+                    method,
+                    protocol_version: &protocol_version,
+                    correlation_id: &correlation_id,
+                    request_json: &request_json,
+                    app_id: app_id.as_deref(),
+                };
+                let stats = d.stats.clone();
+                let res =
+                    d.run(ctx, t, &call, |t| call_by_name(t, &stats, req));
+                Ok(PortalRequest::reply(res))
+            },
+        );
+    })
+}
+
+fn register_token_broker<T>(
+    cr: &mut crossroads::Crossroads,
+    d: Arc<Dispatcher>,
+) -> crossroads::IfaceToken<T>
+where
+    T: SessionBroker + Send + 'static,
+{
+    cr.register(TOKEN_BROKER_INTERFACE, move |b| {
+        for &(name, method) in TOKEN_BROKER_METHODS {
+            let d = d.clone();
+            b.method(
+                name,
+                ("request", "options"),
+                ("response",),
+                move |ctx, t: &mut T, (request, options): TokenBrokerArgs| {
+                    let _span = call_span(method, ctx.message()).entered();
+                    let req = PortalRequest::new(
+                        method,
+                        token_broker::request_json(&request),
+                        &options,
+                    );
+                    let protocol_version = req.protocol_version.clone();
+                    let correlation_id = req.correlation_id.clone();
+                    let request_json = req.request_json.clone();
+                    let call = Broker1Call {
+                        method,
+                        protocol_version: &protocol_version,
+                        correlation_id: &correlation_id,
+                        request_json: &request_json,
+                        app_id: None,
+                    };
+                    let stats = d.stats.clone();
+                    let res =
+                        d.run(ctx, t, &call, |t| call_by_name(t, &stats, req));
+                    token_broker::reply(res)
+                },
+            );
+        }
+    })
+}
+
+/// A span carrying the D-Bus metadata of a method call, so that everything
+/// logged while the call is handled identifies the message and its sender.
+pub(crate) fn call_span(method: &str, msg: &dbus::Message) -> tracing::Span {
+    let sender = msg.sender();
+    let path = msg.path();
+    tracing::info_span!(
+        "dbus_call",
+        method,
+        sender = sender.as_deref().unwrap_or_default(),
+        serial = msg.get_serial().unwrap_or_default(),
+        path = path.as_deref().unwrap_or_default(),
+    )
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic")
+}
+
+/* A panic inside a broker method would otherwise unwind through Crossroads
+ * and stop the service. Instead it is logged against the request's
+ * correlation id and reported to the caller as a failure, and the service
+ * carries on with the next request.
+ */
+pub(crate) fn supervise<R>(
+    method: &str,
+    correlation_id: &str,
+    f: impl FnOnce() -> Result<R, dbus::MethodErr>,
+) -> Result<R, dbus::MethodErr> {
+    panic::catch_unwind(AssertUnwindSafe(f))
+        .unwrap_or_else(|panic| Err(panicked(method, correlation_id, &*panic)))
+}
+
+pub(crate) fn panicked(
+    method: &str,
+    correlation_id: &str,
+    panic: &(dyn Any + Send),
+) -> dbus::MethodErr {
+    error!(
+        "{} request {} panicked -> {}",
+        method,
+        correlation_id,
+        panic_message(panic)
+    );
+    dbus::MethodErr::failed(&format!("{} failed unexpectedly", method))
+}
+
+/// Register the com.microsoft.identity.Broker1 interface, for mounting a
+/// session broker on a Crossroads instance the application already serves.
+/// `T` is the object data of the paths the interface is inserted at, and
+/// must itself implement `SessionBroker`, as `Arc<Mutex<B>>` does for any
+/// broker `B`. Calls pass through the caller policy, replay protection and
+/// the other per-call `options`, and are counted in `stats`. Most
+/// applications should use `session_broker_add_to_crossroads` instead,
+/// which also registers the Diagnostics, Maintenance and capabilities
+/// interfaces.
+pub fn register_session_broker<T>(
+    cr: &mut crossroads::Crossroads,
+    stats: Arc<BrokerStats>,
+    options: &ServeOptions,
+) -> crossroads::IfaceToken<T>
+where
+    T: SessionBroker + Send + 'static,
+{
+    register_broker1(cr, Arc::new(Dispatcher::new(stats, options)))
 }
 
-fn register_session_broker<T>(
+fn register_broker1<T>(
     cr: &mut crossroads::Crossroads,
+    dispatcher: Arc<Dispatcher>,
 ) -> crossroads::IfaceToken<T>
 where
     T: SessionBroker + Send + 'static,
 {
-    cr.register("com.microsoft.identity.Broker1", |b| {
+    cr.register("com.microsoft.identity.Broker1", move |b| {
+        let d = dispatcher.clone();
         b.method(
             "acquireTokenInteractively",
             ("protocol_version", "correlation_id", "request_json"),
             ("result",),
-            |_, t: &mut T, (protocol_version, correlation_id, request_json)| {
-                t.acquire_token_interactively(
-                    protocol_version,
-                    correlation_id,
-                    request_json,
-                )
-                .map(|x| (x,))
+            move |ctx,
+                  t: &mut T,
+                  (protocol_version, correlation_id, request_json)| {
+                d.call(ctx, t, "acquireTokenInteractively", |t| {
+                    d.stats.interactive_flow_started();
+                    let res = t.acquire_token_interactively(
+                        protocol_version,
+                        correlation_id,
+                        request_json,
+                    );
+                    d.stats.interactive_flow_finished();
+                    res
+                })
             },
         );
+        let d = dispatcher.clone();
         b.method(
             "acquireTokenSilently",
             ("protocol_version", "correlation_id", "request_json"),
             ("result",),
-            |_, t: &mut T, (protocol_version, correlation_id, request_json)| {
-                t.acquire_token_silently(
-                    protocol_version,
-                    correlation_id,
-                    request_json,
-                )
-                .map(|x| (x,))
+            move |ctx,
+                  t: &mut T,
+                  (protocol_version, correlation_id, request_json)| {
+                d.call(ctx, t, "acquireTokenSilently", |t| {
+                    t.acquire_token_silently(
+                        protocol_version,
+                        correlation_id,
+                        request_json,
+                    )
+                })
             },
         );
+        let d = dispatcher.clone();
         b.method(
             "getAccounts",
             ("protocol_version", "correlation_id", "request_json"),
             ("result",),
-            |_, t: &mut T, (protocol_version, correlation_id, request_json)| {
-                t.get_accounts(protocol_version, correlation_id, request_json)
-                    .map(|x| (x,))
+            move |ctx,
+                  t: &mut T,
+                  (protocol_version, correlation_id, request_json)| {
+                d.call(ctx, t, "getAccounts", |t| {
+                    t.get_accounts(
+                        protocol_version,
+                        correlation_id,
+                        request_json,
+                    )
+                })
             },
         );
+        let d = dispatcher.clone();
         b.method(
             "removeAccount",
             ("protocol_version", "correlation_id", "request_json"),
             ("result",),
-            |_, t: &mut T, (protocol_version, correlation_id, request_json)| {
-                t.remove_account(protocol_version, correlation_id, request_json)
-                    .map(|x| (x,))
+            move |ctx,
+                  t: &mut T,
+                  (protocol_version, correlation_id, request_json)| {
+                d.call(ctx, t, "removeAccount", |t| {
+                    t.remove_account(
+                        protocol_version,
+                        correlation_id,
+                        request_json,
+                    )
+                })
             },
         );
+        let d = dispatcher.clone();
         b.method(
             "acquirePrtSsoCookie",
             ("protocol_version", "correlation_id", "request_json"),
             ("result",),
-            |_, t: &mut T, (protocol_version, correlation_id, request_json)| {
-                t.acquire_prt_sso_cookie(
-                    protocol_version,
-                    correlation_id,
-                    request_json,
-                )
-                .map(|x| (x,))
+            move |ctx,
+                  t: &mut T,
+                  (protocol_version, correlation_id, request_json)| {
+                d.call(ctx, t, "acquirePrtSsoCookie", |t| {
+                    t.acquire_prt_sso_cookie(
+                        protocol_version,
+                        correlation_id,
+                        request_json,
+                    )
+                })
             },
         );
+        let d = dispatcher.clone();
         b.method(
             "generateSignedHttpRequest",
             ("protocol_version", "correlation_id", "request_json"),
             ("result",),
-            |_, t: &mut T, (protocol_version, correlation_id, request_json)| {
-                t.generate_signed_http_request(
-                    protocol_version,
-                    correlation_id,
-                    request_json,
-                )
-                .map(|x| (x,))
+            move |ctx,
+                  t: &mut T,
+                  (protocol_version, correlation_id, request_json)| {
+                d.call(ctx, t, "generateSignedHttpRequest", |t| {
+                    t.generate_signed_http_request(
+                        protocol_version,
+                        correlation_id,
+                        request_json,
+                    )
+                })
             },
         );
+        let d = dispatcher.clone();
         b.method(
             "cancelInteractiveFlow",
             ("protocol_version", "correlation_id", "request_json"),
             ("result",),
-            |_, t: &mut T, (protocol_version, correlation_id, request_json)| {
-                t.cancel_interactive_flow(
-                    protocol_version,
-                    correlation_id,
-                    request_json,
-                )
-                .map(|x| (x,))
+            move |ctx,
+                  t: &mut T,
+                  (protocol_version, correlation_id, request_json)| {
+                d.call(ctx, t, "cancelInteractiveFlow", |t| {
+                    t.cancel_interactive_flow(
+                        protocol_version,
+                        correlation_id,
+                        request_json,
+                    )
+                })
             },
         );
+        let d = dispatcher.clone();
         b.method(
             "getLinuxBrokerVersion",
             ("protocol_version", "correlation_id", "request_json"),
             ("result",),
-            |_, t: &mut T, (protocol_version, correlation_id, request_json)| {
-                t.get_linux_broker_version(
-                    protocol_version,
-                    correlation_id,
-                    request_json,
-                )
-                .map(|x| (x,))
+            move |ctx,
+                  t: &mut T,
+                  (protocol_version, correlation_id, request_json)| {
+                d.call(ctx, t, "getLinuxBrokerVersion", |t| {
+                    t.get_linux_broker_version(
+                        protocol_version,
+                        correlation_id,
+                        request_json,
+                    )
+                })
             },
         );
     })
 }
 
-pub async fn session_broker_serve<T>(broker: T) -> Result<(), dbus::MethodErr>
+/// Allows a single broker to be shared between several object paths.
+///
+/// A method which panicked (see `supervise`) leaves the lock poisoned, but
+/// the broker continues to serve later calls.
+impl<T> SessionBroker for Arc<Mutex<T>>
 where
-    T: SessionBroker + Send + 'static,
+    T: SessionBroker,
 {
-    // Start up a connection to the session bus and request a name
-    let c = Connection::new_session()?;
-    c.request_name("com.microsoft.identity.broker1", false, true, false)?;
-
-    let mut cr = crossroads::Crossroads::new();
-    let token = register_session_broker::<T>(&mut cr);
-
-    cr.insert("/com/microsoft/identity/broker1", &[token], broker);
-
-    // Serve clients forever.
-    cr.serve(&c)?;
-    unreachable!()
-}
-
-struct HimmelblauSessionBroker {
-    sock_path: String,
-    timeout: u64,
-}
-
-impl HimmelblauSessionBroker {
-    fn request(
-        &self,
-        message: ClientRequest,
-    ) -> Result<String, Box<dyn Error>> {
-        let mut stream = UnixStream::connect(&self.sock_path)
-            .map_err(|e| {
-                error!(
-                    "Unix socket stream setup error while connecting to {} -> {:?}",
-                    self.sock_path, e
-                );
-                e
-            })
-            .map_err(Box::new)?;
-
-        stream
-            .write_all(&serde_json::to_vec(&message)?)
-            .and_then(|_| stream.flush())
-            .map_err(|e| {
-                error!("stream write error -> {:?}", e);
-                e
-            })
-            .map_err(Box::new)?;
-
-        // Now wait on the response.
-        let start = SystemTime::now();
-        let mut read_started = false;
-        let mut data = Vec::with_capacity(1024);
-        let mut counter = 0;
-        let timeout = Duration::from_secs(self.timeout);
-
-        loop {
-            let mut buffer = [0; 1024];
-            let durr =
-                SystemTime::now().duration_since(start).map_err(Box::new)?;
-            if durr > timeout {
-                error!("Socket timeout");
-                break;
-            }
-            match stream.read(&mut buffer) {
-                Ok(0) => {
-                    if read_started {
-                        debug!("read_started true, we have completed");
-                        break;
-                    } else {
-                        debug!("Waiting ...");
-                        continue;
-                    }
-                }
-                Ok(count) => {
-                    data.extend_from_slice(&buffer);
-                    counter += count;
-                    if count == 1024 {
-                        debug!("Filled 1024 bytes, looping ...");
-                        read_started = true;
-                        continue;
-                    } else {
-                        debug!("Filled {} bytes, complete", count);
-                        break;
-                    }
-                }
-                Err(e) => {
-                    error!("Stream read failure from {:?} -> {:?}", &stream, e);
-                    return Err(Box::new(e));
-                }
-            }
-        }
-
-        data.truncate(counter);
-
-        Ok(String::from_utf8(data)?)
-    }
-}
-
-impl SessionBroker for HimmelblauSessionBroker {
     fn acquire_token_interactively(
         &mut self,
         protocol_version: String,
         correlation_id: String,
         request_json: String,
     ) -> Result<String, dbus::MethodErr> {
-        self.request(ClientRequest::acquireTokenInteractively(
-            protocol_version,
-            correlation_id,
-            request_json,
-        ))
-        .map_err(|e| dbus::MethodErr::failed(&e))
+        self.lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .acquire_token_interactively(
+                protocol_version,
+                correlation_id,
+                request_json,
+            )
     }
 
     fn acquire_token_silently(
@@ -302,12 +986,13 @@ impl SessionBroker for HimmelblauSessionBroker {
         correlation_id: String,
         request_json: String,
     ) -> Result<String, dbus::MethodErr> {
-        self.request(ClientRequest::acquireTokenSilently(
-            protocol_version,
-            correlation_id,
-            request_json,
-        ))
-        .map_err(|e| dbus::MethodErr::failed(&e))
+        self.lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .acquire_token_silently(
+                protocol_version,
+                correlation_id,
+                request_json,
+            )
     }
 
     fn get_accounts(
@@ -316,12 +1001,9 @@ impl SessionBroker for HimmelblauSessionBroker {
         correlation_id: String,
         request_json: String,
     ) -> Result<String, dbus::MethodErr> {
-        self.request(ClientRequest::getAccounts(
-            protocol_version,
-            correlation_id,
-            request_json,
-        ))
-        .map_err(|e| dbus::MethodErr::failed(&e))
+        self.lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get_accounts(protocol_version, correlation_id, request_json)
     }
 
     fn remove_account(
@@ -330,12 +1012,9 @@ impl SessionBroker for HimmelblauSessionBroker {
         correlation_id: String,
         request_json: String,
     ) -> Result<String, dbus::MethodErr> {
-        self.request(ClientRequest::removeAccount(
-            protocol_version,
-            correlation_id,
-            request_json,
-        ))
-        .map_err(|e| dbus::MethodErr::failed(&e))
+        self.lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove_account(protocol_version, correlation_id, request_json)
     }
 
     fn acquire_prt_sso_cookie(
@@ -344,12 +1023,13 @@ impl SessionBroker for HimmelblauSessionBroker {
         correlation_id: String,
         request_json: String,
     ) -> Result<String, dbus::MethodErr> {
-        self.request(ClientRequest::acquirePrtSsoCookie(
-            protocol_version,
-            correlation_id,
-            request_json,
-        ))
-        .map_err(|e| dbus::MethodErr::failed(&e))
+        self.lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .acquire_prt_sso_cookie(
+                protocol_version,
+                correlation_id,
+                request_json,
+            )
     }
 
     fn generate_signed_http_request(
@@ -358,12 +1038,13 @@ impl SessionBroker for HimmelblauSessionBroker {
         correlation_id: String,
         request_json: String,
     ) -> Result<String, dbus::MethodErr> {
-        self.request(ClientRequest::generateSignedHttpRequest(
-            protocol_version,
-            correlation_id,
-            request_json,
-        ))
-        .map_err(|e| dbus::MethodErr::failed(&e))
+        self.lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .generate_signed_http_request(
+                protocol_version,
+                correlation_id,
+                request_json,
+            )
     }
 
     fn cancel_interactive_flow(
@@ -372,12 +1053,13 @@ impl SessionBroker for HimmelblauSessionBroker {
         correlation_id: String,
         request_json: String,
     ) -> Result<String, dbus::MethodErr> {
-        self.request(ClientRequest::cancelInteractiveFlow(
-            protocol_version,
-            correlation_id,
-            request_json,
-        ))
-        .map_err(|e| dbus::MethodErr::failed(&e))
+        self.lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .cancel_interactive_flow(
+                protocol_version,
+                correlation_id,
+                request_json,
+            )
     }
 
     fn get_linux_broker_version(
@@ -386,34 +1068,1899 @@ impl SessionBroker for HimmelblauSessionBroker {
         correlation_id: String,
         request_json: String,
     ) -> Result<String, dbus::MethodErr> {
-        self.request(ClientRequest::getLinuxBrokerVersion(
-            protocol_version,
-            correlation_id,
-            request_json,
-        ))
-        .map_err(|e| dbus::MethodErr::failed(&e))
+        self.lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get_linux_broker_version(
+                protocol_version,
+                correlation_id,
+                request_json,
+            )
+    }
+
+    fn stats(&self) -> Option<Arc<BrokerStats>> {
+        self.lock().unwrap_or_else(PoisonError::into_inner).stats()
+    }
+
+    fn set_caller(&mut self, caller: Option<CallerInfo>) {
+        self.lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .set_caller(caller);
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .capabilities()
     }
 }
 
-/* The session Broker is simply a DBus session service which forwards messages
- * to the Himmelblau Broker. This layer is necessary because this service
- * imitates the existing Microsoft Broker. Imitating Microsoft's service buys
- * us compatibility with existing projects, such as linux-entra-sso, and with
- * Microsoft's own apps (Edge, Teams, etc). How well this is compatible with
- * Microsoft's apps will depend on the implementation of the HimmelblauBroker
- * trait in src/himmelblau_broker.rs.
- *
- * To utilize this service, simply call `session_broker_serve()`
+pub async fn session_broker_serve<T>(broker: T) -> Result<(), dbus::MethodErr>
+where
+    T: SessionBroker + Send + 'static,
+{
+    session_broker_serve_with_options(broker, ServeOptions::default()).await
+}
+
+pub async fn session_broker_serve_with_options<T>(
+    broker: T,
+    options: ServeOptions,
+) -> Result<(), dbus::MethodErr>
+where
+    T: SessionBroker + Send + 'static,
+{
+    // Start up a connection to the session bus and request a name
+    session_broker_serve_on(Connection::new_session()?, broker, options).await
+}
+
+/// Serve a session broker on a connection the application has already
+/// established, such as one to a private or test bus, or one shared with
+/// other services. Set `ServeOptions::bus` when the connection is not to
+/// the session bus, so that callers can be identified.
+pub async fn session_broker_serve_on<T>(
+    c: Connection,
+    broker: T,
+    options: ServeOptions,
+) -> Result<(), dbus::MethodErr>
+where
+    T: SessionBroker + Send + 'static,
+{
+    options.check()?;
+    let mut names = options.all_bus_names("com.microsoft.identity.broker1");
+    if options.portal_frontend {
+        names.push(PORTAL_BUS_NAME.to_string());
+    }
+    request_names(&c, &names, options.name_policy)?;
+
+    let mut cr = crossroads::Crossroads::new();
+    let (_, dispatcher) = mount_session_broker(&mut cr, broker, &options);
+
+    let watch = NameWatch::new(
+        names,
+        options.on_takeover,
+        options.on_name_lost.clone(),
+        options.bus_type(BusType::Session),
+    )
+    .idle_exit(dispatcher.idle_timer())
+    .deferred_replies(dispatcher.deferred_replies());
+    options.apply_seccomp()?;
+    serve_watching_names(&c, cr, watch)
+}
+
+/// Mount a session broker on a Crossroads instance the application already
+/// serves, for daemons with D-Bus objects of their own. The Broker1,
+/// Diagnostics, Maintenance, capabilities and Peer interfaces are inserted
+/// at `/com/microsoft/identity/broker1` and any `ServeOptions::object_path`s,
+/// and the portal frontend when enabled. Requesting the broker's
+/// well-known names is left to the application, and the options acting on
+/// the serve loop (names, takeovers and idle exit) are not applied.
+/// Returns the handle the broker is shared through.
+pub fn session_broker_add_to_crossroads<T>(
+    cr: &mut crossroads::Crossroads,
+    broker: T,
+    options: &ServeOptions,
+) -> Arc<Mutex<T>>
+where
+    T: SessionBroker + Send + 'static,
+{
+    mount_session_broker(cr, broker, options).0
+}
+
+fn mount_session_broker<T>(
+    cr: &mut crossroads::Crossroads,
+    broker: T,
+    options: &ServeOptions,
+) -> (Arc<Mutex<T>>, Arc<Dispatcher>)
+where
+    T: SessionBroker + Send + 'static,
+{
+    let stats = broker.stats().unwrap_or_default();
+    let broker = Arc::new(Mutex::new(broker));
+    let dispatcher = Arc::new(Dispatcher::new(stats.clone(), options));
+    let token = register_broker1::<Arc<Mutex<T>>>(cr, dispatcher.clone());
+    let diag_token = register_diagnostics::<Arc<Mutex<T>>>(cr, stats);
+    let maintenance_token =
+        register_maintenance::<Arc<Mutex<T>>>(cr, options.maintenance.clone());
+    let ext_token =
+        register_broker_ext(cr, |t: &Arc<Mutex<T>>| t.capabilities());
+    let peer_token = register_peer::<Arc<Mutex<T>>>(cr);
+    let typed_token =
+        register_token_broker::<Arc<Mutex<T>>>(cr, dispatcher.clone());
+    let tokens = [
+        token,
+        typed_token,
+        diag_token,
+        maintenance_token,
+        ext_token,
+        peer_token,
+    ];
+
+    cr.insert("/com/microsoft/identity/broker1", &tokens, broker.clone());
+    for path in options.extra_object_paths() {
+        cr.insert(path, &tokens, broker.clone());
+    }
+    if options.portal_frontend {
+        let portal_token =
+            register_portal_frontend::<Arc<Mutex<T>>>(cr, dispatcher.clone());
+        cr.insert(
+            PORTAL_OBJECT_PATH,
+            &[portal_token, peer_token],
+            broker.clone(),
+        );
+    }
+    (broker, dispatcher)
+}
+
+/// Per-method socket timeouts in seconds. Interactive flows may need
+/// minutes, while silent and metadata calls should fail fast. Unset values
+/// fall back to the timeout passed to `himmelblau_session_broker_serve`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct MethodTimeouts {
+    /// acquireTokenInteractively and completeInteractiveFlow
+    pub interactive: Option<u64>,
+    /// acquireTokenSilently, acquirePrtSsoCookie,
+    /// generateSignedHttpRequest, getSshCert, the device code flow and PRT
+    /// refreshes
+    pub silent: Option<u64>,
+    /// getAccounts, removeAccount, cancelInteractiveFlow,
+    /// getLinuxBrokerVersion and the batched account methods
+    pub metadata: Option<u64>,
+}
+
+impl MethodTimeouts {
+    pub fn for_method(&self, method: &str, default: Duration) -> Duration {
+        let timeout = match MethodClass::of(method) {
+            MethodClass::Interactive => self.interactive,
+            MethodClass::Silent => self.silent,
+            MethodClass::Metadata => self.metadata,
+        };
+        timeout.map(Duration::from_secs).unwrap_or(default)
+    }
+}
+
+/// How long a method is expected to take, grouping methods for
+/// `MethodTimeouts` and `SlowRequestConfig`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum MethodClass {
+    /// Waits on the user.
+    Interactive,
+    /// Reaches Entra ID without the user.
+    Silent,
+    /// Answered from the broker's own state.
+    Metadata,
+}
+
+impl MethodClass {
+    pub(crate) fn of(method: &str) -> Self {
+        match method {
+            "acquireTokenInteractively" | "completeInteractiveFlow" => {
+                MethodClass::Interactive
+            }
+            "acquireTokenSilently"
+            | "acquirePrtSsoCookie"
+            | "generateSignedHttpRequest"
+            | "getSshCert"
+            | "startDeviceCodeFlow"
+            | "pollDeviceCodeFlow"
+            | "completeDeviceCodeFlow"
+            | "refreshPrt" => MethodClass::Silent,
+            _ => MethodClass::Metadata,
+        }
+    }
+}
+
+/// Behaviour options for the Himmelblau session broker.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct HimmelblauSessionBrokerConfig {
+    pub routing: RoutingConfig,
+    pub caller_policy: Option<CallerPolicy>,
+    /// Methods disabled for every caller (see `ServeOptions::method_policy`).
+    pub method_policy: Option<MethodPolicy>,
+    pub timeouts: MethodTimeouts,
+    /// When acquireTokenSilently fails because the PRT has expired, ask the
+    /// daemon to refresh the PRT and retry the request once, rather than
+    /// returning interaction_required to the application.
+    pub refresh_expired_prt: bool,
+    pub pool: PoolConfig,
+    /// Seconds for which the daemon's getLinuxBrokerVersion response is
+    /// cached and answered locally. The cached response also reports this
+    /// crate's version as `sessionBrokerVersion`. Unset disables caching.
+    pub version_cache_ttl: Option<u64>,
+    /// Seconds for which the accounts returned to a client are cached.
+    /// While they are, getAccounts requests carrying a `filter` (see
+    /// `AccountFilter`) are answered locally. Unset disables caching.
+    pub account_cache_ttl: Option<u64>,
+    /// Reject request_json payloads which do not match the method's schema
+    /// (see `request_schema`) before they reach the daemon. Requires the
+    /// `schema-validation` feature.
+    pub validate_requests: bool,
+    /// Persist acquireTokenSilently responses to this file, encrypted with
+    /// a key kept in `offline_cache_key`. While the daemon or network is
+    /// unavailable, still-valid tokens are served from the cache and marked
+    /// with `"servedFromCache": true`. Unset disables the cache.
+    pub offline_cache: Option<PathBuf>,
+    pub offline_cache_key: CacheKeyStore,
+    /// Seconds for which an acquired PRT SSO cookie is considered fresh.
+    /// When set, the latest cookie's expiry is published on the Diagnostics
+    /// interface and, with the `secret-service` feature, the cookie is
+    /// stored in the freedesktop Secret Service (see `load_sso_cookie`).
+    pub sso_cookie_ttl: Option<u64>,
+    /// Reshape acquirePrtSsoCookie responses for matching callers (see
+    /// `ServeOptions::sso_cookie_shapes`).
+    pub sso_cookie_shapes: Vec<SsoCookieShapeRule>,
+    /// Also serve the broker as a portal backend, for sandboxed applications
+    /// (see `ServeOptions::portal_frontend`).
+    pub portal_frontend: bool,
+    /// Watch for calls repeating a recent correlation id (see
+    /// `ServeOptions::replay_protection`). Unset disables the check.
+    pub replay_protection: Option<ReplayConfig>,
+    /// Seconds without a Broker1 call after which the session broker exits,
+    /// to be restarted by D-Bus activation (see `ServeOptions::idle_exit`).
+    /// Unset keeps it running.
+    pub idle_exit: Option<u64>,
+    /// Also serve the broker over varlink at this address (see
+    /// `ServeOptions::varlink`). Requires the varlink feature.
+    pub varlink: Option<SocketAddress>,
+    /// The wire format requested from the daemon (see `WireFormatKind`).
+    /// Daemons which do not support it are spoken to in JSON.
+    pub wire_format: WireFormatKind,
+    /// Standby daemons to fail over to when the daemon socket is
+    /// unavailable.
+    pub failover: FailoverConfig,
+    /// Subscribe to the daemon's account events, emitting them as signals
+    /// on the org.himmelblau.Broker1.Accounts interface (see
+    /// `AccountEvent`) and refreshing cached accounts as they change.
+    pub account_events: bool,
+    /// Start the broker in maintenance (see `ServeOptions::maintenance`).
+    pub maintenance: Option<MaintenanceNotice>,
+    /// When to log Broker1 calls as slow (see
+    /// `ServeOptions::slow_requests`). Unset keeps the defaults.
+    pub slow_requests: Option<SlowRequestConfig>,
+    /// Record Broker1 calls, redacted and with their timing, in a capture
+    /// bundle at this path (see `ServeOptions::capture`). Unset disables
+    /// capturing.
+    pub capture: Option<PathBuf>,
+    /// Seconds for which the server nonce of a successful
+    /// generateSignedHttpRequest is reused for later requests to the same
+    /// resource which carry no nonce of their own (see `NonceCache`).
+    /// Unset disables caching.
+    pub shr_nonce_ttl: Option<u64>,
+    /// Bind PoP token requests (see `PopTokenRequest`) which carry no
+    /// reqCnf to the device's PoP key, with the device broker on the system
+    /// bus, and return the token as an SHR for the request's popParams.
+    pub pop_device_broker: bool,
+    /// Pad responses and delay errors of account-enumeration-sensitive
+    /// methods (see `ServeOptions::hardening`). Unset disables hardening.
+    pub hardening: Option<HardeningConfig>,
+    /// How the account manager interface authorizes changes (see
+    /// `ServeOptions::account_manager`). Unset keeps the defaults.
+    pub account_manager: Option<AccountManagerConfig>,
+    /// Forward the logind session of each caller to the daemon (see
+    /// `ServeOptions::logind_sessions`).
+    pub logind_sessions: bool,
+    /// Refuse calls made while the caller's session is locked (see
+    /// `ServeOptions::screen_lock`). Unset allows them.
+    pub screen_lock: Option<ScreenLockPolicy>,
+}
+
+/* A request is checked against the capabilities of its protocol version
+ * (see `supported_versions`), returning whether it has the flat shape the
+ * version allows.
+ */
+#[cfg(feature = "schema-validation")]
+fn check_protocol_version(
+    method: &str,
+    protocol_version: &str,
+    request_json: &str,
+) -> Result<bool, String> {
+    let version: ProtocolVersion =
+        protocol_version.parse().map_err(|e| format!("{}", e))?;
+    let caps = version
+        .capabilities()
+        .ok_or_else(|| format!("Unsupported protocol version {}", version))?;
+    if BROKER1_METHODS.contains(&method) && !caps.supports(method) {
+        return Err(format!(
+            "{} is not available in protocol version {}",
+            method, version
+        ));
+    }
+    Ok(caps.flat_requests && is_flat_request(request_json))
+}
+
+/* The daemon reports an expired PRT either as an MSAL broker error
+ * ({"error": {"status": "InteractionRequired", ...}}) or as an OAuth error
+ * ({"error": "interaction_required", ...}). An error carrying a claims
+ * challenge asks for step-up authentication, which refreshing the PRT
+ * cannot satisfy, so it is returned to the application as it is.
+ */
+fn prt_expired(resp: &str) -> bool {
+    if ClaimsChallenge::from_response(resp).is_some() {
+        return false;
+    }
+    match serde_json::from_str::<Value>(resp) {
+        Ok(resp) => match resp.get("error") {
+            Some(Value::Object(err)) => matches!(
+                err.get("status").and_then(Value::as_str),
+                Some("InteractionRequired") | Some("PrtExpired")
+            ),
+            Some(Value::String(err)) => err == "interaction_required",
+            _ => false,
+        },
+        Err(_) => false,
+    }
+}
+
+/* The daemon answers with a NoNetwork broker error when it cannot reach
+ * Entra ID.
+ */
+fn is_offline_response(resp: &str) -> bool {
+    serde_json::from_str::<Value>(resp)
+        .map(|resp| {
+            resp.pointer("/error/status").and_then(Value::as_str)
+                == Some("NoNetwork")
+        })
+        .unwrap_or(false)
+}
+
+/* The daemon answers the extension methods its broker does not implement
+ * with an error response whose context describes what is missing.
+ */
+fn extension_error(resp: String) -> dbus::MethodErr {
+    let context = serde_json::from_str::<Value>(&resp)
+        .ok()
+        .and_then(|resp| {
+            resp.pointer("/error/context")
+                .and_then(Value::as_str)
+                .map(str::to_string)
+        })
+        .unwrap_or(resp);
+    dbus::MethodErr::from(("org.freedesktop.DBus.Error.NotSupported", context))
+}
+
+fn is_unsupported_response(resp: &str) -> bool {
+    serde_json::from_str::<Value>(resp)
+        .map(|resp| {
+            resp.pointer("/error/status").and_then(Value::as_str)
+                == Some("NotSupported")
+        })
+        .unwrap_or(false)
+}
+
+fn is_error_response(resp: &str) -> bool {
+    serde_json::from_str::<Value>(resp)
+        .map(|resp| resp.get("error").is_some())
+        .unwrap_or(false)
+}
+
+type RequestError = Box<dyn Error + Send + Sync>;
+
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(5);
+
+/* Errors returned by the broker keep their name, while failing to reach it
+ * at all is reported as org.freedesktop.DBus.Error.Failed.
+ */
+fn method_error(e: RequestError) -> dbus::MethodErr {
+    match e.downcast::<BrokerError>() {
+        Ok(e) => (*e).into(),
+        Err(e) => dbus::MethodErr::failed(&e),
+    }
+}
+
+/// Whether a failed acquireTokenSilently may be answered from the token
+/// cache, because the broker or Entra ID could not be reached.
+fn is_unavailable(e: &dbus::MethodErr) -> bool {
+    let name: &str = e.errorname();
+    [
+        "org.freedesktop.DBus.Error.Failed",
+        BrokerErrorName::Unavailable.as_str(),
+        BrokerErrorName::NoNetwork.as_str(),
+        BrokerErrorName::Timeout.as_str(),
+    ]
+    .contains(&name)
+}
+
+struct HimmelblauSessionBroker {
+    sockets: SocketSet,
+    timeout: u64,
+    config: HimmelblauSessionBrokerConfig,
+    stats: Arc<BrokerStats>,
+    prompt_handler: Arc<dyn PromptHandler>,
+    federation_handler: Arc<dyn FederationHandler>,
+    accounts: Mutex<AccountRegistry>,
+    pool: ConnectionPool<DaemonStream>,
+    version: Mutex<Option<(String, Instant)>>,
+    token_cache: Option<TokenCache>,
+    nonces: Option<NonceCache>,
+    device_broker: Option<DeviceBroker1Client>,
+    #[cfg(feature = "schema-validation")]
+    validator: Option<RequestValidator>,
+}
+
+/// A connection to the daemon, the wire format negotiated on it, and the
+/// index of the socket it was made to (see `SocketSet`).
+struct DaemonStream {
+    stream: UnixStream,
+    format: WireFormatKind,
+    socket: usize,
+}
+
+impl AsRawFd for DaemonStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+}
+
+impl HimmelblauSessionBroker {
+    fn new(
+        sock_path: impl Into<SocketAddress>,
+        timeout: u64,
+        config: HimmelblauSessionBrokerConfig,
+        prompt_handler: Arc<dyn PromptHandler>,
+        federation_handler: Arc<dyn FederationHandler>,
+    ) -> Result<Self, dbus::MethodErr> {
+        #[cfg(feature = "schema-validation")]
+        let validator = match config.validate_requests {
+            true => Some(RequestValidator::new().or_failed()?),
+            false => None,
+        };
+        #[cfg(not(feature = "schema-validation"))]
+        if config.validate_requests {
+            error!("Request validation requires the schema-validation feature");
+        }
+        let token_cache = match &config.offline_cache {
+            Some(path) => {
+                let key = CacheKey::for_cache(config.offline_cache_key, path)
+                    .or_failed()?;
+                Some(TokenCache::open(path, key))
+            }
+            None => None,
+        };
+        let device_broker = match config.pop_device_broker {
+            true => Some(
+                DeviceBroker1Client::system(Duration::from_secs(timeout))
+                    .or_failed()?,
+            ),
+            false => None,
+        };
+        let sock_path = sock_path.into().expand_specifiers().or_failed()?;
+        let sockets =
+            SocketSet::new(sock_path, &config.failover).or_failed()?;
+        Ok(HimmelblauSessionBroker {
+            sockets,
+            timeout,
+            config: config.clone(),
+            stats: Arc::new(BrokerStats::default()),
+            prompt_handler,
+            federation_handler,
+            accounts: Mutex::new(AccountRegistry::default()),
+            pool: ConnectionPool::new(config.pool.clone()),
+            version: Mutex::new(None),
+            token_cache,
+            nonces: config
+                .shr_nonce_ttl
+                .map(|ttl| NonceCache::new(Duration::from_secs(ttl))),
+            device_broker,
+            #[cfg(feature = "schema-validation")]
+            validator,
+        })
+    }
+
+    async fn connect(&self) -> Result<DaemonStream, RequestError> {
+        let (stream, socket) = self.sockets.connect().await.map_err(|e| {
+            error!(
+                "Unix socket stream setup error while connecting to {} -> {:?}",
+                self.sockets.address(self.sockets.active()),
+                e
+            );
+            self.stats.set_socket_connected(false);
+            e
+        })?;
+        self.stats.set_socket_connected(true);
+        let mut conn = DaemonStream {
+            stream,
+            format: WireFormatKind::Json,
+            socket,
+        };
+        let format = self.config.wire_format;
+        if format != WireFormatKind::Json && format.is_available() {
+            conn.format =
+                self.negotiate_format(&mut conn.stream, format).await?;
+        }
+        Ok(conn)
+    }
+
+    /* A daemon which predates format negotiation rejects negotiateFormat as
+     * a malformed frame, and the connection carries on in JSON.
+     */
+    async fn negotiate_format(
+        &self,
+        stream: &mut UnixStream,
+        format: WireFormatKind,
+    ) -> Result<WireFormatKind, RequestError> {
+        Self::write_message(
+            stream,
+            WireFormatKind::Json,
+            &ClientRequest::negotiateFormat(vec![format]),
+        )
+        .await?;
+        let mut buf = vec![];
+        let mut fds = VecDeque::new();
+        let resp = tokio::time::timeout(
+            Duration::from_secs(self.timeout),
+            Self::read_response(
+                stream,
+                WireFormatKind::Json,
+                &mut buf,
+                &mut fds,
+            ),
+        )
+        .await
+        .map_err(|_| {
+            error!("Socket timeout");
+            "Socket timeout"
+        })??;
+        match resp {
+            ClientResponse::formatSelected(format) => {
+                debug!("Using the {:?} wire format", format);
+                Ok(format)
+            }
+            ClientResponse::protocolError(e) => {
+                debug!("The broker does not negotiate wire formats -> {}", e);
+                Ok(WireFormatKind::Json)
+            }
+            _ => Err("Unexpected response to negotiateFormat".into()),
+        }
+    }
+
+    async fn request(
+        &self,
+        message: ClientRequest,
+        caller: Option<&CallerInfo>,
+    ) -> Result<String, RequestError> {
+        trace!(
+            "Sending {} request: {}",
+            message.method(),
+            message
+                .args()
+                .map(|(_, _, request_json)| redact_json(request_json))
+                .unwrap_or_default()
+        );
+        let message = match caller {
+            Some(caller) => {
+                ClientRequest::withCaller(caller.clone(), Box::new(message))
+            }
+            None => message,
+        };
+        // A pooled connection may have been closed by the daemon since its
+        // liveness check, in which case retry on a fresh connection. Those
+        // made before a failover (or fail back) are not reused.
+        let pooled = self
+            .pool
+            .take()
+            .filter(|conn| conn.socket == self.sockets.active());
+        let mut conn = match pooled {
+            Some(mut conn) => {
+                match Self::write_message(
+                    &mut conn.stream,
+                    conn.format,
+                    &message,
+                )
+                .await
+                {
+                    Ok(()) => conn,
+                    Err(_) => {
+                        debug!("Pooled connection failed, reconnecting");
+                        let mut conn = self.connect().await?;
+                        Self::write_message(
+                            &mut conn.stream,
+                            conn.format,
+                            &message,
+                        )
+                        .await?;
+                        conn
+                    }
+                }
+            }
+            None => {
+                let mut conn = self.connect().await?;
+                Self::write_message(&mut conn.stream, conn.format, &message)
+                    .await?;
+                conn
+            }
+        };
+
+        let timeout = self
+            .config
+            .timeouts
+            .for_method(message.method(), Duration::from_secs(self.timeout));
+        let mut buf = vec![];
+        let mut fds = VecDeque::new();
+        loop {
+            let resp = tokio::time::timeout(
+                timeout,
+                Self::read_response(
+                    &mut conn.stream,
+                    conn.format,
+                    &mut buf,
+                    &mut fds,
+                ),
+            )
+            .await
+            .map_err(|_| {
+                error!("Socket timeout");
+                "Socket timeout"
+            })??;
+            match resp {
+                ClientResponse::result(resp) => {
+                    debug!(
+                        "{} response: {}",
+                        message.method(),
+                        summarize_response(&resp)
+                    );
+                    if buf.is_empty() {
+                        self.pool.put(conn);
+                    }
+                    return Ok(resp);
+                }
+                ClientResponse::error(e) => {
+                    debug!("{} failed -> {}", message.method(), e);
+                    if buf.is_empty() {
+                        self.pool.put(conn);
+                    }
+                    return Err(Box::new(e));
+                }
+                ClientResponse::protocolError(e) => {
+                    error!("Request rejected by the broker -> {}", e);
+                    return Err(Box::new(e));
+                }
+                ClientResponse::promptRequest(prompt) => {
+                    debug!("Received {:?} prompt request", prompt.kind);
+                    // Prompt handlers block on the user, so keep them off
+                    // the runtime's worker threads.
+                    let handler = self.prompt_handler.clone();
+                    let resp = tokio::task::spawn_blocking(move || {
+                        handler.prompt(&prompt).unwrap_or_else(|e| {
+                            error!("Prompt failed -> {:?}", e);
+                            PromptResponse { accepted: false }
+                        })
+                    })
+                    .await?;
+                    Self::write_message(
+                        &mut conn.stream,
+                        conn.format,
+                        &ClientRequest::promptResponse(resp),
+                    )
+                    .await?;
+                }
+                ClientResponse::federationRequest(request) => {
+                    debug!(
+                        "Received {:?} federation request for {}",
+                        request.kind, request.url
+                    );
+                    let handler = self.federation_handler.clone();
+                    let resp = tokio::task::spawn_blocking(move || {
+                        handler.exchange(&request).unwrap_or_else(|e| {
+                            error!("Federation request failed -> {:?}", e);
+                            FederationResponse::failed(e.to_string())
+                        })
+                    })
+                    .await?;
+                    Self::write_message(
+                        &mut conn.stream,
+                        conn.format,
+                        &ClientRequest::federationResponse(resp),
+                    )
+                    .await?;
+                }
+                ClientResponse::formatSelected(format) => {
+                    error!("Unexpected {:?} format selection", format);
+                    return Err("Unexpected format selection".into());
+                }
+                ClientResponse::shutdown => {
+                    debug!("The broker shut down during {}", message.method());
+                    return Err(Box::new(BrokerError::Transport(
+                        "The broker is shutting down".to_string(),
+                    )));
+                }
+                ClientResponse::subscribed | ClientResponse::event(_) => {
+                    error!("Unexpected account event");
+                    return Err("Unexpected account event".into());
+                }
+                ClientResponse::fdHandoff(handoff) => {
+                    let fd = fds.pop_front().ok_or_else(|| {
+                        error!("No file descriptor received with handoff");
+                        "No file descriptor received with handoff"
+                    })?;
+                    debug!("Received {:?} file descriptor", handoff.kind);
+                    let handler = self.prompt_handler.clone();
+                    tokio::task::spawn_blocking(move || {
+                        if let Err(e) = handler.handle_fd(&handoff, fd) {
+                            error!("File descriptor handoff failed -> {:?}", e);
+                        }
+                    })
+                    .await?;
+                }
+            }
+        }
+    }
+
+    /* The subscription is held on a connection of its own, outside the
+     * pool. A daemon which does not publish events answers with an error,
+     * or (predating subscriptions) a protocol error, after which the
+     * session broker stops asking.
+     */
+    async fn subscribe(
+        &self,
+        signals: &AccountSignals,
+        resubscribed: bool,
+    ) -> Result<(), RequestError> {
+        let mut conn = self.connect().await?;
+        Self::write_message(
+            &mut conn.stream,
+            conn.format,
+            &ClientRequest::subscribe,
+        )
+        .await?;
+        let mut buf = vec![];
+        let mut fds = VecDeque::new();
+        loop {
+            let event = match Self::read_response(
+                &mut conn.stream,
+                conn.format,
+                &mut buf,
+                &mut fds,
+            )
+            .await?
+            {
+                ClientResponse::subscribed => {
+                    debug!("Subscribed to account events");
+                    // Events may have been missed while disconnected.
+                    if !resubscribed {
+                        continue;
+                    }
+                    AccountEvent::Resync
+                }
+                ClientResponse::event(event) => event,
+                ClientResponse::shutdown => {
+                    debug!("The broker shut down, resubscribing");
+                    return Ok(());
+                }
+                ClientResponse::error(e) => return Err(Box::new(e)),
+                ClientResponse::protocolError(e) => return Err(Box::new(e)),
+                _ => return Err("Unexpected response to subscribe".into()),
+            };
+            debug!("Account event {:?}", event);
+            self.account_changed(&event);
+            signals.emit(&event);
+        }
+    }
+
+    /// Forget the accounts last returned to each client, so that filtered
+    /// getAccounts requests are answered by the daemon again, and the
+    /// cached tokens of accounts whose tokens were revoked.
+    fn account_changed(&self, event: &AccountEvent) {
+        let mut accounts =
+            self.accounts.lock().unwrap_or_else(PoisonError::into_inner);
+        match event {
+            AccountEvent::AccountRemoved(id) => {
+                accounts.remove(id);
+                accounts.forget_clients();
+            }
+            AccountEvent::AccountAdded(_) | AccountEvent::Resync => {
+                accounts.forget_clients();
+            }
+            AccountEvent::TokensChanged(_) => {}
+            AccountEvent::TokenRevoked {
+                home_account_id, ..
+            }
+            | AccountEvent::ReauthRequired {
+                home_account_id, ..
+            } => {
+                if let Some(cache) = &self.token_cache {
+                    cache.forget_account(home_account_id);
+                }
+            }
+        }
+    }
+
+    async fn write_message(
+        stream: &mut UnixStream,
+        format: WireFormatKind,
+        message: &ClientRequest,
+    ) -> Result<(), RequestError> {
+        let data = format.encode(message)?;
+        stream.write_all(&data).await.map_err(|e| {
+            error!("stream write error -> {:?}", e);
+            e
+        })?;
+        stream.flush().await?;
+        Ok(())
+    }
+
+    /* Responses are not delimited, so read until the buffer holds a complete
+     * JSON value. Any bytes following it are left in `buf` for the next
+     * read, and file descriptors passed by the daemon are queued in `fds`
+     * until their fdHandoff message is parsed.
+     */
+    async fn read_response(
+        stream: &mut UnixStream,
+        format: WireFormatKind,
+        buf: &mut Vec<u8>,
+        fds: &mut VecDeque<OwnedFd>,
+    ) -> Result<ClientResponse, RequestError> {
+        loop {
+            match format.decode::<ClientResponse>(buf) {
+                FrameDecode::Complete(resp, len) => {
+                    buf.drain(..len);
+                    return Ok(resp);
+                }
+                FrameDecode::Malformed(e, _) => return Err(e.into()),
+                FrameDecode::Incomplete => {}
+            }
+
+            // Each attempt to decode an incomplete JSON response parses it
+            // from the start, so take everything the daemon has sent before
+            // trying again.
+            stream.readable().await?;
+            let mut chunk = [0; 64 * 1024];
+            let mut received = false;
+            loop {
+                let count = match stream.try_io(Interest::READABLE, || {
+                    recv_with_fds(stream.as_raw_fd(), &mut chunk, fds)
+                }) {
+                    Ok(count) => count,
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) => {
+                        error!(
+                            "Stream read failure from {:?} -> {:?}",
+                            &stream, e
+                        );
+                        return Err(Box::new(e));
+                    }
+                };
+                if count == 0 && !received {
+                    return Err("The broker closed the connection".into());
+                } else if count == 0 {
+                    break;
+                }
+                buf.extend_from_slice(&chunk[..count]);
+                received = true;
+            }
+        }
+    }
+
+    #[cfg_attr(not(feature = "schema-validation"), allow(unused_variables))]
+    fn validate(&self, message: &ClientRequest) -> Result<(), dbus::MethodErr> {
+        #[cfg(feature = "schema-validation")]
+        if let (Some(validator), Some((protocol_version, _, request_json))) =
+            (&self.validator, message.args())
+        {
+            let method = message.method();
+            check_protocol_version(method, protocol_version, request_json)
+                .and_then(|flat| match flat {
+                    // The daemon upgrades flat requests to the nested shape
+                    // the schemas describe.
+                    true => Ok(()),
+                    false => validator.validate(method, request_json),
+                })
+                .map_err(|e| {
+                    debug!("Rejecting request -> {}", e);
+                    dbus::MethodErr::from((
+                        "org.freedesktop.DBus.Error.InvalidArgs",
+                        e,
+                    ))
+                })?;
+        }
+        Ok(())
+    }
+
+    async fn forward(
+        &self,
+        message: ClientRequest,
+        caller: Option<CallerInfo>,
+    ) -> Result<String, dbus::MethodErr> {
+        self.validate(&message)?;
+        self.request(message, caller.as_ref())
+            .await
+            .map_err(method_error)
+    }
+
+    async fn acquire_token_online(
+        &self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        caller: Option<CallerInfo>,
+    ) -> Result<String, dbus::MethodErr> {
+        self.with_pop_key(request_json, |request_json| {
+            self.acquire_token_routed(
+                protocol_version,
+                correlation_id,
+                request_json,
+                caller,
+            )
+        })
+        .await
+    }
+
+    async fn acquire_token_routed(
+        &self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        caller: Option<CallerInfo>,
+    ) -> Result<String, dbus::MethodErr> {
+        let routed = match self.accounts.lock() {
+            Ok(accounts) => {
+                accounts.route(&self.config.routing, &request_json).ok()
+            }
+            Err(_) => None,
+        };
+        let request_json = routed.unwrap_or_else(|| {
+            debug!("Request not routed");
+            request_json
+        });
+        let request_json =
+            self.choose_account(&correlation_id, request_json).await;
+        let resp = self
+            .forward(
+                ClientRequest::acquireTokenSilently(
+                    protocol_version.clone(),
+                    correlation_id.clone(),
+                    request_json.clone(),
+                ),
+                caller.clone(),
+            )
+            .await?;
+        if !self.config.refresh_expired_prt || !prt_expired(&resp) {
+            return Ok(resp);
+        }
+
+        debug!("PRT expired, refreshing before retrying");
+        match self
+            .request(
+                ClientRequest::refreshPrt(
+                    protocol_version.clone(),
+                    correlation_id.clone(),
+                    request_json.clone(),
+                ),
+                caller.as_ref(),
+            )
+            .await
+        {
+            Ok(refresh) if !is_error_response(&refresh) => {}
+            Ok(refresh) => {
+                debug!(
+                    "PRT refresh failed -> {}",
+                    summarize_response(&refresh)
+                );
+                return Ok(resp);
+            }
+            Err(e) => {
+                error!("PRT refresh failed -> {:?}", e);
+                return Ok(resp);
+            }
+        }
+        self.forward(
+            ClientRequest::acquireTokenSilently(
+                protocol_version,
+                correlation_id,
+                request_json,
+            ),
+            caller,
+        )
+        .await
+    }
+
+    /* A request which routing left without an account, and which could be
+     * meant for several accounts, is bound to the account the user picks
+     * with the prompt handler. If they pick none, it is sent as it is.
+     */
+    async fn choose_account(
+        &self,
+        correlation_id: &str,
+        request_json: String,
+    ) -> String {
+        if !requested_accounts(&request_json).is_empty() {
+            return request_json;
+        }
+        let accounts: Vec<Account> = match self.accounts.lock() {
+            Ok(accounts) => accounts
+                .candidates(&self.config.routing, &request_json)
+                .into_iter()
+                .cloned()
+                .collect(),
+            Err(_) => return request_json,
+        };
+        if accounts.len() < 2 {
+            return request_json;
+        }
+        let request = AccountChoiceRequest {
+            correlation_id: correlation_id.to_string(),
+            client_id: request_client_id(&request_json),
+            accounts,
+        };
+        // The user's choice is awaited off the runtime's worker threads.
+        let handler = self.prompt_handler.clone();
+        let choice = tokio::task::spawn_blocking(move || {
+            handler.choose_account(&request).map_err(|e| e.to_string())
+        })
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|choice| choice);
+        let home_account_id = match choice {
+            Ok(Some(home_account_id)) => home_account_id,
+            Ok(None) => return request_json,
+            Err(e) => {
+                error!("Account choice failed -> {}", e);
+                return request_json;
+            }
+        };
+        debug!("Using the chosen account {}", home_account_id);
+        let bound = match self.accounts.lock() {
+            Ok(accounts) => accounts
+                .bind(&self.config.routing, &request_json, &home_account_id)
+                .ok(),
+            Err(_) => None,
+        };
+        bound.unwrap_or(request_json)
+    }
+
+    /* A PoP token request which leaves its key to the device broker is
+     * bound to the device's PoP key: the key's request confirmation is
+     * added to the request before `acquire` forwards it, and the token
+     * returned is presented in an SHR minted with the same key, in one
+     * device broker session. Requests naming their own reqCnf are the
+     * caller's to sign, and are forwarded unchanged.
+     */
+    async fn with_pop_key<F, Fut>(
+        &self,
+        request_json: String,
+        acquire: F,
+    ) -> Result<String, dbus::MethodErr>
+    where
+        F: FnOnce(String) -> Fut,
+        Fut: Future<Output = Result<String, dbus::MethodErr>>,
+    {
+        let pop = PopTokenRequest::from_request(&request_json);
+        let (device, pop) = match (&self.device_broker, pop) {
+            (Some(device), Some(pop)) if pop.req_cnf.is_none() => (device, pop),
+            _ => return acquire(request_json).await,
+        };
+        let session_id = device.open_session().await?;
+        let res = Self::acquire_pop_token(
+            device,
+            &session_id,
+            pop,
+            request_json,
+            acquire,
+        )
+        .await;
+        if let Err(e) = device.close_session(&session_id).await {
+            debug!("Failed to close the device broker session -> {}", e);
+        }
+        res
+    }
+
+    async fn acquire_pop_token<F, Fut>(
+        device: &DeviceBroker1Client,
+        session_id: &str,
+        mut pop: PopTokenRequest,
+        request_json: String,
+        acquire: F,
+    ) -> Result<String, dbus::MethodErr>
+    where
+        F: FnOnce(String) -> Fut,
+        Fut: Future<Output = Result<String, dbus::MethodErr>>,
+    {
+        let request = RequestConfirmationRequest {
+            home_account_id: requested_accounts(&request_json)
+                .into_iter()
+                .map(|account| account.home_account_id)
+                .find(|id| !id.is_empty()),
+        };
+        let req_cnf = device
+            .get_request_confirmation(session_id, &request)
+            .await?;
+        pop.req_cnf = Some(req_cnf.clone());
+        let bound = pop
+            .apply(&request_json)
+            .map_err(|e| dbus::MethodErr::invalid_arg(&e))?;
+        let resp = acquire(bound).await?;
+        // Errors are returned to the application as they are.
+        let access_token = match response_access_token(&resp) {
+            Some(access_token) => access_token,
+            None => return Ok(resp),
+        };
+        let signed_http_request = device
+            .mint_signed_http_request(
+                session_id,
+                &SignedHttpRequestRequest {
+                    access_token,
+                    req_cnf,
+                    pop_params: pop.pop_params,
+                },
+            )
+            .await?;
+        pop_token_response(&resp, &signed_http_request).or_failed()
+    }
+}
+
+#[async_trait]
+impl AsyncSessionBroker for HimmelblauSessionBroker {
+    fn stats(&self) -> Option<Arc<BrokerStats>> {
+        Some(self.stats.clone())
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::new(BROKER1_METHODS)
+            .with("removeAccounts")
+            .with("getAccountsPage")
+            .with("getDefaultAccount")
+            .with("setDefaultAccount")
+            .with("getDeviceComplianceStatus")
+            .with("getSshCert")
+            .with("completeInteractiveFlow")
+            .with("startDeviceCodeFlow")
+            .with("pollDeviceCodeFlow")
+            .with("completeDeviceCodeFlow")
+    }
+
+    async fn watch_accounts(self: Arc<Self>, signals: AccountSignals) {
+        if !self.config.account_events {
+            return;
+        }
+        let mut resubscribed = false;
+        loop {
+            match self.subscribe(&signals, resubscribed).await {
+                Err(e) if e.is::<BrokerError>() || e.is::<ProtocolError>() => {
+                    info!(
+                        "The broker does not publish account events -> {}",
+                        e
+                    );
+                    return;
+                }
+                Err(e) => {
+                    debug!("Account event subscription lost -> {}", e)
+                }
+                Ok(()) => {}
+            }
+            resubscribed = true;
+            tokio::time::sleep(RESUBSCRIBE_DELAY).await;
+        }
+    }
+
+    async fn acquire_token_interactively(
+        &self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        caller: Option<CallerInfo>,
+    ) -> Result<String, dbus::MethodErr> {
+        let hinted = match self.accounts.lock() {
+            Ok(accounts) => accounts
+                .hint_default(&self.config.routing, &request_json)
+                .ok(),
+            Err(_) => None,
+        };
+        let request_json = hinted.unwrap_or(request_json);
+        self.with_pop_key(request_json, |request_json| {
+            self.forward(
+                ClientRequest::acquireTokenInteractively(
+                    protocol_version,
+                    correlation_id,
+                    request_json,
+                ),
+                caller,
+            )
+        })
+        .await
+    }
+
+    async fn acquire_token_silently(
+        &self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        caller: Option<CallerInfo>,
+    ) -> Result<String, dbus::MethodErr> {
+        let cache = match &self.token_cache {
+            Some(cache) => cache,
+            None => {
+                return self
+                    .acquire_token_online(
+                        protocol_version,
+                        correlation_id,
+                        request_json,
+                        caller,
+                    )
+                    .await
+            }
+        };
+        let res = self
+            .acquire_token_online(
+                protocol_version,
+                correlation_id,
+                request_json.clone(),
+                caller,
+            )
+            .await;
+        match res {
+            Ok(resp) if !is_offline_response(&resp) => {
+                if !is_error_response(&resp) {
+                    cache.store(&request_json, &resp);
+                }
+                Ok(resp)
+            }
+            Err(e) if !is_unavailable(&e) => Err(e),
+            res => match cache.lookup(&request_json) {
+                Some(cached) => {
+                    debug!("Broker unavailable, serving a cached token");
+                    self.stats.record_cache_hit();
+                    Ok(cached)
+                }
+                None => res,
+            },
+        }
+    }
+
+    async fn get_accounts(
+        &self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        caller: Option<CallerInfo>,
+    ) -> Result<String, dbus::MethodErr> {
+        let (request_json, filter) =
+            AccountFilter::take_from_request(&request_json)
+                .map_err(|e| dbus::MethodErr::invalid_arg(&e.to_string()))?;
+        let client_id = request_client_id(&request_json);
+
+        // A filtered request may be answered from the registry.
+        if let (Some(filter), Some(client_id), Some(ttl)) =
+            (&filter, &client_id, self.config.account_cache_ttl)
+        {
+            let cached = self.accounts.lock().ok().and_then(|accounts| {
+                let matching: Vec<&Account> = accounts
+                    .cached_for_client(client_id, Duration::from_secs(ttl))?
+                    .into_iter()
+                    .filter(|account| {
+                        filter.matches(
+                            account,
+                            Some(client_id),
+                            Some(&accounts),
+                        )
+                    })
+                    .collect();
+                Some(json!({ "accounts": matching }).to_string())
+            });
+            if let Some(resp) = cached {
+                self.stats.record_cache_hit();
+                return Ok(resp);
+            }
+        }
+
+        let resp = self
+            .forward(
+                ClientRequest::getAccounts(
+                    protocol_version,
+                    correlation_id,
+                    request_json,
+                ),
+                caller,
+            )
+            .await?;
+        let mut accounts = match self.accounts.lock() {
+            Ok(accounts) => accounts,
+            Err(_) => return Ok(resp),
+        };
+        let updated = match &client_id {
+            Some(client_id) => accounts.update_for_client(client_id, &resp),
+            None => accounts.update_from_get_accounts(&resp),
+        };
+        if let Err(e) = updated {
+            debug!("Failed to update the account registry -> {:?}", e);
+        }
+        match filter {
+            Some(filter) => filter
+                .apply(&resp, client_id.as_deref(), Some(&accounts))
+                .or_failed(),
+            None => Ok(resp),
+        }
+    }
+
+    async fn get_default_account(
+        &self,
+        _protocol_version: String,
+        _correlation_id: String,
+        _request_json: String,
+        _caller: Option<CallerInfo>,
+    ) -> Result<String, dbus::MethodErr> {
+        let accounts =
+            self.accounts.lock().unwrap_or_else(PoisonError::into_inner);
+        let default = DefaultAccount {
+            account: accounts.default_account(&self.config.routing),
+        };
+        serde_json::to_string(&default).or_failed()
+    }
+
+    async fn set_default_account(
+        &self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        caller: Option<CallerInfo>,
+    ) -> Result<String, dbus::MethodErr> {
+        let default: DefaultAccount = serde_json::from_str(&request_json)
+            .map_err(|e| dbus::MethodErr::invalid_arg(&e.to_string()))?;
+        self.accounts
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .set_default(default.account);
+        self.get_default_account(
+            protocol_version,
+            correlation_id,
+            request_json,
+            caller,
+        )
+        .await
+    }
+
+    async fn remove_account(
+        &self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        caller: Option<CallerInfo>,
+    ) -> Result<String, dbus::MethodErr> {
+        self.forward(
+            ClientRequest::removeAccount(
+                protocol_version,
+                correlation_id,
+                request_json,
+            ),
+            caller,
+        )
+        .await
+    }
+
+    async fn remove_accounts(
+        &self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        caller: Option<CallerInfo>,
+    ) -> Result<String, dbus::MethodErr> {
+        self.forward(
+            ClientRequest::removeAccounts(
+                protocol_version,
+                correlation_id,
+                request_json,
+            ),
+            caller,
+        )
+        .await
+    }
+
+    async fn get_accounts_page(
+        &self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        caller: Option<CallerInfo>,
+    ) -> Result<String, dbus::MethodErr> {
+        self.forward(
+            ClientRequest::getAccountsPage(
+                protocol_version,
+                correlation_id,
+                request_json,
+            ),
+            caller,
+        )
+        .await
+    }
+
+    async fn get_device_compliance_status(
+        &self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        caller: Option<CallerInfo>,
+    ) -> Result<String, dbus::MethodErr> {
+        let resp = self
+            .forward(
+                ClientRequest::getDeviceComplianceStatus(
+                    protocol_version,
+                    correlation_id,
+                    request_json,
+                ),
+                caller,
+            )
+            .await?;
+        if is_error_response(&resp) {
+            return Err(extension_error(resp));
+        }
+        let status: DeviceComplianceStatus =
+            serde_json::from_str(&resp).or_failed()?;
+        serde_json::to_string(&status).or_failed()
+    }
+
+    async fn get_ssh_cert(
+        &self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        caller: Option<CallerInfo>,
+    ) -> Result<String, dbus::MethodErr> {
+        let resp = self
+            .forward(
+                ClientRequest::getSshCert(
+                    protocol_version,
+                    correlation_id,
+                    request_json,
+                ),
+                caller,
+            )
+            .await?;
+        if is_error_response(&resp) {
+            return Err(extension_error(resp));
+        }
+        let cert: SshCertificate = serde_json::from_str(&resp).or_failed()?;
+        serde_json::to_string(&cert).or_failed()
+    }
+
+    async fn complete_interactive_flow(
+        &self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        caller: Option<CallerInfo>,
+    ) -> Result<String, dbus::MethodErr> {
+        let resp = self
+            .forward(
+                ClientRequest::completeInteractiveFlow(
+                    protocol_version,
+                    correlation_id,
+                    request_json,
+                ),
+                caller,
+            )
+            .await?;
+        if is_unsupported_response(&resp) {
+            return Err(extension_error(resp));
+        }
+        Ok(resp)
+    }
+
+    async fn start_device_code_flow(
+        &self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        caller: Option<CallerInfo>,
+    ) -> Result<String, dbus::MethodErr> {
+        let resp = self
+            .forward(
+                ClientRequest::startDeviceCodeFlow(
+                    protocol_version,
+                    correlation_id,
+                    request_json,
+                ),
+                caller,
+            )
+            .await?;
+        if is_error_response(&resp) {
+            return Err(extension_error(resp));
+        }
+        let code: DeviceCode = serde_json::from_str(&resp).or_failed()?;
+        serde_json::to_string(&code).or_failed()
+    }
+
+    async fn poll_device_code_flow(
+        &self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        caller: Option<CallerInfo>,
+    ) -> Result<String, dbus::MethodErr> {
+        let resp = self
+            .forward(
+                ClientRequest::pollDeviceCodeFlow(
+                    protocol_version,
+                    correlation_id,
+                    request_json,
+                ),
+                caller,
+            )
+            .await?;
+        if is_error_response(&resp) {
+            return Err(extension_error(resp));
+        }
+        let status: DeviceCodeStatus =
+            serde_json::from_str(&resp).or_failed()?;
+        serde_json::to_string(&status).or_failed()
+    }
+
+    async fn complete_device_code_flow(
+        &self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        caller: Option<CallerInfo>,
+    ) -> Result<String, dbus::MethodErr> {
+        // The token response is passed through as it is for
+        // acquireTokenInteractively, so only a missing flow is an error.
+        let resp = self
+            .forward(
+                ClientRequest::completeDeviceCodeFlow(
+                    protocol_version,
+                    correlation_id,
+                    request_json,
+                ),
+                caller,
+            )
+            .await?;
+        if is_unsupported_response(&resp) {
+            return Err(extension_error(resp));
+        }
+        Ok(resp)
+    }
+
+    async fn acquire_prt_sso_cookie(
+        &self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        caller: Option<CallerInfo>,
+    ) -> Result<String, dbus::MethodErr> {
+        let resp = self
+            .forward(
+                ClientRequest::acquirePrtSsoCookie(
+                    protocol_version,
+                    correlation_id,
+                    request_json,
+                ),
+                caller,
+            )
+            .await?;
+        if let Some(cookie) = self
+            .config
+            .sso_cookie_ttl
+            .and_then(|ttl| SsoCookie::from_response(&resp, ttl))
+        {
+            self.stats.set_sso_cookie_expiry(cookie.expires_at);
+            #[cfg(feature = "secret-service")]
+            tokio::task::spawn_blocking(move || {
+                if let Err(e) = store_sso_cookie(&cookie) {
+                    error!("Failed to store the SSO cookie -> {:?}", e);
+                }
+            });
+        }
+        Ok(resp)
+    }
+
+    /* A request rejected by the daemon for want of a fresh server nonce is
+     * retried once, with the nonce the daemon supplied.
+     */
+    async fn generate_signed_http_request(
+        &self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        caller: Option<CallerInfo>,
+    ) -> Result<String, dbus::MethodErr> {
+        let message = |request_json| {
+            ClientRequest::generateSignedHttpRequest(
+                protocol_version.clone(),
+                correlation_id.clone(),
+                request_json,
+            )
+        };
+        let mut params = match PopParams::from_request(&request_json) {
+            Some(params) => params,
+            None => return self.forward(message(request_json), caller).await,
+        };
+        self.validate(&message(request_json.clone()))?;
+        let origin = params.resource_origin();
+        let nonces = self.nonces.as_ref().zip(origin.as_deref());
+        if params.shr_nonce.is_none() {
+            params.shr_nonce = nonces.and_then(|(n, origin)| n.get(origin));
+        }
+        let mut retried = false;
+        loop {
+            let signed = params
+                .apply(&request_json)
+                .map_err(|e| dbus::MethodErr::invalid_arg(&e))?;
+            let e = match self.request(message(signed), caller.as_ref()).await {
+                Ok(resp) => {
+                    if let (Some((n, origin)), Some(nonce)) =
+                        (nonces, &params.shr_nonce)
+                    {
+                        n.store(origin, nonce);
+                    }
+                    return Ok(resp);
+                }
+                Err(e) => e,
+            };
+            let fresh = match e.downcast_ref::<BrokerError>() {
+                Some(BrokerError::NonceRequired { nonce }) => {
+                    if let Some((n, origin)) = nonces {
+                        n.invalidate(origin);
+                    }
+                    nonce.clone()
+                }
+                _ => None,
+            };
+            match fresh {
+                Some(nonce) if !retried => {
+                    debug!("Retrying the signed request with a fresh nonce");
+                    params.shr_nonce = Some(nonce);
+                    retried = true;
+                }
+                _ => return Err(method_error(e)),
+            }
+        }
+    }
+
+    async fn cancel_interactive_flow(
+        &self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        caller: Option<CallerInfo>,
+    ) -> Result<String, dbus::MethodErr> {
+        self.forward(
+            ClientRequest::cancelInteractiveFlow(
+                protocol_version,
+                correlation_id,
+                request_json,
+            ),
+            caller,
+        )
+        .await
+    }
+
+    async fn get_linux_broker_version(
+        &self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        caller: Option<CallerInfo>,
+    ) -> Result<String, dbus::MethodErr> {
+        let ttl = match self.config.version_cache_ttl {
+            Some(ttl) => Duration::from_secs(ttl),
+            None => {
+                return self
+                    .forward(
+                        ClientRequest::getLinuxBrokerVersion(
+                            protocol_version,
+                            correlation_id,
+                            request_json,
+                        ),
+                        caller,
+                    )
+                    .await
+            }
+        };
+        let cached = self.version.lock().ok().and_then(|v| v.clone());
+        if let Some((version, fetched)) = &cached {
+            if fetched.elapsed() < ttl {
+                self.stats.record_cache_hit();
+                return Ok(version.clone());
+            }
+        }
+
+        match self
+            .forward(
+                ClientRequest::getLinuxBrokerVersion(
+                    protocol_version,
+                    correlation_id,
+                    request_json,
+                ),
+                caller,
+            )
+            .await
+        {
+            Ok(resp) => {
+                let version = with_session_broker_version(resp);
+                if let Ok(mut cache) = self.version.lock() {
+                    *cache = Some((version.clone(), Instant::now()));
+                }
+                Ok(version)
+            }
+            // A stale version is better than none while the daemon is down.
+            Err(e) => match cached {
+                Some((version, _)) => {
+                    debug!("Answering with a stale broker version -> {:?}", e);
+                    Ok(version)
+                }
+                None => Err(e),
+            },
+        }
+    }
+}
+
+/* Combine the daemon's version response with the version of this crate, so
+ * that both sides of the broker can be identified from one call.
+ */
+fn with_session_broker_version(resp: String) -> String {
+    match serde_json::from_str::<Value>(&resp) {
+        Ok(Value::Object(mut obj)) => {
+            obj.insert(
+                "sessionBrokerVersion".to_string(),
+                Value::String(env!("CARGO_PKG_VERSION").to_string()),
+            );
+            Value::Object(obj).to_string()
+        }
+        _ => resp,
+    }
+}
+
+/// The Himmelblau session broker without serving it on D-Bus, for tests
+/// which call it directly against a daemon socket.
+#[cfg(feature = "test-util")]
+pub fn himmelblau_session_broker(
+    sock_path: impl Into<SocketAddress>,
+    timeout: u64,
+    config: HimmelblauSessionBrokerConfig,
+    prompt_handler: Arc<dyn PromptHandler>,
+    federation_handler: Arc<dyn FederationHandler>,
+) -> Result<impl AsyncSessionBroker, dbus::MethodErr> {
+    HimmelblauSessionBroker::new(
+        sock_path,
+        timeout,
+        config,
+        prompt_handler,
+        federation_handler,
+    )
+}
+
+/* The session Broker is simply a DBus session service which forwards messages
+ * to the Himmelblau Broker. This layer is necessary because this service
+ * imitates the existing Microsoft Broker. Imitating Microsoft's service buys
+ * us compatibility with existing projects, such as linux-entra-sso, and with
+ * Microsoft's own apps (Edge, Teams, etc). How well this is compatible with
+ * Microsoft's apps will depend on the implementation of the HimmelblauBroker
+ * trait in src/himmelblau_broker.rs.
+ *
+ * To utilize this service, simply call `session_broker_serve()`
  * from a binary, then install that binary as a session service on your
  * Linux distribution.
  */
 pub async fn himmelblau_session_broker_serve(
-    sock_path: &str,
+    sock_path: impl Into<SocketAddress>,
     timeout: u64,
 ) -> Result<(), dbus::MethodErr> {
-    session_broker_serve(HimmelblauSessionBroker {
-        sock_path: sock_path.to_string(),
+    himmelblau_session_broker_serve_with_config(
+        sock_path,
         timeout,
-    })
+        HimmelblauSessionBrokerConfig::default(),
+    )
+    .await
+}
+
+pub async fn himmelblau_session_broker_serve_with_config(
+    sock_path: impl Into<SocketAddress>,
+    timeout: u64,
+    config: HimmelblauSessionBrokerConfig,
+) -> Result<(), dbus::MethodErr> {
+    himmelblau_session_broker_serve_with_handlers(
+        sock_path,
+        timeout,
+        config,
+        Arc::new(PortalPromptHandler::default()),
+        Arc::new(NoFederationHandler),
+    )
     .await
 }
+
+/// Serve the Himmelblau session broker, presenting the daemon's prompts
+/// with `prompt_handler` and making its federated identity provider
+/// requests with `federation_handler`.
+pub async fn himmelblau_session_broker_serve_with_handlers(
+    sock_path: impl Into<SocketAddress>,
+    timeout: u64,
+    config: HimmelblauSessionBrokerConfig,
+    prompt_handler: Arc<dyn PromptHandler>,
+    federation_handler: Arc<dyn FederationHandler>,
+) -> Result<(), dbus::MethodErr> {
+    himmelblau_session_broker_serve_with_options(
+        sock_path,
+        timeout,
+        config,
+        prompt_handler,
+        federation_handler,
+        ServeOptions::new(),
+    )
+    .await
+}
+
+/// Serve the Himmelblau session broker with handlers, also applying
+/// `options` for what the config cannot express, such as
+/// `ServeOptions::response_transformer`. Options set by `config` take
+/// precedence.
+pub async fn himmelblau_session_broker_serve_with_options(
+    sock_path: impl Into<SocketAddress>,
+    timeout: u64,
+    config: HimmelblauSessionBrokerConfig,
+    prompt_handler: Arc<dyn PromptHandler>,
+    federation_handler: Arc<dyn FederationHandler>,
+    mut options: ServeOptions,
+) -> Result<(), dbus::MethodErr> {
+    if let Some(policy) = &config.caller_policy {
+        options = options.caller_policy(policy.clone());
+    }
+    if let Some(policy) = &config.method_policy {
+        options = options.method_policy(policy.clone());
+    }
+    if config.portal_frontend {
+        options = options.portal_frontend();
+    }
+    if let Some(replay) = &config.replay_protection {
+        options = options.replay_protection(replay.clone());
+    }
+    if let Some(idle) = config.idle_exit {
+        options = options.idle_exit(Duration::from_secs(idle));
+    }
+    if !config.sso_cookie_shapes.is_empty() {
+        options = options.sso_cookie_shapes(config.sso_cookie_shapes.clone());
+    }
+    if let Some(path) = &config.capture {
+        options = options.capture(path);
+    }
+    if let Some(notice) = &config.maintenance {
+        options.maintenance.enter(notice.clone());
+    }
+    if let Some(slow) = &config.slow_requests {
+        options = options.slow_requests(slow.clone());
+    }
+    if let Some(hardening) = &config.hardening {
+        options = options.hardening(hardening.clone());
+    }
+    if let Some(manager) = &config.account_manager {
+        options = options.account_manager(manager.clone());
+    }
+    if config.logind_sessions {
+        options = options.logind_sessions();
+    }
+    if let Some(policy) = &config.screen_lock {
+        options = options.screen_lock(policy.clone());
+    }
+    #[cfg(feature = "varlink")]
+    if let Some(address) = &config.varlink {
+        options = options.varlink(address.clone());
+    }
+    #[cfg(not(feature = "varlink"))]
+    if config.varlink.is_some() {
+        error!("Serving varlink requires the varlink feature");
+    }
+    let broker = HimmelblauSessionBroker::new(
+        sock_path,
+        timeout,
+        config,
+        prompt_handler,
+        federation_handler,
+    )?;
+    async_session_broker_serve_with_options(broker, options).await
+}
@@ -0,0 +1,317 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::caller::CallerInfo;
+use crate::diagnostics::BrokerStats;
+use crate::error::BrokerError;
+use crate::events::BrokerEvents;
+use crate::himmelblau_broker::HimmelblauBroker;
+use crate::prompt::Prompter;
+use async_trait::async_trait;
+use libc::uid_t;
+use std::sync::Arc;
+
+/// A variant of `HimmelblauBroker` for brokers shared by every connection
+/// rather than copied into each. Methods take `&self`, so the broker keeps
+/// whatever interior locking it needs, and `Arc<T>` (including
+/// `Arc<dyn SharedHimmelblauBroker>`) implements `HimmelblauBroker` and may
+/// be passed to `himmelblau_broker_serve`.
+///
+/// Brokers implementing the extension traits (compliance, PAM, SSH
+/// certificates, delegation, device code or federation) implement
+/// `HimmelblauBroker` directly instead.
+#[async_trait]
+pub trait SharedHimmelblauBroker: Send + Sync {
+    async fn acquire_token_interactively(
+        &self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        uid: uid_t,
+    ) -> Result<String, BrokerError>;
+    /// See `HimmelblauBroker::acquire_token_interactively_with_prompt`.
+    async fn acquire_token_interactively_with_prompt(
+        &self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        uid: uid_t,
+        _prompter: &mut dyn Prompter,
+    ) -> Result<String, BrokerError> {
+        self.acquire_token_interactively(
+            protocol_version,
+            correlation_id,
+            request_json,
+            uid,
+        )
+        .await
+    }
+    async fn acquire_token_silently(
+        &self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        uid: uid_t,
+    ) -> Result<String, BrokerError>;
+    async fn get_accounts(
+        &self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        uid: uid_t,
+    ) -> Result<String, BrokerError>;
+    async fn remove_account(
+        &self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        uid: uid_t,
+    ) -> Result<String, BrokerError>;
+    async fn acquire_prt_sso_cookie(
+        &self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        uid: uid_t,
+    ) -> Result<String, BrokerError>;
+    async fn generate_signed_http_request(
+        &self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        uid: uid_t,
+    ) -> Result<String, BrokerError>;
+    async fn cancel_interactive_flow(
+        &self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        uid: uid_t,
+    ) -> Result<String, BrokerError>;
+    async fn get_linux_broker_version(
+        &self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        uid: uid_t,
+    ) -> Result<String, BrokerError>;
+
+    /// See `HimmelblauBroker::refresh_prt`.
+    async fn refresh_prt(
+        &self,
+        _protocol_version: String,
+        _correlation_id: String,
+        _request_json: String,
+        _uid: uid_t,
+    ) -> Result<String, BrokerError> {
+        Err("refreshPrt is not supported".into())
+    }
+
+    /// See `HimmelblauBroker::stats`.
+    fn stats(&self) -> Option<Arc<BrokerStats>> {
+        None
+    }
+
+    /// See `HimmelblauBroker::events`.
+    fn events(&self) -> Option<BrokerEvents> {
+        None
+    }
+
+    /// See `HimmelblauBroker::check_caller`.
+    async fn check_caller(
+        &self,
+        _method: &str,
+        _caller: &CallerInfo,
+        _uid: uid_t,
+    ) -> Result<(), BrokerError> {
+        Ok(())
+    }
+}
+
+/* Each connection gets its own clone of the Arc, so the broker state is
+ * shared rather than duplicated. remove_accounts and get_accounts_page
+ * keep the HimmelblauBroker defaults, which call back into the methods
+ * below.
+ */
+#[async_trait]
+impl<T> HimmelblauBroker for Arc<T>
+where
+    T: SharedHimmelblauBroker + ?Sized,
+{
+    async fn acquire_token_interactively(
+        &mut self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        uid: uid_t,
+    ) -> Result<String, BrokerError> {
+        (**self)
+            .acquire_token_interactively(
+                protocol_version,
+                correlation_id,
+                request_json,
+                uid,
+            )
+            .await
+    }
+    async fn acquire_token_interactively_with_prompt(
+        &mut self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        uid: uid_t,
+        prompter: &mut dyn Prompter,
+    ) -> Result<String, BrokerError> {
+        (**self)
+            .acquire_token_interactively_with_prompt(
+                protocol_version,
+                correlation_id,
+                request_json,
+                uid,
+                prompter,
+            )
+            .await
+    }
+    async fn acquire_token_silently(
+        &mut self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        uid: uid_t,
+    ) -> Result<String, BrokerError> {
+        (**self)
+            .acquire_token_silently(
+                protocol_version,
+                correlation_id,
+                request_json,
+                uid,
+            )
+            .await
+    }
+    async fn get_accounts(
+        &mut self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        uid: uid_t,
+    ) -> Result<String, BrokerError> {
+        (**self)
+            .get_accounts(protocol_version, correlation_id, request_json, uid)
+            .await
+    }
+    async fn remove_account(
+        &mut self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        uid: uid_t,
+    ) -> Result<String, BrokerError> {
+        (**self)
+            .remove_account(protocol_version, correlation_id, request_json, uid)
+            .await
+    }
+    async fn acquire_prt_sso_cookie(
+        &mut self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        uid: uid_t,
+    ) -> Result<String, BrokerError> {
+        (**self)
+            .acquire_prt_sso_cookie(
+                protocol_version,
+                correlation_id,
+                request_json,
+                uid,
+            )
+            .await
+    }
+    async fn generate_signed_http_request(
+        &mut self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        uid: uid_t,
+    ) -> Result<String, BrokerError> {
+        (**self)
+            .generate_signed_http_request(
+                protocol_version,
+                correlation_id,
+                request_json,
+                uid,
+            )
+            .await
+    }
+    async fn cancel_interactive_flow(
+        &mut self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        uid: uid_t,
+    ) -> Result<String, BrokerError> {
+        (**self)
+            .cancel_interactive_flow(
+                protocol_version,
+                correlation_id,
+                request_json,
+                uid,
+            )
+            .await
+    }
+    async fn get_linux_broker_version(
+        &mut self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        uid: uid_t,
+    ) -> Result<String, BrokerError> {
+        (**self)
+            .get_linux_broker_version(
+                protocol_version,
+                correlation_id,
+                request_json,
+                uid,
+            )
+            .await
+    }
+    async fn refresh_prt(
+        &mut self,
+        protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        uid: uid_t,
+    ) -> Result<String, BrokerError> {
+        (**self)
+            .refresh_prt(protocol_version, correlation_id, request_json, uid)
+            .await
+    }
+    fn stats(&self) -> Option<Arc<BrokerStats>> {
+        (**self).stats()
+    }
+    fn events(&self) -> Option<BrokerEvents> {
+        (**self).events()
+    }
+    async fn check_caller(
+        &mut self,
+        method: &str,
+        caller: &CallerInfo,
+        uid: uid_t,
+    ) -> Result<(), BrokerError> {
+        (**self).check_caller(method, caller, uid).await
+    }
+}
@@ -0,0 +1,136 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/// The `popParams` of a generateSignedHttpRequest request, describing the
+/// resource request the signed HTTP request (SHR) is bound to.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct PopParams {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resource_request_method: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resource_request_uri: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shr_claims: Option<String>,
+    /// The server nonce to sign, as issued by the resource (for example in
+    /// Graph's `WWW-Authenticate: PoP nonce="..."` challenge).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shr_nonce: Option<String>,
+    /// Parameters this crate does not interpret, passed through unchanged.
+    #[serde(flatten)]
+    pub other: Map<String, Value>,
+}
+
+impl PopParams {
+    pub fn from_request(request_json: &str) -> Option<Self> {
+        let request: Value = serde_json::from_str(request_json).ok()?;
+        serde_json::from_value(request.get("popParams")?.clone()).ok()
+    }
+
+    /// `request_json` with its popParams replaced by these.
+    pub fn apply(&self, request_json: &str) -> Result<String, String> {
+        let mut request: Map<String, Value> =
+            serde_json::from_str(request_json).map_err(|e| e.to_string())?;
+        request.insert(
+            "popParams".to_string(),
+            serde_json::to_value(self).map_err(|e| e.to_string())?,
+        );
+        Ok(Value::Object(request).to_string())
+    }
+
+    /// The scheme and authority of the resource, which its nonces are
+    /// valid for.
+    pub fn resource_origin(&self) -> Option<String> {
+        let uri = self.resource_request_uri.as_deref()?;
+        let (scheme, rest) = uri.split_once("://")?;
+        let authority = rest.split(['/', '?', '#']).next()?;
+        if authority.is_empty() {
+            return None;
+        }
+        Some(format!(
+            "{}://{}",
+            scheme.to_ascii_lowercase(),
+            authority.to_ascii_lowercase()
+        ))
+    }
+}
+
+/// The nonce in a PoP `WWW-Authenticate` challenge, such as
+/// `PoP nonce="AQAA..."`.
+pub fn nonce_from_challenge(www_authenticate: &str) -> Option<String> {
+    let (scheme, params) = www_authenticate.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("PoP") {
+        return None;
+    }
+    params.split(',').find_map(|param| {
+        let (name, value) = param.trim().split_once('=')?;
+        name.trim()
+            .eq_ignore_ascii_case("nonce")
+            .then(|| value.trim().trim_matches('"').to_string())
+    })
+}
+
+/* Resources hand out nonces which expire within minutes, and fetching one
+ * costs the application a rejected request. Nonces are cached by resource
+ * origin, so that a signed request for a resource which recently issued a
+ * nonce can be made with it rather than being rejected first. A nonce
+ * which the broker reports as stale is replaced (see
+ * `BrokerError::NonceRequired`).
+ */
+/// Server nonces for signed HTTP requests, by resource origin.
+pub struct NonceCache {
+    ttl: Duration,
+    nonces: Mutex<HashMap<String, (String, Instant)>>,
+}
+
+impl NonceCache {
+    pub fn new(ttl: Duration) -> Self {
+        NonceCache {
+            ttl,
+            nonces: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn get(&self, origin: &str) -> Option<String> {
+        let nonces = self.nonces.lock().unwrap_or_else(PoisonError::into_inner);
+        nonces
+            .get(origin)
+            .filter(|(_, stored)| stored.elapsed() < self.ttl)
+            .map(|(nonce, _)| nonce.clone())
+    }
+
+    pub fn store(&self, origin: &str, nonce: &str) {
+        let mut nonces =
+            self.nonces.lock().unwrap_or_else(PoisonError::into_inner);
+        nonces.retain(|_, (_, stored)| stored.elapsed() < self.ttl);
+        nonces.insert(origin.to_string(), (nonce.to_string(), Instant::now()));
+    }
+
+    pub fn invalidate(&self, origin: &str) {
+        self.nonces
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(origin);
+    }
+}
@@ -0,0 +1,96 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::error::BrokerError;
+use libc::uid_t;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Mutex, PoisonError};
+use tokio::sync::watch;
+use tracing::debug;
+
+type FlightResult = Option<Result<String, BrokerError>>;
+type FlightKey = (uid_t, String);
+
+/* Applications tend to ask for the same token at the same moment (Teams
+ * and Edge both do at startup). A request identical to one already in
+ * flight for the same user waits for that request's response instead of
+ * making its own backend call.
+ */
+#[derive(Default)]
+pub(crate) struct SingleFlight {
+    pending: Mutex<HashMap<FlightKey, watch::Receiver<FlightResult>>>,
+}
+
+/// Removes a request from the in-flight set, including when the connection
+/// making it is dropped before it completes.
+struct PendingGuard<'a> {
+    flights: &'a SingleFlight,
+    key: FlightKey,
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        self.flights
+            .pending
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&self.key);
+    }
+}
+
+impl SingleFlight {
+    pub(crate) async fn run<F, Fut>(
+        &self,
+        uid: uid_t,
+        request_json: &str,
+        request: F,
+    ) -> Result<String, BrokerError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<String, BrokerError>>,
+    {
+        let key = (uid, request_json.to_string());
+        let (tx, waiting) = {
+            let mut pending =
+                self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+            match pending.get(&key) {
+                Some(rx) => (None, Some(rx.clone())),
+                None => {
+                    let (tx, rx) = watch::channel(None);
+                    pending.insert(key.clone(), rx);
+                    (Some(tx), None)
+                }
+            }
+        };
+        if let Some(mut rx) = waiting {
+            debug!("Waiting on an identical request for uid {}", uid);
+            return match rx.wait_for(Option::is_some).await {
+                Ok(res) => res
+                    .clone()
+                    .unwrap_or_else(|| Err("Request was abandoned".into())),
+                Err(_) => Err("Request was abandoned".into()),
+            };
+        }
+        let tx = tx.ok_or("Request has no result channel")?;
+        let _guard = PendingGuard { flights: self, key };
+
+        let res = request().await;
+        let _ = tx.send(Some(res.clone()));
+        res
+    }
+}
@@ -0,0 +1,444 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+use serde::{Deserialize, Serialize};
+use std::ffi::{CStr, CString, OsString};
+use std::fmt;
+use std::fs::{self, DirBuilder, Permissions};
+use std::io;
+use std::mem::MaybeUninit;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::{
+    DirBuilderExt, FileTypeExt, MetadataExt, PermissionsExt,
+};
+use std::os::unix::net;
+use std::path::{Path, PathBuf};
+use tokio::net::{UnixListener, UnixStream};
+use tracing::{debug, info, warn};
+
+/// Ownership and permissions of a filesystem socket created by
+/// `himmelblau_broker_serve`. They do not apply to abstract sockets.
+#[derive(Debug, Clone)]
+pub struct SocketPermissions {
+    /// The socket file's mode. Every user's session broker connects to the
+    /// daemon, so by default any user may connect.
+    pub mode: u32,
+    /// Owner applied to the socket after it is bound.
+    pub uid: Option<libc::uid_t>,
+    /// Group applied to the socket after it is bound, for example to limit
+    /// connections to members of a group with a mode of 0o660.
+    pub gid: Option<libc::gid_t>,
+    /// Create missing parent directories with this mode. Unset requires the
+    /// directory to exist.
+    pub directory_mode: Option<u32>,
+}
+
+/// What to do when the daemon's socket path already exists at startup.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SocketTakeover {
+    /// Replace a stale socket left behind by a daemon which did not shut
+    /// down cleanly, but refuse to start while another daemon is answering
+    /// on it.
+    #[default]
+    StaleOnly,
+    /// Replace the socket even while another daemon is answering on it.
+    /// That daemon keeps its existing connections, but new clients reach
+    /// this one.
+    Always,
+    /// Never remove an existing socket.
+    Never,
+}
+
+impl Default for SocketPermissions {
+    fn default() -> Self {
+        SocketPermissions {
+            mode: 0o666,
+            uid: None,
+            gid: None,
+            directory_mode: None,
+        }
+    }
+}
+
+/// The address of the himmelblau daemon socket.
+///
+/// Sandboxed clients (Flatpak, snaps) may not share the host's filesystem,
+/// but do share its network namespace when granted, so the daemon may
+/// instead listen on an abstract-namespace socket. When converting from a
+/// string, a leading NUL selects the abstract namespace, as with the
+/// `sun_path` convention.
+///
+/// Addresses may contain the systemd specifiers `%U` (the uid of the serving
+/// process) and `%u` (its user name), which are expanded when serving, so
+/// that per-user daemon sockets can be laid out without wrapper scripts.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum SocketAddress {
+    Path(PathBuf),
+    Abstract(String),
+    /// A vsock (or Hyper-V socket) port, for reaching a broker on the other
+    /// side of a virtual machine boundary, such as the Windows host from
+    /// WSL (cid 2). The session broker can only connect to it.
+    #[cfg(feature = "wsl")]
+    Vsock {
+        cid: u32,
+        port: u32,
+    },
+    /// A command relaying its standard input and output to the broker, such
+    /// as `npiperelay.exe` for a Windows named pipe. The session broker can
+    /// only connect to it.
+    #[cfg(feature = "wsl")]
+    Bridge(Vec<String>),
+}
+
+impl SocketAddress {
+    /// The address with `%U`, `%u` and `%%` replaced by the uid and user
+    /// name of this process, and a literal `%`.
+    pub fn expand_specifiers(&self) -> io::Result<SocketAddress> {
+        Ok(match self {
+            SocketAddress::Path(path) => SocketAddress::Path(PathBuf::from(
+                OsString::from_vec(expand(path.as_os_str().as_bytes())?),
+            )),
+            SocketAddress::Abstract(name) => SocketAddress::Abstract(
+                String::from_utf8(expand(name.as_bytes())?).map_err(|e| {
+                    io::Error::new(io::ErrorKind::InvalidData, e)
+                })?,
+            ),
+            #[cfg(feature = "wsl")]
+            SocketAddress::Vsock { cid, port } => SocketAddress::Vsock {
+                cid: *cid,
+                port: *port,
+            },
+            #[cfg(feature = "wsl")]
+            SocketAddress::Bridge(command) => SocketAddress::Bridge(
+                command
+                    .iter()
+                    .map(|arg| {
+                        String::from_utf8(expand(arg.as_bytes())?).map_err(
+                            |e| io::Error::new(io::ErrorKind::InvalidData, e),
+                        )
+                    })
+                    .collect::<io::Result<_>>()?,
+            ),
+        })
+    }
+
+    /// Bind the address, returning the socket file created for a
+    /// filesystem address.
+    pub(crate) fn bind(
+        &self,
+        permissions: &SocketPermissions,
+        takeover: SocketTakeover,
+    ) -> io::Result<(UnixListener, Option<SocketFile>)> {
+        match self {
+            SocketAddress::Path(path) => {
+                take_over(path, takeover)?;
+                let listener = bind_path(path, permissions)?;
+                let metadata = fs::symlink_metadata(path)?;
+                Ok((
+                    listener,
+                    Some(SocketFile {
+                        path: path.clone(),
+                        dev: metadata.dev(),
+                        ino: metadata.ino(),
+                    }),
+                ))
+            }
+            SocketAddress::Abstract(name) => {
+                let addr = net::SocketAddr::from_abstract_name(name)?;
+                let listener = net::UnixListener::bind_addr(&addr)?;
+                listener.set_nonblocking(true)?;
+                Ok((UnixListener::from_std(listener)?, None))
+            }
+            #[cfg(feature = "wsl")]
+            SocketAddress::Vsock { .. } | SocketAddress::Bridge(_) => {
+                Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!("Cannot listen on {}", self),
+                ))
+            }
+        }
+    }
+
+    pub(crate) async fn connect(&self) -> io::Result<UnixStream> {
+        match self {
+            SocketAddress::Path(path) => UnixStream::connect(path).await,
+            SocketAddress::Abstract(name) => {
+                // Connecting to a local socket does not block.
+                let addr = net::SocketAddr::from_abstract_name(name)?;
+                let stream = net::UnixStream::connect_addr(&addr)?;
+                stream.set_nonblocking(true)?;
+                UnixStream::from_std(stream)
+            }
+            #[cfg(feature = "wsl")]
+            SocketAddress::Vsock { cid, port } => {
+                crate::wsl::connect_vsock(*cid, *port).await
+            }
+            #[cfg(feature = "wsl")]
+            SocketAddress::Bridge(command) => {
+                crate::wsl::connect_bridge(command)
+            }
+        }
+    }
+}
+
+impl From<&str> for SocketAddress {
+    fn from(addr: &str) -> Self {
+        match addr.strip_prefix('\0') {
+            Some(name) => SocketAddress::Abstract(name.to_string()),
+            None => SocketAddress::Path(PathBuf::from(addr)),
+        }
+    }
+}
+
+impl From<String> for SocketAddress {
+    fn from(addr: String) -> Self {
+        SocketAddress::from(addr.as_str())
+    }
+}
+
+impl From<PathBuf> for SocketAddress {
+    fn from(path: PathBuf) -> Self {
+        SocketAddress::Path(path)
+    }
+}
+
+impl fmt::Display for SocketAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SocketAddress::Path(path) => write!(f, "{}", path.display()),
+            SocketAddress::Abstract(name) => write!(f, "@{}", name),
+            #[cfg(feature = "wsl")]
+            SocketAddress::Vsock { cid, port } => {
+                write!(f, "vsock:{}:{}", cid, port)
+            }
+            #[cfg(feature = "wsl")]
+            SocketAddress::Bridge(command) => {
+                write!(f, "bridge:{}", command.join(" "))
+            }
+        }
+    }
+}
+
+/// A socket file bound by this process.
+pub(crate) struct SocketFile {
+    path: PathBuf,
+    dev: u64,
+    ino: u64,
+}
+
+impl SocketFile {
+    /// Remove the socket when the server shuts down, unless another daemon
+    /// has since replaced it.
+    pub(crate) fn remove(&self) {
+        match fs::symlink_metadata(&self.path) {
+            Ok(m) if m.dev() == self.dev && m.ino() == self.ino => {
+                if let Err(e) = fs::remove_file(&self.path) {
+                    debug!(
+                        "Failed to remove {} -> {:?}",
+                        self.path.display(),
+                        e
+                    );
+                }
+            }
+            _ => debug!("{} was replaced, leaving it", self.path.display()),
+        }
+    }
+}
+
+fn bind_path(
+    path: &Path,
+    permissions: &SocketPermissions,
+) -> io::Result<UnixListener> {
+    if let (Some(mode), Some(parent)) =
+        (permissions.directory_mode, path.parent())
+    {
+        if !parent.exists() {
+            DirBuilder::new()
+                .recursive(true)
+                .mode(mode)
+                .create(parent)?;
+        }
+    }
+    // Bind with a umask matching the requested mode, so that the socket is
+    // never more permissive than requested.
+    let before = unsafe { libc::umask(!permissions.mode & 0o777) };
+    let listener = UnixListener::bind(path);
+    let _ = unsafe { libc::umask(before) };
+    let listener = listener?;
+
+    fs::set_permissions(path, Permissions::from_mode(permissions.mode))?;
+    if permissions.uid.is_some() || permissions.gid.is_some() {
+        let c_path = CString::new(path.as_os_str().as_bytes())?;
+        // An id of -1 leaves the owner or group unchanged.
+        let rc = unsafe {
+            libc::chown(
+                c_path.as_ptr(),
+                permissions.uid.unwrap_or(libc::uid_t::MAX),
+                permissions.gid.unwrap_or(libc::gid_t::MAX),
+            )
+        };
+        if rc != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(listener)
+}
+
+/* A socket left behind by a daemon which did not shut down cleanly makes
+ * the bind fail. It is detected by nothing answering on it. A file which is
+ * not a socket is never removed.
+ */
+fn take_over(path: &Path, takeover: SocketTakeover) -> io::Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", path.display()),
+        ));
+    }
+    if takeover == SocketTakeover::Never {
+        return Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("{} already exists", path.display()),
+        ));
+    }
+    match net::UnixStream::connect(path) {
+        Ok(_) if takeover == SocketTakeover::Always => {
+            warn!("Taking over {} from another running daemon", path.display());
+            fs::remove_file(path)
+        }
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("{} is in use by another daemon", path.display()),
+        )),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => {
+            info!("Removing stale socket {} ({})", path.display(), e);
+            fs::remove_file(path)
+        }
+    }
+}
+
+fn expand(template: &[u8]) -> io::Result<Vec<u8>> {
+    if !template.contains(&b'%') {
+        return Ok(template.to_vec());
+    }
+    let uid = unsafe { libc::getuid() };
+    let mut expanded = Vec::with_capacity(template.len());
+    let mut chars = template.iter();
+    while let Some(&c) = chars.next() {
+        if c != b'%' {
+            expanded.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some(b'U') => {
+                expanded.extend_from_slice(uid.to_string().as_bytes())
+            }
+            Some(b'u') => expanded.extend_from_slice(&user_name(uid)?),
+            Some(b'%') => expanded.push(b'%'),
+            Some(&other) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "Unknown specifier %{} in socket address",
+                        other as char
+                    ),
+                ))
+            }
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Trailing % in socket address",
+                ))
+            }
+        }
+    }
+    Ok(expanded)
+}
+
+pub(crate) fn user_name(uid: libc::uid_t) -> io::Result<Vec<u8>> {
+    passwd_entry(uid).map(|(name, _)| name)
+}
+
+/// The primary and supplementary groups of `uid`.
+pub(crate) fn user_groups(uid: libc::uid_t) -> io::Result<Vec<libc::gid_t>> {
+    let (name, gid) = passwd_entry(uid)?;
+    let name = CString::new(name)?;
+    let mut groups = vec![0 as libc::gid_t; 64];
+    loop {
+        let mut count = groups.len() as libc::c_int;
+        let rc = unsafe {
+            libc::getgrouplist(
+                name.as_ptr(),
+                gid,
+                groups.as_mut_ptr(),
+                &mut count,
+            )
+        };
+        if rc >= 0 {
+            groups.truncate(count as usize);
+            return Ok(groups);
+        }
+        if groups.len() >= 1 << 16 {
+            return Err(io::Error::other(format!(
+                "Too many groups for uid {}",
+                uid
+            )));
+        }
+        let len = (count as usize).max(groups.len() * 2);
+        groups.resize(len, 0);
+    }
+}
+
+fn passwd_entry(uid: libc::uid_t) -> io::Result<(Vec<u8>, libc::gid_t)> {
+    let mut buf = vec![0 as libc::c_char; 1024];
+    loop {
+        let mut pwd = MaybeUninit::<libc::passwd>::uninit();
+        let mut result = std::ptr::null_mut();
+        let rc = unsafe {
+            libc::getpwuid_r(
+                uid,
+                pwd.as_mut_ptr(),
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut result,
+            )
+        };
+        if rc == libc::ERANGE && buf.len() < 1 << 20 {
+            buf.resize(buf.len() * 2, 0);
+            continue;
+        }
+        if rc != 0 {
+            return Err(io::Error::from_raw_os_error(rc));
+        }
+        if result.is_null() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No user name for uid {}", uid),
+            ));
+        }
+        let name = unsafe { CStr::from_ptr((*result).pw_name) };
+        let gid = unsafe { (*result).pw_gid };
+        return Ok((name.to_bytes().to_vec(), gid));
+    }
+}
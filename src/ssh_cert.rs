@@ -0,0 +1,80 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::error::BrokerError;
+use async_trait::async_trait;
+use libc::uid_t;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+/// The scope of the access token Entra ID issues as an OpenSSH certificate,
+/// when requested with `token_type=ssh-cert` and the public key as `req_cnf`.
+pub const SSH_CERT_SCOPE: &str =
+    "https://pas.windows.net/CheckMyAccess/Linux/.default";
+
+/// The request_json of getSshCert.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct SshCertRequest {
+    /// The OpenSSH public key to certify, e.g. `ssh-rsa AAAA...`.
+    pub public_key: String,
+    /// The home account id of the account to certify, when the user has
+    /// more than one.
+    #[serde(default)]
+    pub home_account_id: Option<String>,
+}
+
+/// The response to getSshCert.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct SshCertificate {
+    /// The certificate in OpenSSH format, as written to `id_rsa-cert.pub`.
+    pub certificate: String,
+    /// The principals the certificate is valid for, such as the user's
+    /// UPN.
+    pub principals: Vec<String>,
+    /// The certificate's validity period, in seconds since the epoch.
+    pub valid_after: u64,
+    pub valid_before: u64,
+}
+
+/* Like compliance reporting, SSH certificates need support which not every
+ * broker has, so they are a separate trait. A HimmelblauBroker which mints
+ * them returns itself from `HimmelblauBroker::ssh_cert`.
+ */
+#[async_trait]
+pub trait SshCertBroker {
+    /// Request a certificate for `request.public_key` on behalf of `uid`.
+    async fn get_ssh_cert(
+        &mut self,
+        correlation_id: String,
+        request: SshCertRequest,
+        uid: uid_t,
+    ) -> Result<SshCertificate, BrokerError>;
+}
+
+/// The response sent when the broker does not mint SSH certificates.
+pub(crate) fn ssh_cert_unsupported() -> String {
+    json!({
+        "error": {
+            "status": "NotSupported",
+            "context": "SSH certificates are not issued by this broker",
+        }
+    })
+    .to_string()
+}
@@ -0,0 +1,271 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::caller::CallerInfo;
+use crate::protocol_version::ProtocolVersion;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+#[cfg(feature = "secret-service")]
+use std::error::Error;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::debug;
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// A PRT SSO cookie returned by acquirePrtSsoCookie, with the time (seconds
+/// since the epoch) after which it should no longer be presented.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct SsoCookie {
+    pub cookie_name: String,
+    pub cookie_content: String,
+    pub expires_at: u64,
+}
+
+impl SsoCookie {
+    /// The cookie in an acquirePrtSsoCookie response, considered fresh for
+    /// `ttl` seconds from now.
+    pub fn from_response(response_json: &str, ttl: u64) -> Option<Self> {
+        let response: Value = serde_json::from_str(response_json).ok()?;
+        let (cookie_name, cookie_content) = cookie_fields(&response)?;
+        Some(SsoCookie {
+            cookie_name,
+            cookie_content,
+            expires_at: now().saturating_add(ttl),
+        })
+    }
+
+    pub fn is_fresh(&self) -> bool {
+        now() < self.expires_at
+    }
+}
+
+/* The cookie is read from either response shape, so that a response is
+ * understood whichever shape the daemon produced.
+ */
+fn cookie_fields(response: &Value) -> Option<(String, String)> {
+    let field = |value: &Value, name: &str| {
+        value.get(name).and_then(Value::as_str).map(str::to_string)
+    };
+    match (
+        field(response, "cookieName"),
+        field(response, "cookieContent"),
+    ) {
+        (Some(name), Some(content)) => Some((name, content)),
+        _ => {
+            let item = response.get("response")?.get(0)?;
+            Some((field(item, "name")?, field(item, "data")?))
+        }
+    }
+}
+
+/// The shape of acquirePrtSsoCookie responses a client expects.
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq,
+)]
+pub enum SsoCookieShape {
+    /// Return the daemon's response unchanged.
+    #[default]
+    Unchanged,
+    /// `cookieName` and `cookieContent` fields, as returned by Microsoft's
+    /// broker and expected by linux-entra-sso.
+    CookieFields,
+    /// A `response` list of cookie items with `name` and `data` fields, as
+    /// returned by the BrowserCore native messaging host used by Edge.
+    BrowserCore,
+}
+
+impl SsoCookieShape {
+    /// Rewrite an acquirePrtSsoCookie response in this shape. Other fields
+    /// of the response (such as `account`) are kept, and responses without
+    /// a cookie are returned unchanged.
+    pub fn apply(self, response_json: String) -> String {
+        if self == SsoCookieShape::Unchanged {
+            return response_json;
+        }
+        let mut response: Map<String, Value> =
+            match serde_json::from_str(&response_json) {
+                Ok(response) => response,
+                Err(_) => return response_json,
+            };
+        let (name, content) =
+            match cookie_fields(&Value::Object(response.clone())) {
+                Some(cookie) => cookie,
+                None => return response_json,
+            };
+        response.remove("cookieName");
+        response.remove("cookieContent");
+        response.remove("response");
+        match self {
+            SsoCookieShape::CookieFields => {
+                response.insert("cookieName".to_string(), name.into());
+                response.insert("cookieContent".to_string(), content.into());
+            }
+            SsoCookieShape::BrowserCore => {
+                response.insert(
+                    "response".to_string(),
+                    json!([{ "name": name, "data": content }]),
+                );
+            }
+            SsoCookieShape::Unchanged => (),
+        }
+        Value::Object(response).to_string()
+    }
+}
+
+/// Selects the acquirePrtSsoCookie response shape for matching callers. The
+/// first matching rule applies.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct SsoCookieShapeRule {
+    /// Caller executables the rule applies to, either as full paths or as
+    /// file names (e.g. `msedge`). When unset, the rule applies to any
+    /// caller.
+    pub executables: Option<Vec<PathBuf>>,
+    /// protocol_version values the rule applies to. A version also matches
+    /// the versions it prefixes, so `0` matches `0.1`. When unset, the rule
+    /// applies to any version.
+    pub protocol_versions: Option<Vec<String>>,
+    pub shape: SsoCookieShape,
+}
+
+impl SsoCookieShapeRule {
+    fn matches(
+        &self,
+        protocol_version: &str,
+        caller: Option<&CallerInfo>,
+    ) -> bool {
+        if let Some(versions) = &self.protocol_versions {
+            let version = protocol_version.parse::<ProtocolVersion>().ok();
+            let matched = versions.iter().any(|v| {
+                protocol_version == v
+                    || version.is_some_and(|version| version.matches(v))
+            });
+            if !matched {
+                return false;
+            }
+        }
+        if let Some(executables) = &self.executables {
+            let exe = match caller.and_then(CallerInfo::executable) {
+                Some(exe) => exe,
+                None => return false,
+            };
+            return executables.iter().any(|e| {
+                *e == exe
+                    || (e.components().count() == 1
+                        && exe.file_name() == Some(e.as_os_str()))
+            });
+        }
+        true
+    }
+}
+
+/// The shape of the acquirePrtSsoCookie response for a call with
+/// `protocol_version` from `caller`.
+pub(crate) fn sso_cookie_shape(
+    rules: &[SsoCookieShapeRule],
+    protocol_version: &str,
+    caller: Option<&CallerInfo>,
+) -> SsoCookieShape {
+    let shape = rules
+        .iter()
+        .find(|rule| rule.matches(protocol_version, caller))
+        .map(|rule| rule.shape)
+        .unwrap_or_default();
+    if shape != SsoCookieShape::Unchanged {
+        debug!("Shaping the SSO cookie response as {:?}", shape);
+    }
+    shape
+}
+
+#[cfg(feature = "secret-service")]
+pub use secret_service::{load_sso_cookie, store_sso_cookie};
+
+/* The latest cookie is kept as a single Secret Service item, identified by
+ * the `application` and `type` attributes. The cookie name and expiry are
+ * published as attributes as well, so that other clients of the Secret
+ * Service can check the cookie's freshness without unlocking it.
+ */
+#[cfg(feature = "secret-service")]
+mod secret_service {
+    use super::{Error, SsoCookie};
+    use dbus_secret_service::{EncryptionType, SecretService};
+    use std::collections::HashMap;
+
+    const ITEM_ATTRIBUTES: [(&str, &str); 2] = [
+        ("application", "identity_dbus_broker"),
+        ("type", "prt-sso-cookie"),
+    ];
+
+    /// Replace the SSO cookie stored in the Secret Service's default
+    /// collection with `cookie`.
+    pub fn store_sso_cookie(
+        cookie: &SsoCookie,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let service = SecretService::connect(EncryptionType::Plain)?;
+        let found = service.search_items(HashMap::from(ITEM_ATTRIBUTES))?;
+        for item in found.unlocked.iter().chain(found.locked.iter()) {
+            item.delete()?;
+        }
+        let expires_at = cookie.expires_at.to_string();
+        let mut attributes = HashMap::from(ITEM_ATTRIBUTES);
+        attributes.insert("cookieName", &cookie.cookie_name);
+        attributes.insert("expiresAt", &expires_at);
+        let collection = service.get_any_collection()?;
+        collection.ensure_unlocked()?;
+        collection.create_item(
+            "Entra ID PRT SSO cookie",
+            attributes,
+            cookie.cookie_content.as_bytes(),
+            true,
+            "text/plain",
+        )?;
+        Ok(())
+    }
+
+    /// The SSO cookie stored in the Secret Service, whether or not it is
+    /// still fresh.
+    pub fn load_sso_cookie(
+    ) -> Result<Option<SsoCookie>, Box<dyn Error + Send + Sync>> {
+        let service = SecretService::connect(EncryptionType::Plain)?;
+        let found = service.search_items(HashMap::from(ITEM_ATTRIBUTES))?;
+        let item = match found.unlocked.first().or(found.locked.first()) {
+            Some(item) => item,
+            None => return Ok(None),
+        };
+        item.ensure_unlocked()?;
+        let attributes = item.get_attributes()?;
+        let expires_at = attributes
+            .get("expiresAt")
+            .and_then(|expires_at| expires_at.parse().ok())
+            .ok_or("Stored SSO cookie has no expiry")?;
+        Ok(Some(SsoCookie {
+            cookie_name: attributes
+                .get("cookieName")
+                .cloned()
+                .ok_or("Stored SSO cookie has no name")?,
+            cookie_content: String::from_utf8(item.get_secret()?)?,
+            expires_at,
+        }))
+    }
+}
@@ -0,0 +1,138 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::himmelblau_broker::HimmelblauBroker;
+use dbus::message::MatchRule;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::{mpsc, Notify};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info};
+
+/// NM_STATE_CONNECTED_GLOBAL, NetworkManager's state once the device has
+/// full connectivity.
+const NM_STATE_CONNECTED_GLOBAL: u32 = 70;
+
+/// A change to the device after which cached cookies and tokens may be
+/// stale (see `HimmelblauBroker::system_event`).
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum SystemEvent {
+    /// The device is about to suspend.
+    Suspending,
+    /// The device resumed from suspend.
+    Resumed,
+    /// The device gained full network connectivity.
+    NetworkConnected,
+    /// The device lost full network connectivity.
+    NetworkDisconnected,
+}
+
+enum Signal {
+    PrepareForSleep(bool),
+    NetworkState(u32),
+}
+
+/* Suspend and resume are announced by logind's PrepareForSleep signal,
+ * and connectivity changes by NetworkManager's StateChanged. NetworkManager
+ * passes through several states while connecting, so only crossing in or
+ * out of full connectivity is reported. After resuming or reconnecting,
+ * the refresh scheduler (see `RefreshSchedulerConfig`) is woken rather
+ * than waiting for its next interval.
+ */
+pub(crate) async fn watch_system_events<T>(
+    mut broker: T,
+    wake: Arc<Notify>,
+    shutdown: CancellationToken,
+) where
+    T: HimmelblauBroker + Send,
+{
+    let (resource, c) = match dbus_tokio::connection::new_system_sync() {
+        Ok(connection) => connection,
+        Err(e) => {
+            error!("Failed to watch for suspend and network changes -> {}", e);
+            return;
+        }
+    };
+    let connection = tokio::spawn(resource);
+    let (tx, mut signals) = mpsc::unbounded_channel();
+    let t = tx.clone();
+    let sleep = MatchRule::new_signal(
+        "org.freedesktop.login1.Manager",
+        "PrepareForSleep",
+    );
+    let network =
+        MatchRule::new_signal("org.freedesktop.NetworkManager", "StateChanged");
+    let matches = async {
+        let sleep =
+            c.add_match(sleep).await?.cb(move |_, (start,): (bool,)| {
+                let _ = t.send(Signal::PrepareForSleep(start));
+                true
+            });
+        let network =
+            c.add_match(network).await?.cb(move |_, (state,): (u32,)| {
+                let _ = tx.send(Signal::NetworkState(state));
+                true
+            });
+        Ok::<_, dbus::Error>((sleep, network))
+    };
+    let _matches = match matches.await {
+        Ok(matches) => matches,
+        Err(e) => {
+            error!("Failed to watch for suspend and network changes -> {}", e);
+            connection.abort();
+            return;
+        }
+    };
+
+    let mut connected = None;
+    loop {
+        let signal = tokio::select! {
+            _ = shutdown.cancelled() => break,
+            signal = signals.recv() => match signal {
+                Some(signal) => signal,
+                None => break,
+            },
+        };
+        let event = match signal {
+            Signal::PrepareForSleep(true) => SystemEvent::Suspending,
+            Signal::PrepareForSleep(false) => SystemEvent::Resumed,
+            Signal::NetworkState(state) => {
+                debug!("NetworkManager state is now {}", state);
+                let now = state == NM_STATE_CONNECTED_GLOBAL;
+                if connected.replace(now) == Some(now) {
+                    continue;
+                }
+                if now {
+                    SystemEvent::NetworkConnected
+                } else {
+                    SystemEvent::NetworkDisconnected
+                }
+            }
+        };
+        info!("Notifying the broker of {:?}", event);
+        if let Err(e) = broker.system_event(event).await {
+            error!("Failed to handle {:?} -> {}", event, e);
+        }
+        if matches!(event, SystemEvent::Resumed | SystemEvent::NetworkConnected)
+        {
+            wake.notify_one();
+        }
+    }
+    connection.abort();
+}
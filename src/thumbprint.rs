@@ -0,0 +1,122 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+use openssl::base64;
+use openssl::hash::{hash, MessageDigest};
+use openssl::pkey::{HasPublic, PKeyRef};
+use openssl::x509::X509Ref;
+use std::error::Error;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThumbprintAlgorithm {
+    /// The Windows default, as shown by certutil and used for `x5t`.
+    Sha1,
+    /// As used for `x5t#S256`.
+    Sha256,
+}
+
+/// A certificate or key thumbprint. Windows displays thumbprints as
+/// uppercase hex, while JOSE headers carry them as unpadded base64url;
+/// `Display` uses the Windows form.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Thumbprint(Vec<u8>);
+
+impl Thumbprint {
+    /// The digest of arbitrary DER, such as a certificate or a
+    /// SubjectPublicKeyInfo.
+    pub fn of_der(
+        der: &[u8],
+        alg: ThumbprintAlgorithm,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let md = match alg {
+            ThumbprintAlgorithm::Sha1 => MessageDigest::sha1(),
+            ThumbprintAlgorithm::Sha256 => MessageDigest::sha256(),
+        };
+        Ok(Thumbprint(hash(md, der)?.to_vec()))
+    }
+
+    /// The thumbprint of a certificate, over its DER encoding.
+    pub fn of_certificate(
+        cert: &X509Ref,
+        alg: ThumbprintAlgorithm,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        Thumbprint::of_der(&cert.to_der()?, alg)
+    }
+
+    /// The thumbprint of a key, over its DER SubjectPublicKeyInfo.
+    pub fn of_public_key<T: HasPublic>(
+        key: &PKeyRef<T>,
+        alg: ThumbprintAlgorithm,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        Thumbprint::of_der(&key.public_key_to_der()?, alg)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn to_hex(&self) -> String {
+        self.0.iter().map(|b| format!("{:02X}", b)).collect()
+    }
+
+    pub fn to_base64url(&self) -> String {
+        base64::encode_block(&self.0)
+            .trim_end_matches('=')
+            .replace('+', "-")
+            .replace('/', "_")
+    }
+
+    /// Parse a thumbprint in either hex (of any case, optionally separated
+    /// by spaces or colons) or base64url (with or without padding).
+    pub fn parse(s: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let s = s.trim();
+        let hex: String = s
+            .chars()
+            .filter(|c| *c != ':' && !c.is_whitespace())
+            .collect();
+        if !hex.is_empty()
+            && hex.len().is_multiple_of(2)
+            && hex.chars().all(|c| c.is_ascii_hexdigit())
+        {
+            let bytes = (0..hex.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+                .collect::<Result<Vec<u8>, _>>()?;
+            return Ok(Thumbprint(bytes));
+        }
+        let mut b64 =
+            s.trim_end_matches('=').replace('-', "+").replace('_', "/");
+        while !b64.len().is_multiple_of(4) {
+            b64.push('=');
+        }
+        base64::decode_block(&b64)
+            .map(Thumbprint)
+            .map_err(|_| format!("Invalid thumbprint {:?}", s).into())
+    }
+
+    /// Whether `s`, in any encoding accepted by `parse`, is this thumbprint.
+    pub fn matches(&self, s: &str) -> bool {
+        Thumbprint::parse(s).map(|t| t == *self).unwrap_or(false)
+    }
+}
+
+impl fmt::Display for Thumbprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.to_hex())
+    }
+}
@@ -0,0 +1,144 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use dbus::arg::{ArgType, PropMap, RefArg, Variant};
+use serde_json::{Map, Number, Value};
+
+/// The interface serving the broker's methods with typed arguments.
+pub const TOKEN_BROKER_INTERFACE: &str = "org.himmelblau.TokenBroker";
+
+/// The methods of `TOKEN_BROKER_INTERFACE`, with the Broker1 method each
+/// maps onto.
+pub(crate) const TOKEN_BROKER_METHODS: &[(&str, &str)] = &[
+    ("AcquireTokenInteractively", "acquireTokenInteractively"),
+    ("AcquireTokenSilently", "acquireTokenSilently"),
+    ("GetAccounts", "getAccounts"),
+    ("RemoveAccount", "removeAccount"),
+    ("AcquirePrtSsoCookie", "acquirePrtSsoCookie"),
+    ("GenerateSignedHttpRequest", "generateSignedHttpRequest"),
+    ("CancelInteractiveFlow", "cancelInteractiveFlow"),
+    ("GetLinuxBrokerVersion", "getLinuxBrokerVersion"),
+];
+
+pub(crate) type TokenBrokerArgs = (PropMap, PropMap);
+
+/* Some consumers would rather not build and parse JSON carried in strings.
+ * The TokenBroker interface takes each request as an a{sv} dictionary
+ * with the same keys as the Broker1 request_json, and options holding
+ * protocol_version and correlation_id as the portal frontend does. The
+ * dictionary is converted to JSON, the Broker1 method called, and its JSON
+ * response converted back: objects become a{sv} dictionaries, arrays av,
+ * strings s, booleans b, integers x (t when too large), and other numbers
+ * d. Nulls, which D-Bus cannot carry, are left out of dictionaries.
+ */
+/// The request_json of a TokenBroker request.
+pub(crate) fn request_json(request: &PropMap) -> String {
+    Value::Object(
+        request
+            .iter()
+            .map(|(key, value)| (key.clone(), to_json(&value.0)))
+            .collect(),
+    )
+    .to_string()
+}
+
+/// The TokenBroker reply to a Broker1 response.
+pub(crate) fn reply(
+    res: Result<String, dbus::MethodErr>,
+) -> Result<(PropMap,), dbus::MethodErr> {
+    let resp: Value = serde_json::from_str(&res?).map_err(|e| {
+        dbus::MethodErr::failed(&format!("Invalid broker response: {}", e))
+    })?;
+    match resp {
+        Value::Object(resp) => Ok((to_dict(resp),)),
+        _ => Err(dbus::MethodErr::failed(
+            "Invalid broker response: not a JSON object",
+        )),
+    }
+}
+
+fn to_dict(object: Map<String, Value>) -> PropMap {
+    object
+        .into_iter()
+        .filter(|(_, value)| !value.is_null())
+        .map(|(key, value)| (key, Variant(to_variant(value))))
+        .collect()
+}
+
+fn to_variant(value: Value) -> Box<dyn RefArg> {
+    match value {
+        Value::Null => Box::new(PropMap::new()),
+        Value::Bool(b) => Box::new(b),
+        Value::Number(n) => match (n.as_i64(), n.as_u64()) {
+            (Some(i), _) => Box::new(i),
+            (None, Some(u)) => Box::new(u),
+            _ => Box::new(n.as_f64().unwrap_or_default()),
+        },
+        Value::String(s) => Box::new(s),
+        Value::Array(values) => Box::new(
+            values
+                .into_iter()
+                .map(|value| Variant(to_variant(value)))
+                .collect::<Vec<_>>(),
+        ),
+        Value::Object(object) => Box::new(to_dict(object)),
+    }
+}
+
+fn to_json(value: &dyn RefArg) -> Value {
+    match value.arg_type() {
+        ArgType::Boolean => Value::Bool(value.as_i64() == Some(1)),
+        ArgType::Double => Number::from_f64(value.as_f64().unwrap_or_default())
+            .map(Value::Number)
+            .unwrap_or(Value::Null),
+        ArgType::UInt64 => value.as_u64().map(Value::from).unwrap_or_default(),
+        ArgType::Byte
+        | ArgType::Int16
+        | ArgType::UInt16
+        | ArgType::Int32
+        | ArgType::UInt32
+        | ArgType::Int64
+        | ArgType::UnixFd => {
+            value.as_i64().map(Value::from).unwrap_or_default()
+        }
+        ArgType::String | ArgType::ObjectPath | ArgType::Signature => value
+            .as_str()
+            .map(|s| Value::String(s.to_string()))
+            .unwrap_or_default(),
+        ArgType::Variant => value
+            .as_iter()
+            .and_then(|mut inner| inner.next().map(to_json))
+            .unwrap_or_default(),
+        ArgType::Array if value.signature().starts_with("a{") => {
+            let mut object = Map::new();
+            let mut items = value.as_iter().into_iter().flatten();
+            while let (Some(key), Some(value)) = (items.next(), items.next()) {
+                let key = match to_json(key) {
+                    Value::String(key) => key,
+                    key => key.to_string(),
+                };
+                object.insert(key, to_json(value));
+            }
+            Value::Object(object)
+        }
+        ArgType::Array | ArgType::Struct => Value::Array(
+            value.as_iter().into_iter().flatten().map(to_json).collect(),
+        ),
+        ArgType::DictEntry | ArgType::Invalid => Value::Null,
+    }
+}
@@ -0,0 +1,221 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::cache_store::{CacheKey, EncryptedFile};
+use crate::claims::ClaimsChallenge;
+use crate::pop::PopTokenRequest;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Mutex, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{debug, error};
+
+// Tokens this close to expiry are not served from the cache.
+const EXPIRY_MARGIN: u64 = 300;
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+struct CachedToken {
+    response: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    home_account_id: Option<String>,
+    expires_on: u64,
+    cached_at: u64,
+}
+
+/* Tokens are cached per client, account, authority and (unordered) scope
+ * set, so that a cached token is only served for the request it answered.
+ */
+fn cache_key(request_json: &str) -> Option<String> {
+    let request: Value = serde_json::from_str(request_json).ok()?;
+    let params = request.get("authParameters")?;
+    let field = |name: &str| params.get(name).and_then(Value::as_str);
+    let mut scopes: Vec<&str> = params
+        .get("requestedScopes")?
+        .as_array()?
+        .iter()
+        .filter_map(Value::as_str)
+        .collect();
+    scopes.sort_unstable();
+    let account = params
+        .get("account")
+        .and_then(|a| a.get("homeAccountId"))
+        .and_then(Value::as_str)?;
+
+    let mut hasher = Sha256::new();
+    for part in [
+        field("clientId")?,
+        account,
+        field("authority").unwrap_or(""),
+    ] {
+        hasher.update(part.as_bytes());
+        hasher.update([0]);
+    }
+    hasher.update(scopes.join(" ").as_bytes());
+    Some(
+        hasher
+            .finalize()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect(),
+    )
+}
+
+fn request_account(request_json: &str) -> Option<String> {
+    let request: Value = serde_json::from_str(request_json).ok()?;
+    request
+        .get("authParameters")?
+        .get("account")?
+        .get("homeAccountId")?
+        .as_str()
+        .map(str::to_string)
+}
+
+/// The expiry (seconds since the epoch) of the access token in a
+/// brokerTokenResponse. Millisecond timestamps are also accepted.
+fn token_expiry(response_json: &str) -> Option<u64> {
+    let response: Value = serde_json::from_str(response_json).ok()?;
+    let token = response.get("brokerTokenResponse")?;
+    token.get("accessToken")?.as_str()?;
+    let expires_on = match token.get("expiresOn")? {
+        Value::Number(n) => n.as_u64()?,
+        Value::String(s) => s.parse().ok()?,
+        _ => return None,
+    };
+    Some(if expires_on > 100_000_000_000 {
+        expires_on / 1000
+    } else {
+        expires_on
+    })
+}
+
+/// A persisted cache of acquireTokenSilently responses, encrypted at rest,
+/// from which still-valid tokens may be served while the daemon or network
+/// is unavailable.
+pub struct TokenCache {
+    file: EncryptedFile,
+    entries: Mutex<HashMap<String, CachedToken>>,
+}
+
+impl TokenCache {
+    /// Open the cache at `path`. A cache which cannot be decrypted (for
+    /// example after the key was replaced) is discarded.
+    pub fn open(path: impl AsRef<Path>, key: CacheKey) -> Self {
+        let file = EncryptedFile::new(path, key);
+        let entries = file.load().unwrap_or_default();
+        TokenCache {
+            file,
+            entries: Mutex::new(entries),
+        }
+    }
+
+    /// Cache a successful acquireTokenSilently response for `request_json`.
+    /// Responses without an access token and expiry, and PoP tokens, are
+    /// ignored.
+    pub fn store(&self, request_json: &str, response_json: &str) {
+        // The SHR of a PoP token presents it for a single resource request.
+        if PopTokenRequest::from_request(request_json).is_some() {
+            return;
+        }
+        let (key, expires_on) =
+            match (cache_key(request_json), token_expiry(response_json)) {
+                (Some(key), Some(expires_on)) => (key, expires_on),
+                _ => return,
+            };
+        let mut entries =
+            self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        let now = now();
+        entries.retain(|_, token| token.expires_on > now);
+        entries.insert(
+            key,
+            CachedToken {
+                response: response_json.to_string(),
+                home_account_id: request_account(request_json),
+                expires_on,
+                cached_at: now,
+            },
+        );
+        if let Err(e) = self.file.store(&*entries) {
+            error!(
+                "Failed to save token cache {} -> {:?}",
+                self.file.path().display(),
+                e
+            );
+        }
+    }
+
+    /// Drop the cached tokens of an account, after they were revoked.
+    pub fn forget_account(&self, home_account_id: &str) {
+        let mut entries =
+            self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        let before = entries.len();
+        entries.retain(|_, token| {
+            token.home_account_id.as_deref() != Some(home_account_id)
+        });
+        if entries.len() == before {
+            return;
+        }
+        debug!(
+            "Dropped {} cached tokens of {}",
+            before - entries.len(),
+            home_account_id
+        );
+        if let Err(e) = self.file.store(&*entries) {
+            error!(
+                "Failed to save token cache {} -> {:?}",
+                self.file.path().display(),
+                e
+            );
+        }
+    }
+
+    /* A cached response is marked with `servedFromCache` and the time it
+     * was cached (`cachedAt`), so that clients can tell it apart from a
+     * fresh response.
+     */
+    /// A still-valid cached response for `request_json`.
+    pub fn lookup(&self, request_json: &str) -> Option<String> {
+        // A token answering a claims challenge must be newly issued.
+        if ClaimsChallenge::from_request(request_json).is_some() {
+            debug!("Not serving a cached token for a claims challenge");
+            return None;
+        }
+        let key = cache_key(request_json)?;
+        let entries =
+            self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        let token = entries.get(&key)?;
+        if token.expires_on <= now() + EXPIRY_MARGIN {
+            debug!("Cached token has expired");
+            return None;
+        }
+        let mut response: serde_json::Map<String, Value> =
+            serde_json::from_str(&token.response).ok()?;
+        response.insert("servedFromCache".to_string(), Value::Bool(true));
+        response.insert("cachedAt".to_string(), token.cached_at.into());
+        Some(Value::Object(response).to_string())
+    }
+}
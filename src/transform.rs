@@ -0,0 +1,41 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::caller::CallerInfo;
+use std::fmt;
+
+/// Rewrites the responses of Broker1 methods before they are returned to
+/// the caller, so that integrators can work around the quirks of specific
+/// applications without forking the crate.
+pub trait ResponseTransformer: Send + Sync {
+    /// The response to return to `caller` for a successful call to
+    /// `method`, given the broker's `response_json`. Return it unchanged
+    /// for calls which need no adapting.
+    fn transform(
+        &self,
+        method: &str,
+        caller: Option<&CallerInfo>,
+        response_json: String,
+    ) -> String;
+}
+
+impl fmt::Debug for dyn ResponseTransformer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ResponseTransformer")
+    }
+}
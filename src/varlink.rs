@@ -0,0 +1,412 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::async_session_broker::{
+    call_by_name, dispatch_async, AsyncSessionBroker,
+};
+use crate::broker_ext::BROKER1_METHODS;
+use crate::caller::CallerInfo;
+use crate::himmelblau_broker::DEFAULT_MAX_FRAME_SIZE;
+use crate::session_broker::{Broker1Call, Dispatcher, PortalRequest};
+use crate::socket::{
+    SocketAddress, SocketFile, SocketPermissions, SocketTakeover,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::io;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info_span, Instrument};
+
+/// The varlink interface serving the Broker1 methods (see
+/// `ServeOptions::varlink`).
+pub const VARLINK_INTERFACE: &str = "org.himmelblau.broker1";
+
+const VARLINK_SERVICE: &str = "org.varlink.service";
+
+const BROKER1_DESCRIPTION: &str = "\
+# Brokered authentication against Microsoft Entra ID. Each method takes the
+# same arguments as its com.microsoft.identity.Broker1 D-Bus counterpart.
+interface org.himmelblau.broker1
+
+method AcquireTokenInteractively(protocol_version: string, correlation_id: string, request_json: string) -> (result: string)
+method AcquireTokenSilently(protocol_version: string, correlation_id: string, request_json: string) -> (result: string)
+method GetAccounts(protocol_version: string, correlation_id: string, request_json: string) -> (result: string)
+method RemoveAccount(protocol_version: string, correlation_id: string, request_json: string) -> (result: string)
+method AcquirePrtSsoCookie(protocol_version: string, correlation_id: string, request_json: string) -> (result: string)
+method GenerateSignedHttpRequest(protocol_version: string, correlation_id: string, request_json: string) -> (result: string)
+method CancelInteractiveFlow(protocol_version: string, correlation_id: string, request_json: string) -> (result: string)
+method GetLinuxBrokerVersion(protocol_version: string, correlation_id: string, request_json: string) -> (result: string)
+
+error InteractionRequired (message: string)
+error NoNetwork (message: string)
+error Timeout (message: string)
+error InvalidRequest (message: string)
+error AccountNotFound (message: string)
+error Cancelled (message: string)
+error Unavailable (message: string)
+error Disabled (message: string)
+error Failed (message: string)
+";
+
+const SERVICE_DESCRIPTION: &str = "\
+interface org.varlink.service
+
+method GetInfo() -> (vendor: string, product: string, version: string, url: string, interfaces: []string)
+method GetInterfaceDescription(interface: string) -> (description: string)
+
+error InterfaceNotFound (interface: string)
+error MethodNotFound (method: string)
+error MethodNotImplemented (method: string)
+error InvalidParameter (parameter: string)
+";
+
+#[derive(Deserialize)]
+struct VarlinkCall {
+    method: String,
+    #[serde(default)]
+    parameters: Value,
+    #[serde(default)]
+    oneway: bool,
+}
+
+#[derive(Serialize)]
+struct VarlinkReply {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    parameters: Value,
+}
+
+impl VarlinkReply {
+    fn ok(parameters: Value) -> Self {
+        VarlinkReply {
+            error: None,
+            parameters,
+        }
+    }
+
+    fn error(interface: &str, name: &str, parameters: Value) -> Self {
+        VarlinkReply {
+            error: Some(format!("{}.{}", interface, name)),
+            parameters,
+        }
+    }
+
+    /* Broker errors keep their Broker1 name. Anything else (policy
+     * rejections, unsupported methods) is reported as Failed.
+     */
+    fn method_error(e: &dbus::MethodErr) -> Self {
+        let name: &str = e.errorname();
+        let name = name
+            .strip_prefix("com.microsoft.identity.Broker1.Error.")
+            .unwrap_or("Failed");
+        VarlinkReply::error(
+            VARLINK_INTERFACE,
+            name,
+            json!({ "message": e.description() }),
+        )
+    }
+}
+
+#[derive(Deserialize)]
+struct Broker1Parameters {
+    protocol_version: String,
+    correlation_id: String,
+    request_json: String,
+}
+
+/// The varlink listener of a session broker. It stops serving, and removes
+/// its socket file, when dropped.
+pub(crate) struct VarlinkServer {
+    task: JoinHandle<()>,
+    socket_file: Option<SocketFile>,
+}
+
+impl Drop for VarlinkServer {
+    fn drop(&mut self) {
+        self.task.abort();
+        if let Some(socket_file) = &self.socket_file {
+            socket_file.remove();
+        }
+    }
+}
+
+/* Containers without a session bus reach the broker through a socket bind
+ * mounted from the host. The socket is only accessible to the broker's own
+ * user, and connections from other uids (such as root in the host's user
+ * namespace) are refused as well. The caller policy is applied to the
+ * connecting process, as it would be to a D-Bus caller.
+ */
+pub(crate) fn serve_varlink<T>(
+    address: &SocketAddress,
+    broker: Arc<T>,
+    dispatcher: Arc<Dispatcher>,
+) -> io::Result<VarlinkServer>
+where
+    T: AsyncSessionBroker + 'static,
+{
+    let address = address.expand_specifiers()?;
+    let permissions = SocketPermissions {
+        mode: 0o600,
+        ..Default::default()
+    };
+    let (listener, socket_file) =
+        address.bind(&permissions, SocketTakeover::StaleOnly)?;
+    debug!("Serving varlink on {}", address);
+    let uid = unsafe { libc::geteuid() };
+    let task = tokio::spawn(async move {
+        let mut connections: u64 = 0;
+        loop {
+            let sock = match listener.accept().await {
+                Ok((sock, _)) => sock,
+                Err(e) => {
+                    error!(
+                        "Error while accepting varlink connection -> {:?}",
+                        e
+                    );
+                    continue;
+                }
+            };
+            let cred = match sock.peer_cred() {
+                Ok(cred) if cred.uid() == uid => cred,
+                Ok(cred) => {
+                    error!(
+                        "Refusing varlink connection from uid {}",
+                        cred.uid()
+                    );
+                    continue;
+                }
+                Err(e) => {
+                    error!("Unable to verify peer credentials: {:?}", e);
+                    continue;
+                }
+            };
+            connections += 1;
+            let sender = format!("varlink:{}", connections);
+            let caller = CallerInfo {
+                bus_name: sender.clone(),
+                uid: Some(cred.uid()),
+                pid: cred.pid().map(|pid| pid as u32),
+                security_label: None,
+                session: None,
+                app_id: None,
+            };
+            let span = info_span!(
+                "varlink",
+                connection = connections,
+                pid = cred.pid().unwrap_or_default()
+            );
+            let broker = broker.clone();
+            let dispatcher = dispatcher.clone();
+            tokio::spawn(
+                async move {
+                    if let Err(e) =
+                        serve_connection(sock, broker, dispatcher, caller).await
+                    {
+                        debug!("Varlink connection closed -> {}", e);
+                    }
+                }
+                .instrument(span),
+            );
+        }
+    });
+    Ok(VarlinkServer { task, socket_file })
+}
+
+async fn serve_connection<T>(
+    sock: UnixStream,
+    broker: Arc<T>,
+    dispatcher: Arc<Dispatcher>,
+    caller: CallerInfo,
+) -> io::Result<()>
+where
+    T: AsyncSessionBroker + 'static,
+{
+    let (reader, mut writer) = sock.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut buf = vec![];
+    loop {
+        buf.clear();
+        (&mut reader)
+            .take(DEFAULT_MAX_FRAME_SIZE as u64 + 1)
+            .read_until(0, &mut buf)
+            .await?;
+        match buf.pop() {
+            Some(0) => {}
+            None => return Ok(()),
+            Some(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Unterminated or oversized varlink message",
+                ))
+            }
+        }
+        let call: VarlinkCall = serde_json::from_slice(&buf)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let oneway = call.oneway;
+        let reply = handle_call(call, &broker, &dispatcher, &caller).await;
+        if oneway {
+            continue;
+        }
+        let mut message = serde_json::to_vec(&reply)?;
+        message.push(0);
+        writer.write_all(&message).await?;
+    }
+}
+
+async fn handle_call<T>(
+    call: VarlinkCall,
+    broker: &Arc<T>,
+    dispatcher: &Dispatcher,
+    caller: &CallerInfo,
+) -> VarlinkReply
+where
+    T: AsyncSessionBroker + 'static,
+{
+    let (interface, name) = match call.method.rsplit_once('.') {
+        Some(split) => split,
+        None => {
+            return VarlinkReply::error(
+                VARLINK_SERVICE,
+                "InterfaceNotFound",
+                json!({ "interface": call.method }),
+            )
+        }
+    };
+    match interface {
+        VARLINK_SERVICE => service_call(name, &call.parameters),
+        VARLINK_INTERFACE => {
+            broker1_call(name, call.parameters, broker, dispatcher, caller)
+                .await
+        }
+        _ => VarlinkReply::error(
+            VARLINK_SERVICE,
+            "InterfaceNotFound",
+            json!({ "interface": interface }),
+        ),
+    }
+}
+
+fn service_call(name: &str, parameters: &Value) -> VarlinkReply {
+    match name {
+        "GetInfo" => VarlinkReply::ok(json!({
+            "vendor": "Himmelblau",
+            "product": env!("CARGO_PKG_NAME"),
+            "version": env!("CARGO_PKG_VERSION"),
+            "url": env!("CARGO_PKG_REPOSITORY"),
+            "interfaces": [VARLINK_SERVICE, VARLINK_INTERFACE],
+        })),
+        "GetInterfaceDescription" => {
+            match parameters.get("interface").and_then(Value::as_str) {
+                Some(VARLINK_SERVICE) => VarlinkReply::ok(
+                    json!({ "description": SERVICE_DESCRIPTION }),
+                ),
+                Some(VARLINK_INTERFACE) => VarlinkReply::ok(
+                    json!({ "description": BROKER1_DESCRIPTION }),
+                ),
+                Some(interface) => VarlinkReply::error(
+                    VARLINK_SERVICE,
+                    "InterfaceNotFound",
+                    json!({ "interface": interface }),
+                ),
+                None => VarlinkReply::error(
+                    VARLINK_SERVICE,
+                    "InvalidParameter",
+                    json!({ "parameter": "interface" }),
+                ),
+            }
+        }
+        _ => VarlinkReply::error(
+            VARLINK_SERVICE,
+            "MethodNotFound",
+            json!({ "method": format!("{}.{}", VARLINK_SERVICE, name) }),
+        ),
+    }
+}
+
+/// The Broker1 D-Bus method a varlink method name (such as
+/// `AcquireTokenSilently`) stands for.
+fn broker1_method(name: &str) -> Option<&'static str> {
+    let mut chars = name.chars();
+    let first = chars.next()?.to_ascii_lowercase();
+    BROKER1_METHODS.iter().copied().find(|method| {
+        method.starts_with(first) && method[1..] == *chars.as_str()
+    })
+}
+
+async fn broker1_call<T>(
+    name: &str,
+    parameters: Value,
+    broker: &Arc<T>,
+    dispatcher: &Dispatcher,
+    caller: &CallerInfo,
+) -> VarlinkReply
+where
+    T: AsyncSessionBroker + 'static,
+{
+    let method = match broker1_method(name) {
+        Some(method) => method,
+        None => {
+            return VarlinkReply::error(
+                VARLINK_SERVICE,
+                "MethodNotFound",
+                json!({ "method": format!("{}.{}", VARLINK_INTERFACE, name) }),
+            )
+        }
+    };
+    let params: Broker1Parameters = match serde_json::from_value(parameters) {
+        Ok(params) => params,
+        Err(e) => {
+            debug!("Invalid {} parameters -> {}", name, e);
+            return VarlinkReply::error(
+                VARLINK_SERVICE,
+                "InvalidParameter",
+                json!({ "parameter": "parameters" }),
+            );
+        }
+    };
+    let call = Broker1Call {
+        method,
+        protocol_version: &params.protocol_version,
+        correlation_id: &params.correlation_id,
+        request_json: &params.request_json,
+        app_id: None,
+    };
+    let req = PortalRequest {
+        method,
+        protocol_version: params.protocol_version.clone(),
+        correlation_id: params.correlation_id.clone(),
+        request_json: params.request_json.clone(),
+        app_id: None,
+    };
+    let stats = dispatcher.stats.clone();
+    let res = dispatch_async(
+        dispatcher,
+        &call,
+        Some(&caller.bus_name),
+        Some(caller.clone()),
+        |caller| call_by_name(broker.clone(), stats, req, caller),
+    )
+    .await;
+    match res {
+        Ok(result) => VarlinkReply::ok(json!({ "result": result })),
+        Err(e) => VarlinkReply::method_error(&e),
+    }
+}
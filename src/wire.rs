@@ -0,0 +1,219 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::io;
+
+/// A serialization format of the daemon socket protocol. Connections start
+/// in JSON, and the session broker may ask the daemon to switch to another
+/// format with a negotiateFormat request. CBOR and bincode require the
+/// `cbor` and `bincode` features.
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default,
+)]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+#[serde(rename_all = "snake_case")]
+pub enum WireFormatKind {
+    #[default]
+    Json,
+    Cbor,
+    Bincode,
+}
+
+impl WireFormatKind {
+    /// The formats compiled into this build.
+    pub fn available() -> Vec<WireFormatKind> {
+        [
+            WireFormatKind::Json,
+            WireFormatKind::Cbor,
+            WireFormatKind::Bincode,
+        ]
+        .into_iter()
+        .filter(|format| format.is_available())
+        .collect()
+    }
+
+    pub fn is_available(self) -> bool {
+        match self {
+            WireFormatKind::Json => true,
+            WireFormatKind::Cbor => cfg!(feature = "cbor"),
+            WireFormatKind::Bincode => cfg!(feature = "bincode"),
+        }
+    }
+}
+
+/// The first of the client's `offered` formats which the server `accepts`,
+/// falling back to JSON.
+pub(crate) fn select_format(
+    offered: &[WireFormatKind],
+    accepted: &[WireFormatKind],
+) -> WireFormatKind {
+    offered
+        .iter()
+        .find(|format| format.is_available() && accepted.contains(format))
+        .copied()
+        .unwrap_or_default()
+}
+
+/// The outcome of decoding a message from the front of a buffer.
+#[derive(Debug)]
+pub(crate) enum FrameDecode<T> {
+    /// A message, and the number of bytes it occupied.
+    Complete(T, usize),
+    /// More data is needed.
+    Incomplete,
+    /// A message which can never decode, and the number of bytes to discard.
+    Malformed(String, usize),
+}
+
+pub(crate) trait WireFormat {
+    fn encode<T: Serialize>(&self, msg: &T) -> io::Result<Vec<u8>>;
+    fn decode<T: DeserializeOwned>(&self, src: &[u8]) -> FrameDecode<T>;
+}
+
+/* JSON values delimit themselves, so JSON messages are sent unframed, as the
+ * protocol always has been. A malformed message has no known end, so the
+ * whole buffer is discarded.
+ */
+pub(crate) struct Json;
+
+impl WireFormat for Json {
+    fn encode<T: Serialize>(&self, msg: &T) -> io::Result<Vec<u8>> {
+        serde_json::to_vec(msg).map_err(io::Error::from)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, src: &[u8]) -> FrameDecode<T> {
+        let mut frames = serde_json::Deserializer::from_slice(src).into_iter();
+        match frames.next() {
+            Some(Ok(msg)) => FrameDecode::Complete(msg, frames.byte_offset()),
+            Some(Err(e)) if e.is_eof() => FrameDecode::Incomplete,
+            Some(Err(e)) => FrameDecode::Malformed(e.to_string(), src.len()),
+            None => FrameDecode::Incomplete,
+        }
+    }
+}
+
+/* Binary messages are sent with a 4 byte big endian length prefix, so that a
+ * message which fails to decode is skipped on its own.
+ */
+#[cfg(any(feature = "cbor", feature = "bincode"))]
+const LENGTH_PREFIX: usize = 4;
+
+#[cfg(any(feature = "cbor", feature = "bincode"))]
+fn length_prefixed(payload: Vec<u8>) -> io::Result<Vec<u8>> {
+    let len = u32::try_from(payload.len()).map_err(|_| {
+        io::Error::new(io::ErrorKind::InvalidInput, "Message is too large")
+    })?;
+    let mut frame = Vec::with_capacity(LENGTH_PREFIX + payload.len());
+    frame.extend_from_slice(&len.to_be_bytes());
+    frame.extend_from_slice(&payload);
+    Ok(frame)
+}
+
+/// The payload of the length prefixed frame at the front of `src`, and the
+/// length of the whole frame.
+#[cfg(any(feature = "cbor", feature = "bincode"))]
+fn unframe(src: &[u8]) -> Option<(&[u8], usize)> {
+    let prefix: [u8; LENGTH_PREFIX] =
+        src.get(..LENGTH_PREFIX)?.try_into().ok()?;
+    let end = LENGTH_PREFIX + u32::from_be_bytes(prefix) as usize;
+    src.get(LENGTH_PREFIX..end).map(|payload| (payload, end))
+}
+
+#[cfg(feature = "cbor")]
+pub(crate) struct Cbor;
+
+#[cfg(feature = "cbor")]
+impl WireFormat for Cbor {
+    fn encode<T: Serialize>(&self, msg: &T) -> io::Result<Vec<u8>> {
+        let mut payload = vec![];
+        ciborium::into_writer(msg, &mut payload).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+        })?;
+        length_prefixed(payload)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, src: &[u8]) -> FrameDecode<T> {
+        match unframe(src) {
+            Some((payload, len)) => match ciborium::from_reader(payload) {
+                Ok(msg) => FrameDecode::Complete(msg, len),
+                Err(e) => FrameDecode::Malformed(e.to_string(), len),
+            },
+            None => FrameDecode::Incomplete,
+        }
+    }
+}
+
+#[cfg(feature = "bincode")]
+pub(crate) struct Bincode;
+
+#[cfg(feature = "bincode")]
+impl WireFormat for Bincode {
+    fn encode<T: Serialize>(&self, msg: &T) -> io::Result<Vec<u8>> {
+        let payload = bincode::serialize(msg).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+        })?;
+        length_prefixed(payload)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, src: &[u8]) -> FrameDecode<T> {
+        match unframe(src) {
+            Some((payload, len)) => match bincode::deserialize(payload) {
+                Ok(msg) => FrameDecode::Complete(msg, len),
+                Err(e) => FrameDecode::Malformed(e.to_string(), len),
+            },
+            None => FrameDecode::Incomplete,
+        }
+    }
+}
+
+fn unavailable(format: WireFormatKind) -> String {
+    format!(
+        "The {:?} wire format is not available in this build",
+        format
+    )
+}
+
+impl WireFormat for WireFormatKind {
+    fn encode<T: Serialize>(&self, msg: &T) -> io::Result<Vec<u8>> {
+        match self {
+            WireFormatKind::Json => Json.encode(msg),
+            #[cfg(feature = "cbor")]
+            WireFormatKind::Cbor => Cbor.encode(msg),
+            #[cfg(feature = "bincode")]
+            WireFormatKind::Bincode => Bincode.encode(msg),
+            #[allow(unreachable_patterns)]
+            format => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                unavailable(*format),
+            )),
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(&self, src: &[u8]) -> FrameDecode<T> {
+        match self {
+            WireFormatKind::Json => Json.decode(src),
+            #[cfg(feature = "cbor")]
+            WireFormatKind::Cbor => Cbor.decode(src),
+            #[cfg(feature = "bincode")]
+            WireFormatKind::Bincode => Bincode.decode(src),
+            #[allow(unreachable_patterns)]
+            format => FrameDecode::Malformed(unavailable(*format), src.len()),
+        }
+    }
+}
@@ -0,0 +1,92 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::net;
+use std::process::Stdio;
+use tokio::net::UnixStream;
+use tokio::process::Command;
+use tracing::debug;
+
+/* The session broker only reads, writes and receives messages on its daemon
+ * stream, which behave alike on any stream socket, so a vsock connection is
+ * carried in a UnixStream as well. No peer credentials or file descriptors
+ * cross a vsock connection.
+ */
+pub(crate) async fn connect_vsock(
+    cid: u32,
+    port: u32,
+) -> io::Result<UnixStream> {
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_VSOCK,
+            libc::SOCK_STREAM | libc::SOCK_CLOEXEC | libc::SOCK_NONBLOCK,
+            0,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    let mut addr: libc::sockaddr_vm = unsafe { mem::zeroed() };
+    addr.svm_family = libc::AF_VSOCK as libc::sa_family_t;
+    addr.svm_cid = cid;
+    addr.svm_port = port;
+    let res = unsafe {
+        libc::connect(
+            fd.as_raw_fd(),
+            &addr as *const libc::sockaddr_vm as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_vm>() as libc::socklen_t,
+        )
+    };
+    if res < 0 {
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() != Some(libc::EINPROGRESS) {
+            return Err(e);
+        }
+    }
+    let stream = UnixStream::from_std(net::UnixStream::from(fd))?;
+    stream.writable().await?;
+    match stream.take_error()? {
+        Some(e) => Err(e),
+        None => Ok(stream),
+    }
+}
+
+/* A bridge is a command which relays its standard input and output to the
+ * broker, such as npiperelay.exe connecting to a Windows named pipe from
+ * WSL. Each connection starts its own bridge on one end of a socket pair,
+ * and the bridge exits once the session broker closes the other end.
+ */
+pub(crate) fn connect_bridge(command: &[String]) -> io::Result<UnixStream> {
+    let (program, args) = command.split_first().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "Empty bridge command")
+    })?;
+    let (ours, theirs) = net::UnixStream::pair()?;
+    let stdout = OwnedFd::from(theirs.try_clone()?);
+    let child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::from(OwnedFd::from(theirs)))
+        .stdout(Stdio::from(stdout))
+        .spawn()?;
+    debug!("Started bridge {} (pid {:?})", program, child.id());
+    ours.set_nonblocking(true)?;
+    UnixStream::from_std(ours)
+}
@@ -0,0 +1,118 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use identity_dbus_broker::{CacheKey, CacheKeyStore, EncryptedFile};
+use serde_json::{json, Value};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+
+// The nonce and GCM tag before the ciphertext.
+const HEADER_LEN: usize = 12 + 16;
+
+fn cache_path(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+        "cache-store-{}-{}",
+        name,
+        std::process::id()
+    ));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir.join("cache.json")
+}
+
+fn value() -> Value {
+    json!({"refreshToken": "secret", "expiresOn": 1700000000})
+}
+
+fn stored(name: &str) -> EncryptedFile {
+    let file =
+        EncryptedFile::new(cache_path(name), CacheKey::from_bytes([7; 32]));
+    file.store(&value()).unwrap();
+    file
+}
+
+#[test]
+fn values_round_trip_encrypted() {
+    let file = stored("round-trip");
+    assert_eq!(file.load::<Value>(), Some(value()));
+    let sealed = fs::read(file.path()).unwrap();
+    assert!(!String::from_utf8_lossy(&sealed).contains("secret"));
+    let mode = fs::metadata(file.path()).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+
+    // Each store is sealed under a fresh nonce.
+    file.store(&value()).unwrap();
+    assert_ne!(fs::read(file.path()).unwrap(), sealed);
+    assert_eq!(file.load::<Value>(), Some(value()));
+}
+
+#[test]
+fn missing_files_load_as_none() {
+    let file = EncryptedFile::new(
+        cache_path("missing"),
+        CacheKey::from_bytes([7; 32]),
+    );
+    assert_eq!(file.load::<Value>(), None);
+}
+
+#[test]
+fn tampered_files_load_as_none() {
+    let file = stored("tampered");
+    let sealed = fs::read(file.path()).unwrap();
+    // The tag, then the ciphertext.
+    for offset in [12, HEADER_LEN + 1] {
+        let mut tampered = sealed.clone();
+        tampered[offset] ^= 1;
+        fs::write(file.path(), &tampered).unwrap();
+        assert_eq!(file.load::<Value>(), None, "offset {}", offset);
+    }
+}
+
+#[test]
+fn truncated_files_load_as_none() {
+    let file = stored("truncated");
+    let sealed = fs::read(file.path()).unwrap();
+    for len in [0, HEADER_LEN - 1, HEADER_LEN, sealed.len() - 1] {
+        fs::write(file.path(), &sealed[..len]).unwrap();
+        assert_eq!(file.load::<Value>(), None, "length {}", len);
+    }
+}
+
+#[test]
+fn other_keys_load_as_none() {
+    let file = stored("wrong-key");
+    let other = EncryptedFile::new(file.path(), CacheKey::from_bytes([8; 32]));
+    assert_eq!(other.load::<Value>(), None);
+}
+
+#[test]
+fn key_files_are_private_and_reused() {
+    let path = cache_path("key-file");
+    let key = CacheKey::for_cache(CacheKeyStore::File, &path).unwrap();
+    let key_path = path.with_extension("json.key");
+    let mode = fs::metadata(&key_path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+    assert_eq!(fs::read(&key_path).unwrap(), key.as_bytes());
+
+    let again = CacheKey::for_cache(CacheKeyStore::File, &path).unwrap();
+    assert_eq!(again.as_bytes(), key.as_bytes());
+
+    fs::write(&key_path, [0; 16]).unwrap();
+    assert!(CacheKey::load_or_create(&key_path).is_err());
+}
@@ -0,0 +1,138 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use identity_dbus_broker::{read_policy_denials, CallerInfo, CallerPolicy};
+
+fn caller(label: Option<&str>) -> CallerInfo {
+    CallerInfo {
+        bus_name: ":1.42".to_string(),
+        uid: Some(1000),
+        pid: Some(std::process::id()),
+        security_label: label.map(str::to_string),
+        session: None,
+        app_id: None,
+    }
+}
+
+#[test]
+fn selinux_domains_are_enforced() {
+    let policy = CallerPolicy {
+        allowed_domains: Some(vec!["mozilla_t".to_string()]),
+        ..Default::default()
+    };
+    let browser = caller(Some("unconfined_u:unconfined_r:mozilla_t:s0"));
+    let other = caller(Some(
+        "unconfined_u:unconfined_r:unconfined_t:s0-s0:c0.c1023",
+    ));
+    assert_eq!(browser.selinux_domain(), Some("mozilla_t"));
+    assert_eq!(other.selinux_domain(), Some("unconfined_t"));
+
+    assert!(policy.check("acquireTokenSilently", Some(&browser)).is_ok());
+    assert!(policy.check("acquireTokenSilently", Some(&other)).is_err());
+    assert!(policy
+        .check("acquireTokenSilently", Some(&caller(None)))
+        .is_err());
+    // Only token methods are restricted.
+    assert!(policy.check("getAccounts", Some(&other)).is_ok());
+}
+
+#[test]
+fn denials_are_recorded() {
+    let log = std::env::temp_dir()
+        .join(format!("caller-policy-denials-{}", std::process::id()));
+    let _ = std::fs::remove_file(&log);
+    let mut policy = CallerPolicy {
+        allowed_domains: Some(vec!["mozilla_t".to_string()]),
+        denial_log: Some(log.clone()),
+        ..Default::default()
+    };
+    let other = caller(Some("unconfined_u:unconfined_r:unconfined_t:s0"));
+    assert!(policy.check("acquirePrtSsoCookie", Some(&other)).is_err());
+    policy.permissive = true;
+    assert!(policy.check("acquireTokenSilently", Some(&other)).is_ok());
+
+    let denials = read_policy_denials(&log).unwrap();
+    let _ = std::fs::remove_file(&log);
+    assert_eq!(denials.len(), 2);
+    assert_eq!(denials[0].method, "acquirePrtSsoCookie");
+    assert!(denials[0].enforced);
+    assert_eq!(
+        denials[1].security_label.as_deref(),
+        Some("unconfined_u:unconfined_r:unconfined_t:s0")
+    );
+    assert!(!denials[1].enforced);
+}
+
+#[test]
+fn forwarded_callers_must_match_the_connection() {
+    // This process runs as the test's user.
+    let uid = unsafe { libc::getuid() };
+    let mut forwarded = caller(None);
+    forwarded.uid = Some(uid);
+
+    // Root may forward any caller.
+    assert!(caller(None).check_peer(0).is_ok());
+    // Others may not claim another uid, nor another uid's process.
+    assert!(forwarded.check_peer(uid + 1).is_err());
+    forwarded.uid = Some(uid + 1);
+    assert!(forwarded.check_peer(uid + 1).is_err());
+    forwarded.uid = None;
+    assert!(forwarded.check_peer(uid + 1).is_err());
+}
+
+#[test]
+fn portal_callers_are_checked_by_app_id() {
+    let portal = "unconfined_u:unconfined_r:xdg_portal_t:s0";
+    let mut app = caller(Some(portal));
+    app.app_id = Some("org.mozilla.firefox".to_string());
+    let mut other = app.clone();
+    other.app_id = Some("com.example.Other".to_string());
+
+    // The portal's own domain says nothing about the application.
+    let domains = CallerPolicy {
+        allowed_domains: Some(vec!["xdg_portal_t".to_string()]),
+        ..Default::default()
+    };
+    assert!(domains.check("acquireTokenSilently", Some(&app)).is_err());
+    assert!(CallerPolicy::default()
+        .check("acquireTokenSilently", Some(&app))
+        .is_ok());
+
+    let log = std::env::temp_dir()
+        .join(format!("portal-policy-denials-{}", std::process::id()));
+    let _ = std::fs::remove_file(&log);
+    let apps = CallerPolicy {
+        allowed_app_ids: Some(vec!["org.mozilla.firefox".to_string()]),
+        denial_log: Some(log.clone()),
+        ..domains
+    };
+    assert!(apps.check("acquireTokenSilently", Some(&app)).is_ok());
+    assert!(apps.check("acquireTokenSilently", Some(&other)).is_err());
+    // The portal must still pass the other checks.
+    let mut elsewhere = app.clone();
+    elsewhere.security_label =
+        Some("unconfined_u:unconfined_r:unconfined_t:s0".to_string());
+    assert!(apps
+        .check("acquireTokenSilently", Some(&elsewhere))
+        .is_err());
+
+    let denials = read_policy_denials(&log).unwrap();
+    let _ = std::fs::remove_file(&log);
+    assert_eq!(denials.len(), 2);
+    assert_eq!(denials[0].app_id.as_deref(), Some("com.example.Other"));
+}
@@ -0,0 +1,139 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use identity_dbus_broker::{CacheKey, ClaimsChallenge, TokenCache};
+use serde_json::{json, Value};
+
+// The challenge Microsoft Graph sends when a Continuous Access Evaluation
+// event (such as a revoked session) invalidates a token, as documented for
+// CAE-enabled clients.
+const CAE_CHALLENGE: &str = r#"Bearer authorization_uri="https://login.windows.net/common/oauth2/authorize", error="insufficient_claims", claims="eyJhY2Nlc3NfdG9rZW4iOnsibmJmIjp7ImVzc2VudGlhbCI6dHJ1ZSwgInZhbHVlIjoiMTYwNDEwNjY1MSJ9fX0=""#;
+const CAE_CLAIMS: &str =
+    r#"{"access_token":{"nbf":{"essential":true, "value":"1604106651"}}}"#;
+
+// A Conditional Access authentication context step-up challenge.
+const AUTH_CONTEXT_CHALLENGE: &str = r#"Bearer realm="", authorization_uri="https://login.microsoftonline.com/common/oauth2/authorize", client_id="00000003-0000-0000-c000-000000000000", error="insufficient_claims", claims="eyJhY2Nlc3NfdG9rZW4iOnsiYWNycyI6eyJlc3NlbnRpYWwiOnRydWUsInZhbHVlIjoiYzEifX19""#;
+
+// Entra ID's answer to a silent request which requires MFA.
+const MFA_ERROR: &str = r#"{"error":"interaction_required","error_description":"AADSTS50076: Due to a configuration change made by your administrator, or because you moved to a new location, you must use multi-factor authentication to access '00000003-0000-0000-c000-000000000000'.","error_codes":[50076],"suberror":"basic_action","claims":"{\"access_token\":{\"capolids\":{\"essential\":true,\"values\":[\"01234567-89ab-cdef-0123-456789abcdef\"]}}}"}"#;
+
+#[test]
+fn www_authenticate_challenges() {
+    let challenge =
+        ClaimsChallenge::from_www_authenticate(CAE_CHALLENGE).unwrap();
+    assert_eq!(challenge.claims, CAE_CLAIMS);
+    assert_eq!(
+        challenge.value().unwrap(),
+        json!({"access_token": {"nbf": {"essential": true, "value": "1604106651"}}})
+    );
+
+    let challenge =
+        ClaimsChallenge::from_www_authenticate(AUTH_CONTEXT_CHALLENGE).unwrap();
+    assert_eq!(
+        challenge.value().unwrap(),
+        json!({"access_token": {"acrs": {"essential": true, "value": "c1"}}})
+    );
+
+    for other in [
+        r#"Bearer error="invalid_token", error_description="The access token expired""#,
+        r#"PoP nonce="AQAAAAAAAAAAAA""#,
+        "Bearer",
+    ] {
+        assert_eq!(ClaimsChallenge::from_www_authenticate(other), None);
+    }
+}
+
+#[test]
+fn error_response_challenges() {
+    let challenge = ClaimsChallenge::from_response(MFA_ERROR).unwrap();
+    assert_eq!(
+        challenge.value().unwrap()["access_token"]["capolids"]["values"][0],
+        "01234567-89ab-cdef-0123-456789abcdef"
+    );
+
+    let response = challenge.error_response("MFA is required");
+    let error: Value = serde_json::from_str(&response).unwrap();
+    assert_eq!(error["error"]["status"], "InteractionRequired");
+    assert_eq!(ClaimsChallenge::from_response(&response), Some(challenge));
+
+    let expired = r#"{"error":"interaction_required","error_description":"AADSTS700082: The refresh token has expired due to inactivity."}"#;
+    assert_eq!(ClaimsChallenge::from_response(expired), None);
+}
+
+#[test]
+fn request_claims_pass_through() {
+    let request = json!({
+        "authParameters": {
+            "clientId": "d3590ed6-52b3-4102-aeff-aad2292ab01c",
+            "requestedScopes": ["https://graph.microsoft.com/.default"],
+        }
+    })
+    .to_string();
+    assert_eq!(ClaimsChallenge::from_request(&request), None);
+
+    let challenge = ClaimsChallenge::new(CAE_CLAIMS);
+    let request = challenge.apply(&request).unwrap();
+    let parsed: Value = serde_json::from_str(&request).unwrap();
+    // The claims are sent as they were received, not re-serialized.
+    assert_eq!(parsed["authParameters"]["claims"], CAE_CLAIMS);
+    assert_eq!(
+        ClaimsChallenge::from_request(&request),
+        Some(challenge.clone())
+    );
+
+    // Claims given as an object are accepted.
+    let request = json!({
+        "authParameters": {"claims": challenge.value().unwrap()}
+    })
+    .to_string();
+    assert_eq!(
+        ClaimsChallenge::from_request(&request)
+            .unwrap()
+            .value()
+            .unwrap(),
+        challenge.value().unwrap()
+    );
+}
+
+#[test]
+fn cached_tokens_do_not_answer_challenges() {
+    let dir = std::env::temp_dir()
+        .join(format!("claims-token-cache-{}", std::process::id()));
+    let cache = TokenCache::open(&dir, CacheKey::from_bytes([7; 32]));
+    let request = json!({
+        "authParameters": {
+            "clientId": "d3590ed6-52b3-4102-aeff-aad2292ab01c",
+            "requestedScopes": ["https://graph.microsoft.com/.default"],
+            "account": {"homeAccountId": "oid.tid"},
+        }
+    })
+    .to_string();
+    let response = json!({
+        "brokerTokenResponse": {
+            "accessToken": "token",
+            "expiresOn": u32::MAX,
+        }
+    })
+    .to_string();
+    cache.store(&request, &response);
+    assert!(cache.lookup(&request).is_some());
+
+    let challenged = ClaimsChallenge::new(CAE_CLAIMS).apply(&request).unwrap();
+    assert_eq!(cache.lookup(&challenged), None);
+    let _ = std::fs::remove_file(&dir);
+}
@@ -0,0 +1,697 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use async_trait::async_trait;
+use identity_dbus_broker::{
+    himmelblau_broker_serve_with_config, himmelblau_broker_serve_with_factory,
+    himmelblau_session_broker, serve_fault_proxy, AsyncSessionBroker,
+    BrokerError, FaultConfig, HimmelblauBroker, HimmelblauBrokerConfig,
+    HimmelblauSessionBrokerConfig, NoFederationHandler, PeerCred,
+    PortalPromptHandler, PromptKind, PromptRequest, PromptVerification,
+    Prompter,
+};
+use libc::uid_t;
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::broadcast;
+
+/* These tests run the daemon's socket server and the session broker's
+ * client end to end over a real socket, without D-Bus. The daemon side is a
+ * ScriptedBroker, whose answer to each request is spelled out in the
+ * request_json itself:
+ *
+ *   {"sleep": <ms>}   answer after sleeping
+ *   {"size": <bytes>} answer with a payload of that many bytes
+ *   {"prompt": <kind>} prompt for the PromptKind, answering whether the
+ *                     prompt was accepted (acquireTokenInteractively only)
+ *
+ * Anything else is echoed back as it was received.
+ */
+#[derive(Clone, Default)]
+struct ScriptedBroker {
+    calls: Arc<AtomicUsize>,
+}
+
+impl ScriptedBroker {
+    async fn answer(
+        &self,
+        request_json: String,
+    ) -> Result<String, BrokerError> {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        let request: Value = serde_json::from_str(&request_json)
+            .map_err(|e| BrokerError::InvalidRequest(e.to_string()))?;
+        if let Some(ms) = request["sleep"].as_u64() {
+            tokio::time::sleep(Duration::from_millis(ms)).await;
+        }
+        match request["size"].as_u64() {
+            Some(size) => {
+                Ok(json!({"payload": "x".repeat(size as usize)}).to_string())
+            }
+            None => Ok(request_json),
+        }
+    }
+}
+
+#[async_trait]
+impl HimmelblauBroker for ScriptedBroker {
+    async fn acquire_token_interactively_with_prompt(
+        &mut self,
+        _protocol_version: String,
+        correlation_id: String,
+        request_json: String,
+        _uid: uid_t,
+        prompter: &mut dyn Prompter,
+    ) -> Result<String, BrokerError> {
+        let request: Value = serde_json::from_str(&request_json)
+            .map_err(|e| BrokerError::InvalidRequest(e.to_string()))?;
+        let kind = match serde_json::from_value(request["prompt"].clone()) {
+            Ok(kind) => kind,
+            Err(_) => return self.answer(request_json).await,
+        };
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        let resp = prompter
+            .prompt(PromptRequest {
+                kind,
+                correlation_id,
+                message: "Verify it's you".to_string(),
+            })
+            .await?;
+        Ok(json!({"accepted": resp.accepted}).to_string())
+    }
+    async fn acquire_token_interactively(
+        &mut self,
+        _protocol_version: String,
+        _correlation_id: String,
+        request_json: String,
+        _uid: uid_t,
+    ) -> Result<String, BrokerError> {
+        self.answer(request_json).await
+    }
+    async fn acquire_token_silently(
+        &mut self,
+        _protocol_version: String,
+        _correlation_id: String,
+        request_json: String,
+        _uid: uid_t,
+    ) -> Result<String, BrokerError> {
+        self.answer(request_json).await
+    }
+    async fn get_accounts(
+        &mut self,
+        _protocol_version: String,
+        _correlation_id: String,
+        request_json: String,
+        _uid: uid_t,
+    ) -> Result<String, BrokerError> {
+        self.answer(request_json).await
+    }
+    async fn remove_account(
+        &mut self,
+        _protocol_version: String,
+        _correlation_id: String,
+        request_json: String,
+        _uid: uid_t,
+    ) -> Result<String, BrokerError> {
+        self.answer(request_json).await
+    }
+    async fn acquire_prt_sso_cookie(
+        &mut self,
+        _protocol_version: String,
+        _correlation_id: String,
+        request_json: String,
+        _uid: uid_t,
+    ) -> Result<String, BrokerError> {
+        self.answer(request_json).await
+    }
+    async fn generate_signed_http_request(
+        &mut self,
+        _protocol_version: String,
+        _correlation_id: String,
+        request_json: String,
+        _uid: uid_t,
+    ) -> Result<String, BrokerError> {
+        self.answer(request_json).await
+    }
+    async fn cancel_interactive_flow(
+        &mut self,
+        _protocol_version: String,
+        _correlation_id: String,
+        request_json: String,
+        _uid: uid_t,
+    ) -> Result<String, BrokerError> {
+        self.answer(request_json).await
+    }
+    async fn get_linux_broker_version(
+        &mut self,
+        _protocol_version: String,
+        _correlation_id: String,
+        _request_json: String,
+        _uid: uid_t,
+    ) -> Result<String, BrokerError> {
+        Ok(json!({"linuxBrokerVersion": "1.0.0"}).to_string())
+    }
+}
+
+fn socket_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!(
+        "himmelblau-{}-{}.sock",
+        name,
+        std::process::id()
+    ))
+}
+
+/* A daemon served on its own socket, stopped when dropped. */
+struct Daemon {
+    path: PathBuf,
+    broker: ScriptedBroker,
+    _shutdown: broadcast::Sender<bool>,
+}
+
+impl Daemon {
+    async fn start(name: &str, config: HimmelblauBrokerConfig) -> Self {
+        let path = socket_path(name);
+        let broker = ScriptedBroker::default();
+        let (shutdown, rx) = broadcast::channel(1);
+        himmelblau_broker_serve_with_config(
+            broker.clone(),
+            path.clone(),
+            rx,
+            config,
+        )
+        .await
+        .unwrap();
+        Daemon {
+            path,
+            broker,
+            _shutdown: shutdown,
+        }
+    }
+
+    fn session_broker(
+        &self,
+        timeout: u64,
+    ) -> Arc<dyn AsyncSessionBroker + 'static> {
+        session_broker(&self.path, timeout)
+    }
+
+    fn calls(&self) -> usize {
+        self.broker.calls.load(Ordering::SeqCst)
+    }
+}
+
+fn session_broker(
+    path: &Path,
+    timeout: u64,
+) -> Arc<dyn AsyncSessionBroker + 'static> {
+    Arc::new(
+        himmelblau_session_broker(
+            path.to_path_buf(),
+            timeout,
+            HimmelblauSessionBrokerConfig::default(),
+            Arc::new(PortalPromptHandler::default()),
+            Arc::new(NoFederationHandler),
+        )
+        .unwrap(),
+    )
+}
+
+async fn silent(
+    broker: &dyn AsyncSessionBroker,
+    correlation_id: &str,
+    request: Value,
+) -> Result<String, dbus::MethodErr> {
+    broker
+        .acquire_token_silently(
+            "0.0".to_string(),
+            correlation_id.to_string(),
+            request.to_string(),
+            None,
+        )
+        .await
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn large_payloads_round_trip() {
+    let daemon =
+        Daemon::start("large", HimmelblauBrokerConfig::default()).await;
+    let broker = daemon.session_broker(10);
+
+    // A request just short of the daemon's frame limit is echoed back whole.
+    let blob = "a".repeat(1000 * 1000);
+    let resp = silent(&*broker, "large-request", json!({"blob": blob}))
+        .await
+        .unwrap();
+    let resp: Value = serde_json::from_str(&resp).unwrap();
+    assert_eq!(resp["blob"].as_str().map(str::len), Some(blob.len()));
+
+    // Responses are not bound by the request limit.
+    let size = 4 * 1024 * 1024;
+    let resp = silent(&*broker, "large-response", json!({"size": size}))
+        .await
+        .unwrap();
+    let resp: Value = serde_json::from_str(&resp).unwrap();
+    assert_eq!(resp["payload"].as_str().map(str::len), Some(size));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn oversized_requests_are_refused() {
+    let config = HimmelblauBrokerConfig {
+        max_frame_size: 64 * 1024,
+        ..Default::default()
+    };
+    let daemon = Daemon::start("oversized", config).await;
+    let broker = daemon.session_broker(10);
+
+    let blob = "a".repeat(128 * 1024);
+    assert!(silent(&*broker, "too-large", json!({"blob": blob}))
+        .await
+        .is_err());
+    assert_eq!(daemon.calls(), 0);
+
+    // The refused connection is not reused for the next request.
+    let resp = silent(&*broker, "after", json!({"n": 1})).await.unwrap();
+    assert_eq!(resp, json!({"n": 1}).to_string());
+}
+
+/* Reads the next JSON response from a raw daemon connection, or None once
+ * the daemon has closed it. */
+async fn next_response(stream: &mut UnixStream) -> Option<Value> {
+    let mut buf = vec![];
+    let mut chunk = [0; 4096];
+    loop {
+        let n = stream.read(&mut chunk).await.unwrap();
+        if n == 0 {
+            return None;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if let Ok(resp) = serde_json::from_slice(&buf) {
+            return Some(resp);
+        }
+    }
+}
+
+fn silent_frame(correlation_id: &str, request: Value) -> Vec<u8> {
+    serde_json::to_vec(&json!({"acquireTokenSilently": [
+        "0.0", correlation_id, request.to_string()
+    ]}))
+    .unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn oversized_frames_close_the_connection() {
+    let config = HimmelblauBrokerConfig {
+        max_frame_size: 1024,
+        ..Default::default()
+    };
+    let daemon = Daemon::start("oversized-frame", config).await;
+
+    // An incomplete request which has already outgrown the limit is refused
+    // without waiting for the rest of it.
+    let mut stream = UnixStream::connect(&daemon.path).await.unwrap();
+    let mut frame =
+        silent_frame("oversized", json!({"blob": "a".repeat(2048)}));
+    frame.truncate(1536);
+    stream.write_all(&frame).await.unwrap();
+
+    let resp = next_response(&mut stream).await.unwrap();
+    assert_eq!(resp["protocolError"]["kind"], "FrameTooLarge");
+    assert_eq!(next_response(&mut stream).await, None);
+    assert_eq!(daemon.calls(), 0);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn malformed_frames_are_reported() {
+    let config = HimmelblauBrokerConfig {
+        max_malformed_frames: 2,
+        ..Default::default()
+    };
+    let daemon = Daemon::start("malformed-frame", config).await;
+    let mut stream = UnixStream::connect(&daemon.path).await.unwrap();
+
+    // A malformed frame is reported, and the connection remains usable.
+    stream.write_all(b"}}garbage").await.unwrap();
+    let resp = next_response(&mut stream).await.unwrap();
+    assert_eq!(resp["protocolError"]["kind"], "MalformedFrame");
+
+    stream
+        .write_all(&silent_frame("valid", json!({"n": 4})))
+        .await
+        .unwrap();
+    let resp = next_response(&mut stream).await.unwrap();
+    assert_eq!(resp["result"], json!({"n": 4}).to_string());
+    assert_eq!(daemon.calls(), 1);
+
+    // Consecutive malformed frames up to the limit disconnect the client.
+    for _ in 0..2 {
+        stream.write_all(b"[1, 2]").await.unwrap();
+        let resp = next_response(&mut stream).await.unwrap();
+        assert_eq!(resp["protocolError"]["kind"], "MalformedFrame");
+    }
+    assert_eq!(next_response(&mut stream).await, None);
+    assert_eq!(daemon.calls(), 1);
+}
+
+fn interactive_frame(correlation_id: &str, request: Value) -> Vec<u8> {
+    serde_json::to_vec(&json!({"acquireTokenInteractively": [
+        "0.0", correlation_id, request.to_string()
+    ]}))
+    .unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn pin_prompts_are_verified_by_the_daemon() {
+    let verified = Arc::new(std::sync::Mutex::new(vec![]));
+    let seen = verified.clone();
+    let config = HimmelblauBrokerConfig {
+        prompt_verification: PromptVerification::Daemon(Arc::new(
+            move |request: &PromptRequest, pid, uid| {
+                seen.lock().unwrap().push((request.kind, pid, uid));
+                Ok(false)
+            },
+        )),
+        ..Default::default()
+    };
+    let daemon = Daemon::start("prompt-verify", config).await;
+    let mut stream = UnixStream::connect(&daemon.path).await.unwrap();
+
+    // The session is not asked, so cannot answer for the user.
+    stream
+        .write_all(&interactive_frame("pin", json!({"prompt": "Pin"})))
+        .await
+        .unwrap();
+    let resp = next_response(&mut stream).await.unwrap();
+    assert_eq!(resp["result"], json!({"accepted": false}).to_string());
+    let uid = unsafe { libc::getuid() };
+    assert_eq!(
+        *verified.lock().unwrap(),
+        [(PromptKind::Pin, std::process::id() as i32, uid)]
+    );
+
+    // Presence confirmations are still answered by the session.
+    stream
+        .write_all(&interactive_frame(
+            "presence",
+            json!({"prompt": "UserPresence"}),
+        ))
+        .await
+        .unwrap();
+    let resp = next_response(&mut stream).await.unwrap();
+    assert_eq!(resp["promptRequest"]["kind"], "UserPresence");
+    stream
+        .write_all(br#"{"promptResponse": {"accepted": true}}"#)
+        .await
+        .unwrap();
+    let resp = next_response(&mut stream).await.unwrap();
+    assert_eq!(resp["result"], json!({"accepted": true}).to_string());
+    assert_eq!(verified.lock().unwrap().len(), 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn session_verification_trusts_the_session() {
+    let config = HimmelblauBrokerConfig {
+        prompt_verification: PromptVerification::Session,
+        ..Default::default()
+    };
+    let daemon = Daemon::start("prompt-session", config).await;
+    let mut stream = UnixStream::connect(&daemon.path).await.unwrap();
+
+    stream
+        .write_all(&interactive_frame("pin", json!({"prompt": "Biometric"})))
+        .await
+        .unwrap();
+    let resp = next_response(&mut stream).await.unwrap();
+    assert_eq!(resp["promptRequest"]["kind"], "Biometric");
+    stream
+        .write_all(br#"{"promptResponse": {"accepted": true}}"#)
+        .await
+        .unwrap();
+    let resp = next_response(&mut stream).await.unwrap();
+    assert_eq!(resp["result"], json!({"accepted": true}).to_string());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn concurrent_clients_get_their_own_responses() {
+    let daemon =
+        Daemon::start("concurrent", HimmelblauBrokerConfig::default()).await;
+    let brokers: Vec<_> = (0..4).map(|_| daemon.session_broker(10)).collect();
+
+    let mut tasks = vec![];
+    for n in 0..64u64 {
+        let broker = brokers[n as usize % brokers.len()].clone();
+        tasks.push(tokio::spawn(async move {
+            let request = json!({"n": n, "sleep": n % 8 * 10});
+            let resp = silent(&*broker, &format!("concurrent-{}", n), request)
+                .await
+                .unwrap();
+            (n, resp)
+        }));
+    }
+    for task in tasks {
+        let (n, resp) = task.await.unwrap();
+        let resp: Value = serde_json::from_str(&resp).unwrap();
+        assert_eq!(resp["n"], n);
+    }
+    assert_eq!(daemon.calls(), 64);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn slow_daemons_time_out() {
+    let daemon =
+        Daemon::start("timeout", HimmelblauBrokerConfig::default()).await;
+    let broker = daemon.session_broker(1);
+
+    let start = Instant::now();
+    let err = silent(&*broker, "slow", json!({"sleep": 3000}))
+        .await
+        .unwrap_err();
+    assert!(start.elapsed() < Duration::from_secs(3));
+    assert_eq!(err.description(), "Socket timeout");
+
+    // The late response to the timed out request must not answer this one.
+    let resp = silent(&*broker, "fast", json!({"n": 2})).await.unwrap();
+    assert_eq!(resp, json!({"n": 2}).to_string());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn clients_disconnecting_mid_request_are_dropped() {
+    let daemon =
+        Daemon::start("client-hangup", HimmelblauBrokerConfig::default()).await;
+
+    for partial in [
+        &br#"{"acquireTokenSilently":["0.0","hangup","{\"n\""#[..],
+        &br#"{"acquireTokenSil"#[..],
+        &b"{"[..],
+    ] {
+        let mut stream = UnixStream::connect(&daemon.path).await.unwrap();
+        stream.write_all(partial).await.unwrap();
+        drop(stream);
+    }
+    // A client which is answered but hangs up before reading the response.
+    let mut stream = UnixStream::connect(&daemon.path).await.unwrap();
+    stream
+        .write_all(
+            &serde_json::to_vec(&json!({"acquireTokenSilently": [
+                "0.0", "hangup", json!({"sleep": 100}).to_string()
+            ]}))
+            .unwrap(),
+        )
+        .await
+        .unwrap();
+    drop(stream);
+
+    let broker = daemon.session_broker(10);
+    let resp = silent(&*broker, "after", json!({"n": 3})).await.unwrap();
+    assert_eq!(resp, json!({"n": 3}).to_string());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn daemons_disconnecting_mid_response_are_reported() {
+    let path = socket_path("daemon-hangup");
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path).unwrap();
+    let daemon = tokio::spawn(async move {
+        // The first connection is closed part way through its response,
+        // the second is answered.
+        for reply in [&br#"{"result":"{\"n\":"#[..], &br#"{"result":"ok"}"#[..]]
+        {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 4096];
+            let _ = stream.read(&mut buf).await.unwrap();
+            stream.write_all(reply).await.unwrap();
+        }
+    });
+
+    let broker = session_broker(&path, 10);
+    let err = silent(&*broker, "cut", json!({})).await.unwrap_err();
+    assert_eq!(err.description(), "The broker closed the connection");
+    let resp = silent(&*broker, "whole", json!({})).await.unwrap();
+    assert_eq!(resp, "ok");
+
+    daemon.await.unwrap();
+    let _ = std::fs::remove_file(&path);
+}
+
+async fn fault_proxy(
+    daemon: &Daemon,
+    name: &str,
+    config: FaultConfig,
+) -> (PathBuf, broadcast::Sender<bool>) {
+    let path = socket_path(name);
+    let _ = std::fs::remove_file(&path);
+    let (shutdown, rx) = broadcast::channel(1);
+    serve_fault_proxy(&path, &daemon.path, config, rx)
+        .await
+        .unwrap();
+    (path, shutdown)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn injected_faults_fail_requests() {
+    let daemon =
+        Daemon::start("faults", HimmelblauBrokerConfig::default()).await;
+    for (name, config) in [
+        (
+            "faults-reset",
+            FaultConfig {
+                reset: 1.0,
+                ..Default::default()
+            },
+        ),
+        (
+            "faults-partial",
+            FaultConfig {
+                partial_write: 1.0,
+                ..Default::default()
+            },
+        ),
+        (
+            "faults-malformed",
+            FaultConfig {
+                malformed: 1.0,
+                ..Default::default()
+            },
+        ),
+        (
+            "faults-timeout",
+            FaultConfig {
+                timeout: 1.0,
+                stall: 5,
+                ..Default::default()
+            },
+        ),
+    ] {
+        let (path, _shutdown) = fault_proxy(&daemon, name, config).await;
+        let broker = session_broker(&path, 1);
+        let start = Instant::now();
+        assert!(
+            silent(&*broker, name, json!({"n": 4})).await.is_err(),
+            "{}",
+            name
+        );
+        assert!(start.elapsed() < Duration::from_secs(5), "{}", name);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn session_broker_recovers_from_faults() {
+    let daemon =
+        Daemon::start("chaos", HimmelblauBrokerConfig::default()).await;
+    let (path, _shutdown) = fault_proxy(
+        &daemon,
+        "chaos-proxy",
+        FaultConfig {
+            malformed: 0.2,
+            partial_write: 0.2,
+            reset: 0.2,
+            seed: Some(1897),
+            ..Default::default()
+        },
+    )
+    .await;
+    let broker = session_broker(&path, 10);
+
+    // Every request either fails or is answered with its own response,
+    // never with one left over from a faulted request.
+    let mut answered = 0;
+    for n in 0..200 {
+        let id = format!("chaos-{}", n);
+        if let Ok(resp) = silent(&*broker, &id, json!({"n": n})).await {
+            assert_eq!(resp, json!({"n": n}).to_string());
+            answered += 1;
+        }
+    }
+    assert!((40..160).contains(&answered), "{}", answered);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn pam_connections_get_a_broker_for_root() {
+    let path = socket_path("pam-factory");
+    let pam_path = socket_path("pam-factory-pam");
+    let creds = Arc::new(Mutex::new(Vec::new()));
+    let seen = creds.clone();
+    let config = HimmelblauBrokerConfig {
+        pam_socket: Some(pam_path.clone().into()),
+        ..Default::default()
+    };
+    let (_shutdown, rx) = broadcast::channel(1);
+    himmelblau_broker_serve_with_factory(
+        move |cred: PeerCred| {
+            seen.lock().unwrap().push(cred);
+            ScriptedBroker::default()
+        },
+        Default::default(),
+        path,
+        rx,
+        config,
+    )
+    .await
+    .unwrap();
+
+    let mut stream = UnixStream::connect(&pam_path).await.unwrap();
+    let request = json!({"authenticated": {
+        "uid": 0, "user": "root", "service": "login"
+    }});
+    // Root is answered with a line, while anyone else is hung up on, which
+    // the write may already see.
+    let _ = stream.write_all(format!("{}\n", request).as_bytes()).await;
+    let mut resp = vec![];
+    let mut byte = [0; 1];
+    while stream.read(&mut byte).await.unwrap_or(0) == 1 {
+        resp.push(byte[0]);
+        if byte[0] == b'\n' {
+            break;
+        }
+    }
+    let resp = String::from_utf8(resp).unwrap();
+
+    // Only root may use the PAM socket, and only root is given a broker.
+    let creds = creds.lock().unwrap();
+    if unsafe { libc::geteuid() } == 0 {
+        assert_eq!(creds.len(), 1, "{}", resp);
+        assert_eq!(creds[0].uid, 0);
+        assert_eq!(creds[0].pid, Some(std::process::id() as i32));
+    } else {
+        assert!(creds.is_empty());
+        assert!(resp.is_empty(), "{}", resp);
+    }
+}
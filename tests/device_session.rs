@@ -0,0 +1,99 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use identity_dbus_broker::{BusType, CallerResolver, DeviceSessions};
+use std::collections::HashSet;
+
+const ACCESS_DENIED: &str = "org.freedesktop.DBus.Error.AccessDenied";
+
+/* The resolver points at a bus which does not exist, so callers are known
+ * only by their bus name, as when resolution fails on a real bus.
+ */
+fn sessions() -> DeviceSessions {
+    DeviceSessions::new(CallerResolver::new(BusType::Address(
+        "unix:path=/nonexistent/bus".to_string(),
+    )))
+}
+
+fn denied(res: Result<impl std::fmt::Debug, dbus::MethodErr>) -> bool {
+    matches!(res, Err(e) if e.errorname() == ACCESS_DENIED)
+}
+
+#[test]
+fn sessions_are_bound_to_their_caller() {
+    let sessions = sessions();
+    let id = sessions.open(Some(":1.1")).unwrap();
+    let caller = sessions.check(Some(":1.1"), &id).unwrap();
+    assert_eq!(caller.bus_name, ":1.1");
+    assert_eq!(caller.uid, None);
+
+    // Neither another connection, an unidentified caller nor an id which
+    // was never issued is let through.
+    assert!(denied(sessions.check(Some(":1.2"), &id)));
+    assert!(denied(sessions.check(None, &id)));
+    assert!(denied(sessions.check(Some(":1.1"), "not-a-session")));
+    assert!(denied(sessions.check(Some(":1.1"), "")));
+
+    let ids: HashSet<_> = (0..10)
+        .map(|_| sessions.open(Some(":1.1")).unwrap())
+        .collect();
+    assert_eq!(ids.len(), 10);
+    assert!(!ids.contains(&id));
+}
+
+#[test]
+fn unidentified_callers_get_no_session() {
+    assert!(denied(sessions().open(None)));
+}
+
+#[test]
+fn only_the_owner_closes_a_session() {
+    let sessions = sessions();
+    let id = sessions.open(Some(":1.1")).unwrap();
+    assert!(denied(sessions.close(Some(":1.2"), &id)));
+    assert!(sessions.check(Some(":1.1"), &id).is_ok());
+
+    sessions.close(Some(":1.1"), &id).unwrap();
+    assert!(denied(sessions.check(Some(":1.1"), &id)));
+    assert!(denied(sessions.close(Some(":1.1"), &id)));
+}
+
+#[test]
+fn sessions_end_when_their_caller_leaves() {
+    let sessions = sessions();
+    let first = sessions.open(Some(":1.1")).unwrap();
+    let second = sessions.open(Some(":1.1")).unwrap();
+    let other = sessions.open(Some(":1.2")).unwrap();
+    sessions.release(":1.1");
+    assert!(denied(sessions.check(Some(":1.1"), &first)));
+    assert!(denied(sessions.check(Some(":1.1"), &second)));
+    assert!(sessions.check(Some(":1.2"), &other).is_ok());
+}
+
+#[test]
+fn open_sessions_are_limited_per_caller() {
+    let sessions = sessions();
+    let ids: Vec<_> = (0..64)
+        .map(|_| sessions.open(Some(":1.1")).unwrap())
+        .collect();
+    assert!(sessions.open(Some(":1.1")).is_err());
+    assert!(sessions.open(Some(":1.2")).is_ok());
+
+    sessions.close(Some(":1.1"), &ids[0]).unwrap();
+    assert!(sessions.open(Some(":1.1")).is_ok());
+}
@@ -0,0 +1,114 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use identity_dbus_broker::{
+    AsyncSessionBroker, Fault, FaultConfig, FaultInjector, MockSessionBroker,
+    MALFORMED_RESPONSE,
+};
+
+fn injected(config: FaultConfig, count: usize) -> Vec<Option<Fault>> {
+    let faults = FaultInjector::new(config);
+    (0..count).map(|_| faults.next_fault()).collect()
+}
+
+#[test]
+fn seeded_faults_repeat() {
+    let config = FaultConfig {
+        timeout: 0.1,
+        malformed: 0.1,
+        partial_write: 0.1,
+        reset: 0.1,
+        seed: Some(1894),
+        ..Default::default()
+    };
+    let first = injected(config.clone(), 1000);
+    assert_eq!(first, injected(config, 1000));
+
+    // Each fault turns up at roughly its configured rate.
+    for fault in [
+        Fault::Timeout,
+        Fault::Malformed,
+        Fault::PartialWrite,
+        Fault::Reset,
+    ] {
+        let count = first.iter().filter(|f| **f == Some(fault)).count();
+        assert!((50..150).contains(&count), "{:?} {}", fault, count);
+    }
+    assert!(injected(FaultConfig::default(), 1000)
+        .iter()
+        .all(Option::is_none));
+}
+
+async fn silent(mock: &MockSessionBroker) -> Result<String, dbus::MethodErr> {
+    mock.acquire_token_silently(
+        "0.0".to_string(),
+        "7a0e6f2c".to_string(),
+        "{}".to_string(),
+        None,
+    )
+    .await
+}
+
+#[tokio::test]
+async fn mock_injects_faults() {
+    let resp = r#"{"brokerTokenResponse":{"accessToken":"token"}}"#;
+    let mock = MockSessionBroker::new();
+    for _ in 0..4 {
+        mock.respond("acquireTokenSilently", resp);
+    }
+
+    mock.inject_faults(FaultConfig {
+        malformed: 1.0,
+        ..Default::default()
+    });
+    assert_eq!(silent(&mock).await.unwrap(), MALFORMED_RESPONSE);
+
+    mock.inject_faults(FaultConfig {
+        partial_write: 1.0,
+        ..Default::default()
+    });
+    let partial = silent(&mock).await.unwrap();
+    assert!(resp.starts_with(&partial) && partial.len() < resp.len());
+
+    mock.inject_faults(FaultConfig {
+        reset: 1.0,
+        ..Default::default()
+    });
+    let err = silent(&mock).await.unwrap_err();
+    assert_eq!(err.errorname(), "org.freedesktop.DBus.Error.NoReply");
+
+    mock.inject_faults(FaultConfig {
+        timeout: 1.0,
+        stall: 1,
+        ..Default::default()
+    });
+    let start = std::time::Instant::now();
+    assert_eq!(silent(&mock).await.unwrap(), resp);
+    assert!(start.elapsed() >= std::time::Duration::from_secs(1));
+
+    let faults: Vec<_> = mock.calls().iter().map(|call| call.fault).collect();
+    assert_eq!(
+        faults,
+        [
+            Some(Fault::Malformed),
+            Some(Fault::PartialWrite),
+            Some(Fault::Reset),
+            Some(Fault::Timeout),
+        ]
+    );
+}
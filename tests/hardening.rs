@@ -0,0 +1,61 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use identity_dbus_broker::HardeningConfig;
+use serde_json::Value;
+use std::time::{Duration, Instant};
+
+#[test]
+fn responses_are_padded_to_buckets() {
+    let hardening = HardeningConfig {
+        bucket_size: 256,
+        ..Default::default()
+    };
+    let none = r#"{"accounts":[]}"#.to_string();
+    let one = r#"{"accounts":[{"homeAccountId":"oid.tid","username":"a"}]}"#
+        .to_string();
+    let (none, one) = (hardening.pad(none), hardening.pad(one));
+    assert_eq!(none.len(), 256);
+    assert_eq!(one.len(), 256);
+    assert!(serde_json::from_str::<Value>(&one).is_ok());
+
+    let large = hardening.pad("x".repeat(257));
+    assert_eq!(large.len(), 512);
+
+    let unpadded = HardeningConfig {
+        bucket_size: 0,
+        ..Default::default()
+    };
+    assert_eq!(unpadded.pad("{}".to_string()), "{}");
+}
+
+#[test]
+fn errors_wait_for_the_floor() {
+    let hardening = HardeningConfig {
+        error_floor: 500,
+        ..Default::default()
+    };
+    assert!(hardening.applies_to("getAccounts"));
+    assert!(!hardening.applies_to("getLinuxBrokerVersion"));
+
+    let delay = hardening.error_delay(Instant::now());
+    assert!(delay > Duration::from_millis(400));
+    assert!(delay <= Duration::from_millis(500));
+    let late = Instant::now() - Duration::from_secs(1);
+    assert_eq!(hardening.error_delay(late), Duration::ZERO);
+}
@@ -0,0 +1,181 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use identity_dbus_broker::{
+    device_broker_introspection, session_broker_introspection,
+};
+use std::collections::BTreeMap;
+
+/* tests/microsoft/ holds Microsoft's own introspection of the interfaces
+ * this crate reimplements. MSAL and the session broker are built against
+ * those, so a method that is renamed, dropped or has its arguments
+ * reordered here breaks existing clients even when our own generated XML
+ * in interfaces/ is current. Interfaces which Microsoft does not publish
+ * (the org.himmelblau.* extensions) are not compared.
+ */
+
+#[derive(Debug, PartialEq)]
+struct Arg {
+    name: String,
+    signature: String,
+    direction: String,
+}
+
+/* Method name -> arguments in declaration order, for one interface. */
+type Methods = BTreeMap<String, Vec<Arg>>;
+
+/* Yields (tag, attributes) for every element, opening or self-closing, in
+ * document order. Closing tags come back with a leading '/'. Comments and
+ * the DOCTYPE are skipped, and attribute order and quoting do not matter,
+ * so an unedited `gdbus introspect --xml` capture can be dropped in.
+ */
+fn elements(xml: &str) -> Vec<(String, BTreeMap<String, String>)> {
+    let mut out = vec![];
+    let mut rest = xml;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start..];
+        if let Some(comment) = rest.strip_prefix("<!--") {
+            let end = comment.find("-->").expect("unterminated comment");
+            rest = &comment[end + 3..];
+            continue;
+        }
+        let end = rest.find('>').expect("unterminated tag");
+        let tag = &rest[1..end];
+        rest = &rest[end + 1..];
+        if tag.starts_with('!') || tag.starts_with('?') {
+            continue;
+        }
+        let tag = tag.trim_end_matches('/');
+        let (name, mut attrs) = match tag.find(char::is_whitespace) {
+            Some(i) => (&tag[..i], &tag[i..]),
+            None => (tag, ""),
+        };
+        let mut parsed = BTreeMap::new();
+        while let Some(eq) = attrs.find('=') {
+            let key = attrs[..eq].trim().to_string();
+            let value = attrs[eq + 1..].trim_start();
+            let quote = value.chars().next().expect("attribute without value");
+            let value = &value[1..];
+            let close = value.find(quote).expect("unterminated attribute");
+            parsed.insert(key, value[..close].to_string());
+            attrs = &value[close + 1..];
+        }
+        out.push((name.to_string(), parsed));
+    }
+    out
+}
+
+fn interfaces(xml: &str) -> BTreeMap<String, Methods> {
+    let mut interfaces: BTreeMap<String, Methods> = BTreeMap::new();
+    let mut interface = None;
+    let mut method = None;
+    for (tag, attrs) in elements(xml) {
+        match tag.as_str() {
+            "interface" => {
+                let name = attrs["name"].clone();
+                interfaces.entry(name.clone()).or_default();
+                interface = Some(name);
+            }
+            "/interface" => interface = None,
+            "method" => {
+                let name = attrs["name"].clone();
+                let iface =
+                    interface.as_ref().expect("method outside interface");
+                interfaces
+                    .get_mut(iface)
+                    .unwrap()
+                    .insert(name.clone(), vec![]);
+                method = Some(name);
+            }
+            "/method" => method = None,
+            "arg" => {
+                /* Signal arguments have no place in the method table. */
+                let (Some(iface), Some(m)) = (&interface, &method) else {
+                    continue;
+                };
+                interfaces.get_mut(iface).unwrap().get_mut(m).unwrap().push(
+                    Arg {
+                        name: attrs.get("name").cloned().unwrap_or_default(),
+                        signature: attrs["type"].clone(),
+                        direction: attrs
+                            .get("direction")
+                            .cloned()
+                            .unwrap_or_else(|| "in".to_string()),
+                    },
+                );
+            }
+            _ => {}
+        }
+    }
+    interfaces
+}
+
+fn check(vendored: &str, generated: String) {
+    let path = format!(
+        "{}/tests/microsoft/{}",
+        env!("CARGO_MANIFEST_DIR"),
+        vendored
+    );
+    let reference = interfaces(&std::fs::read_to_string(&path).unwrap());
+    let ours = interfaces(&generated);
+    assert!(!reference.is_empty(), "{} declares no interfaces", path);
+    for (name, methods) in reference {
+        let served = ours
+            .get(&name)
+            .unwrap_or_else(|| panic!("{} is not registered", name));
+        let missing: Vec<_> = methods
+            .keys()
+            .filter(|m| !served.contains_key(*m))
+            .collect();
+        let extra: Vec<_> = served
+            .keys()
+            .filter(|m| !methods.contains_key(*m))
+            .collect();
+        assert!(
+            missing.is_empty() && extra.is_empty(),
+            "{} differs from {}: missing {:?}, not in Microsoft's {:?}",
+            name,
+            path,
+            missing,
+            extra
+        );
+        for (method, args) in methods {
+            assert_eq!(
+                served[&method], args,
+                "{}.{} arguments differ from {}",
+                name, method, path
+            );
+        }
+    }
+}
+
+#[test]
+fn session_broker_matches_microsoft() {
+    check(
+        "com.microsoft.identity.Broker1.xml",
+        session_broker_introspection(),
+    );
+}
+
+#[test]
+fn device_broker_matches_microsoft() {
+    check(
+        "com.microsoft.identity.DeviceBroker1.xml",
+        device_broker_introspection(),
+    );
+}
@@ -0,0 +1,106 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use identity_dbus_broker::{
+    device_broker_examples, device_broker_introspection,
+    session_broker_examples, session_broker_introspection,
+};
+
+/* The files in interfaces/ are kept for packagers and client authors. They
+ * must match what the registration code produces, and are regenerated with
+ * `cargo run --bin himmelblau-broker-introspect -- --write interfaces`.
+ */
+fn check(name: &str, generated: String) {
+    let path = format!("{}/interfaces/{}", env!("CARGO_MANIFEST_DIR"), name);
+    let kept = std::fs::read_to_string(&path).unwrap();
+    assert!(
+        kept == generated,
+        "{} is out of date, regenerate it with \
+         `cargo run --bin himmelblau-broker-introspect -- --write interfaces`",
+        path
+    );
+}
+
+#[test]
+fn interfaces_are_current() {
+    check(
+        "com.microsoft.identity.broker1.xml",
+        session_broker_introspection(),
+    );
+    check(
+        "com.microsoft.identity.broker1.sh",
+        session_broker_examples(),
+    );
+    check(
+        "com.microsoft.identity.DeviceBroker1.xml",
+        device_broker_introspection(),
+    );
+    check(
+        "com.microsoft.identity.DeviceBroker1.sh",
+        device_broker_examples(),
+    );
+}
+
+#[test]
+fn examples_cover_every_method() {
+    let xml = session_broker_introspection();
+    let examples = session_broker_examples();
+    for method in ["acquireTokenSilently", "getDefaultAccount", "getSshCert"] {
+        assert!(xml.contains(&format!("<method name=\"{}\">", method)));
+        assert!(examples.contains(&format!(".{} \\\n", method)));
+    }
+    let examples = device_broker_examples();
+    assert!(examples.contains(
+        "--method com.microsoft.identity.DeviceBroker1.openSession\n"
+    ));
+    assert!(examples.contains(
+        "--method com.microsoft.identity.DeviceBroker1.sign \\\n    \
+         \"$SESSION_ID\" '{}'\n"
+    ));
+}
+
+#[test]
+fn account_manager_is_typed() {
+    let xml = session_broker_introspection();
+    let start = xml
+        .find("<interface name=\"org.himmelblau.Broker1.AccountManager\">")
+        .unwrap();
+    let manager =
+        &xml[start..start + xml[start..].find("</interface>").unwrap()];
+    assert!(manager.contains(
+        "<arg name=\"accounts\" type=\"aa{sv}\" direction=\"out\"/>"
+    ));
+    assert!(!manager.contains("request_json"));
+}
+
+#[test]
+fn token_broker_is_typed() {
+    let xml = session_broker_introspection();
+    let start = xml
+        .find("<interface name=\"org.himmelblau.TokenBroker\">")
+        .unwrap();
+    let typed = &xml[start..start + xml[start..].find("</interface>").unwrap()];
+    assert_eq!(typed.matches("<method ").count(), 8);
+    assert_eq!(
+        typed
+            .matches("<arg name=\"request\" type=\"a{sv}\"")
+            .count(),
+        8
+    );
+    assert!(!typed.contains("type=\"s\""));
+}
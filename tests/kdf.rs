@@ -0,0 +1,88 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+use identity_dbus_broker::{
+    derive_key, kdf_counter_hmac_sha256, kdf_fixed_input,
+    SECURE_CONVERSATION_LABEL,
+};
+
+fn unhex(s: &str) -> Vec<u8> {
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+// NIST CAVP KDFCTR, [PRF=HMAC_SHA256] [CTRLOCATION=BEFORE_FIXED]
+// [RLEN=32_BITS], COUNT=0.
+#[test]
+fn nist_counter_mode_vector() {
+    let key = unhex(
+        "dd1d91b7d90b2bd3138533ce92b272fbf8a369316aefe242e659cc0ae238afe0",
+    );
+    let fixed = unhex(
+        "01322b96b30acd197979444e468e1c5c6859bf1b1cf951b7e725303e237e46b8\
+         64a145fab25e517b08f8683d0315bb2911d80a0e8aba17f3b413faac",
+    );
+    let out = kdf_counter_hmac_sha256(&key, &fixed, 16).unwrap();
+    assert_eq!(out, unhex("10621342bfb0fd40046c0e29f2cfdbf0"));
+}
+
+// Output spanning several PRF blocks, truncated within the last one. The
+// expected values here and below were computed independently with Python's
+// hmac module.
+#[test]
+fn multi_block_derivation() {
+    let key = unhex(
+        "dd1d91b7d90b2bd3138533ce92b272fbf8a369316aefe242e659cc0ae238afe0",
+    );
+    let out = derive_key(&key, b"label", b"context", 80).unwrap();
+    assert_eq!(
+        out,
+        unhex(
+            "f2f7d6874c968fa27c21a70e9bfe9fad95c97a9e47f4df8f02ae5c9c444869ea\
+             f093b307ba3c90e018f016c6cf81276f1350cd7341f6b6d43ff9d06c89a8b160\
+             e19d3ee9aa2682ec799fc8a0d5fe5769"
+        )
+    );
+    // A shorter key is a prefix of a longer one only if L matches, since L
+    // is part of the fixed input.
+    let short = derive_key(&key, b"label", b"context", 32).unwrap();
+    assert_ne!(&short[..], &out[..32]);
+}
+
+#[test]
+fn secure_conversation_session_key() {
+    let key: Vec<u8> = (0..32).collect();
+    let context: Vec<u8> = (100..124).collect();
+    let out =
+        derive_key(&key, SECURE_CONVERSATION_LABEL, &context, 32).unwrap();
+    assert_eq!(
+        out,
+        unhex(
+            "fd86b1821bd2effa68009dee12e23d90b6889712dc9ec3a625075817ca47f47a"
+        )
+    );
+}
+
+#[test]
+fn fixed_input_layout() {
+    assert_eq!(
+        kdf_fixed_input(b"ab", b"cd", 32),
+        [b'a', b'b', 0, b'c', b'd', 0, 0, 1, 0]
+    );
+}
@@ -0,0 +1,185 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use identity_dbus_broker::{DeviceKeyStore, ThumbprintAlgorithm};
+use openssl::asn1::Asn1Time;
+use openssl::ec::{EcGroup, EcKey};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::x509::{X509NameBuilder, X509};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+fn store_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+        "keystore-{}-{}",
+        name,
+        std::process::id()
+    ));
+    let _ = fs::remove_dir_all(&dir);
+    dir.join("keys")
+}
+
+fn key() -> PKey<Private> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+    PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+}
+
+fn certificate(key: &PKey<Private>) -> X509 {
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_text("CN", "device").unwrap();
+    let name = name.build();
+    let mut cert = X509::builder().unwrap();
+    cert.set_version(2).unwrap();
+    cert.set_subject_name(&name).unwrap();
+    cert.set_issuer_name(&name).unwrap();
+    cert.set_pubkey(key).unwrap();
+    cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+        .unwrap();
+    cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+        .unwrap();
+    cert.sign(key, MessageDigest::sha256()).unwrap();
+    cert.build()
+}
+
+fn mode(path: &Path) -> u32 {
+    fs::metadata(path).unwrap().permissions().mode() & 0o777
+}
+
+fn same_key(a: &PKey<Private>, b: &PKey<Private>) -> bool {
+    a.public_key_to_der().unwrap() == b.public_key_to_der().unwrap()
+}
+
+#[test]
+fn keys_round_trip_in_private_files() {
+    let dir = store_dir("round-trip");
+    let store = DeviceKeyStore::open(&dir).unwrap();
+    assert_eq!(mode(&dir), 0o700);
+    assert!(store.load_key("device").unwrap().is_none());
+
+    let device = key();
+    store.store_key("device", &device).unwrap();
+    assert_eq!(mode(&dir.join("device.key")), 0o600);
+    assert!(same_key(
+        &store.load_key("device").unwrap().unwrap(),
+        &device
+    ));
+
+    // A reopened store sees the same key.
+    let store = DeviceKeyStore::open(&dir).unwrap();
+    assert!(same_key(
+        &store.load_key("device").unwrap().unwrap(),
+        &device
+    ));
+}
+
+#[test]
+fn key_names_stay_in_the_store() {
+    let store = DeviceKeyStore::open(store_dir("names")).unwrap();
+    for name in ["", ".hidden", "../device", "a/b"] {
+        assert!(store.store_key(name, &key()).is_err(), "{:?}", name);
+        assert!(store.load_key(name).is_err(), "{:?}", name);
+    }
+}
+
+#[test]
+fn certificates_must_match_their_key() {
+    let dir = store_dir("certificates");
+    let store = DeviceKeyStore::open(&dir).unwrap();
+    let device = key();
+    let cert = certificate(&device);
+    // There is no key to check the certificate against yet.
+    assert!(store
+        .store_certificate_chain("device", std::slice::from_ref(&cert))
+        .is_err());
+    store.store_key("device", &device).unwrap();
+    assert!(store
+        .store_certificate_chain("device", &[certificate(&key())])
+        .is_err());
+    assert!(store.store_certificate_chain("device", &[]).is_err());
+
+    store
+        .store_certificate_chain("device", std::slice::from_ref(&cert))
+        .unwrap();
+    assert_eq!(mode(&dir.join("device.crt")), 0o600);
+    let chain = store.load_certificate_chain("device").unwrap().unwrap();
+    assert_eq!(chain[0].to_der().unwrap(), cert.to_der().unwrap());
+}
+
+#[test]
+fn rotated_keys_serve_their_grace_window() {
+    let dir = store_dir("rotation");
+    let store = DeviceKeyStore::open(&dir).unwrap();
+    let old = key();
+    store.store_key("device", &old).unwrap();
+    store
+        .store_certificate_chain("device", &[certificate(&old)])
+        .unwrap();
+    let new = key();
+    store
+        .rotate_key("device", &new, Duration::from_secs(3600))
+        .unwrap();
+    assert_eq!(mode(&dir.join("device.key.prev")), 0o600);
+
+    assert!(same_key(&store.load_key("device").unwrap().unwrap(), &new));
+    assert!(same_key(
+        &store.previous_key("device").unwrap().unwrap(),
+        &old
+    ));
+    let thumbprints = store
+        .key_thumbprints("device", ThumbprintAlgorithm::Sha1)
+        .unwrap();
+    assert_eq!(thumbprints.len(), 2);
+    let found = store
+        .key_for_thumbprint("device", &thumbprints[1].to_string())
+        .unwrap()
+        .unwrap();
+    assert!(same_key(&found, &old));
+    assert!(store
+        .key_for_thumbprint(
+            "device",
+            "00112233445566778899AABBCCDDEEFF00112233"
+        )
+        .unwrap()
+        .is_none());
+
+    // Without a grace window the old key is gone at once.
+    store.rotate_key("device", &key(), Duration::ZERO).unwrap();
+    assert!(store.previous_key("device").unwrap().is_none());
+    assert!(!dir.join("device.key.prev").exists());
+}
+
+#[test]
+fn deleted_keys_leave_nothing_behind() {
+    let dir = store_dir("delete");
+    let store = DeviceKeyStore::open(&dir).unwrap();
+    let device = key();
+    store.store_key("device", &device).unwrap();
+    store
+        .store_certificate_chain("device", &[certificate(&device)])
+        .unwrap();
+    store
+        .rotate_key("device", &key(), Duration::from_secs(3600))
+        .unwrap();
+    store.delete("device").unwrap();
+    assert!(store.load_key("device").unwrap().is_none());
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+}
@@ -0,0 +1,155 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use identity_dbus_broker::{
+    Account, AccountIdentity, AccountOwnership, OwnershipEnforcement,
+    StaticAccountResolver,
+};
+use std::sync::Arc;
+
+fn ownership(enforcement: OwnershipEnforcement) -> AccountOwnership {
+    let resolver = StaticAccountResolver(
+        [(1000, AccountIdentity::upn("alice@example.com"))].into(),
+    );
+    AccountOwnership::new(Arc::new(resolver), enforcement)
+}
+
+/* The accounts the broker holds for alice. */
+fn held() -> Vec<Account> {
+    serde_json::from_str(
+        r#"[{"homeAccountId": "a.t", "username": "alice@example.com"}]"#,
+    )
+    .unwrap()
+}
+
+#[test]
+fn foreign_accounts_are_rejected_by_default() {
+    let ownership = ownership(OwnershipEnforcement::default());
+    let alice = r#"{"account": {"homeAccountId": "a.t", "username": "alice@example.com"}}"#;
+    let bob = r#"{"account": {"homeAccountId": "b.t", "username": "bob@example.com"}}"#;
+
+    let held = held();
+
+    for method in ["acquireTokenSilently", "refreshPrt", "removeAccount"] {
+        assert!(ownership.check_request(method, 1000, alice, &held).is_ok());
+        assert!(ownership.check_request(method, 1000, bob, &held).is_err());
+        // Root is not checked.
+        assert!(ownership.check_request(method, 0, bob, &[]).is_ok());
+    }
+    assert!(ownership
+        .check_request("getAccounts", 1000, bob, &held)
+        .is_ok());
+}
+
+#[test]
+fn home_account_ids_are_verified() {
+    let ownership = ownership(OwnershipEnforcement::Reject);
+    let method = "acquireTokenSilently";
+    // Alice's own username, paired with bob's home account id.
+    let spoofed = r#"{"account": {"homeAccountId": "b.t", "username": "alice@example.com"}}"#;
+    let alice = r#"{"account": {"homeAccountId": "a.t", "username": "alice@example.com"}}"#;
+
+    assert!(ownership.needs_held_accounts(method, 1000, spoofed));
+    assert!(ownership
+        .check_request(method, 1000, spoofed, &held())
+        .is_err());
+    // Without the broker's accounts, no id can be verified.
+    assert!(ownership.check_request(method, 1000, alice, &[]).is_err());
+
+    // An object id verifies the id without the broker's accounts.
+    let resolver = StaticAccountResolver(
+        [(
+            1000,
+            AccountIdentity {
+                object_id: Some("a".to_string()),
+                ..AccountIdentity::upn("alice@example.com")
+            },
+        )]
+        .into(),
+    );
+    let ownership =
+        AccountOwnership::new(Arc::new(resolver), OwnershipEnforcement::Reject);
+    assert!(!ownership.needs_held_accounts(method, 1000, spoofed));
+    assert!(ownership.check_request(method, 1000, alice, &[]).is_ok());
+    assert!(ownership.check_request(method, 1000, spoofed, &[]).is_err());
+}
+
+#[test]
+fn login_hints_select_accounts() {
+    let ownership = ownership(OwnershipEnforcement::Reject);
+    let method = "acquireTokenSilently";
+
+    let hint = r#"{"authParameters": {"loginHint": "alice@example.com"}}"#;
+    assert!(!ownership.needs_held_accounts(method, 1000, hint));
+    assert!(ownership.check_request(method, 1000, hint, &[]).is_ok());
+    let hint = r#"{"authParameters": {"loginHint": "bob@example.com"}}"#;
+    assert!(ownership.check_request(method, 1000, hint, &[]).is_err());
+    let username = r#"{"username": "bob@example.com"}"#;
+    assert!(ownership
+        .check_request(method, 1000, username, &[])
+        .is_err());
+}
+
+#[test]
+fn requests_without_accounts_fail_closed() {
+    let method = "acquireTokenSilently";
+    let reject = ownership(OwnershipEnforcement::Reject);
+    assert!(reject.check_request(method, 1000, "{}", &[]).is_err());
+    assert!(reject.check_request(method, 1000, "not json", &[]).is_err());
+
+    let log = ownership(OwnershipEnforcement::Log);
+    assert!(log.check_request(method, 1000, "not json", &[]).is_ok());
+}
+
+#[test]
+fn account_lists_are_scoped_to_the_caller() {
+    let response = r#"{"accounts": [
+        {"homeAccountId": "a.t", "username": "alice@example.com"},
+        {"homeAccountId": "b.t", "username": "bob@example.com"}
+    ]}"#;
+    let usernames = |response: String| -> Vec<String> {
+        let response: serde_json::Value =
+            serde_json::from_str(&response).unwrap();
+        response["accounts"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|a| a["username"].as_str().unwrap().to_string())
+            .collect()
+    };
+
+    for enforcement in [OwnershipEnforcement::Log, OwnershipEnforcement::Reject]
+    {
+        let ownership = ownership(enforcement);
+        let (request, scope) = ownership.take_scope(1000, "{}").unwrap();
+        let scoped = ownership.scope_response(1000, scope, response.into());
+        assert_eq!(request, "{}");
+        assert_eq!(usernames(scoped), ["alice@example.com"]);
+        // Only administrators may ask for every account.
+        assert!(ownership
+            .take_scope(1000, r#"{"allAccounts": true}"#)
+            .is_err());
+        let (_, scope) =
+            ownership.take_scope(0, r#"{"allAccounts": true}"#).unwrap();
+        let all = ownership.scope_response(0, scope, response.into());
+        let root =
+            ownership.scope_response(0, Default::default(), response.into());
+        assert!(usernames(root).is_empty());
+        assert_eq!(usernames(all).len(), 2);
+    }
+}
@@ -0,0 +1,44 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use identity_dbus_broker::{
+    AccountManagerConfig, PolkitPromptVerifier, PortalPromptHandler,
+    AUTHENTICATE_ACTION, MANAGE_ACCOUNTS_ACTION,
+};
+
+/* polkit cannot authorize an action it has no definition for, so each
+ * action the crate checks by default must be defined by the policy file
+ * shipped in polkit/.
+ */
+#[test]
+fn default_actions_are_defined() {
+    let policy = std::fs::read_to_string(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/polkit/org.himmelblau.broker.policy"
+    ))
+    .unwrap();
+    let defined =
+        |action: &str| policy.contains(&format!("<action id=\"{}\">", action));
+
+    assert!(defined(AUTHENTICATE_ACTION));
+    assert!(defined(MANAGE_ACCOUNTS_ACTION));
+    assert!(defined(&PolkitPromptVerifier::default().action_id));
+    assert!(defined(&PortalPromptHandler::default().polkit_action_id));
+    let manager = AccountManagerConfig::default();
+    assert!(defined(manager.polkit_action_id.as_deref().unwrap()));
+}
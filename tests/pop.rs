@@ -0,0 +1,100 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use identity_dbus_broker::{
+    pop_token_response, response_access_token, PopTokenRequest,
+    RequestConfirmation, Thumbprint,
+};
+use serde_json::{json, Value};
+
+// The kid of the req_cnf MSAL sends for an RSA key, with the req_cnf it
+// encodes to.
+const KID: &str = "NzbLsXh8uDCcd-6MNwXF4W_7noWXFZAfHkxZsRGC9Xs";
+const REQ_CNF: &str =
+    "eyJraWQiOiJOemJMc1hoOHVEQ2NkLTZNTndYRjRXXzdub1dYRlpBZkhreFpzUkdDOVhzIn0";
+
+#[test]
+fn request_confirmation() {
+    let cnf = RequestConfirmation::new(KID);
+    assert_eq!(cnf.encode(), REQ_CNF);
+    assert_eq!(RequestConfirmation::decode(REQ_CNF), Some(cnf.clone()));
+    assert_eq!(
+        RequestConfirmation::decode(&format!("{}==", REQ_CNF)),
+        Some(cnf)
+    );
+    assert_eq!(RequestConfirmation::decode("not base64!"), None);
+
+    let thumbprint = Thumbprint::parse(KID).unwrap();
+    assert_eq!(RequestConfirmation::for_thumbprint(&thumbprint).kid, KID);
+}
+
+#[test]
+fn pop_token_requests() {
+    let request = json!({
+        "authParameters": {
+            "clientId": "client",
+            "tokenType": "POP",
+            "popParams": {
+                "resourceRequestMethod": "GET",
+                "resourceRequestUri": "https://graph.microsoft.com/v1.0/me",
+            },
+        },
+    })
+    .to_string();
+    let mut pop = PopTokenRequest::from_request(&request).unwrap();
+    assert_eq!(pop.req_cnf, None);
+    assert_eq!(
+        pop.pop_params.resource_request_uri.as_deref(),
+        Some("https://graph.microsoft.com/v1.0/me")
+    );
+
+    pop.req_cnf = Some(REQ_CNF.to_string());
+    let bound = pop.apply(&request).unwrap();
+    let params =
+        &serde_json::from_str::<Value>(&bound).unwrap()["authParameters"];
+    assert_eq!(params["clientId"], "client");
+    assert_eq!(params["tokenType"], "pop");
+    assert_eq!(params["reqCnf"], REQ_CNF);
+    assert_eq!(PopTokenRequest::from_request(&bound), Some(pop));
+
+    let bearer = json!({"authParameters": {"clientId": "client"}});
+    assert_eq!(PopTokenRequest::from_request(&bearer.to_string()), None);
+}
+
+#[test]
+fn pop_token_responses() {
+    let resp = json!({
+        "brokerTokenResponse": {
+            "accessToken": "at",
+            "tokenType": "Bearer",
+            "expiresOn": 1700000000,
+        },
+    })
+    .to_string();
+    assert_eq!(response_access_token(&resp).as_deref(), Some("at"));
+    let pop = pop_token_response(&resp, "shr").unwrap();
+    let token =
+        &serde_json::from_str::<Value>(&pop).unwrap()["brokerTokenResponse"];
+    assert_eq!(token["accessToken"], "shr");
+    assert_eq!(token["tokenType"], "pop");
+    assert_eq!(token["expiresOn"], 1700000000);
+
+    let error = json!({"error": {"status": "InteractionRequired"}});
+    assert_eq!(response_access_token(&error.to_string()), None);
+    assert!(pop_token_response(&error.to_string(), "shr").is_err());
+}
@@ -0,0 +1,434 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+use identity_dbus_broker::fuzzing::{
+    decode_chunks, decode_chunks_in, decode_response_in, encode_request_in,
+    encode_response, encode_response_in, ClientRequest, ClientResponse,
+    Decoded, ProtocolError, ProtocolErrorKind,
+};
+use identity_dbus_broker::{
+    AccountEvent, BrokerError, CallerInfo, FdHandoff, FdKind,
+    FederationRequest, FederationRequestKind, FederationResponse,
+    LogLevelChange, PromptKind, PromptRequest, PromptResponse, WireFormatKind,
+};
+use proptest::prelude::*;
+
+const MAX_FRAME_SIZE: usize = 64 * 1024;
+
+fn args() -> impl Strategy<Value = (String, String, String)> {
+    (".{0,8}", "[0-9a-f-]{0,36}", ".{0,64}")
+}
+
+fn broker_error() -> impl Strategy<Value = BrokerError> {
+    prop_oneof![
+        ".{0,32}".prop_map(BrokerError::Transport),
+        ".{0,32}".prop_map(BrokerError::Timeout),
+        ".{0,32}".prop_map(BrokerError::InteractionRequired),
+        ".{0,32}".prop_map(BrokerError::InvalidRequest),
+        ("[A-Za-z]{0,16}", ".{0,32}")
+            .prop_map(|(code, msg)| BrokerError::Backend { code, msg }),
+        Just(BrokerError::Cancelled),
+        proptest::option::of(".{0,32}")
+            .prop_map(|nonce| BrokerError::NonceRequired { nonce }),
+        "[A-Za-z]{0,16}".prop_map(BrokerError::Disabled),
+        (any::<u64>(), ".{0,32}").prop_map(|(retry_after, reason)| {
+            BrokerError::Maintenance {
+                retry_after,
+                reason,
+            }
+        }),
+    ]
+}
+
+fn method_request() -> impl Strategy<Value = ClientRequest> {
+    prop_oneof![
+        args().prop_map(|(a, b, c)| {
+            ClientRequest::acquireTokenInteractively(a, b, c)
+        }),
+        args()
+            .prop_map(|(a, b, c)| ClientRequest::acquireTokenSilently(a, b, c)),
+        args().prop_map(|(a, b, c)| ClientRequest::getAccounts(a, b, c)),
+        args().prop_map(|(a, b, c)| ClientRequest::removeAccount(a, b, c)),
+        args().prop_map(|(a, b, c)| ClientRequest::removeAccounts(a, b, c)),
+        args().prop_map(|(a, b, c)| ClientRequest::getAccountsPage(a, b, c)),
+        args()
+            .prop_map(|(a, b, c)| ClientRequest::acquirePrtSsoCookie(a, b, c)),
+        args().prop_map(|(a, b, c)| {
+            ClientRequest::generateSignedHttpRequest(a, b, c)
+        }),
+        args().prop_map(|(a, b, c)| ClientRequest::cancelInteractiveFlow(
+            a, b, c
+        )),
+        args().prop_map(|(a, b, c)| ClientRequest::getLinuxBrokerVersion(
+            a, b, c
+        )),
+        args().prop_map(|(a, b, c)| ClientRequest::refreshPrt(a, b, c)),
+        args().prop_map(|(a, b, c)| {
+            ClientRequest::getDeviceComplianceStatus(a, b, c)
+        }),
+        args().prop_map(|(a, b, c)| ClientRequest::getSshCert(a, b, c)),
+        args().prop_map(|(a, b, c)| {
+            ClientRequest::completeInteractiveFlow(a, b, c)
+        }),
+        args().prop_map(|(a, b, c)| {
+            ClientRequest::startDeviceCodeFlow(a, b, c)
+        }),
+        args().prop_map(|(a, b, c)| ClientRequest::pollDeviceCodeFlow(a, b, c)),
+        args().prop_map(|(a, b, c)| {
+            ClientRequest::completeDeviceCodeFlow(a, b, c)
+        }),
+        any::<bool>().prop_map(|accepted| {
+            ClientRequest::promptResponse(PromptResponse { accepted })
+        }),
+        (any::<u16>(), ".{0,64}", proptest::option::of(".{0,32}")).prop_map(
+            |(status, body, error)| {
+                ClientRequest::federationResponse(FederationResponse {
+                    status,
+                    body,
+                    error,
+                })
+            }
+        ),
+        proptest::collection::vec(wire_format(), 0..3)
+            .prop_map(ClientRequest::negotiateFormat),
+        ("[a-z]{0,8}", proptest::option::of("[a-z_:]{0,32}")).prop_map(
+            |(level, module)| {
+                ClientRequest::setLogLevel(LogLevelChange { level, module })
+            }
+        ),
+        Just(ClientRequest::subscribe),
+    ]
+}
+
+fn account_event() -> impl Strategy<Value = AccountEvent> {
+    prop_oneof![
+        ".{0,36}".prop_map(AccountEvent::AccountAdded),
+        ".{0,36}".prop_map(AccountEvent::AccountRemoved),
+        ".{0,36}".prop_map(AccountEvent::TokensChanged),
+        (".{0,36}", ".{0,36}").prop_map(|(home_account_id, reason)| {
+            AccountEvent::TokenRevoked {
+                home_account_id,
+                reason,
+            }
+        }),
+        (".{0,36}", ".{0,36}").prop_map(|(home_account_id, reason)| {
+            AccountEvent::ReauthRequired {
+                home_account_id,
+                reason,
+            }
+        }),
+        Just(AccountEvent::Resync),
+    ]
+}
+
+fn wire_format() -> impl Strategy<Value = WireFormatKind> {
+    prop_oneof![
+        Just(WireFormatKind::Json),
+        Just(WireFormatKind::Cbor),
+        Just(WireFormatKind::Bincode),
+    ]
+}
+
+fn available_format() -> impl Strategy<Value = WireFormatKind> {
+    proptest::sample::select(WireFormatKind::available())
+}
+
+fn caller() -> impl Strategy<Value = CallerInfo> {
+    (
+        ":[0-9]\\.[0-9]{1,4}",
+        any::<Option<u32>>(),
+        any::<Option<u32>>(),
+        proptest::option::of(".{0,32}"),
+    )
+        .prop_map(|(bus_name, uid, pid, security_label)| CallerInfo {
+            bus_name,
+            uid,
+            pid,
+            security_label,
+            session: None,
+            app_id: None,
+        })
+}
+
+fn client_request() -> impl Strategy<Value = ClientRequest> {
+    prop_oneof![
+        3 => method_request(),
+        1 => (caller(), method_request()).prop_map(|(caller, req)| {
+            ClientRequest::withCaller(caller, Box::new(req))
+        }),
+    ]
+}
+
+fn client_response() -> impl Strategy<Value = ClientResponse> {
+    let prompt_kind = prop_oneof![
+        Just(PromptKind::UserPresence),
+        Just(PromptKind::Pin),
+        Just(PromptKind::Biometric),
+    ];
+    let fd_kind = prop_oneof![
+        Just(FdKind::Pipe),
+        Just(FdKind::Pty),
+        Just(FdKind::Display),
+    ];
+    let federation_kind = prop_oneof![
+        Just(FederationRequestKind::MetadataExchange),
+        Just(FederationRequestKind::WsTrust),
+    ];
+    let error_kind = prop_oneof![
+        Just(ProtocolErrorKind::FrameTooLarge),
+        Just(ProtocolErrorKind::MalformedFrame),
+    ];
+    prop_oneof![
+        ".{0,128}".prop_map(ClientResponse::result),
+        broker_error().prop_map(ClientResponse::error),
+        (prompt_kind, ".{0,36}", ".{0,64}").prop_map(
+            |(kind, correlation_id, message)| {
+                ClientResponse::promptRequest(PromptRequest {
+                    kind,
+                    correlation_id,
+                    message,
+                })
+            }
+        ),
+        (error_kind, ".{0,64}").prop_map(|(kind, message)| {
+            ClientResponse::protocolError(ProtocolError { kind, message })
+        }),
+        (
+            federation_kind,
+            ".{0,36}",
+            ".{0,64}",
+            proptest::option::of(".{0,64}"),
+            proptest::collection::vec((".{0,16}", ".{0,32}"), 0..3),
+        )
+            .prop_map(|(kind, correlation_id, url, body, headers)| {
+                ClientResponse::federationRequest(FederationRequest {
+                    kind,
+                    correlation_id,
+                    url,
+                    body,
+                    headers,
+                })
+            }),
+        wire_format().prop_map(ClientResponse::formatSelected),
+        Just(ClientResponse::subscribed),
+        Just(ClientResponse::shutdown),
+        account_event().prop_map(ClientResponse::event),
+        (fd_kind, ".{0,36}").prop_map(|(kind, correlation_id)| {
+            ClientResponse::fdHandoff(FdHandoff {
+                kind,
+                correlation_id,
+            })
+        }),
+    ]
+}
+
+/* Split `data` at the given (unordered, possibly repeated) offsets. */
+fn split_at<'a>(data: &'a [u8], cuts: &[usize]) -> Vec<&'a [u8]> {
+    let mut cuts: Vec<usize> =
+        cuts.iter().map(|cut| cut % (data.len() + 1)).collect();
+    cuts.sort_unstable();
+    let mut chunks = vec![];
+    let mut start = 0;
+    for cut in cuts {
+        chunks.push(&data[start..cut]);
+        start = cut;
+    }
+    chunks.push(&data[start..]);
+    chunks
+}
+
+proptest! {
+    #[test]
+    fn request_round_trips(req in client_request()) {
+        let data = serde_json::to_vec(&req).unwrap();
+        let decoded: ClientRequest = serde_json::from_slice(&data).unwrap();
+        prop_assert_eq!(decoded, req);
+    }
+
+    #[test]
+    fn response_round_trips(resp in client_response()) {
+        let data = encode_response(resp.clone()).unwrap();
+        let decoded: ClientResponse = serde_json::from_slice(&data).unwrap();
+        prop_assert_eq!(decoded, resp);
+    }
+
+    #[test]
+    fn responses_round_trip_in_every_format(
+        format in available_format(),
+        resp in client_response(),
+    ) {
+        let data = encode_response_in(format, resp.clone()).unwrap();
+        prop_assert_eq!(decode_response_in(format, &data).unwrap(), resp);
+    }
+
+    #[test]
+    fn split_frames_decode_in_every_format(
+        format in available_format(),
+        reqs in proptest::collection::vec(client_request(), 1..8),
+        cuts in proptest::collection::vec(any::<usize>(), 0..16),
+    ) {
+        let data: Vec<u8> = reqs
+            .iter()
+            .flat_map(|req| encode_request_in(format, req).unwrap())
+            .collect();
+        let expected: Vec<Decoded> =
+            reqs.into_iter().map(Decoded::Request).collect();
+        prop_assert_eq!(
+            decode_chunks_in(format, MAX_FRAME_SIZE, &split_at(&data, &cuts)),
+            expected
+        );
+    }
+
+    #[test]
+    fn concatenated_frames_decode_in_order(
+        reqs in proptest::collection::vec(client_request(), 1..8),
+    ) {
+        let data: Vec<u8> = reqs
+            .iter()
+            .flat_map(|req| serde_json::to_vec(req).unwrap())
+            .collect();
+        let expected: Vec<Decoded> =
+            reqs.into_iter().map(Decoded::Request).collect();
+        prop_assert_eq!(decode_chunks(MAX_FRAME_SIZE, &[&data]), expected);
+    }
+
+    #[test]
+    fn split_frames_decode_in_order(
+        reqs in proptest::collection::vec(client_request(), 1..8),
+        cuts in proptest::collection::vec(any::<usize>(), 0..16),
+    ) {
+        let data: Vec<u8> = reqs
+            .iter()
+            .flat_map(|req| serde_json::to_vec(req).unwrap())
+            .collect();
+        let expected: Vec<Decoded> =
+            reqs.into_iter().map(Decoded::Request).collect();
+        prop_assert_eq!(
+            decode_chunks(MAX_FRAME_SIZE, &split_at(&data, &cuts)),
+            expected
+        );
+    }
+
+    #[test]
+    fn malformed_frames_are_discarded(
+        garbage in "[^\\[{\"0-9tfn \t\r\n-][ -~]{0,32}",
+        req in client_request(),
+    ) {
+        let data = serde_json::to_vec(&req).unwrap();
+        let frames =
+            decode_chunks(MAX_FRAME_SIZE, &[garbage.as_bytes(), &data]);
+        prop_assert_eq!(frames.len(), 2);
+        let malformed = matches!(
+            &frames[0],
+            Decoded::Malformed(ProtocolError {
+                kind: ProtocolErrorKind::MalformedFrame,
+                ..
+            })
+        );
+        prop_assert!(malformed);
+        prop_assert_eq!(&frames[1], &Decoded::Request(req));
+    }
+
+    #[test]
+    fn oversized_frames_are_fatal(len in 65usize..512) {
+        let data = vec![b' '; len];
+        let frames = decode_chunks(64, &[&data]);
+        prop_assert_eq!(frames.len(), 1);
+        prop_assert!(matches!(frames[0], Decoded::Fatal(_)));
+    }
+
+    #[test]
+    fn arbitrary_input_does_not_panic(
+        data in proptest::collection::vec(any::<u8>(), 0..512),
+        cuts in proptest::collection::vec(any::<usize>(), 0..8),
+    ) {
+        decode_chunks(MAX_FRAME_SIZE, &split_at(&data, &cuts));
+    }
+}
+
+/* Nests `depth` withCaller requests around a getAccounts request, building
+ * the frame from the encoding of a single withCaller, since encoding (and
+ * dropping) a deeply nested request would itself exhaust the stack.
+ */
+fn nested_frame(format: WireFormatKind, depth: usize) -> Vec<u8> {
+    let framed = format != WireFormatKind::Json;
+    let payload = |req: &ClientRequest| {
+        let data = encode_request_in(format, req).unwrap();
+        if framed {
+            data[4..].to_vec()
+        } else {
+            data
+        }
+    };
+    let inner = ClientRequest::getAccounts(
+        "0.0".to_string(),
+        "nested".to_string(),
+        "{}".to_string(),
+    );
+    let caller = CallerInfo {
+        bus_name: ":1.42".to_string(),
+        ..Default::default()
+    };
+    let outer = ClientRequest::withCaller(caller, Box::new(inner.clone()));
+    let (inner, outer) = (payload(&inner), payload(&outer));
+    let at = outer
+        .windows(inner.len())
+        .position(|window| window == inner.as_slice())
+        .unwrap();
+    let (prefix, suffix) = (&outer[..at], &outer[at + inner.len()..]);
+
+    let mut data = prefix.repeat(depth);
+    data.extend_from_slice(&inner);
+    data.extend_from_slice(&suffix.repeat(depth));
+    if framed {
+        let mut frame = (data.len() as u32).to_be_bytes().to_vec();
+        frame.extend_from_slice(&data);
+        data = frame;
+    }
+    data
+}
+
+#[test]
+fn nested_caller_requests_are_malformed() {
+    for format in WireFormatKind::available() {
+        for depth in [2, 10_000] {
+            let data = nested_frame(format, depth);
+            let frames = decode_chunks_in(format, 8 * 1024 * 1024, &[&data]);
+            assert_eq!(frames.len(), 1, "{:?} at depth {}", format, depth);
+            assert!(
+                matches!(
+                    &frames[0],
+                    Decoded::Malformed(ProtocolError {
+                        kind: ProtocolErrorKind::MalformedFrame,
+                        ..
+                    })
+                ),
+                "{:?} at depth {}: {:?}",
+                format,
+                depth,
+                frames[0]
+            );
+        }
+        // A single caller request is accepted.
+        let data = nested_frame(format, 1);
+        let frames = decode_chunks_in(format, 8 * 1024 * 1024, &[&data]);
+        assert!(matches!(
+            &frames[0],
+            Decoded::Request(ClientRequest::withCaller(..))
+        ));
+    }
+}
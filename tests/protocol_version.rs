@@ -0,0 +1,66 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use identity_dbus_broker::{
+    is_flat_request, supported_versions, ProtocolVersion, BROKER1_METHODS,
+};
+
+#[test]
+fn parsing_and_ordering() {
+    assert_eq!("0.0".parse(), Ok(ProtocolVersion::V0_0));
+    assert_eq!(" 1 ".parse(), Ok(ProtocolVersion::V1_0));
+    assert_eq!("1.10".parse(), Ok(ProtocolVersion::new(1, 10)));
+    for invalid in ["", "1.", ".1", "1.0.0", "v1", "-1.0", "1.+2"] {
+        assert!(invalid.parse::<ProtocolVersion>().is_err(), "{}", invalid);
+    }
+    assert!(ProtocolVersion::new(1, 2) < ProtocolVersion::new(1, 10));
+    assert!(ProtocolVersion::new(0, 9) < ProtocolVersion::V1_0);
+    assert_eq!(ProtocolVersion::new(1, 10).to_string(), "1.10");
+    assert_eq!(
+        serde_json::to_string(&ProtocolVersion::V1_0).unwrap(),
+        "\"1.0\""
+    );
+
+    let version = ProtocolVersion::new(0, 1);
+    assert!(version.matches("0"));
+    assert!(version.matches("0.1"));
+    assert!(!version.matches("0.0"));
+    assert!(!version.matches("1"));
+}
+
+#[test]
+fn capability_matrix() {
+    let versions = supported_versions();
+    assert!(versions.windows(2).all(|v| v[0].version < v[1].version));
+
+    let legacy = ProtocolVersion::new(0, 3).capabilities().unwrap();
+    assert_eq!(legacy.version, ProtocolVersion::V0_0);
+    assert!(legacy.flat_requests);
+    let current = ProtocolVersion::new(1, 4).capabilities().unwrap();
+    assert_eq!(current.version, ProtocolVersion::V1_0);
+    assert!(!current.flat_requests);
+    assert!(BROKER1_METHODS
+        .iter()
+        .all(|method| current.supports(method)));
+    assert!(!current.supports("refreshPrt"));
+    assert_eq!(ProtocolVersion::new(2, 0).capabilities(), None);
+
+    assert!(is_flat_request(r#"{"clientId":"c","scopes":"openid"}"#));
+    assert!(!is_flat_request(r#"{"authParameters":{"clientId":"c"}}"#));
+    assert!(!is_flat_request("[]"));
+}
@@ -0,0 +1,201 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use async_trait::async_trait;
+use identity_dbus_broker::{
+    himmelblau_broker_serve_with_config, ArtifactKind, ArtifactRefresh,
+    BrokerError, CachedArtifact, HimmelblauBroker, HimmelblauBrokerConfig,
+    RefreshBroker, RefreshSchedulerConfig,
+};
+use libc::uid_t;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::sync::broadcast;
+
+/* A broker caching a PRT about to expire, a PRT with an hour left and an
+ * SSO cookie about to expire, which records what it is asked to refresh.
+ */
+#[derive(Clone, Default)]
+struct CachingBroker {
+    refreshed: Arc<Mutex<Vec<String>>>,
+}
+
+#[async_trait]
+impl HimmelblauBroker for CachingBroker {
+    async fn acquire_token_interactively(
+        &mut self,
+        _protocol_version: String,
+        _correlation_id: String,
+        _request_json: String,
+        _uid: uid_t,
+    ) -> Result<String, BrokerError> {
+        Ok("{}".to_string())
+    }
+    async fn acquire_token_silently(
+        &mut self,
+        _protocol_version: String,
+        _correlation_id: String,
+        _request_json: String,
+        _uid: uid_t,
+    ) -> Result<String, BrokerError> {
+        Ok("{}".to_string())
+    }
+    async fn get_accounts(
+        &mut self,
+        _protocol_version: String,
+        _correlation_id: String,
+        _request_json: String,
+        _uid: uid_t,
+    ) -> Result<String, BrokerError> {
+        Ok("{}".to_string())
+    }
+    async fn remove_account(
+        &mut self,
+        _protocol_version: String,
+        _correlation_id: String,
+        _request_json: String,
+        _uid: uid_t,
+    ) -> Result<String, BrokerError> {
+        Ok("{}".to_string())
+    }
+    async fn acquire_prt_sso_cookie(
+        &mut self,
+        _protocol_version: String,
+        _correlation_id: String,
+        _request_json: String,
+        _uid: uid_t,
+    ) -> Result<String, BrokerError> {
+        Ok("{}".to_string())
+    }
+    async fn generate_signed_http_request(
+        &mut self,
+        _protocol_version: String,
+        _correlation_id: String,
+        _request_json: String,
+        _uid: uid_t,
+    ) -> Result<String, BrokerError> {
+        Ok("{}".to_string())
+    }
+    async fn cancel_interactive_flow(
+        &mut self,
+        _protocol_version: String,
+        _correlation_id: String,
+        _request_json: String,
+        _uid: uid_t,
+    ) -> Result<String, BrokerError> {
+        Ok("{}".to_string())
+    }
+    async fn get_linux_broker_version(
+        &mut self,
+        _protocol_version: String,
+        _correlation_id: String,
+        _request_json: String,
+        _uid: uid_t,
+    ) -> Result<String, BrokerError> {
+        Ok("{}".to_string())
+    }
+
+    fn refresh(&mut self) -> Option<&mut (dyn RefreshBroker + Send)> {
+        Some(self)
+    }
+}
+
+#[async_trait]
+impl RefreshBroker for CachingBroker {
+    async fn cached_artifacts(
+        &mut self,
+        kind: ArtifactKind,
+    ) -> Result<Vec<CachedArtifact>, BrokerError> {
+        let artifact = |id: &str, expires_in: u64| CachedArtifact {
+            kind,
+            uid: 1000,
+            id: id.to_string(),
+            expires_at: SystemTime::now() + Duration::from_secs(expires_in),
+        };
+        Ok(match kind {
+            ArtifactKind::Prt => {
+                vec![artifact("later", 3600), artifact("soon", 60)]
+            }
+            ArtifactKind::SsoCookie => vec![artifact("cookie", 60)],
+        })
+    }
+
+    async fn refresh_artifact(
+        &mut self,
+        artifact: &CachedArtifact,
+    ) -> Result<(), BrokerError> {
+        self.refreshed.lock().unwrap().push(artifact.id.clone());
+        Ok(())
+    }
+}
+
+async fn refreshed_after_serving(
+    name: &str,
+    refresh: RefreshSchedulerConfig,
+) -> Vec<String> {
+    let path = std::env::temp_dir().join(format!(
+        "himmelblau-refresh-{}-{}.sock",
+        name,
+        std::process::id()
+    ));
+    let broker = CachingBroker::default();
+    let (shutdown, rx) = broadcast::channel(1);
+    let config = HimmelblauBrokerConfig {
+        refresh: Some(refresh),
+        ..Default::default()
+    };
+    let server =
+        himmelblau_broker_serve_with_config(broker.clone(), path, rx, config)
+            .await
+            .unwrap();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    shutdown.send(true).unwrap();
+    server.await.unwrap();
+    let refreshed = broker.refreshed.lock().unwrap().clone();
+    refreshed
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn refreshes_artifacts_due_when_idle() {
+    let refreshed = refreshed_after_serving(
+        "idle",
+        RefreshSchedulerConfig {
+            idle_for: 0,
+            prt: Some(ArtifactRefresh {
+                refresh_before: 600,
+            }),
+            sso_cookie: None,
+            ..Default::default()
+        },
+    )
+    .await;
+    assert_eq!(refreshed, vec!["soon"]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn waits_for_idle_to_refresh() {
+    let refreshed = refreshed_after_serving(
+        "busy",
+        RefreshSchedulerConfig {
+            idle_for: 60,
+            ..Default::default()
+        },
+    )
+    .await;
+    assert!(refreshed.is_empty());
+}
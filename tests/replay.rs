@@ -0,0 +1,121 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use identity_dbus_broker::{
+    BrokerError, ReplayAction, ReplayConfig, ReplayGuard,
+};
+
+const REQUEST: &str = r#"{"correlationId":"c1","scope":"openid"}"#;
+
+fn guard(action: ReplayAction) -> ReplayGuard {
+    ReplayGuard::new(&ReplayConfig {
+        window: 300,
+        action,
+    })
+}
+
+fn ok(resp: &str) -> Result<String, BrokerError> {
+    Ok(resp.to_string())
+}
+
+#[test]
+fn repeats_are_logged_and_handled() {
+    let replay = guard(ReplayAction::Log);
+    assert_eq!(replay.check(":1.1", "sign", "c1", REQUEST).unwrap(), None);
+    replay.complete(":1.1", "sign", "c1", &ok("first"));
+    // The repeat is handled again rather than answered from the first call.
+    assert_eq!(replay.check(":1.1", "sign", "c1", REQUEST).unwrap(), None);
+    assert_eq!(replay.check(":1.2", "sign", "c1", REQUEST).unwrap(), None);
+}
+
+#[test]
+fn dedupe_only_answers_the_same_sender() {
+    let replay = guard(ReplayAction::Dedupe);
+    assert_eq!(replay.check(":1.1", "sign", "c1", REQUEST).unwrap(), None);
+    // A concurrent repeat has no response to dedupe to yet.
+    assert_eq!(replay.check(":1.1", "sign", "c1", REQUEST).unwrap(), None);
+    replay.complete(":1.1", "sign", "c1", &ok("first"));
+    assert_eq!(
+        replay.check(":1.1", "sign", "c1", REQUEST).unwrap(),
+        Some("first".to_string())
+    );
+    // Another connection never sees the first caller's response.
+    assert_eq!(replay.check(":1.2", "sign", "c1", REQUEST).unwrap(), None);
+    replay.complete(":1.2", "sign", "c1", &ok("second"));
+    assert_eq!(
+        replay.check(":1.1", "sign", "c1", REQUEST).unwrap(),
+        Some("first".to_string())
+    );
+    // Nor does the same connection sending a different request.
+    let other = r#"{"correlationId":"c1","scope":"profile"}"#;
+    assert_eq!(replay.check(":1.1", "sign", "c1", other).unwrap(), None);
+    // Correlation ids are remembered per method.
+    assert_eq!(
+        replay.check(":1.1", "decrypt", "c1", REQUEST).unwrap(),
+        None
+    );
+}
+
+#[test]
+fn reject_fails_repeats() {
+    let replay = guard(ReplayAction::Reject);
+    assert_eq!(replay.check(":1.1", "sign", "c1", REQUEST).unwrap(), None);
+    replay.complete(":1.1", "sign", "c1", &ok("first"));
+    for sender in [":1.1", ":1.2"] {
+        match replay.check(sender, "sign", "c1", REQUEST) {
+            Err(BrokerError::InvalidRequest(msg)) => {
+                assert!(msg.contains("c1"))
+            }
+            other => panic!("repeat was not rejected: {:?}", other),
+        }
+    }
+    assert_eq!(replay.check(":1.1", "sign", "c2", REQUEST).unwrap(), None);
+}
+
+#[test]
+fn failed_calls_may_be_retried() {
+    let replay = guard(ReplayAction::Reject);
+    assert_eq!(replay.check(":1.1", "sign", "c1", REQUEST).unwrap(), None);
+    replay.complete::<BrokerError>(
+        ":1.1",
+        "sign",
+        "c1",
+        &Err(BrokerError::InvalidRequest("failed".to_string())),
+    );
+    assert_eq!(replay.check(":1.1", "sign", "c1", REQUEST).unwrap(), None);
+}
+
+#[test]
+fn calls_without_a_correlation_id_are_not_checked() {
+    let replay = guard(ReplayAction::Reject);
+    for _ in 0..2 {
+        assert_eq!(replay.check(":1.1", "sign", "", "{}").unwrap(), None);
+        replay.complete(":1.1", "sign", "", &ok("done"));
+    }
+}
+
+#[test]
+fn ids_are_forgotten_after_the_window() {
+    let replay = ReplayGuard::new(&ReplayConfig {
+        window: 0,
+        action: ReplayAction::Reject,
+    });
+    assert_eq!(replay.check(":1.1", "sign", "c1", REQUEST).unwrap(), None);
+    replay.complete(":1.1", "sign", "c1", &ok("first"));
+    assert_eq!(replay.check(":1.1", "sign", "c1", REQUEST).unwrap(), None);
+}
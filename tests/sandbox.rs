@@ -0,0 +1,52 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use identity_dbus_broker::{Sandbox, SocketAddress};
+use std::path::{Path, PathBuf};
+
+/* Confining the process would confine the rest of the test run, so only
+ * the refusal to confine a process with threads running is tested here.
+ */
+#[test]
+fn sandbox_refuses_running_threads() {
+    let worker = std::thread::spawn(|| {
+        std::thread::sleep(std::time::Duration::from_millis(200))
+    });
+    let err = Sandbox::new().read("/etc").apply().unwrap_err();
+    assert!(err.to_string().contains("before other threads"), "{}", err);
+    worker.join().unwrap();
+}
+
+#[test]
+fn daemon_sandbox_allows_what_the_daemon_reads() {
+    let sandbox = Sandbox::daemon();
+    for path in ["/etc/passwd", "/proc"] {
+        assert!(sandbox.read.contains(&PathBuf::from(path)), "{}", path);
+    }
+    assert!(sandbox.read.iter().all(|path| path.exists()));
+    assert!(sandbox.execute.is_empty());
+    assert!(sandbox.keep_capabilities.is_empty());
+}
+
+#[test]
+fn sockets_allow_their_directory() {
+    let sandbox = Sandbox::new()
+        .socket(&SocketAddress::Path("/run/himmelblau/broker.sock".into()))
+        .socket(&SocketAddress::Abstract("himmelblau".to_string()));
+    assert_eq!(sandbox.read_write, [Path::new("/run/himmelblau")]);
+}
@@ -0,0 +1,72 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use identity_dbus_broker::{
+    BrokerErrorName, CallerInfo, LockedAction, ScreenLockPolicy, SessionInfo,
+};
+
+#[test]
+fn token_methods_are_denied_while_locked() {
+    let policy = ScreenLockPolicy::default();
+    let mut locked = CallerInfo {
+        session: Some(SessionInfo {
+            locked: true,
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let unknown = CallerInfo::default();
+
+    let e = policy
+        .check("acquireTokenSilently", Some(&locked))
+        .unwrap_err();
+    assert_eq!(e.errorname(), "org.freedesktop.DBus.Error.AccessDenied");
+    assert!(policy.check("getAccounts", Some(&locked)).is_ok());
+    // Callers without a known session are let through.
+    assert!(policy.check("acquireTokenSilently", Some(&unknown)).is_ok());
+
+    locked.session.as_mut().unwrap().locked = false;
+    assert!(policy.check("acquireTokenSilently", Some(&locked)).is_ok());
+    assert!(policy.check("acquireTokenSilently", None).is_ok());
+}
+
+#[test]
+fn actions_are_set_per_method() {
+    let policy: ScreenLockPolicy = serde_json::from_str(
+        r#"{"methods": {"acquirePrtSsoCookie": "reauthenticate"}}"#,
+    )
+    .unwrap();
+    assert_eq!(
+        policy.methods.get("acquirePrtSsoCookie"),
+        Some(&LockedAction::Reauthenticate)
+    );
+    let locked = CallerInfo {
+        session: Some(SessionInfo {
+            locked: true,
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+
+    let e = policy
+        .check("acquirePrtSsoCookie", Some(&locked))
+        .unwrap_err();
+    assert_eq!(e.errorname(), BrokerErrorName::InteractionRequired.as_str());
+    // Methods left out of a configured policy are allowed.
+    assert!(policy.check("acquireTokenSilently", Some(&locked)).is_ok());
+}
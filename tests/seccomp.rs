@@ -0,0 +1,55 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use identity_dbus_broker::SeccompFilter;
+
+/* A seccomp filter cannot be removed, so it is installed in a forked child,
+ * which reports through its exit status whether a call the broker needs
+ * was allowed (1), one outside the filter was refused (2), and one added
+ * with `allow` was allowed (3).
+ */
+#[test]
+fn seccomp_filter_refuses_other_calls() {
+    let pid = unsafe { libc::fork() };
+    assert!(pid >= 0);
+    if pid == 0 {
+        let code = if SeccompFilter::new()
+            .allow(libc::SYS_getsid)
+            .apply()
+            .is_err()
+        {
+            10
+        } else if unsafe { libc::syscall(libc::SYS_getpid) } < 0 {
+            1
+        } else if unsafe { libc::syscall(libc::SYS_getpgid, 0) } != -1
+            || std::io::Error::last_os_error().raw_os_error()
+                != Some(libc::EPERM)
+        {
+            2
+        } else if unsafe { libc::syscall(libc::SYS_getsid, 0) } < 0 {
+            3
+        } else {
+            0
+        };
+        unsafe { libc::_exit(code) };
+    }
+    let mut status = 0;
+    assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+    assert!(libc::WIFEXITED(status));
+    assert_eq!(libc::WEXITSTATUS(status), 0);
+}
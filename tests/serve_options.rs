@@ -0,0 +1,38 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use identity_dbus_broker::ServeOptions;
+
+#[test]
+fn invalid_paths_and_names_are_refused() {
+    let options = ServeOptions::new()
+        .object_path("/org/example/broker1")
+        .bus_name("org.example.Broker1");
+    assert!(options.check().is_ok());
+
+    let e = ServeOptions::new()
+        .object_path("org/example/broker1")
+        .check()
+        .unwrap_err();
+    assert_eq!(e.errorname(), "org.freedesktop.DBus.Error.InvalidArgs");
+    assert!(ServeOptions::new()
+        .object_path("/org/example/")
+        .check()
+        .is_err());
+    assert!(ServeOptions::new().bus_name("not a name").check().is_err());
+}
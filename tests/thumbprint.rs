@@ -0,0 +1,129 @@
+/*
+   Unix Azure Entra ID implementation
+   Copyright (C) David Mulder <dmulder@samba.org> 2024
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Lesser General Public License as published by
+   the Free Software Foundation, either version 3 of the License, or
+   (at your option) any later version.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+   GNU Lesser General Public License for more details.
+
+   You should have received a copy of the GNU Lesser General Public License
+   along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+use identity_dbus_broker::{Thumbprint, ThumbprintAlgorithm};
+use openssl::x509::X509;
+
+/* No output captured from a Windows broker (certutil, dsregcmd) is at
+ * hand, so thumbprints are checked against Microsoft's own instead: the
+ * Windows thumbprints Microsoft publishes for its root certificates, kept
+ * in tests/microsoft/. The device certificate below is a generated one, in
+ * the shape of an Entra ID device certificate, whose thumbprints were
+ * computed independently with `openssl x509 -fingerprint` and `openssl
+ * dgst`, in the forms Windows reports them (uppercase hex, and unpadded
+ * base64url as in x5t).
+ */
+const CERT: &str = "-----BEGIN CERTIFICATE-----
+MIIBszCCAVmgAwIBAgIUBFJ4gNgonfxVFFsFtdYILmj3u6UwCgYIKoZIzj0EAwIw
+LzEtMCsGA1UEAwwkN0U5ODBBRDktQjg2RC00MzA2LTk0MjUtOUFDMDY2RkIwMTRB
+MB4XDTI2MTAxNjEwMjUzNFoXDTM2MTAxMzEwMjUzNFowLzEtMCsGA1UEAwwkN0U5
+ODBBRDktQjg2RC00MzA2LTk0MjUtOUFDMDY2RkIwMTRBMFkwEwYHKoZIzj0CAQYI
+KoZIzj0DAQcDQgAEtcOfJ/FaWmJVLAHKEx46hFIeuRxr0bs4yBfxstsAeRyO2Tds
+WdnmC4hNCaxoCfErt0qTZwggedNwXLmYtas+U6NTMFEwHQYDVR0OBBYEFKvAMkoe
+3NeNznbAgKobatyW9TDZMB8GA1UdIwQYMBaAFKvAMkoe3NeNznbAgKobatyW9TDZ
+MA8GA1UdEwEB/wQFMAMBAf8wCgYIKoZIzj0EAwIDSAAwRQIhANDPnbLxg+IJ+Er2
+8hfLE09J3V625AWCyO6AcOlxzv4LAiB99wyD4o1J0Yh8lPxxalW9+fl7sgFv0wJg
+O7WboYSkqA==
+-----END CERTIFICATE-----
+";
+const CERT_SHA1: &str = "6B7D4312EDEDDB9CA1E35D28D6A3247D4FEF41B0";
+const CERT_SHA256: &str =
+    "AD1C85458FE2895E9FDFFC612F2DAFF1B0610B51CCBDB4437405BD341C40E1DD";
+const CERT_X5T: &str = "a31DEu3t25yh410o1qMkfU_vQbA";
+
+// Thumbprints of the certificate's SubjectPublicKeyInfo.
+const KEY_SHA1: &str = "62323E0569E92DCBBA0757D084F3E48CCB83A1D5";
+const KEY_SHA256_B64URL: &str = "mQqMEtCEXTO9lDezhLk-mpBm8tpXjg3w6cs5bwMcK9Y";
+
+fn cert() -> X509 {
+    X509::from_pem(CERT.as_bytes()).unwrap()
+}
+
+fn microsoft_cert(name: &str) -> X509 {
+    let path = format!(
+        "{}/tests/microsoft/{}.pem",
+        env!("CARGO_MANIFEST_DIR"),
+        name
+    );
+    X509::from_pem(&std::fs::read(path).unwrap()).unwrap()
+}
+
+#[test]
+fn windows_thumbprints_of_microsoft_roots() {
+    let roots = [
+        (
+            "MicrosoftRSARootCertificateAuthority2017",
+            "73A5E64A3BFF8316FF0EDCCC618A906E4EAE4D74",
+        ),
+        (
+            "MicrosoftECCRootCertificateAuthority2017",
+            "999A64C37FF47D9FAB95F14769891460EEC4C3C5",
+        ),
+    ];
+    for (name, windows) in roots {
+        let sha1 = Thumbprint::of_certificate(
+            &microsoft_cert(name),
+            ThumbprintAlgorithm::Sha1,
+        )
+        .unwrap();
+        assert_eq!(sha1.to_string(), windows, "{}", name);
+        assert!(sha1.matches(&windows.to_lowercase()), "{}", name);
+    }
+}
+
+#[test]
+fn certificate_thumbprints() {
+    let sha1 =
+        Thumbprint::of_certificate(&cert(), ThumbprintAlgorithm::Sha1).unwrap();
+    assert_eq!(sha1.to_hex(), CERT_SHA1);
+    assert_eq!(sha1.to_string(), CERT_SHA1);
+    assert_eq!(sha1.to_base64url(), CERT_X5T);
+
+    let sha256 =
+        Thumbprint::of_certificate(&cert(), ThumbprintAlgorithm::Sha256)
+            .unwrap();
+    assert_eq!(sha256.to_hex(), CERT_SHA256);
+}
+
+#[test]
+fn public_key_thumbprints() {
+    let key = cert().public_key().unwrap();
+    let sha1 =
+        Thumbprint::of_public_key(&key, ThumbprintAlgorithm::Sha1).unwrap();
+    assert_eq!(sha1.to_hex(), KEY_SHA1);
+    let sha256 =
+        Thumbprint::of_public_key(&key, ThumbprintAlgorithm::Sha256).unwrap();
+    assert_eq!(sha256.to_base64url(), KEY_SHA256_B64URL);
+}
+
+#[test]
+fn parse_any_encoding() {
+    let sha1 =
+        Thumbprint::of_certificate(&cert(), ThumbprintAlgorithm::Sha1).unwrap();
+    assert!(sha1.matches(CERT_SHA1));
+    assert!(sha1.matches(&CERT_SHA1.to_lowercase()));
+    assert!(sha1.matches(
+        "6b 7d 43 12 ed ed db 9c a1 e3 5d 28 d6 a3 24 7d 4f ef 41 b0"
+    ));
+    assert!(sha1.matches(
+        "6B:7D:43:12:ED:ED:DB:9C:A1:E3:5D:28:D6:A3:24:7D:4F:EF:41:B0"
+    ));
+    assert!(sha1.matches(CERT_X5T));
+    assert!(sha1.matches(&format!("{}=", CERT_X5T)));
+    assert!(!sha1.matches(KEY_SHA1));
+    assert!(Thumbprint::parse("not a thumbprint!").is_err());
+}